@@ -0,0 +1,293 @@
+use std::env;
+
+use ark_bls12_381::Bls12_381;
+use ark_crypto_primitives::commitment::pedersen::Randomness;
+use ark_crypto_primitives::snark::SNARK;
+use ark_ec::{AffineRepr, CurveGroup};
+use ark_ed_on_bls12_381::Fr;
+use ark_ff::{PrimeField, UniformRand};
+use ark_groth16::{prepare_verifying_key, Groth16};
+use ark_relations::r1cs::{
+    ConstraintSynthesizer, ConstraintSystem, ConstraintSystemRef, SynthesisError,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rand::thread_rng;
+
+use zcash_sapling_protocol::circuit::{
+    ConstraintF, Output, OutputPublicInputs, SpendPublicInputs,
+};
+use zcash_sapling_protocol::commitment::{
+    homomorphic_pedersen_commitment, note_commitment, NoteCommitmentMode, ValueCommitTrapdoor,
+};
+use zcash_sapling_protocol::keygen::KeyChain;
+use zcash_sapling_protocol::merkle::{
+    compute_root, MerkleHash, PedersenMerkleHash, PoseidonMerkleHash, WithDepth, SAPLING_DEPTH,
+};
+use zcash_sapling_protocol::note::NoteValue;
+use zcash_sapling_protocol::spend_description::{Nullifier, NullifierMode};
+use zcash_sapling_protocol::thread_pool::ProvingPool;
+use zcash_sapling_protocol::witness::{OutputWitness, SpendWitness};
+
+const SK: &[u8] = &[7; 32];
+
+/// Comma-separated tree depths to measure Spend at, each in
+/// `1..=SAPLING_DEPTH`.
+const DEPTHS_VAR: &str = "SPEND_BENCH_DEPTHS";
+const DEFAULT_DEPTHS: &str = "16,32";
+/// Comma-separated Merkle hashes to measure Spend with, `poseidon` or
+/// `pedersen`.
+const TREES_VAR: &str = "SPEND_BENCH_TREES";
+const DEFAULT_TREES: &str = "poseidon,pedersen";
+
+/// The comma-separated values of the environment variable `var`.
+fn env_list(var: &str, default: &str) -> Vec<String> {
+    env::var(var)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// A witness spending a 2-zatoshi note of `SK` at position 1000 of a tree
+/// with random siblings.
+fn spend_witness<H: MerkleHash>(
+    note_commitment_mode: NoteCommitmentMode,
+    nullifier_mode: NullifierMode,
+) -> SpendWitness {
+    let mut rng = thread_rng();
+    let kc = KeyChain::from(SK);
+    let (_, g_d, pk_d) = kc.get_diversified_transmission_address();
+    let (alpha, rk) = kc.get_randomized_ak();
+
+    let note_value = NoteValue(2);
+    let rcv = ValueCommitTrapdoor::random();
+    let rcm = Fr::rand(&mut rng);
+    let cm = note_commitment(note_commitment_mode, &g_d, &pk_d.0, &note_value, &Randomness(rcm));
+
+    let pos = 1000u64;
+    let auth_path: Vec<(ConstraintF, bool)> = (0..H::DEPTH)
+        .map(|i| (ConstraintF::rand(&mut rng), (pos >> i) & 1 == 1))
+        .collect();
+
+    SpendWitness {
+        public_inputs: SpendPublicInputs {
+            rk: rk.0,
+            cv: homomorphic_pedersen_commitment(note_value.clone(), &rcv),
+            anchor: compute_root::<H>(H::leaf(&cm), &auth_path),
+            nf: Nullifier::new(cm, pos, kc.nk.0, nullifier_mode),
+        },
+        ak: kc.ak.0,
+        alpha,
+        nsk: kc.nsk.0,
+        note_value,
+        rcv,
+        rcm,
+        g_d,
+        auth_path,
+        note_commitment_mode,
+        nullifier_mode,
+        ivk_mode: kc.ivk_mode,
+    }
+}
+
+/// A witness creating a 10-zatoshi note to an address of `SK`.
+fn output_witness(note_commitment_mode: NoteCommitmentMode) -> OutputWitness {
+    let mut rng = thread_rng();
+    let kc = KeyChain::from(SK);
+    let (_, g_d, pk_d) = kc.get_diversified_transmission_address();
+
+    let note_value = NoteValue(10);
+    let rcv = ValueCommitTrapdoor::random();
+    let rcm = Fr::rand(&mut rng);
+    let esk = Fr::rand(&mut rng);
+
+    OutputWitness {
+        public_inputs: OutputPublicInputs {
+            cv: homomorphic_pedersen_commitment(note_value.clone(), &rcv),
+            cm: note_commitment(note_commitment_mode, &g_d, &pk_d.0, &note_value, &Randomness(rcm)),
+            epk: g_d.mul_bigint(esk.into_bigint()).into_affine(),
+        },
+        g_d,
+        pk_d: pk_d.0,
+        note_value,
+        rcv,
+        rcm,
+        esk,
+        note_commitment_mode,
+    }
+}
+
+type Synthesize =
+    dyn FnOnce(ConstraintSystemRef<ConstraintF>) -> Result<(), SynthesisError> + Send;
+
+/// A circuit that owns its witness, so that one can be built per iteration.
+/// It is boxed so that Groth16 is compiled once rather than for every depth.
+struct BoxedCircuit(Box<Synthesize>);
+
+impl ConstraintSynthesizer<ConstraintF> for BoxedCircuit {
+    fn generate_constraints(
+        self,
+        cs: ConstraintSystemRef<ConstraintF>,
+    ) -> Result<(), SynthesisError> {
+        (self.0)(cs)
+    }
+}
+
+/// Times synthesis, key generation, proving on one thread and, with the
+/// `parallel` feature, on every CPU, and verification of the circuits `circuit` builds.
+fn bench_circuit(
+    c: &mut Criterion,
+    name: &str,
+    circuit: &dyn Fn() -> BoxedCircuit,
+    public_inputs: Vec<ConstraintF>,
+) {
+    let mut rng = thread_rng();
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+
+    group.bench_function("constraints", |b| {
+        b.iter(|| {
+            let cs = ConstraintSystem::new_ref();
+            circuit().generate_constraints(cs.clone()).unwrap();
+            cs.num_constraints()
+        })
+    });
+
+    group.bench_function("setup", |b| {
+        b.iter(|| Groth16::<Bls12_381>::circuit_specific_setup(circuit(), &mut rng).unwrap())
+    });
+
+    let (pk, vk) = Groth16::<Bls12_381>::circuit_specific_setup(circuit(), &mut rng).unwrap();
+    let pools = [ProvingPool::new(1), ProvingPool::default()];
+    let pools = if pools[1].num_threads() > 1 { &pools[..] } else { &pools[..1] };
+    for pool in pools {
+        let threads = pool.num_threads();
+        group.bench_with_input(BenchmarkId::new("prove", threads), &threads, |b, _| {
+            b.iter_batched(
+                circuit,
+                |circuit| {
+                    pool.install(|| {
+                        Groth16::<Bls12_381>::prove(&pk, circuit, &mut thread_rng()).unwrap()
+                    })
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    let pvk = prepare_verifying_key(&vk);
+    let proof = Groth16::<Bls12_381>::prove(&pk, circuit(), &mut rng).unwrap();
+    group.bench_function("verify", |b| {
+        b.iter(|| {
+            assert!(
+                Groth16::<Bls12_381>::verify_with_processed_vk(&pvk, &public_inputs, &proof)
+                    .unwrap()
+            )
+        })
+    });
+
+    group.finish();
+}
+
+fn bench_spend<H: MerkleHash + Send>(
+    c: &mut Criterion,
+    tree: &str,
+    note_commitment_mode: NoteCommitmentMode,
+    nullifier_mode: NullifierMode,
+) {
+    let witness = spend_witness::<H>(note_commitment_mode, nullifier_mode);
+    bench_circuit(
+        c,
+        &format!("spend/{}/depth {}", tree, H::DEPTH),
+        &|| {
+            let witness = witness.clone();
+            BoxedCircuit(Box::new(move |cs| {
+                witness.with_circuit::<H, _>(|spend| spend.generate_constraints(cs))
+            }))
+        },
+        witness.public_inputs.to_field_elements(),
+    );
+}
+
+/// Spends on a tree of `H` at each of `depths`. The depth of a circuit is
+/// fixed at compile time, so every depth up to `SAPLING_DEPTH` is built in.
+fn bench_tree<H: MerkleHash + Send>(
+    c: &mut Criterion,
+    tree: &str,
+    depths: &[usize],
+    note_commitment_mode: NoteCommitmentMode,
+    nullifier_mode: NullifierMode,
+) {
+    macro_rules! bench_depths {
+        ($($depth:literal)*) => {
+            for &depth in depths {
+                match depth {
+                    $($depth => bench_spend::<WithDepth<H, $depth>>(
+                        c,
+                        tree,
+                        note_commitment_mode,
+                        nullifier_mode,
+                    ),)*
+                    _ => panic!("{}: depth {} is not in 1..={}", DEPTHS_VAR, depth, SAPLING_DEPTH),
+                }
+            }
+        };
+    }
+
+    bench_depths!(
+        1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16
+        17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+    );
+}
+
+/// Spends with the trees of `SPEND_BENCH_TREES` at the depths of
+/// `SPEND_BENCH_DEPTHS`.
+fn spend(c: &mut Criterion) {
+    let depths: Vec<usize> = env_list(DEPTHS_VAR, DEFAULT_DEPTHS)
+        .iter()
+        .map(|d| d.parse().unwrap_or_else(|_| panic!("{}: invalid depth {}", DEPTHS_VAR, d)))
+        .collect();
+
+    for tree in env_list(TREES_VAR, DEFAULT_TREES) {
+        match tree.as_str() {
+            "poseidon" => bench_tree::<PoseidonMerkleHash>(
+                c,
+                "poseidon",
+                &depths,
+                NoteCommitmentMode::Arkworks,
+                NullifierMode::Poseidon,
+            ),
+            "pedersen" => bench_tree::<PedersenMerkleHash>(
+                c,
+                "pedersen",
+                &depths,
+                NoteCommitmentMode::Sapling,
+                NullifierMode::Sapling,
+            ),
+            _ => panic!("{}: unknown tree {}", TREES_VAR, tree),
+        }
+    }
+}
+
+fn output(c: &mut Criterion) {
+    for (name, mode) in [
+        ("output/arkworks", NoteCommitmentMode::Arkworks),
+        ("output/sapling", NoteCommitmentMode::Sapling),
+    ] {
+        let output = Output::from(&output_witness(mode));
+        let public_inputs = output.public_inputs.as_ref().unwrap().to_field_elements();
+        bench_circuit(
+            c,
+            name,
+            &|| {
+                let output = output.clone();
+                BoxedCircuit(Box::new(move |cs| output.generate_constraints(cs)))
+            },
+            public_inputs,
+        );
+    }
+}
+
+criterion_group!(benches, spend, output);
+criterion_main!(benches);
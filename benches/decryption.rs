@@ -0,0 +1,72 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use zcash_sapling_protocol::address::PaymentAddress;
+use zcash_sapling_protocol::batch_decryption::batch_try_compact_note_decryption;
+use zcash_sapling_protocol::commitment::NoteCommitmentMode;
+use zcash_sapling_protocol::keygen::{IncomingViewingKey, KeyChain};
+use zcash_sapling_protocol::memo::Memo;
+use zcash_sapling_protocol::note::NoteValue;
+use zcash_sapling_protocol::note_encryption::try_compact_note_decryption;
+use zcash_sapling_protocol::output_description::OutputDescription;
+use zcash_sapling_protocol::prover::MockProver;
+use zcash_sapling_protocol::scan::CompactOutput;
+
+const OUTPUTS: usize = 10;
+
+fn ivk(i: usize) -> IncomingViewingKey {
+    let seed = [(i % 256) as u8, (i / 256) as u8, 1, 2, 3, 4, 5, 6].repeat(4);
+    IncomingViewingKey::from(&KeyChain::from(&seed[..]))
+}
+
+/// Trial decryption of `OUTPUTS` compact outputs, none of them to the keys,
+/// one key at a time and as a batch.
+fn decryption(c: &mut Criterion) {
+    let recipient = PaymentAddress::from(KeyChain::from(&[0xff; 32][..]));
+    let outputs: Vec<_> = (0..OUTPUTS)
+        .map(|i| {
+            let (output, _) = OutputDescription::new(
+                &MockProver,
+                &[0; 32],
+                &recipient,
+                NoteValue(i as u64),
+                &Memo::Empty,
+                NoteCommitmentMode::Arkworks,
+            )
+            .unwrap();
+            CompactOutput::from(&output)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("trial decryption");
+    group.sample_size(10);
+    for keys in [1, 8, 64, 512] {
+        let ivks: Vec<_> = (0..keys).map(ivk).collect();
+        group.throughput(Throughput::Elements((keys * OUTPUTS) as u64));
+
+        group.bench_with_input(BenchmarkId::new("single", keys), &ivks, |b, ivks| {
+            b.iter(|| {
+                outputs
+                    .iter()
+                    .flat_map(|output| {
+                        ivks.iter().filter_map(|ivk| {
+                            try_compact_note_decryption(
+                                &ivk.0,
+                                &output.epk,
+                                &output.cmu,
+                                &output.ciphertext,
+                                NoteCommitmentMode::Arkworks,
+                            )
+                        })
+                    })
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("batch", keys), &ivks, |b, ivks| {
+            b.iter(|| batch_try_compact_note_decryption(ivks, &outputs, NoteCommitmentMode::Arkworks).len())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decryption);
+criterion_main!(benches);
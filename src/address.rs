@@ -1,11 +1,26 @@
-use ark_ec::AffineRepr;
 use ark_ed_on_bls12_381::EdwardsAffine;
+use bech32::{FromBase32, ToBase32, Variant};
 
+use crate::group_hash::{self, is_small_order};
 use crate::keygen::{KeyChain, PublicKey};
 
+/// Which chain an encoded address or payment request belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
+}
 
+impl Network {
+    pub fn sapling_hrp(self) -> &'static str {
+        match self {
+            Network::Main => "zs",
+            Network::Test => "ztestsapling",
+        }
+    }
+}
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PaymentAddress {
     diversifier: [u8; 11],
     pk_d: PublicKey,
@@ -16,7 +31,7 @@ impl<'a> From<KeyChain<'a>> for PaymentAddress {
         let (d, _,  pk_d) = value.get_diversified_transmission_address();
         Self {
             diversifier: d,
-            pk_d: pk_d
+            pk_d
         }
     }
 }
@@ -27,16 +42,60 @@ impl From<[u8; 43]> for PaymentAddress {
         d.copy_from_slice(&value[..11]);
         let mut pk_db: [u8; 32] = [0; 32];
         pk_db.copy_from_slice(&value[11..]);
-        let pk_d: PublicKey = PublicKey(EdwardsAffine::from_random_bytes(&pk_db).unwrap());
+        let pk_d: PublicKey = PublicKey(group_hash::abst_j(&pk_db).unwrap());
 
         PaymentAddress {
             diversifier: d,
-            pk_d: pk_d,
+            pk_d,
         }
     }
 }
 
 impl PaymentAddress {
+    /// Decodes `to_bytes`, checking that the diversifier is valid and that
+    /// `pk_d` is a canonical point that is not of small order.
+    pub fn from_bytes(bytes: &[u8; 43]) -> Option<Self> {
+        let diversifier: [u8; 11] = bytes[..11].try_into().unwrap();
+        let _g_d = group_hash::diversify_hash(&diversifier)?;
+        let pk_d = group_hash::abst_j(bytes[11..].try_into().unwrap())?;
+        if is_small_order(&pk_d) {
+            return None;
+        }
+
+        Some(PaymentAddress {
+            diversifier,
+            pk_d: PublicKey(pk_d),
+        })
+    }
+
+    pub fn diversifier(&self) -> &[u8; 11] {
+        &self.diversifier
+    }
+
+    pub fn g_d(&self) -> EdwardsAffine {
+        group_hash::diversify_hash(&self.diversifier).expect("diversifier was checked")
+    }
+
+    pub fn pk_d(&self) -> &EdwardsAffine {
+        &self.pk_d.0
+    }
+
+    /// The Bech32 encoding, e.g. `zs1...` on mainnet.
+    pub fn encode(&self, network: Network) -> String {
+        bech32::encode(network.sapling_hrp(), self.to_bytes().to_base32(), Variant::Bech32)
+            .expect("valid human-readable part")
+    }
+
+    pub fn decode(address: &str, network: Network) -> Option<Self> {
+        let (hrp, data, variant) = bech32::decode(address).ok()?;
+        if hrp != network.sapling_hrp() || variant != Variant::Bech32 {
+            return None;
+        }
+
+        let bytes = Vec::<u8>::from_base32(&data).ok()?;
+        Self::from_bytes(bytes.as_slice().try_into().ok()?)
+    }
+
     pub fn to_bytes(&self) -> [u8; 43] {
         let mut bytes: [u8; 43] = [0; 43];
         bytes[..11].copy_from_slice(&self.diversifier);
@@ -48,13 +107,9 @@ impl PaymentAddress {
 
 #[cfg(test)]
 mod tests {
-    use crate::signing_key::SigningKey;
+    use crate::fixtures::SK;
 
-    use super::{KeyChain, PaymentAddress};
-    const SK: SigningKey = &[
-        24, 226, 141, 234, 92, 17, 129, 122, 238, 178, 26, 25, 152, 29, 40, 54, 142, 196, 56, 175,
-        194, 90, 141, 185, 78, 190, 8, 215, 160, 40, 142, 9,
-    ];
+    use super::{KeyChain, Network, PaymentAddress};
     
     #[test]
     pub fn test_to_fro_pa() {
@@ -66,4 +121,27 @@ mod tests {
         let pa_bc = PaymentAddress::from(pa_b);
         assert_eq!(pa_b, pa_bc.to_bytes());
     }
+
+    #[test]
+    pub fn test_bech32_address() {
+        let pa = PaymentAddress::from(KeyChain::from(SK));
+        let encoded = pa.encode(Network::Main);
+        assert!(encoded.starts_with("zs1"));
+        assert_eq!(PaymentAddress::decode(&encoded, Network::Main), Some(pa.clone()));
+        assert_eq!(PaymentAddress::decode(&encoded, Network::Test), None);
+
+        let test = pa.encode(Network::Test);
+        assert!(test.starts_with("ztestsapling1"));
+        assert_eq!(PaymentAddress::decode(&test, Network::Test), Some(pa.clone()));
+
+        let mut corrupted = encoded.into_bytes();
+        let last = corrupted.len() - 1;
+        corrupted[last] = if corrupted[last] == b'q' { b'p' } else { b'q' };
+        assert_eq!(PaymentAddress::decode(std::str::from_utf8(&corrupted).unwrap(), Network::Main), None);
+
+        let mut small_order = pa.to_bytes();
+        small_order[11..].copy_from_slice(&[0; 32]);
+        small_order[11] = 1;
+        assert!(PaymentAddress::from_bytes(&small_order).is_none());
+    }
 }
\ No newline at end of file
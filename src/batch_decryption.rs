@@ -0,0 +1,274 @@
+//! Trial decryption of many outputs with many incoming viewing keys at once.
+//!
+//! Decrypting an output with `try_decrypt_note` costs one variable-base
+//! scalar multiplication `[ivk] epk` and one field inversion to get back to
+//! affine coordinates. With `n` keys and `m` outputs the batch functions here
+//! instead build, once per output, a fixed-base comb of `[8] epk` (the
+//! cofactor is applied to the comb, not to every product): the points
+//! `[j 16^i] [8] epk` for every 4-bit window `i` and digit `j`, normalized
+//! with a single inversion. Each key then costs one mixed addition per
+//! nonzero window of `ivk` and no doublings, and the `n` shared secrets of an
+//! output are normalized with a single inversion too. Below `COMB_MIN_KEYS`
+//! keys the comb does not pay for itself, and a table of the 15 small
+//! multiples of `[8] epk` is used with doublings instead. With the `parallel`
+//! feature the outputs are spread over the rayon thread pool.
+
+use ark_ec::{AffineRepr, CurveGroup, Group};
+use ark_ed_on_bls12_381::{EdwardsAffine, EdwardsProjective, Fr};
+use ark_ff::{PrimeField, Zero};
+use chacha20poly1305::Key;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::commitment::NoteCommitmentMode;
+use crate::keygen::IncomingViewingKey;
+use crate::note_encryption::{
+    kdf, try_compact_note_decryption_with_key, try_decrypt_note_with_key, NotePlaintext,
+};
+use crate::output_description::OutputDescription;
+use crate::scan::CompactOutput;
+
+const WINDOW_BITS: usize = 4;
+const WINDOWS: usize = 256 / WINDOW_BITS;
+const TABLE_SIZE: usize = (1 << WINDOW_BITS) - 1;
+/// The fewest keys for which building a comb per output is faster.
+const COMB_MIN_KEYS: usize = 8;
+
+/// An output that `ivks[ivk_index]` decrypted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecryptedOutput {
+    pub output_index: usize,
+    pub ivk_index: usize,
+    pub note: NotePlaintext,
+}
+
+/// Decrypts every output with every key, like `try_decrypt_note`, and
+/// returns the matches in the order of `outputs`.
+pub fn batch_try_decrypt_notes(
+    ivks: &[IncomingViewingKey],
+    outputs: &[OutputDescription],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Vec<DecryptedOutput> {
+    batch_decrypt(ivks, outputs, OutputDescription::epk, |output, key, ivk| {
+        try_decrypt_note_with_key(key, ivk, output.cmu(), output.enc_ciphertext(), note_commitment_mode)
+    })
+}
+
+/// Decrypts every compact output with every key, like
+/// `try_compact_note_decryption`, and returns the matches in the order of
+/// `outputs`.
+pub fn batch_try_compact_note_decryption(
+    ivks: &[IncomingViewingKey],
+    outputs: &[CompactOutput],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Vec<DecryptedOutput> {
+    batch_decrypt(ivks, outputs, |output| &output.epk, |output, key, ivk| {
+        try_compact_note_decryption_with_key(key, ivk, &output.cmu, &output.ciphertext, note_commitment_mode)
+    })
+}
+
+fn batch_decrypt<O, E, D>(
+    ivks: &[IncomingViewingKey],
+    outputs: &[O],
+    epk: E,
+    decrypt: D,
+) -> Vec<DecryptedOutput>
+where
+    O: Sync,
+    E: Fn(&O) -> &EdwardsAffine + Sync,
+    D: Fn(&O, &Key, &Fr) -> Option<NotePlaintext> + Sync,
+{
+    if ivks.is_empty() {
+        return vec![];
+    }
+    let windows: Vec<_> = ivks.iter().map(|ivk| windows(&ivk.0)).collect();
+
+    let decrypt_output = |(output_index, output): (usize, &O)| {
+        let epk = epk(output);
+        let shared_secrets: Vec<_> = if ivks.len() >= COMB_MIN_KEYS {
+            let comb = comb(epk);
+            windows.iter().map(|w| comb_multiply(&comb, w)).collect()
+        } else {
+            let table = table(epk);
+            windows.iter().map(|w| multiply(&table, w)).collect()
+        };
+        EdwardsProjective::normalize_batch(&shared_secrets)
+            .iter()
+            .zip(ivks)
+            .enumerate()
+            .filter_map(|(ivk_index, (shared_secret, ivk))| {
+                let note = decrypt(output, &kdf(shared_secret, epk), &ivk.0)?;
+                Some(DecryptedOutput { output_index, ivk_index, note })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    #[cfg(feature = "parallel")]
+    {
+        outputs.par_iter().enumerate().flat_map_iter(decrypt_output).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        outputs.iter().enumerate().flat_map(decrypt_output).collect()
+    }
+}
+
+/// The 4-bit windows of `ivk`, least significant first.
+fn windows(ivk: &Fr) -> [u8; WINDOWS] {
+    let limbs = ivk.into_bigint().0;
+    let mut windows = [0u8; WINDOWS];
+    for (i, window) in windows.iter_mut().enumerate() {
+        let bit = i * WINDOW_BITS;
+        *window = ((limbs[bit / 64] >> (bit % 64)) & TABLE_SIZE as u64) as u8;
+    }
+    windows
+}
+
+/// `[1..=15] [8] epk`, in affine coordinates for mixed additions.
+fn table(epk: &EdwardsAffine) -> Vec<EdwardsAffine> {
+    let base = epk.mul_by_cofactor_to_group();
+    let mut multiples = Vec::with_capacity(TABLE_SIZE);
+    multiples.push(base);
+    for i in 1..TABLE_SIZE {
+        multiples.push(multiples[i - 1] + base);
+    }
+    EdwardsProjective::normalize_batch(&multiples)
+}
+
+/// `[j 16^i] [8] epk` at `i * TABLE_SIZE + j - 1`, for every window `i` and
+/// `j` in `1..=15`.
+fn comb(epk: &EdwardsAffine) -> Vec<EdwardsAffine> {
+    let mut base = epk.mul_by_cofactor_to_group();
+    let mut points = Vec::with_capacity(WINDOWS * TABLE_SIZE);
+    for _ in 0..WINDOWS {
+        let mut multiple = base;
+        points.push(multiple);
+        for _ in 1..TABLE_SIZE {
+            multiple += base;
+            points.push(multiple);
+        }
+        // `[16] base`, one past the last multiple.
+        base = multiple + base;
+    }
+    EdwardsProjective::normalize_batch(&points)
+}
+
+fn comb_multiply(comb: &[EdwardsAffine], windows: &[u8; WINDOWS]) -> EdwardsProjective {
+    let mut acc = EdwardsProjective::zero();
+    for (i, &window) in windows.iter().enumerate() {
+        if window != 0 {
+            acc += &comb[i * TABLE_SIZE + window as usize - 1];
+        }
+    }
+    acc
+}
+
+fn multiply(table: &[EdwardsAffine], windows: &[u8; WINDOWS]) -> EdwardsProjective {
+    let mut acc = EdwardsProjective::zero();
+    for &window in windows.iter().rev() {
+        for _ in 0..WINDOW_BITS {
+            acc.double_in_place();
+        }
+        if window != 0 {
+            acc += &table[window as usize - 1];
+        }
+    }
+    acc
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::address::PaymentAddress;
+    use crate::keygen::KeyChain;
+    use crate::memo::Memo;
+    use crate::note::NoteValue;
+    use crate::note_encryption::{try_compact_note_decryption, try_decrypt_note};
+    use crate::prover::MockProver;
+    use crate::signing_key::SigningKey;
+    use ark_ff::UniformRand;
+    use rand::thread_rng;
+
+    #[test]
+    pub fn test_comb_matches_multiplication() {
+        let mut rng = thread_rng();
+        let epk = EdwardsAffine::rand(&mut rng);
+        let (comb, table) = (comb(&epk), table(&epk));
+        for ivk in [Fr::from(0u64), Fr::from(1u64), -Fr::from(1u64), Fr::rand(&mut rng)] {
+            let expected = epk.mul_by_cofactor_to_group() * ivk;
+            assert_eq!(comb_multiply(&comb, &windows(&ivk)), expected);
+            assert_eq!(multiply(&table, &windows(&ivk)), expected);
+        }
+    }
+
+    #[test]
+    pub fn test_batch_decryption_matches_single_decryption() {
+        let seeds: Vec<[u8; 32]> = (0..10).map(|i| [i; 32]).collect();
+        let keychain = |i: usize| {
+            let sk: SigningKey = &seeds[i];
+            KeyChain::from(sk)
+        };
+        let ivks: Vec<_> = (1..=4).map(|i| IncomingViewingKey::from(&keychain(i))).collect();
+
+        // Outputs to the second and fourth keys, and one to a key not in the
+        // batch.
+        let outputs: Vec<_> = [2, 9, 4, 2]
+            .into_iter()
+            .enumerate()
+            .map(|(i, seed)| {
+                OutputDescription::new(
+                    &MockProver,
+                    &keychain(0).ovk,
+                    &PaymentAddress::from(keychain(seed)),
+                    NoteValue(i as u64 + 1),
+                    &Memo::text("batch").unwrap(),
+                    NoteCommitmentMode::Arkworks,
+                )
+                .unwrap()
+                .0
+            })
+            .collect();
+
+        let matches = batch_try_decrypt_notes(&ivks, &outputs, NoteCommitmentMode::Arkworks);
+        let found: Vec<_> = matches.iter().map(|m| (m.output_index, m.ivk_index)).collect();
+        assert_eq!(found, vec![(0, 1), (2, 3), (3, 1)]);
+        for m in &matches {
+            let output = &outputs[m.output_index];
+            let single = try_decrypt_note(
+                &ivks[m.ivk_index].0,
+                output.epk(),
+                output.cmu(),
+                output.enc_ciphertext(),
+                NoteCommitmentMode::Arkworks,
+            );
+            assert_eq!(Some(&m.note), single.as_ref());
+            assert_eq!(m.note.value, NoteValue(m.output_index as u64 + 1));
+        }
+        assert!(batch_try_decrypt_notes(&ivks, &outputs, NoteCommitmentMode::Sapling).is_empty());
+        assert!(batch_try_decrypt_notes(&[], &outputs, NoteCommitmentMode::Arkworks).is_empty());
+
+        // Enough keys to decrypt with a comb per output.
+        let many: Vec<_> = (1..=COMB_MIN_KEYS).map(|i| IncomingViewingKey::from(&keychain(i))).collect();
+        let found: Vec<_> = batch_try_decrypt_notes(&many, &outputs, NoteCommitmentMode::Arkworks)
+            .iter()
+            .map(|m| (m.output_index, m.ivk_index))
+            .collect();
+        assert_eq!(found, vec![(0, 1), (2, 3), (3, 1)]);
+
+        let compact: Vec<_> = outputs.iter().map(CompactOutput::from).collect();
+        let compact_matches =
+            batch_try_compact_note_decryption(&ivks, &compact, NoteCommitmentMode::Arkworks);
+        assert_eq!(compact_matches.len(), matches.len());
+        for m in &compact_matches {
+            let output = &compact[m.output_index];
+            let single = try_compact_note_decryption(
+                &ivks[m.ivk_index].0,
+                &output.epk,
+                &output.cmu,
+                &output.ciphertext,
+                NoteCommitmentMode::Arkworks,
+            );
+            assert_eq!(Some(&m.note), single.as_ref());
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Proves Spend and Output witnesses for other processes, see
+//! `zcash_sapling_protocol::prover_server` for the protocol.
+//!
+//! ```text
+//! prover_server setup --spend-params FILE --output-params FILE [--sapling]
+//!                     [--ivk blake2s|poseidon]
+//! prover_server serve --spend-params FILE --output-params FILE [--sapling]
+//!                     [--ivk blake2s|poseidon] [--socket PATH] [--connections N]
+//!                     [--jobs N] [--threads N]
+//! ```
+//!
+//! `setup` generates fresh parameters for testing. `serve` loads them once and
+//! answers requests on the Unix socket at `PATH`, or on stdin/stdout without
+//! `--socket`. At most `--connections` clients are served at once; more wait
+//! to be accepted. `--sapling` selects the Sapling note commitment, nullifier and
+//! Pedersen note commitment tree instead of the Poseidon ones, and `--ivk` the
+//! `CRH^ivk` of the spending keys, BLAKE2s by default. `serve` refuses
+//! parameters that `setup` generated for other options.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::process;
+use std::thread;
+
+use rand::thread_rng;
+
+use zcash_sapling_protocol::commitment::NoteCommitmentMode;
+use zcash_sapling_protocol::merkle::{MerkleHash, PedersenMerkleHash, PoseidonMerkleHash};
+use zcash_sapling_protocol::prf::prf_expand::IvkMode;
+use zcash_sapling_protocol::prover::LocalProver;
+use zcash_sapling_protocol::prover_server::{ProverServer, ServerConfig};
+use zcash_sapling_protocol::spend_description::NullifierMode;
+use zcash_sapling_protocol::thread_pool::ProvingPool;
+
+const USAGE: &str = "usage: prover_server (setup | serve) --spend-params FILE --output-params FILE \
+[--sapling] [--ivk blake2s|poseidon] [--socket PATH] [--connections N] [--jobs N] [--threads N]";
+
+struct Options {
+    command: String,
+    spend_params: String,
+    output_params: String,
+    sapling: bool,
+    ivk_mode: IvkMode,
+    socket: Option<String>,
+    connections: usize,
+    jobs: usize,
+    threads: usize,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().ok_or("missing command")?;
+    if command != "setup" && command != "serve" {
+        return Err(format!("unknown command {}", command));
+    }
+
+    let mut spend_params = None;
+    let mut output_params = None;
+    let mut sapling = false;
+    let mut ivk_mode = IvkMode::Blake2s;
+    let mut socket = None;
+    let mut connections = 8;
+    let mut jobs = 1;
+    let mut threads = 0;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--spend-params" => spend_params = Some(value()?),
+            "--output-params" => output_params = Some(value()?),
+            "--sapling" => sapling = true,
+            "--ivk" => {
+                ivk_mode = match value()?.as_str() {
+                    "blake2s" => IvkMode::Blake2s,
+                    "poseidon" => IvkMode::Poseidon,
+                    _ => return Err("invalid --ivk".into()),
+                }
+            }
+            "--socket" => socket = Some(value()?),
+            "--connections" => connections = value()?.parse().map_err(|_| "invalid --connections")?,
+            "--jobs" => jobs = value()?.parse().map_err(|_| "invalid --jobs")?,
+            "--threads" => threads = value()?.parse().map_err(|_| "invalid --threads")?,
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    if jobs == 0 {
+        return Err("--jobs must be at least 1".into());
+    }
+    if connections == 0 {
+        return Err("--connections must be at least 1".into());
+    }
+
+    Ok(Options {
+        command,
+        spend_params: spend_params.ok_or("missing --spend-params")?,
+        output_params: output_params.ok_or("missing --output-params")?,
+        sapling,
+        ivk_mode,
+        socket,
+        connections,
+        jobs,
+        threads,
+    })
+}
+
+fn run<H: MerkleHash + Sync>(options: &Options, config: ServerConfig) -> io::Result<()> {
+    if options.command == "setup" {
+        let prover = LocalProver::<H>::setup(
+            config.note_commitment_mode,
+            config.nullifier_mode,
+            config.ivk_mode,
+            &mut thread_rng(),
+        );
+        return prover
+            .write_params(
+                BufWriter::new(File::create(&options.spend_params)?),
+                BufWriter::new(File::create(&options.output_params)?),
+            )
+            .map_err(io::Error::other);
+    }
+
+    let prover = LocalProver::<H>::read_params(
+        BufReader::new(File::open(&options.spend_params)?),
+        BufReader::new(File::open(&options.output_params)?),
+        config.note_commitment_mode,
+        config.nullifier_mode,
+        config.ivk_mode,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let server = ProverServer::<_, H>::new(prover, config, ProvingPool::new(options.threads));
+
+    match &options.socket {
+        None => server.serve(io::stdin().lock(), io::stdout()),
+        Some(path) => serve_socket(&server, path, options.connections),
+    }
+}
+
+/// Serves every connection on its own thread, at most `max_connections` at
+/// once. The concurrency limit of the proofs is shared across connections.
+#[cfg(unix)]
+fn serve_socket<H: MerkleHash + Sync>(
+    server: &ProverServer<LocalProver<H>, H>,
+    path: &str,
+    max_connections: usize,
+) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::{Condvar, Mutex};
+    use std::time::Duration;
+
+    /// Removes the socket file when the server stops.
+    struct SocketFile<'a>(&'a str);
+
+    impl Drop for SocketFile<'_> {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(self.0);
+        }
+    }
+
+    // A socket left by a server that crashed refuses connections; one that
+    // accepts them belongs to a server still running.
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket && UnixStream::connect(path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let _socket_file = SocketFile(path);
+
+    let open = Mutex::new(0usize);
+    let closed = Condvar::new();
+    thread::scope(|s| loop {
+        {
+            let mut open = open.lock().unwrap();
+            while *open >= max_connections {
+                open = closed.wait(open).unwrap();
+            }
+        }
+
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // E.g. out of file descriptors: wait for some to be closed.
+                eprintln!("accept failed: {}", e);
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+
+        *open.lock().unwrap() += 1;
+        let (open, closed) = (&open, &closed);
+        s.spawn(move || {
+            let result = stream
+                .try_clone()
+                .and_then(|reader| server.serve(BufReader::new(reader), stream));
+            if let Err(e) = result {
+                eprintln!("connection failed: {}", e);
+            }
+            *open.lock().unwrap() -= 1;
+            closed.notify_one();
+        });
+    })
+}
+
+#[cfg(not(unix))]
+fn serve_socket<H: MerkleHash + Sync>(
+    _server: &ProverServer<LocalProver<H>, H>,
+    _path: &str,
+    _max_connections: usize,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--socket needs Unix domain sockets",
+    ))
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    let result = if options.sapling {
+        let config = ServerConfig {
+            note_commitment_mode: NoteCommitmentMode::Sapling,
+            nullifier_mode: NullifierMode::Sapling,
+            ivk_mode: options.ivk_mode,
+            max_jobs: options.jobs,
+        };
+        run::<PedersenMerkleHash>(&options, config)
+    } else {
+        let config = ServerConfig {
+            note_commitment_mode: NoteCommitmentMode::Arkworks,
+            nullifier_mode: NullifierMode::Poseidon,
+            ivk_mode: options.ivk_mode,
+            max_jobs: options.jobs,
+        };
+        run::<PoseidonMerkleHash>(&options, config)
+    };
+
+    if let Err(e) = result {
+        eprintln!("prover_server: {}", e);
+        process::exit(1);
+    }
+}
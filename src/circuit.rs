@@ -5,35 +5,43 @@ use ark_crypto_primitives::commitment::pedersen::constraints::{
     CommGadget
 };
 use ark_crypto_primitives::commitment::CommitmentGadget;
-use ark_crypto_primitives::crh::poseidon::constraints::CRHParametersVar;
+use ark_crypto_primitives::crh::poseidon::constraints::{CRHGadget, CRHParametersVar};
+use ark_crypto_primitives::crh::CRHSchemeGadget;
 use ark_crypto_primitives::crh::TwoToOneCRHSchemeGadget;
-use ark_crypto_primitives::prf::blake2s::constraints::Blake2sGadget;
+use ark_crypto_primitives::prf::blake2s::constraints::{
+    evaluate_blake2s_with_parameters, Blake2sGadget,
+};
 use ark_crypto_primitives::prf::PRFGadget;
-use ark_crypto_primitives::signature::schnorr::constraints::SchnorrRandomizePkGadget;
-use ark_ed_on_bls12_381::{constraints::EdwardsVar, EdwardsAffine, EdwardsProjective, Fr, Fq};
-use ark_ff::BigInteger;
+use ark_ed_on_bls12_381::{constraints::EdwardsVar, EdwardsAffine, EdwardsProjective, Fr};
+use ark_ff::{BigInteger, Field, PrimeField, ToConstraintField};
 use ark_r1cs_std::select::CondSelectGadget;
-use ark_r1cs_std::{alloc::AllocVar, fields::fp::FpVar, prelude::Boolean, uint8::UInt8, ToBitsGadget};
-use ark_relations::r1cs::SynthesisError;
+use ark_r1cs_std::R1CSVar;
+use ark_r1cs_std::{alloc::AllocVar, fields::fp::FpVar, prelude::Boolean, uint8::UInt8, ToBitsGadget, ToBytesGadget, ToConstraintFieldGadget};
+use ark_relations::r1cs::{
+    ConstraintLayer, ConstraintSystem, ConstraintSystemRef, SynthesisError, TracingMode,
+};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use std::marker::PhantomData;
+use tracing_subscriber::layer::SubscriberExt;
 use ark_relations::r1cs::{ConstraintSynthesizer, SynthesisError::AssignmentMissing};
 use ark_r1cs_std::eq::EqGadget;
 use ark_r1cs_std::groups::CurveVar;
 use ark_r1cs_std::fields::FieldVar;
-use ark_crypto_primitives::signature::*;
-use blake2::Blake2b512;
-use crate::circuit::schnorr::Schnorr;
-use ark_r1cs_std::R1CSVar;
 use ark_crypto_primitives::crh::poseidon::constraints::TwoToOneCRHGadget;
 
 use crate::group_hash;
 use crate::pedersen_crh::Window;
-use crate::spend_description::Nullifier;
-use crate::{commitment::{Commitment, ValueCommitTrapdoor}, note::NoteValue};
-use crate::keygen::Params;
+use crate::spend_description::{Nullifier, NullifierMode};
+use crate::{commitment::{NoteCommitmentMode, NoteCommitmentParams, ValueCommitTrapdoor}, note::NoteValue};
+use crate::pedersen_hash::{self, Personalization};
+use crate::keygen::{self, Params};
+use crate::merkle::{MerkleHash, PoseidonMerkleHash};
 use crate::prf::poseidon_config::poseidon_parameters;
+use crate::prf::prf_expand::{self, IvkMode};
 
 pub type ConstraintF = ark_bls12_381::Fr;
 
+/// In-circuit `repr_J`: the 255 bits of `v` followed by the parity of `u`.
 pub fn to_repr(affine: EdwardsVar) -> Vec<UInt8<ConstraintF>> {
     let mut tmp: Vec<_> = vec![];
     let mut tmp1: Vec<_> = vec![];
@@ -43,8 +51,7 @@ pub fn to_repr(affine: EdwardsVar) -> Vec<UInt8<ConstraintF>> {
     let mut y: Vec<Boolean<ConstraintF>> = 
         <FpVar<_> as ToBitsGadget<_>>::to_bits_le(&affine.y).unwrap();
 
-    let msb = &x[x.len() - 1];
-    y.push(msb.clone());
+    y.push(x[0].clone());
 
     for i in 1..y.len() + 1 {
         tmp1.push(y[i - 1].clone());
@@ -58,82 +65,392 @@ pub fn to_repr(affine: EdwardsVar) -> Vec<UInt8<ConstraintF>> {
     tmp
 }
 
-pub struct Spend<'a> {
+/// Enforces that `p` is not of small order. `[8] p` lies in the prime-order
+/// subgroup, where only the identity has `u = 0`.
+#[tracing::instrument(target = "r1cs", skip(p))]
+pub fn enforce_not_small_order(p: &EdwardsVar) -> Result<(), SynthesisError> {
+    let p8 = p.double()?.double()?.double()?;
+
+    // u * u_inv = 1. A zero u is left to fail this constraint rather than
+    // aborting synthesis, so that an unsatisfied system can be inspected.
+    let u_inv = FpVar::new_witness(ark_relations::ns!(p8.x.cs(), "u inverse"), || {
+        Ok(p8.x.value()?.inverse().unwrap_or_default())
+    })?;
+    p8.x.mul_equals(&u_inv, &FpVar::one())
+}
+
+/// Allocates a note value as its 8 little-endian bytes. Every bit is
+/// boolean-constrained, which range checks the value to 64 bits; callers share
+/// this one witness between the value and note commitments.
+pub fn alloc_note_value(
+    cs: ConstraintSystemRef<ConstraintF>,
+    value: Option<&NoteValue>,
+) -> Result<Vec<UInt8<ConstraintF>>, SynthesisError> {
+    let bytes = match value {
+        Some(v) => v.0.to_le_bytes().map(Some),
+        None => [None; 8],
+    };
+
+    UInt8::new_witness_vec(ark_relations::ns!(cs, "note value"), &bytes)
+}
+
+/// In-circuit note commitment over `g_d`, `pk_d` and the 8-byte little-endian
+/// value, computed according to `mode`.
+pub fn note_commitment(
+    cs: ConstraintSystemRef<ConstraintF>,
+    mode: NoteCommitmentMode,
+    g_d: &EdwardsVar,
+    pk_d: &EdwardsVar,
+    value: &[UInt8<ConstraintF>],
+    rcm: Option<Randomness<EdwardsProjective>>,
+) -> Result<EdwardsVar, SynthesisError> {
+    let g_d_repr = to_repr(g_d.clone());
+    let pk_d_repr = to_repr(pk_d.clone());
+
+    match mode {
+        NoteCommitmentMode::Arkworks => {
+            let mut note_com_inp = vec![];
+            note_com_inp.extend(g_d_repr);
+            note_com_inp.extend(pk_d_repr);
+            note_com_inp.extend_from_slice(value);
+
+            let pdcm_params: pdcmParamsVar<EdwardsProjective, EdwardsVar> =
+                <pdcmParamsVar<_, _> as AllocVar<_, _>>::new_constant(
+                    ark_relations::ns!(cs, "crh params"),
+                    &NoteCommitmentParams::get().params,
+                )?;
+            let pdcm_randomness =
+                pdcmRandVar::new_witness(ark_relations::ns!(cs, "crh randomness"), || {
+                    rcm.ok_or(AssignmentMissing)
+                })?;
+
+            CommGadget::<EdwardsProjective, EdwardsVar, Window>::commit(
+                &pdcm_params,
+                &note_com_inp,
+                &pdcm_randomness,
+            )
+        }
+        NoteCommitmentMode::Sapling => {
+            let note_com_inp = value
+                .iter()
+                .chain(g_d_repr.iter())
+                .chain(pk_d_repr.iter())
+                .flat_map(|b| b.to_bits_le().unwrap())
+                .collect::<Vec<_>>();
+
+            let rcm = match rcm {
+                Some(r) => UInt8::new_witness_vec(
+                    ark_relations::ns!(cs, "rcm"),
+                    &r.0.into_bigint().to_bytes_le(),
+                )?,
+                None => UInt8::new_witness_vec(ark_relations::ns!(cs, "rcm"), &[None; 32])?,
+            };
+            let rcm_bits = rcm
+                .iter()
+                .flat_map(|b| b.to_bits_le().unwrap())
+                .collect::<Vec<_>>();
+
+            pedersen_hash::constraints::windowed_pedersen_commit(
+                Personalization::NoteCommitment,
+                &note_com_inp,
+                &rcm_bits,
+            )
+        }
+    }
+}
+
+/// In-circuit nullifier of a note with nullifier key `nk` and mixed commitment
+/// `rho`, as 32 little-endian bytes, computed according to `mode`.
+pub fn nullifier(
+    cs: ConstraintSystemRef<ConstraintF>,
+    mode: NullifierMode,
+    nk: &EdwardsVar,
+    rho: &EdwardsVar,
+) -> Result<Vec<UInt8<ConstraintF>>, SynthesisError> {
+    match mode {
+        NullifierMode::Poseidon => {
+            let params = CRHParametersVar::new_constant(
+                ark_relations::ns!(cs, "poseidon hash var"),
+                poseidon_parameters(),
+            )?;
+
+            <TwoToOneCRHGadget<_> as TwoToOneCRHSchemeGadget<_, _>>::evaluate(
+                &params, &nk.y, &rho.y,
+            )?
+            .to_bytes()
+        }
+        NullifierMode::Sapling => {
+            let input = to_repr(nk.clone())
+                .iter()
+                .chain(to_repr(rho.clone()).iter())
+                .flat_map(|b| b.to_bits_le().unwrap())
+                .collect::<Vec<_>>();
+
+            let mut personalization = [0u8; 8];
+            personalization.copy_from_slice(prf_expand::NF);
+
+            let mut parameters = [0u32; 8];
+            parameters[0] = 0x01010000 ^ 32;
+            parameters[6] = u32::from_le_bytes(personalization[0..4].try_into().unwrap());
+            parameters[7] = u32::from_le_bytes(personalization[4..8].try_into().unwrap());
+
+            let mut nf = vec![];
+            for word in evaluate_blake2s_with_parameters(&input, &parameters)? {
+                nf.extend(word.to_bytes()?);
+            }
+
+            Ok(nf)
+        }
+    }
+}
+
+/// In-circuit `CRH^ivk` over `ak` and `nk`, as little-endian bits of the
+/// truncated `ivk`.
+pub fn crh_ivk(
+    cs: ConstraintSystemRef<ConstraintF>,
+    mode: IvkMode,
+    ak: &EdwardsVar,
+    nk: &EdwardsVar,
+) -> Result<Vec<Boolean<ConstraintF>>, SynthesisError> {
+    match mode {
+        IvkMode::Blake2s => {
+            let ak_repr = to_repr(ak.clone());
+            let nk_repr = to_repr(nk.clone());
+
+            let ivk = Blake2sGadget::evaluate(&ak_repr, &nk_repr)?;
+
+            let mut bits = ivk
+                .0
+                .iter()
+                .flat_map(|b| b.to_bits_le().unwrap())
+                .collect::<Vec<_>>();
+            bits.truncate(prf_expand::IVK_BITS);
+
+            Ok(bits)
+        }
+        IvkMode::Poseidon => {
+            let params = CRHParametersVar::new_constant(
+                ark_relations::ns!(cs, "poseidon hash var"),
+                poseidon_parameters(),
+            )?;
+            let tag = FpVar::constant(ConstraintF::from_le_bytes_mod_order(prf_expand::IVK));
+
+            let ivk = <CRHGadget<_> as CRHSchemeGadget<_, _>>::evaluate(
+                &params,
+                &[tag, ak.y.clone(), nk.y.clone()],
+            )?;
+
+            let mut bits = ivk.to_bits_le()?;
+            bits.truncate(prf_expand::IVK_BITS);
+
+            Ok(bits)
+        }
+    }
+}
+
+/// The public statement of a Spend proof: exactly the Sapling `rk`, `cv`,
+/// `anchor` and `nf`.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct SpendPublicInputs {
+    pub rk: EdwardsAffine,
+    pub cv: EdwardsAffine,
+    pub anchor: ConstraintF,
+    pub nf: Nullifier,
+}
+
+impl SpendPublicInputs {
+    /// The Groth16 public inputs, in the order `Spend` allocates them.
+    pub fn to_field_elements(&self) -> Vec<ConstraintF> {
+        let mut inputs = vec![];
+        inputs.extend(self.rk.to_field_elements().unwrap());
+        inputs.extend(self.cv.to_field_elements().unwrap());
+        inputs.push(self.anchor);
+        inputs.extend(ToConstraintField::<ConstraintF>::to_field_elements(&self.nf.0[..]).unwrap());
+        inputs
+    }
+}
+
+pub struct SpendPublicInputsVar {
+    pub rk: EdwardsVar,
+    pub cv: EdwardsVar,
+    pub anchor: FpVar<ConstraintF>,
+    pub nf: Vec<UInt8<ConstraintF>>,
+}
+
+impl SpendPublicInputsVar {
+    pub fn new_input(
+        cs: ConstraintSystemRef<ConstraintF>,
+        value: Option<&SpendPublicInputs>,
+    ) -> Result<Self, SynthesisError> {
+        let rk = EdwardsVar::new_input(ark_relations::ns!(cs, "rk"), || {
+            value.map(|v| v.rk).ok_or(AssignmentMissing)
+        })?;
+        let cv = EdwardsVar::new_input(ark_relations::ns!(cs, "cv"), || {
+            value.map(|v| v.cv).ok_or(AssignmentMissing)
+        })?;
+        let anchor = FpVar::new_input(ark_relations::ns!(cs, "anchor"), || {
+            value.map(|v| v.anchor).ok_or(AssignmentMissing)
+        })?;
+        let nf = new_packed_input_bytes(
+            ark_relations::ns!(cs, "nf").cs(),
+            value.map(|v| v.nf.0),
+        )?;
+
+        Ok(Self { rk, cv, anchor, nf })
+    }
+}
+
+/// The public statement of an Output proof: `cv`, the note commitment and `epk`.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct OutputPublicInputs {
+    pub cv: EdwardsAffine,
+    pub cm: EdwardsAffine,
+    pub epk: EdwardsAffine,
+}
+
+impl OutputPublicInputs {
+    /// The Groth16 public inputs, in the order `Output` allocates them.
+    pub fn to_field_elements(&self) -> Vec<ConstraintF> {
+        let mut inputs = vec![];
+        inputs.extend(self.cv.to_field_elements().unwrap());
+        inputs.extend(self.cm.to_field_elements().unwrap());
+        inputs.extend(self.epk.to_field_elements().unwrap());
+        inputs
+    }
+}
+
+pub struct OutputPublicInputsVar {
+    pub cv: EdwardsVar,
+    pub cm: EdwardsVar,
+    pub epk: EdwardsVar,
+}
+
+impl OutputPublicInputsVar {
+    pub fn new_input(
+        cs: ConstraintSystemRef<ConstraintF>,
+        value: Option<&OutputPublicInputs>,
+    ) -> Result<Self, SynthesisError> {
+        let cv = EdwardsVar::new_input(ark_relations::ns!(cs, "cv"), || {
+            value.map(|v| v.cv).ok_or(AssignmentMissing)
+        })?;
+        let cm = EdwardsVar::new_input(ark_relations::ns!(cs, "cm"), || {
+            value.map(|v| v.cm).ok_or(AssignmentMissing)
+        })?;
+        let epk = EdwardsVar::new_input(ark_relations::ns!(cs, "epk"), || {
+            value.map(|v| v.epk).ok_or(AssignmentMissing)
+        })?;
+
+        Ok(Self { cv, cm, epk })
+    }
+}
+
+/// Allocates 32 bytes as witnesses and exposes them as public inputs packed the
+/// same way as `[u8]::to_field_elements`.
+fn new_packed_input_bytes(
+    cs: ConstraintSystemRef<ConstraintF>,
+    value: Option<[u8; 32]>,
+) -> Result<Vec<UInt8<ConstraintF>>, SynthesisError> {
+    let bytes = UInt8::new_witness_vec(
+        ark_relations::ns!(cs, "bytes"),
+        &value.map_or([None; 32], |v| v.map(Some)),
+    )?;
+    let packed: Option<Vec<ConstraintF>> = value.map(|v| v[..].to_field_elements().unwrap());
+
+    for (i, chunk) in bytes.to_constraint_field()?.iter().enumerate() {
+        let input = FpVar::new_input(ark_relations::ns!(cs, "packed"), || {
+            packed.as_ref().map(|p| p[i]).ok_or(AssignmentMissing)
+        })?;
+        chunk.enforce_equal(&input)?;
+    }
+
+    Ok(bytes)
+}
+
+pub struct Spend<'a, H: MerkleHash = PoseidonMerkleHash> {
+    pub public_inputs: Option<SpendPublicInputs>,
     pub ak: Option<EdwardsAffine>,
     pub sig_params: Params,
     pub randomness: &'a [Option<u8>],
-    pub randomized_ak: Option<EdwardsAffine>,
     pub nsk: &'a [Option<u8>],
-    pub nk: Option<EdwardsAffine>,
-    pub val_cm_old: Option<EdwardsProjective>,
     pub note_val: Option<NoteValue>,
     pub rcv_old: Option<ValueCommitTrapdoor>,
-    pub cm_params: Option<Commitment>,
     pub crh_rand: Option<Randomness<EdwardsProjective>>,
-    pub note_com: EdwardsAffine,
-    pub ivk: Option<Fr>,
+    pub note_commitment_mode: NoteCommitmentMode,
+    pub ivk_mode: IvkMode,
     pub gd: Option<EdwardsAffine>,
-    pub pk_d: Option<EdwardsAffine>,
-    pub nf_old: Option<Nullifier>,
-    pub pos: Option<u64>,
-    pub root: Option<ConstraintF>,
+    pub nullifier_mode: NullifierMode,
     pub auth_path: Vec<Option<(ConstraintF, bool)>>,
+    pub merkle_hash: PhantomData<H>,
 }
 
-impl ConstraintSynthesizer<ConstraintF> for Spend<'_> {
+impl<H: MerkleHash> Spend<'static, H> {
+    /// A `Spend` without any assignment, of the same shape as a filled one, to
+    /// generate parameters from.
+    pub fn blank(
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+    ) -> Self {
+        Spend {
+            public_inputs: None,
+            ak: None,
+            sig_params: keygen::spend_auth_params().clone(),
+            randomness: &[None; 32],
+            nsk: &[None; 32],
+            note_val: None,
+            rcv_old: None,
+            crh_rand: None,
+            note_commitment_mode,
+            ivk_mode,
+            gd: None,
+            nullifier_mode,
+            auth_path: vec![None; H::DEPTH],
+            merkle_hash: PhantomData,
+        }
+    }
+}
+
+impl<H: MerkleHash> ConstraintSynthesizer<ConstraintF> for Spend<'_, H> {
     #[tracing::instrument(target = "r1cs", skip(self, cs))]
     fn generate_constraints(
         self, 
         cs: ark_relations::r1cs::ConstraintSystemRef<ConstraintF>
     ) -> ark_relations::r1cs::Result<()> {
 
+        let public_inputs = SpendPublicInputsVar::new_input(
+            ark_relations::ns!(cs, "public inputs").cs(),
+            self.public_inputs.as_ref(),
+        )?;
+
+        //Spend Authority check: rk = ak + [alpha] G
+        let section = ark_relations::ns!(cs, "spend authority");
         let ak = <EdwardsVar as AllocVar<_, _>>::new_witness(ark_relations::ns!(cs, "ak"), || {
             self.ak.ok_or(AssignmentMissing)
         })?;
-
-        //Spend Authority check
+        enforce_not_small_order(&ak)?;
         {
-            let params_var =
-                schnorr::constraints::ParametersVar::<EdwardsProjective, EdwardsVar>::new_constant(
-                    ark_relations::ns!(cs, "sig params"),
-                    self.sig_params,
-                )?;
-
-            let pk_var: schnorr::constraints::PublicKeyVar<EdwardsProjective, EdwardsVar>;
-            
-            if let Some(pk) = self.ak {
-                pk_var = schnorr::constraints::PublicKeyVar::new_witness(
-                    ark_relations::ns!(cs, "pk_ak"),
-                    || Ok(pk),
-                )?
-            } else {
-                pk_var = schnorr::constraints::PublicKeyVar::new_witness(
-                    ark_relations::ns!(cs, "pk_ak"),
-                    || Result::<EdwardsAffine, SynthesisError>::Err(AssignmentMissing),
-                )?
-            }
+            let generator = <EdwardsVar as AllocVar<_, _>>::new_constant(
+                ark_relations::ns!(cs, "spend auth generator"),
+                self.sig_params.generator,
+            )?;
 
             let rand = UInt8::<ConstraintF>::new_witness_vec(
                 ark_relations::ns!(cs, "random"),
                 self.randomness,
             )?;
+            let rand_bits = rand
+                .iter()
+                .flat_map(|b| b.to_bits_le().unwrap())
+                .collect::<Vec<_>>();
 
-            let computed_rand_pk =
-                <SchnorrRandomizePkGadget<EdwardsProjective, EdwardsVar> as SigRandomizePkGadget<
-                    Schnorr<EdwardsProjective, Blake2b512>,
-                    ConstraintF,
-                >>::randomize(&params_var, &pk_var, &rand)?;
+            let computed_rk = &ak + &generator.scalar_mul_le(rand_bits.iter())?;
 
-            let randomized_ak: schnorr::constraints::PublicKeyVar<EdwardsProjective, EdwardsVar> =
-                schnorr::constraints::PublicKeyVar::new_input(
-                    ark_relations::ns!(cs, "orig rand pk"),
-                    || self.randomized_ak.ok_or(AssignmentMissing),
-                )?;
-            
-            computed_rand_pk.enforce_equal(&randomized_ak)?;
+            computed_rk.enforce_equal(&public_inputs.rk)?;
         }
+        section.leave_namespace();
 
         //calculate nk
+        let section = ark_relations::ns!(cs, "nk");
         let nk: EdwardsVar;
         {
             let proof_generator = 
@@ -142,11 +459,7 @@ impl ConstraintSynthesizer<ConstraintF> for Spend<'_> {
                     group_hash::group_hash_h_sapling(),
                 )?;
 
-            let nsk = 
-                UInt8::new_witness_vec(
-                    ark_relations::ns!(cs, "nsk"), 
-                    &self.nsk
-                );
+            let nsk = UInt8::new_witness_vec(ark_relations::ns!(cs, "nsk"), self.nsk)?;
 
             let nsk = nsk
                 .iter()
@@ -154,16 +467,12 @@ impl ConstraintSynthesizer<ConstraintF> for Spend<'_> {
                 .collect::<Vec<_>>();
             
             nk = proof_generator.scalar_mul_le(nsk.iter())?;
-            
-            let claimed_nk = <EdwardsVar as AllocVar<_, _>>::new_input(
-                ark_relations::ns!(cs, "claimed nk"),
-                || self.nk.ok_or(SynthesisError::AssignmentMissing),
-            )?;
-
-            nk.enforce_equal(&claimed_nk)?;
         }
+        section.leave_namespace();
 
         //value_commitment
+        let section = ark_relations::ns!(cs, "value commitment");
+        let note_value = alloc_note_value(cs.clone(), self.note_val.as_ref())?;
         {
             let v_sap_raw = group_hash::calc_v_sapling();
             let r_sap_raw = group_hash::calc_r_sapling();
@@ -177,17 +486,6 @@ impl ConstraintSynthesizer<ConstraintF> for Spend<'_> {
                 r_sap_raw,
             )?;
             
-            let note_value;
-            
-            if let Some(v) = self.note_val.clone() {
-                note_value = UInt8::new_witness_vec(
-                    ark_relations::ns!(cs, "note_value"),
-                    &Fr::from(v.0).0.to_bytes_le(),
-                )?;
-            } else {
-                note_value = UInt8::new_witness_vec(ark_relations::ns!(cs, "note_value"), &[None])?;
-            }
-            
             let note_value_bits = note_value
                 .iter()
                 .flat_map(|b| b.to_bits_le().unwrap())
@@ -195,9 +493,9 @@ impl ConstraintSynthesizer<ConstraintF> for Spend<'_> {
             
             let rcv;
             if let Some(v) = self.rcv_old {
-                rcv = UInt8::new_witness_vec(ark_relations::ns!(cs, "rcv"), &v.0 .0.to_bytes_le());
+                rcv = UInt8::new_witness_vec(ark_relations::ns!(cs, "rcv"), &v.0.into_bigint().to_bytes_le())?;
             } else {
-                rcv = UInt8::new_witness_vec(ark_relations::ns!(cs, "rcv"), &[None]);
+                rcv = UInt8::new_witness_vec(ark_relations::ns!(cs, "rcv"), &[None; 32])?;
             }
             
             let rcv_bits = rcv
@@ -207,200 +505,181 @@ impl ConstraintSynthesizer<ConstraintF> for Spend<'_> {
             
             let computed_val_cm = &v_sap.scalar_mul_le(note_value_bits.iter())?
                 + &r_sap.scalar_mul_le(rcv_bits.iter())?;
-            let old_val_cm = <EdwardsVar as AllocVar<_, _>>::new_input(
-                ark_relations::ns!(cs, "old_val_cm"),
-                || self.val_cm_old.ok_or(SynthesisError::AssignmentMissing),
-            )?;
             
-            computed_val_cm.enforce_equal(&old_val_cm)?;
+            computed_val_cm.enforce_equal(&public_inputs.cv)?;
         }
+        section.leave_namespace();
 
         //IVK
-        let nk_repr: Vec<_> = to_repr(nk.clone());
+        let section = ark_relations::ns!(cs, "ivk");
         let g_d: EdwardsVar;
         let pk_d: EdwardsVar;
         {
-            let ak_repr = to_repr(ak);
-            //
-            let mut ivk = Blake2sGadget::evaluate(&ak_repr, &nk_repr)?;
-            let mut x = ivk.0.value()?[31];
-            x &= 0b00000111;
-            let x = UInt8::new_witness(ark_relations::ns!(cs, "truncate"), || Ok(x))?;
-            ivk.0.pop();
-            ivk.0.push(x);
+            let ivk_bits = crh_ivk(
+                ark_relations::ns!(cs, "crh ivk").cs(),
+                self.ivk_mode,
+                &ak,
+                &nk,
+            )?;
 
             g_d =
                 <EdwardsVar as AllocVar<_, _>>::new_witness(ark_relations::ns!(cs, "g_d"), || {
-                    Ok(self.gd.unwrap())
+                    self.gd.ok_or(AssignmentMissing)
                 })?;
-            let ivk_bits = ivk
-                .0
-                .iter()
-                .flat_map(|b| b.to_bits_le().unwrap())
-                .collect::<Vec<_>>();
-            
+            enforce_not_small_order(&g_d)?;
             pk_d = g_d.scalar_mul_le(ivk_bits.iter())?;
-            let claimed_pk_d = <EdwardsVar as AllocVar<_, _>>::new_input(
-                ark_relations::ns!(cs, "claimed pk"),
-                || self.pk_d.ok_or(SynthesisError::AssignmentMissing),
-            )?;
-            
-            pk_d.enforce_equal(&claimed_pk_d)?;
         }
+        section.leave_namespace();
 
         //note commitment
-        let comm: EdwardsVar;
-        {
-            let g_d_repr = to_repr(g_d.clone());
-            let pk_d_repr = to_repr(pk_d);
-
-            let v_old;
-            if let Some(v) = self.note_val {
-                v_old = UInt8::new_witness_vec(
-                    ark_relations::ns!(cs, "note_value"),
-                    &v.0.to_le_bytes(),
-                )?;
-            } else {
-                v_old = UInt8::new_witness_vec(ark_relations::ns!(cs, "note_value"), &[None])?;
-            }
-            
-            let mut note_com_inp = vec![];
-            note_com_inp.extend(g_d_repr);
-            note_com_inp.extend(pk_d_repr);
-            note_com_inp.extend(v_old);
-            
-            let pdcm_params: pdcmParamsVar<EdwardsProjective, EdwardsVar> =
-                <pdcmParamsVar<_, _> as AllocVar<_, _>>::new_constant(
-                    ark_relations::ns!(cs, "crh params"),
-                    self.cm_params.unwrap().params,
-                )?;
-            let pdcm_randomness =
-                pdcmRandVar::new_witness(ark_relations::ns!(cs, "crh randomness"), || {
-                    Ok(self.crh_rand.unwrap())
-                })?;
-            comm = CommGadget::<EdwardsProjective, EdwardsVar, Window>::commit(
-                &pdcm_params,
-                &note_com_inp,
-                &pdcm_randomness,
-            )?;
+        let section = ark_relations::ns!(cs, "note commitment");
+        let comm = note_commitment(
+            ark_relations::ns!(cs, "note commitment").cs(),
+            self.note_commitment_mode,
+            &g_d,
+            &pk_d,
+            &note_value,
+            self.crh_rand,
+        )?;
+        section.leave_namespace();
 
-            let claimed_comm = <EdwardsVar as AllocVar<_, _>>::new_constant(
-                ark_relations::ns!(cs, "claimed note com"),
-                self.note_com,
-            )?;
-            
-            comm.enforce_equal(&claimed_comm)?;
+        if self.auth_path.len() != H::DEPTH {
+            return Err(SynthesisError::Unsatisfiable);
         }
 
         //Nullifier
-        let posiedon_hash_params = CRHParametersVar::new_constant(
-            ark_relations::ns!(cs, "poseidon hash var"),
-            poseidon_parameters(),
-        )?;
+        let section = ark_relations::ns!(cs, "nullifier");
+        // The note's position, little-endian: whether it is the right child at
+        // each layer of the auth path. The Merkle path below uses the same
+        // bits, so the nullifier is bound to the position the anchor proves.
+        let pos_bits = self
+            .auth_path
+            .iter()
+            .map(|node| {
+                Boolean::new_witness(ark_relations::ns!(cs, "position bit"), || {
+                    node.map(|(_, bit)| bit).ok_or(AssignmentMissing)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
         {
             let j_sap = <EdwardsVar as AllocVar<_, _>>::new_constant(
                 ark_relations::ns!(cs, "J_sap"),
                 group_hash::calc_pedersen_hash(),
             )?;
             
-            let pos;
-            if let Some(p) = self.pos {
-                pos = UInt8::new_witness_vec(
-                    ark_relations::ns!(cs, "pos"),
-                    &Fr::from(p).0.to_bytes_le(),
-                )?;
-            } else {
-                pos = UInt8::new_witness_vec(ark_relations::ns!(cs, "pos"), &[None])?;
-            }
-            
-            let pos_bits = pos
-                .iter()
-                .flat_map(|b| b.to_bits_le().unwrap())
-                .collect::<Vec<_>>();
-            
             let rho: EdwardsVar = comm.clone() + j_sap.scalar_mul_le(pos_bits.iter())?;
 
-            let nf = <TwoToOneCRHGadget<_> as TwoToOneCRHSchemeGadget<_, _>>::evaluate(
-                &posiedon_hash_params,
-                &nk.y,
-                &rho.y,
+            let nf = nullifier(
+                ark_relations::ns!(cs, "nullifier").cs(),
+                self.nullifier_mode,
+                &nk,
+                &rho,
             )?;
 
-            let nf_old;
-            if let Some(nf_temp) = self.nf_old {
-                nf_old = FpVar::new_witness(
-                    ark_relations::ns!(cs, "nf_old"), 
-                    || Ok(nf_temp.0)
-                )?;
-            } else {
-                nf_old = FpVar::new_witness(
-                    ark_relations::ns!(cs, "nf_old"), 
-                    || Result::<Fq, SynthesisError>::Err(AssignmentMissing)
-                )?;
-            }
-            
-            nf.enforce_equal(&nf_old)?;
+            nf.enforce_equal(&public_inputs.nf)?;
         }
+        section.leave_namespace();
 
         //Merkle Path
-        let mut curr_node: FpVar<ConstraintF> = comm.clone().y;
+        let section = ark_relations::ns!(cs, "merkle path");
+        let mut curr_node: FpVar<ConstraintF> = H::leaf_gadget(&comm);
         
-        for (_, val) in self.auth_path.iter().enumerate() {
-            let pos_bit;
-            let sibling;
-            
-            if let Some((sib, p)) = val {
-                pos_bit =
-                    Boolean::new_witness(ark_relations::ns!(cs, "flip the 2 children"), || Ok(p))?;
-                sibling = FpVar::new_witness(ark_relations::ns!(cs, "sibling"), || Ok(sib))?;
-            } else {
-                pos_bit =
-                    Boolean::new_witness(ark_relations::ns!(cs, "flip the 2 children"), || {
-                        Result::<bool, SynthesisError>::Err(AssignmentMissing)
-                    })?;
-                sibling = FpVar::new_witness(ark_relations::ns!(cs, "sibling"), || {
-                    Result::<ConstraintF, SynthesisError>::Err(AssignmentMissing)
-                })?;
-            }
+        for (layer, (val, pos_bit)) in self.auth_path.iter().zip(&pos_bits).enumerate() {
+            let sibling = FpVar::new_witness(ark_relations::ns!(cs, "sibling"), || {
+                val.map(|(sib, _)| sib).ok_or(AssignmentMissing)
+            })?;
             
             let (lef, rig);
             
             rig = <FpVar<_> as CondSelectGadget<_>>::conditionally_select(
-                &pos_bit, &curr_node, &sibling,
+                pos_bit, &curr_node, &sibling,
             )?;
             
             lef = <FpVar<_> as CondSelectGadget<_>>::conditionally_select(
-                &pos_bit, &sibling, &curr_node,
+                pos_bit, &sibling, &curr_node,
             )?;
             
-            curr_node = <TwoToOneCRHGadget<_> as TwoToOneCRHSchemeGadget<_, _>>::evaluate(
-                &posiedon_hash_params,
+            curr_node = H::hash_gadget(
+                ark_relations::ns!(cs, "merkle hash").cs(),
+                layer,
                 &lef,
                 &rig,
             )?;
         }
         
-        let claimed_root =
-            FpVar::new_input(ark_relations::ns!(cs, "commitment tree root"), || {
-                self.root.ok_or(SynthesisError::AssignmentMissing)
-            })?;
-        
-        curr_node.enforce_equal(&claimed_root)?;
+        curr_node.enforce_equal(&public_inputs.anchor)?;
+        section.leave_namespace();
 
         Ok(())
     }
 }
+
+/// The namespaces `Spend` synthesizes each part of the statement in.
+pub const SPEND_SECTIONS: [&str; 7] = [
+    "spend authority",
+    "nk",
+    "value commitment",
+    "ivk",
+    "note commitment",
+    "nullifier",
+    "merkle path",
+];
+
+/// Number of constraints in each of `SPEND_SECTIONS`, in order, followed by
+/// `"other"` for those outside any section (the public inputs).
+pub fn spend_constraint_report<H: MerkleHash>(
+    spend: Spend<'_, H>,
+) -> Result<Vec<(&'static str, usize)>, SynthesisError> {
+    let mut layer = ConstraintLayer::default();
+    layer.mode = TracingMode::OnlyConstraints;
+    let subscriber = tracing_subscriber::Registry::default().with(layer);
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let cs = ConstraintSystem::new_ref();
+    spend.generate_constraints(cs.clone())?;
+    let names = cs.constraint_names().ok_or(SynthesisError::MissingCS)?;
+
+    let mut report: Vec<(&'static str, usize)> =
+        SPEND_SECTIONS.iter().chain(&["other"]).map(|s| (*s, 0)).collect();
+    for name in names {
+        // Each step of the path is `module::span`; the outermost section wins.
+        let idx = name
+            .split('/')
+            .filter_map(|step| step.rsplit("::").next())
+            .find_map(|span| SPEND_SECTIONS.iter().position(|s| *s == span))
+            .unwrap_or(SPEND_SECTIONS.len());
+        report[idx].1 += 1;
+    }
+
+    Ok(report)
+}
+
+#[derive(Clone)]
 pub struct Output {
-    pub cv_new: Option<EdwardsAffine>,
-    pub note_com_new: Option<EdwardsAffine>,
-    pub epk: Option<EdwardsAffine>,
+    pub public_inputs: Option<OutputPublicInputs>,
     pub gd: Option<EdwardsAffine>,
     pub pk_d: Option<EdwardsAffine>,
     pub v_new: Option<NoteValue>,
     pub rcv_new: Option<ValueCommitTrapdoor>,
     pub rcm_new: Option<Randomness<EdwardsProjective>>,
     pub esk: Option<Fr>,
-    pub note_com_params: Commitment
+    pub note_commitment_mode: NoteCommitmentMode,
+}
+
+impl Output {
+    /// An `Output` without any assignment, to generate parameters from.
+    pub fn blank(note_commitment_mode: NoteCommitmentMode) -> Self {
+        Output {
+            public_inputs: None,
+            gd: None,
+            pk_d: None,
+            v_new: None,
+            rcv_new: None,
+            rcm_new: None,
+            esk: None,
+            note_commitment_mode,
+        }
+    }
 }
 
 impl ConstraintSynthesizer<ConstraintF> for Output {
@@ -408,7 +687,11 @@ impl ConstraintSynthesizer<ConstraintF> for Output {
         self, 
         cs: ark_relations::r1cs::ConstraintSystemRef<ConstraintF>
     ) -> ark_relations::r1cs::Result<()> {
-        
+        let public_inputs = OutputPublicInputsVar::new_input(
+            ark_relations::ns!(cs, "public inputs").cs(),
+            self.public_inputs.as_ref(),
+        )?;
+
         // Note Commitment
         let gd = 
             <EdwardsVar as AllocVar<_,_>>::new_witness(
@@ -422,58 +705,18 @@ impl ConstraintSynthesizer<ConstraintF> for Output {
                 || { self.pk_d.ok_or(AssignmentMissing) }
             )?;
 
-        let note_value;
-        let v_new;
-
-        if let Some(v_n) = self.v_new {
-            v_new = UInt8::new_witness_vec(
-                ark_relations::ns!(cs, "v_new"), 
-                &v_n.0.to_le_bytes()).unwrap();
-            note_value = UInt8::new_witness_vec(
-                ark_relations::ns!(cs, "note_value"), 
-                &Fr::from(v_n.0).0.to_bytes_le())?;
-        } else {
-            v_new = UInt8::new_witness_vec(
-                ark_relations::ns!(cs, "v_new"), 
-                &[None]).unwrap();
-            note_value = UInt8::new_witness_vec(
-                ark_relations::ns!(cs, "note_value"), 
-                &[None])?;
-        }
-
-        let gd_repr = to_repr(gd.clone());
-        let pk_d_repr = to_repr(pk_d);
-
-        let mut note_com_inp = vec![];
-        note_com_inp.extend(gd_repr);
-        note_com_inp.extend(pk_d_repr);
-        note_com_inp.extend(v_new);
+        let note_value = alloc_note_value(cs.clone(), self.v_new.as_ref())?;
 
-        let pdcm_params: pdcmParamsVar<EdwardsProjective, EdwardsVar> = 
-            pdcmParamsVar::<EdwardsProjective, EdwardsVar>::new_constant(
-                ark_relations::ns!(cs, "note_com_params"), 
-                self.note_com_params.params
-            )?;
-        
-        let rcm_new = pdcmRandVar::new_witness(
-                ark_relations::ns!(cs, "rcm_new"), 
-                || { self.rcm_new.ok_or(AssignmentMissing) }
-            )?;
-
-        let note_comm = CommGadget::<EdwardsProjective, EdwardsVar, Window>::commit(
-            &pdcm_params, 
-            &note_com_inp, 
-            &rcm_new
+        let note_comm = note_commitment(
+            ark_relations::ns!(cs, "note commitment").cs(),
+            self.note_commitment_mode,
+            &gd,
+            &pk_d,
+            &note_value,
+            self.rcm_new,
         )?;
 
-        // First input claimed note commitment
-        let claimed_note_com = 
-            <EdwardsVar as AllocVar<_,_>>::new_input(
-                ark_relations::ns!(cs, "claimed_note_com"), 
-                || { self.note_com_new.ok_or(AssignmentMissing) }
-            )?;
-        
-        note_comm.enforce_equal(&claimed_note_com)?;
+        note_comm.enforce_equal(&public_inputs.cm)?;
 
         // Value commitment
         let v_sap_raw = group_hash::calc_v_sapling();
@@ -496,11 +739,11 @@ impl ConstraintSynthesizer<ConstraintF> for Output {
         if let Some(rc) = self.rcv_new {
             rcv = UInt8::new_witness_vec(
                 ark_relations::ns!(cs, "rcv"), 
-                &rc.0.0.to_bytes_le())?;
+                &rc.0.into_bigint().to_bytes_le())?;
         } else {
             rcv = UInt8::new_witness_vec(
                 ark_relations::ns!(cs, "rcv"), 
-                &[None])?;
+                &[None; 32])?;
         }
 
         let rcv_bits = rcv
@@ -510,37 +753,23 @@ impl ConstraintSynthesizer<ConstraintF> for Output {
 
         let computed_val_cm = &v_sap.scalar_mul_le(note_val_bits.iter())?
             + &r_sap.scalar_mul_le(rcv_bits.iter())?;
-        //second public input value commitment
-        let cv_new =
-            <EdwardsVar as AllocVar<_, _>>::new_input(ark_relations::ns!(cs, "cv_new"), || {
-                self.cv_new.ok_or(AssignmentMissing)
-            })?;
-        computed_val_cm.enforce_equal(&cv_new)?;
+        computed_val_cm.enforce_equal(&public_inputs.cv)?;
 
         let esk;
         if let Some(es) = self.esk {
-            esk = UInt8::new_witness_vec(ark_relations::ns!(cs, "esk"), &es.0.to_bytes_le())?;
+            esk = UInt8::new_witness_vec(ark_relations::ns!(cs, "esk"), &es.into_bigint().to_bytes_le())?;
         } else {
-            esk = UInt8::new_witness_vec(ark_relations::ns!(cs, "esk"), &[None])?;
+            esk = UInt8::new_witness_vec(ark_relations::ns!(cs, "esk"), &[None; 32])?;
         }
 
-        //third public input epk
-        let epk = <EdwardsVar as AllocVar<_, _>>::new_input(
-            ark_relations::ns!(cs, "epk"), 
-            || { self.epk.ok_or(AssignmentMissing) }
-        )?;
-
         let esk_bits = esk
             .iter()
             .flat_map(|b| b.to_bits_le().unwrap())
             .collect::<Vec<_>>();
         let computed_epk = gd.scalar_mul_le(esk_bits.iter())?;
-        epk.enforce_equal(&computed_epk)?;
+        public_inputs.epk.enforce_equal(&computed_epk)?;
         
-        let temp = gd.double()?;
-        let temp = temp.double()?;
-        let temp = temp.double()?;
-        temp.x.enforce_not_equal(&FpVar::<ConstraintF>::zero())?;
+        enforce_not_small_order(&gd)?;
         Ok(())
     }
 }
@@ -548,213 +777,558 @@ impl ConstraintSynthesizer<ConstraintF> for Output {
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use crate::commitment::homomorphic_pedersen_commitment;
-    use crate::spend_description::Nullifier;
-    use crate::signing_key::SigningKey;
-    use crate::prf::poseidon_config;
+    use crate::fixtures::{output_witness, spend_witness, SK, SPEND_POSITION};
+    use crate::commitment::{homomorphic_pedersen_commitment, mixing_pedersen_hash};
     use crate::keygen::KeyChain;
-    use ark_crypto_primitives::commitment::{
-        pedersen::{Commitment as pdCommit, Randomness as pdRand},
-        CommitmentScheme,
-    };
-    use ark_crypto_primitives::crh::poseidon::TwoToOneCRH;
-    use ark_crypto_primitives::crh::TwoToOneCRHScheme;
-    use ark_ec::AffineRepr;
-    use ark_ff::{BigInteger, UniformRand};
+    use crate::commitment;
+    use crate::merkle::PedersenMerkleHash;
+    use ark_crypto_primitives::commitment::pedersen::Randomness as pdRand;
+    use ark_ec::{AffineRepr, CurveGroup, Group};
+    use ark_ff::{BigInteger, Field, UniformRand};
     use ark_relations::r1cs::{
-        ConstraintLayer, ConstraintSynthesizer, ConstraintSystem, TracingMode::OnlyConstraints,
+        ConstraintLayer, ConstraintSynthesizer, ConstraintSystem, SynthesisMode,
+        TracingMode::OnlyConstraints,
     };
-    use ark_serialize::CanonicalSerialize;
-    use ark_std::One;
+    use crate::group_hash::test::small_order_points;
+    use ark_r1cs_std::alloc::AllocationMode;
+    use ark_relations::r1cs::Variable;
+    use ark_std::{One, Zero};
     use rand::thread_rng;
     use tracing_subscriber::layer::SubscriberExt;
-    const SK: SigningKey = &[
-        24, 226, 141, 234, 92, 17, 129, 122, 238, 178, 26, 25, 152, 29, 40, 54, 142, 196, 56, 175,
-        194, 90, 141, 185, 78, 190, 8, 215, 160, 40, 142, 9,
-    ];
+
+    /// Builds a satisfying `Spend` from `spend_witness`, except that `cv` is
+    /// offset by `cv_offset`, and hands it to `f` along with its public inputs.
+    fn with_spend_circuit<H: MerkleHash, R>(
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+        cv_offset: Fr,
+        f: impl FnOnce(Spend<'_, H>, &SpendPublicInputs) -> R,
+    ) -> R {
+        let mut witness = spend_witness::<H>(note_commitment_mode, nullifier_mode, ivk_mode);
+        witness.public_inputs.cv = (witness.public_inputs.cv
+            + group_hash::calc_v_sapling().mul_bigint(cv_offset.into_bigint()))
+        .into_affine();
+
+        witness.with_circuit::<H, _>(|spend| f(spend, &witness.public_inputs))
+    }
+
+    /// `cv` commits to the note value plus `cv_offset`, so a nonzero offset
+    /// makes the value and note commitments disagree.
+    fn spend_circuit_is_satisfied<H: MerkleHash>(
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+        cv_offset: Fr,
+    ) -> bool {
+        with_spend_circuit::<H, _>(
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode,
+            cv_offset,
+            |spend, public_inputs| {
+                let mut layer = ConstraintLayer::default();
+                layer.mode = OnlyConstraints;
+                let subscriber = tracing_subscriber::Registry::default().with(layer);
+                let _guard = tracing::subscriber::set_default(subscriber);
+
+                let cs = ConstraintSystem::new_ref();
+                spend.generate_constraints(cs.clone()).unwrap();
+
+                // The circuit exposes exactly the Sapling statement, in order.
+                let mut expected = vec![ConstraintF::one()];
+                expected.extend(public_inputs.to_field_elements());
+                assert_eq!(cs.borrow().unwrap().instance_assignment, expected);
+
+                let result = cs.is_satisfied().unwrap();
+
+                println!("total constraints : {:?}", cs.num_constraints());
+                println!("result {:?}", result);
+
+                if !result {
+                    println!("{:?}", cs.which_is_unsatisfied());
+                }
+
+                result
+            },
+        )
+    }
+
+    fn check_spend_circuit<H: MerkleHash>(
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+    ) {
+        assert!(spend_circuit_is_satisfied::<H>(
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode,
+            Fr::zero(),
+        ));
+    }
 
     #[test]
     pub fn test_spend_circuit() {
+        check_spend_circuit::<PoseidonMerkleHash>(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+        );
+    }
+
+    #[test]
+    pub fn test_spend_circuit_sapling_note_commitment() {
+        check_spend_circuit::<PoseidonMerkleHash>(
+            NoteCommitmentMode::Sapling,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+        );
+    }
+
+    #[test]
+    pub fn test_spend_circuit_sapling_nullifier() {
+        check_spend_circuit::<PoseidonMerkleHash>(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Sapling,
+            IvkMode::Blake2s,
+        );
+    }
+
+    #[test]
+    pub fn test_spend_circuit_sapling_merkle_tree() {
+        check_spend_circuit::<PedersenMerkleHash>(
+            NoteCommitmentMode::Sapling,
+            NullifierMode::Sapling,
+            IvkMode::Blake2s,
+        );
+    }
+
+    #[test]
+    pub fn test_nullifier_is_bound_to_the_auth_path() {
+        with_spend_circuit::<PoseidonMerkleHash, _>(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+            Fr::zero(),
+            |mut spend, _| {
+                let kc = KeyChain::new(SK, IvkMode::Blake2s);
+                let g_d = spend.gd.unwrap();
+                let pk_d = (g_d * kc.ivk.0).into_affine();
+                let cm = commitment::note_commitment(
+                    NoteCommitmentMode::Arkworks,
+                    &g_d,
+                    &pk_d,
+                    spend.note_val.as_ref().unwrap(),
+                    spend.crh_rand.as_ref().unwrap(),
+                );
+
+                // The path is for `SPEND_POSITION`; the nullifier of the same
+                // note at any other position must not verify against it.
+                let public_inputs = spend.public_inputs.as_mut().unwrap();
+                assert_eq!(
+                    public_inputs.nf,
+                    Nullifier::new(cm, SPEND_POSITION, kc.nk.0, NullifierMode::Poseidon)
+                );
+                public_inputs.nf = Nullifier::new(cm, SPEND_POSITION + 1, kc.nk.0, NullifierMode::Poseidon);
+
+                let cs = ConstraintSystem::new_ref();
+                spend.generate_constraints(cs.clone()).unwrap();
+                assert!(!cs.is_satisfied().unwrap());
+            },
+        );
+    }
+
+    #[test]
+    pub fn test_spend_circuit_poseidon_ivk() {
+        check_spend_circuit::<PoseidonMerkleHash>(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Poseidon,
+        );
+    }
+
+    /// Parses the `[config]` block of `constraint_budget.txt` into
+    /// `section = budget` pairs.
+    fn constraint_budget(config: &str) -> Vec<(String, usize)> {
+        let header = format!("[{}]", config);
+        include_str!("../constraint_budget.txt")
+            .lines()
+            .map(str::trim)
+            .skip_while(|line| *line != header)
+            .skip(1)
+            .take_while(|line| !line.starts_with('['))
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (section, budget) = line.split_once('=').unwrap();
+                (section.trim().to_string(), budget.trim().parse().unwrap())
+            })
+            .collect()
+    }
+
+    fn check_constraint_budget<H: MerkleHash>(
+        config: &str,
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+    ) {
+        let report = with_spend_circuit::<H, _>(
+            note_commitment_mode,
+            nullifier_mode,
+            IvkMode::Blake2s,
+            Fr::zero(),
+            |spend, _| spend_constraint_report(spend).unwrap(),
+        );
+        let budget = constraint_budget(config);
+
+        assert_eq!(
+            budget.iter().map(|(s, _)| s.as_str()).collect::<Vec<_>>(),
+            report.iter().map(|(s, _)| *s).collect::<Vec<_>>(),
+        );
+        for ((section, count), (_, limit)) in report.iter().zip(&budget) {
+            assert!(
+                count <= limit,
+                "[{}] {} uses {} constraints, over its budget of {}; all sections: {:?}",
+                config,
+                section,
+                count,
+                limit,
+                report
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_spend_constraint_budget() {
+        check_constraint_budget::<PoseidonMerkleHash>(
+            "poseidon",
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+        );
+    }
+
+    #[test]
+    pub fn test_spend_constraint_budget_sapling() {
+        check_constraint_budget::<PedersenMerkleHash>(
+            "sapling",
+            NoteCommitmentMode::Sapling,
+            NullifierMode::Sapling,
+        );
+    }
+
+    #[test]
+    pub fn test_crh_ivk_gadget() {
+        let kc = KeyChain::new(SK, IvkMode::Poseidon);
+
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
+        let ak = EdwardsVar::new_witness(cs.clone(), || Ok(kc.ak.0)).unwrap();
+        let nk = EdwardsVar::new_witness(cs.clone(), || Ok(kc.nk.0)).unwrap();
+
+        let ivk_bits = crh_ivk(cs.clone(), IvkMode::Poseidon, &ak, &nk).unwrap();
+        let ivk_bits = ivk_bits.value().unwrap();
+
+        assert_eq!(ivk_bits.len(), prf_expand::IVK_BITS);
+        assert_eq!(
+            Fr::from_bigint(BigInteger::from_bits_le(&ivk_bits)).unwrap(),
+            kc.ivk.0
+        );
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    /// Flips the witness assigned to `bit`, as a malicious prover could.
+    fn flip_witness(cs: &ConstraintSystemRef<ConstraintF>, bit: &Boolean<ConstraintF>) {
+        let var = match bit {
+            Boolean::Is(b) | Boolean::Not(b) => b.variable(),
+            Boolean::Constant(_) => panic!("ivk bit is a constant"),
+        };
+        let Variable::Witness(i) = var else {
+            panic!("ivk bit is not a witness")
+        };
+
+        let mut cs = cs.borrow_mut().unwrap();
+        cs.witness_assignment[i] = ConstraintF::one() - cs.witness_assignment[i];
+    }
+
+    #[test]
+    pub fn test_tampered_ivk_is_unsatisfiable() {
+        for mode in [IvkMode::Blake2s, IvkMode::Poseidon] {
+            let kc = KeyChain::new(SK, mode);
+
+            let cs = ConstraintSystem::<ConstraintF>::new_ref();
+            let ak = EdwardsVar::new_witness(cs.clone(), || Ok(kc.ak.0)).unwrap();
+            let nk = EdwardsVar::new_witness(cs.clone(), || Ok(kc.nk.0)).unwrap();
+            let ivk_bits = crh_ivk(cs.clone(), mode, &ak, &nk).unwrap();
+
+            assert_eq!(ivk_bits.len(), prf_expand::IVK_BITS);
+            assert!(cs.is_satisfied().unwrap());
+
+            // The top byte is the one that used to be re-allocated unconstrained.
+            for i in [0, 100, 247, 248, 249, 250] {
+                flip_witness(&cs, &ivk_bits[i]);
+                assert!(!cs.is_satisfied().unwrap(), "{:?}: bit {} is unconstrained", mode, i);
+                flip_witness(&cs, &ivk_bits[i]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_spend_with_tampered_pk_d_is_unsatisfiable() {
         let kc = KeyChain::from(SK);
-        
         let (_d, g_d, pk_d) = kc.get_diversified_transmission_address();
-        let randmized_pk = kc.get_randomized_ak();
-        
-        let note_val = NoteValue(2);
-        let rcv = ValueCommitTrapdoor::random();
-        
-        let val_commitment = homomorphic_pedersen_commitment(note_val.clone(), &rcv).into_group();
-        let comm = Commitment::setup();
-        
-        let crh_rand = pdRand::<EdwardsProjective>(Fr::one());
-        
-        let mut kc_key = vec![];
-        kc_key.extend(kc.ak.to_repr_j());
-        kc_key.extend(kc.nk.to_repr_j());
-        
-        let mut note_com_inp = vec![];
-        
-        let mut g_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&g_d, &mut g_d_repr[..])
-            .expect("failed");
 
-        let mut pk_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&pk_d.0, &mut pk_d_repr[..])
-            .expect("failed");
-        
-        let v_repr = note_val.0.to_le_bytes();
-        
-        note_com_inp.extend(g_d_repr);
-        note_com_inp.extend(pk_d_repr);
-        note_com_inp.extend(v_repr);
-        
-        let note_com = pdCommit::<EdwardsProjective, Window>::commit(
-            &comm.params.clone(),
-            &note_com_inp,
-            &crh_rand,
-        )
-        .expect("asdf");
-        
-        let mut ivk: [u8; 32] = [0; 32];
-        ivk.copy_from_slice(&kc.ivk.0 .0.to_bytes_le());
-        
-        let mut pos: u64 = 1000;
-        let mut merkle_path: Vec<Option<(ark_bls12_381::Fr, bool)>> = vec![];
-        let mut root_till_now: ark_bls12_381::Fr = note_com.y;
+        // pk_d for an ivk that agrees with the real one except in its top byte.
+        let tampered_ivk = kc.ivk.0 + Fr::from(2u8).pow([248u64]);
+        let tampered_pk_d = (g_d * tampered_ivk).into_affine();
+        assert_ne!(tampered_pk_d, pk_d.0);
+
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
+        let ak = EdwardsVar::new_witness(cs.clone(), || Ok(kc.ak.0)).unwrap();
+        let nk = EdwardsVar::new_witness(cs.clone(), || Ok(kc.nk.0)).unwrap();
+        let g_d_var = EdwardsVar::new_witness(cs.clone(), || Ok(g_d)).unwrap();
+        let claimed = EdwardsVar::new_input(cs.clone(), || Ok(tampered_pk_d)).unwrap();
+
+        let ivk_bits = crh_ivk(cs.clone(), IvkMode::Blake2s, &ak, &nk).unwrap();
+        let computed = g_d_var.scalar_mul_le(ivk_bits.iter()).unwrap();
+        computed.enforce_equal(&claimed).unwrap();
+
+        assert_eq!(computed.value().unwrap().into_affine(), pk_d.0);
+        assert!(!cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    pub fn test_nullifier_gadget() {
+        let kc = KeyChain::from(SK);
         let mut rng = thread_rng();
-        
-        let p = pos.clone();
-        
-        for _ in 0..32 {
-            let (lef, rig);
-            if pos % 2 == 1 {
-                lef = ark_bls12_381::Fr::rand(&mut rng);
+        let cm = (EdwardsProjective::generator() * Fr::rand(&mut rng)).into_affine();
+        let pos = 1000;
 
-                rig = root_till_now;
-                merkle_path.push(Some((lef, true)));
-            } else {
-                rig = ark_bls12_381::Fr::rand(&mut rng);
-                lef = root_till_now;
-                merkle_path.push(Some((rig, false)));
-            }
-            root_till_now = <TwoToOneCRH<_> as TwoToOneCRHScheme>::evaluate(
-                &poseidon_config::poseidon_parameters(),
-                lef,
-                rig,
-            )
-            .expect("hash failed");
-            pos = pos / 2;
-        }
-        
-        let nf = Nullifier::new(note_com, p, kc.nk.0);
-        
-        let mut oa = vec![];
-        for i in randmized_pk.0 .0.to_bytes_le() {
-            oa.push(Some(i))
-        }
-        
-        let mut nsk = vec![];
-        for i in kc.nsk.0 .0.to_bytes_le() {
-            nsk.push(Some(i));
+        for mode in [NullifierMode::Poseidon, NullifierMode::Sapling] {
+            let cs = ConstraintSystem::<ConstraintF>::new_ref();
+            let nk = EdwardsVar::new_witness(cs.clone(), || Ok(kc.nk.0)).unwrap();
+            let rho = EdwardsVar::new_witness(cs.clone(), || {
+                Ok(mixing_pedersen_hash(cm, Fr::from_le_bytes_mod_order(&u64::to_le_bytes(pos))))
+            })
+            .unwrap();
+
+            let nf = nullifier(cs.clone(), mode, &nk, &rho).unwrap();
+
+            assert_eq!(nf.value().unwrap(), Nullifier::new(cm, pos, kc.nk.0, mode).0);
+            assert!(cs.is_satisfied().unwrap());
         }
-        
-        let spend = Spend {
-            auth_path: merkle_path,
-            root: Some(root_till_now),
-            ak: Some(kc.ak.clone().0),
-            randomized_ak: Some(randmized_pk.1 .0),
-            randomness: &oa,
-            sig_params: kc.params.clone(),
-            nsk: &nsk,
-            nk: Some(kc.nk.0),
-            note_val: Some(note_val.clone()),
-            rcv_old: Some(rcv.clone()),
-            val_cm_old: Some(val_commitment.into()),
-            cm_params: Some(comm.clone()),
-            crh_rand: Some(crh_rand),
-            note_com: note_com,
-            ivk: Some(kc.ivk.0),
-            gd: Some(g_d),
-            pk_d: Some(pk_d.0),
-            nf_old: Some(nf),
-            pos: Some(p),
-        };
-        
+    }
+    
+    fn sample_output(note_commitment_mode: NoteCommitmentMode, cv_offset: Fr) -> Output {
+        let mut witness = output_witness(note_commitment_mode);
+        witness.public_inputs.cv = (witness.public_inputs.cv
+            + group_hash::calc_v_sapling().mul_bigint(cv_offset.into_bigint()))
+        .into_affine();
+        Output::from(&witness)
+    }
+
+    fn output_circuit_is_satisfied(note_commitment_mode: NoteCommitmentMode, cv_offset: Fr) -> bool {
+        let output = sample_output(note_commitment_mode, cv_offset);
+        let public_inputs = output.public_inputs.clone().unwrap();
+
         let mut layer = ConstraintLayer::default();
         layer.mode = OnlyConstraints;
         let subscriber = tracing_subscriber::Registry::default().with(layer);
         let _guard = tracing::subscriber::set_default(subscriber);
         
         let cs = ConstraintSystem::new_ref();
-        spend.generate_constraints(cs.clone()).unwrap();
+        output.generate_constraints(cs.clone()).unwrap();
+
+        let mut expected = vec![ConstraintF::one()];
+        expected.extend(public_inputs.to_field_elements());
+        assert_eq!(cs.borrow().unwrap().instance_assignment, expected);
 
         let result = cs.is_satisfied().unwrap();
-        
-        println!("total constraints : {:?}", cs.num_constraints());
         println!("result {:?}", result);
-        
         if !result {
             println!("{:?}", cs.which_is_unsatisfied());
         }
+        println!("num constraints: {:?}", cs.num_constraints());
+        result
+    }
 
-        assert!(result);
+    fn shape(cs: &ConstraintSystemRef<ConstraintF>) -> (usize, usize, usize) {
+        (
+            cs.num_constraints(),
+            cs.num_instance_variables(),
+            cs.num_witness_variables(),
+        )
+    }
+
+    fn setup_cs() -> ConstraintSystemRef<ConstraintF> {
+        let cs = ConstraintSystem::new_ref();
+        cs.set_mode(SynthesisMode::Setup);
+        cs
+    }
+
+    #[test]
+    pub fn test_blank_circuits_have_the_same_shape() {
+        for (nc_mode, nf_mode) in [
+            (NoteCommitmentMode::Arkworks, NullifierMode::Poseidon),
+            (NoteCommitmentMode::Sapling, NullifierMode::Sapling),
+        ] {
+            with_spend_circuit::<PoseidonMerkleHash, _>(
+                nc_mode,
+                nf_mode,
+                IvkMode::Blake2s,
+                Fr::zero(),
+                |spend, _| {
+                    let filled = ConstraintSystem::new_ref();
+                    spend.generate_constraints(filled.clone()).unwrap();
+
+                    let blank = setup_cs();
+                    Spend::<PoseidonMerkleHash>::blank(nc_mode, nf_mode, IvkMode::Blake2s)
+                        .generate_constraints(blank.clone())
+                        .unwrap();
+
+                    assert_eq!(shape(&filled), shape(&blank));
+                },
+            );
+
+            let filled = ConstraintSystem::new_ref();
+            sample_output(nc_mode, Fr::zero())
+                .generate_constraints(filled.clone())
+                .unwrap();
+
+            let blank = setup_cs();
+            Output::blank(nc_mode).generate_constraints(blank.clone()).unwrap();
+
+            assert_eq!(shape(&filled), shape(&blank));
+        }
+    }
+
+    fn check_output_circuit(note_commitment_mode: NoteCommitmentMode) {
+        assert!(output_circuit_is_satisfied(note_commitment_mode, Fr::zero()));
     }
-    
     #[test]
     pub fn test_output_circuit() {
-        let kc = KeyChain::from(SK);
+        check_output_circuit(NoteCommitmentMode::Arkworks);
+    }
+
+    #[test]
+    pub fn test_output_circuit_sapling_note_commitment() {
+        check_output_circuit(NoteCommitmentMode::Sapling);
+    }
+
+    /// Offsets of the `cv` value from the note value of 10: the next value, a
+    /// value that only fits the 32-byte encoding, a negative value, and the
+    /// Montgomery form the value commitment used to be computed over.
+    fn mismatched_value_offsets() -> Vec<Fr> {
+        vec![
+            Fr::one(),
+            Fr::from(2u8).pow([64u64]),
+            -Fr::from(20u8),
+            Fr::from(10u8) * (Fr::from(2u8).pow([256u64]) - Fr::one()),
+        ]
+    }
+
+    #[test]
+    pub fn test_spend_value_encodings_must_agree() {
+        for offset in mismatched_value_offsets() {
+            assert!(!spend_circuit_is_satisfied::<PoseidonMerkleHash>(
+                NoteCommitmentMode::Arkworks,
+                NullifierMode::Poseidon,
+                IvkMode::Blake2s,
+                offset,
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_output_value_encodings_must_agree() {
+        for mode in [NoteCommitmentMode::Arkworks, NoteCommitmentMode::Sapling] {
+            for offset in mismatched_value_offsets() {
+                assert!(!output_circuit_is_satisfied(mode, offset));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_note_value_is_range_checked() {
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
+        let value = alloc_note_value(cs.clone(), Some(&NoteValue(u64::MAX))).unwrap();
+
+        assert_eq!(value.len(), 8);
+        assert_eq!(value.value().unwrap(), u64::MAX.to_le_bytes());
+        assert_eq!(cs.num_witness_variables(), 64);
+        assert!(cs.is_satisfied().unwrap());
+
+        // Every allocated bit is boolean-constrained, so no witness can encode
+        // anything outside [0, 2^64).
+        let bits = value.iter().flat_map(|b| b.to_bits_le().unwrap()).collect::<Vec<_>>();
+        let Boolean::Is(bit) = &bits[63] else { panic!() };
+        let Variable::Witness(i) = bit.variable() else { panic!() };
+        cs.borrow_mut().unwrap().witness_assignment[i] = ConstraintF::from(2u8);
+        assert!(!cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    pub fn test_small_order_points_are_rejected() {
+        for p in small_order_points() {
+            // Unchecked allocation, and the regular witness allocation which
+            // maps small-order points to the identity.
+            let cs = ConstraintSystem::<ConstraintF>::new_ref();
+            let unchecked =
+                EdwardsVar::new_variable_omit_on_curve_check(cs.clone(), || Ok(p), AllocationMode::Witness)
+                    .unwrap();
+            enforce_not_small_order(&unchecked).unwrap();
+            assert!(!cs.is_satisfied().unwrap());
+
+            let cs = ConstraintSystem::<ConstraintF>::new_ref();
+            let witness = EdwardsVar::new_witness(cs.clone(), || Ok(p)).unwrap();
+            enforce_not_small_order(&witness).unwrap();
+            assert!(!cs.is_satisfied().unwrap());
+        }
+
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
+        let g = EdwardsVar::new_witness(cs.clone(), || Ok(group_hash::group_hash_spend_auth()))
+            .unwrap();
+        enforce_not_small_order(&g).unwrap();
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    pub fn test_output_rejects_identity_g_d() {
+        // Every other relation holds for g_d = O, so only the small-order check
+        // can reject it.
+        let g_d = EdwardsAffine::zero();
+        let pk_d = group_hash::group_hash_spend_auth();
         let value = NoteValue(10);
         let rcv = ValueCommitTrapdoor::random();
-        let cv_new = homomorphic_pedersen_commitment(value.clone(), &rcv);
-        let (_, g_d, pk_d) = kc.get_diversified_transmission_address();
-
-        let cm_params = Commitment::setup();
         let rcm = pdRand::<EdwardsProjective>(Fr::from(46));
-        let mut note_com_inp = vec![];
-        let mut g_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&g_d, &mut g_d_repr[..])
-            .expect("failed");
-
-        let mut pk_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&pk_d.0, &mut pk_d_repr[..])
-            .expect("failed");
-        let v_repr = value.0.to_le_bytes();
-        note_com_inp.extend(g_d_repr);
-        note_com_inp.extend(pk_d_repr);
-        note_com_inp.extend(v_repr);
-        let note_comm =
-            pdCommit::<EdwardsProjective, Window>::commit(&cm_params.params, &note_com_inp, &rcm)
-                .expect("failed");
-        
         let esk = Fr::from(5345345);
-        let epk = g_d.mul_bigint(esk.0);
+
         let output = Output {
-            cv_new: Some(cv_new),
-            note_com_new: Some(note_comm),
-            epk: Some(epk.into()),
+            public_inputs: Some(OutputPublicInputs {
+                cv: homomorphic_pedersen_commitment(value.clone(), &rcv),
+                cm: commitment::note_commitment(
+                    NoteCommitmentMode::Arkworks,
+                    &g_d,
+                    &pk_d,
+                    &value,
+                    &rcm,
+                ),
+                epk: g_d.mul_bigint(esk.into_bigint()).into_affine(),
+            }),
             gd: Some(g_d),
-            pk_d: Some(pk_d.0),
+            pk_d: Some(pk_d),
             v_new: Some(value),
             rcv_new: Some(rcv),
             rcm_new: Some(rcm),
             esk: Some(esk),
-            note_com_params: cm_params,
+            note_commitment_mode: NoteCommitmentMode::Arkworks,
         };
-        
+
         let mut layer = ConstraintLayer::default();
         layer.mode = OnlyConstraints;
         let subscriber = tracing_subscriber::Registry::default().with(layer);
         let _guard = tracing::subscriber::set_default(subscriber);
-        
-        let cs = ConstraintSystem::new_ref();
+
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
         output.generate_constraints(cs.clone()).unwrap();
 
-        let result = cs.is_satisfied().unwrap();
-        println!("result {:?}", result);
-        if !result {
-            println!("{:?}", cs.which_is_unsatisfied());
-        }
-        println!("num constraints: {:?}", cs.num_constraints());
-        assert!(result);
+        assert!(!cs.is_satisfied().unwrap());
+        assert!(cs.which_is_unsatisfied().unwrap().unwrap().contains("enforce_not_small_order"));
     }
-}
\ No newline at end of file
+}
@@ -1,63 +1,121 @@
+use std::sync::OnceLock;
+
 use ark_ec::AffineRepr;
-use ark_ed_on_bls12_381::{EdwardsProjective, Fq, Fr};
-use ark_crypto_primitives::commitment::{pedersen, CommitmentScheme};
+use ark_ed_on_bls12_381::{EdwardsProjective, Fr};
+use ark_crypto_primitives::commitment::pedersen;
 use ark_ed_on_bls12_381::EdwardsAffine;
 use rand::{thread_rng, Rng};
 use ark_ff::PrimeField;
-use ark_std::Zero;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use ark_crypto_primitives::commitment::pedersen::Window as pdWindow;
+use ark_crypto_primitives::commitment::CommitmentScheme;
+use ark_crypto_primitives::signature::schnorr;
+use crate::keygen::SecretKey;
 use crate::{group_hash::{self}, note::NoteValue, pedersen_crh::{self, Window}};
-use ark_ff::BigInteger;
-use ark_ec::CurveGroup;
-
-const X: [u8; 32] = [
-    98, 100, 227, 168, 52, 59, 20, 165, 218, 236, 177, 255, 6, 157, 145, 240, 44, 236, 59, 243,
-    161, 154, 64, 161, 140, 42, 199, 158, 138, 159, 235, 38,
-];
-const Y: [u8; 32] = [
-    231, 232, 93, 224, 247, 249, 122, 70, 210, 73, 161, 245, 234, 81, 223, 80, 204, 72, 73, 15,
-    132, 1, 201, 222, 122, 42, 223, 24, 7, 209, 182, 84,
-];
+use crate::pedersen_hash::{self, Personalization};
+use ark_ec::{CurveGroup, Group};
+
+static NOTE_COMMITMENT_PARAMS: OnceLock<NoteCommitmentParams> = OnceLock::new();
 
+/// Generator table of the windowed Pedersen note commitment.
+///
+/// Every base is derived from the `Zcash_PH` group hash, so the table is
+/// identical across processes and only computed once.
 #[derive(Clone)]
-pub struct Commitment {
-    pub params: pedersen::Parameters<ark_ed_on_bls12_381::EdwardsProjective>,
+pub struct NoteCommitmentParams {
+    pub params: pedersen::Parameters<EdwardsProjective>,
 }
 
-impl Commitment {
-    pub fn setup() -> Self {
-        let mut rng = thread_rng();
-        let mut params = 
-            pedersen::Commitment::<ark_ed_on_bls12_381::EdwardsProjective, Window>::setup(&mut rng).unwrap();
-        
-        let randomness_generator = EdwardsAffine::new_unchecked(
-            Fq::from_le_bytes_mod_order(&X),
-            Fq::from_le_bytes_mod_order(&Y),
+impl NoteCommitmentParams {
+    pub fn get() -> &'static Self {
+        NOTE_COMMITMENT_PARAMS.get_or_init(Self::generate)
+    }
+
+    fn generate() -> Self {
+        let randomness_generator = powers_of_two(
+            group_hash::note_commitment_randomness_generator().into_group(),
+            Fr::MODULUS_BIT_SIZE as usize,
         );
 
-        params.randomness_generator = vec![randomness_generator.into_group()];
-        let v: Vec<EdwardsProjective> = pedersen_crh::get_pedersen_generators();
-        let mut generators: Vec<Vec<EdwardsProjective>> = vec![];
-        for g in v.iter() {
-            let mut nv: Vec<EdwardsProjective> = vec![];
-            let mut base = EdwardsProjective::zero();
+        let generators = pedersen_crh::get_pedersen_generators()
+            .into_iter()
+            .map(|g| powers_of_two(g, Window::WINDOW_SIZE))
+            .collect();
+
+        Self {
+            params: pedersen::Parameters {
+                randomness_generator,
+                generators,
+            },
+        }
+    }
+}
+
+/// Selects how note commitments are computed, natively and in the circuits.
+///
+/// `Arkworks` is the 72x8-bit windowed Pedersen commitment over
+/// [`NoteCommitmentParams`]; `Sapling` is the spec's `NoteCommit^Sapling`,
+/// which yields `cmu` values matching real Sapling notes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoteCommitmentMode {
+    #[default]
+    Arkworks,
+    Sapling,
+}
 
-            for _ in 0..Window::NUM_WINDOWS {
-                nv.push(base);
-                base += g;
-            }
+pub fn note_commitment(
+    mode: NoteCommitmentMode,
+    g_d: &EdwardsAffine,
+    pk_d: &EdwardsAffine,
+    value: &NoteValue,
+    rcm: &pedersen::Randomness<EdwardsProjective>,
+) -> EdwardsAffine {
+    let g_d_repr = group_hash::repr_j(g_d);
+    let pk_d_repr = group_hash::repr_j(pk_d);
+    let v_repr = value.0.to_le_bytes();
 
-            generators.push(nv);
+    match mode {
+        NoteCommitmentMode::Arkworks => {
+            let mut inp = vec![];
+            inp.extend(g_d_repr);
+            inp.extend(pk_d_repr);
+            inp.extend(v_repr);
+
+            pedersen::Commitment::<EdwardsProjective, Window>::commit(
+                &NoteCommitmentParams::get().params,
+                &inp,
+                rcm,
+            )
+            .expect("note commitment input fits the window")
         }
-        params.generators = generators;
+        NoteCommitmentMode::Sapling => {
+            let mut inp = vec![];
+            inp.extend(v_repr);
+            inp.extend(g_d_repr);
+            inp.extend(pk_d_repr);
 
-        Self {
-        params
+            pedersen_hash::windowed_pedersen_commit(
+                Personalization::NoteCommitment,
+                pedersen_hash::bytes_to_bits_le(&inp),
+                &rcm.0,
+            )
         }
     }
 }
 
-#[derive(Clone, Debug)]
+fn powers_of_two(base: EdwardsProjective, n: usize) -> Vec<EdwardsProjective> {
+    let mut powers = Vec::with_capacity(n);
+    let mut power = base;
+
+    for _ in 0..n {
+        powers.push(power);
+        power.double_in_place();
+    }
+
+    powers
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
 pub struct ValueCommitTrapdoor(pub Fr);
 
 impl ValueCommitTrapdoor {
@@ -69,33 +127,64 @@ impl ValueCommitTrapdoor {
     }
 }
 
+/// `bsk`, the spends' value commitment trapdoors minus the outputs'.
+pub fn binding_signing_key(
+    spends: &[ValueCommitTrapdoor],
+    outputs: &[ValueCommitTrapdoor],
+) -> SecretKey {
+    let sum = |rcvs: &[ValueCommitTrapdoor]| {
+        rcvs.iter().map(|rcv| rcv.0).sum::<Fr>()
+    };
+    schnorr::SecretKey(sum(spends) - sum(outputs))
+}
+
 pub fn homomorphic_pedersen_commitment(val: NoteValue, rcv: &ValueCommitTrapdoor) -> EdwardsAffine {
     let v_sap = group_hash::calc_v_sapling();
     let r_sap = group_hash::calc_r_sapling();
 
     let v = Fr::from(val.0);
-    println!("note_val: {:?}", v.0.to_bytes_le());
 
-    (v_sap.mul_bigint(v.0) + r_sap.mul_bigint(rcv.0 .0)).into_affine()
+    (v_sap.mul_bigint(v.into_bigint()) + r_sap.mul_bigint(rcv.0.into_bigint())).into_affine()
 }
 
+/// `MixingPedersenHash`: `cm + [x] J`. The Spend circuit multiplies by the
+/// bits of the note position, so `x` is taken by its canonical value.
 pub fn mixing_pedersen_hash(note_comm: EdwardsAffine, x: Fr) -> EdwardsAffine {
-    println!("pos fr{:?}", x.0.to_bytes_le());
     let j_sap = group_hash::calc_pedersen_hash();
-    (note_comm + j_sap.mul_bigint(x.0)).into_affine()
+    (note_comm + j_sap.mul_bigint(x.into_bigint())).into_affine()
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::pedersen_crh::test::{GENERATORS_X, GENERATORS_Y};
     use crate::pedersen_crh::Window;
     use ark_crypto_primitives::commitment::pedersen::{Commitment as pdCommit, Randomness};
     use ark_crypto_primitives::commitment::CommitmentScheme;
-    use ark_ed_on_bls12_381::Fr;
+    use ark_ed_on_bls12_381::{Fq, Fr};
     use ark_std::One;
+
+    // NOTE_COMMITMENT_RANDOMNESS_GENERATOR from the Sapling reference implementation.
+    const R_X: [u64; 4] = [
+        0xa514_3b34_a8e3_6462,
+        0xf091_9d06_ffb1_ecda,
+        0xa140_9aa1_f33b_ec2c,
+        0x26eb_9f8a_9ec7_2a8c,
+    ];
+    const R_Y: [u64; 4] = [
+        0xd4fc_6365_796c_77ac,
+        0x96b7_8bea_fa9c_c44c,
+        0x949d_7747_6e26_2c95,
+        0x114b_7501_ad10_4c57,
+    ];
+
+    fn fq_from_limbs(limbs: &[u64; 4]) -> Fq {
+        Fq::from_bigint(ark_ff::BigInt(*limbs)).unwrap()
+    }
+
     #[test]
     pub fn comm_test() {
-        let c = Commitment::setup();
+        let c = NoteCommitmentParams::get();
         let r = Randomness::<ark_ed_on_bls12_381::EdwardsProjective>(Fr::one());
 
         let cm = pdCommit::<ark_ed_on_bls12_381::EdwardsProjective, Window>::commit(
@@ -103,7 +192,87 @@ pub mod test {
         );
         println!("com {:?}", cm.unwrap().is_on_curve());
     }
+
+    #[test]
+    pub fn test_note_commitment_params() {
+        let c = NoteCommitmentParams::get();
+        let r = EdwardsAffine::new(fq_from_limbs(&R_X), fq_from_limbs(&R_Y));
+
+        assert_eq!(c.params.randomness_generator[0].into_affine(), r);
+        assert_eq!(c.params.randomness_generator.len(), Fr::MODULUS_BIT_SIZE as usize);
+        assert_eq!(c.params.generators.len(), Window::NUM_WINDOWS);
+
+        for (g, powers) in pedersen_crh::get_pedersen_generators()
+            .iter()
+            .zip(&c.params.generators)
+        {
+            assert_eq!(powers.len(), Window::WINDOW_SIZE);
+            assert_eq!(&powers[0], g);
+            assert_eq!(powers[1], g.double());
+        }
+
+        // The first message generators are Sapling's Pedersen hash generators.
+        for (i, (x, y)) in GENERATORS_X.iter().zip(&GENERATORS_Y).enumerate() {
+            let g = EdwardsAffine::new(fq_from_limbs(x), fq_from_limbs(y));
+            assert_eq!(c.params.generators[i][0].into_affine(), g, "generator {}", i);
+        }
+
+        assert!(std::ptr::eq(c, NoteCommitmentParams::get()));
+    }
+
+    #[test]
+    pub fn test_commitment_binds_every_bit() {
+        let c = NoteCommitmentParams::get();
+        let r = Randomness::<EdwardsProjective>(Fr::from(2));
+
+        let cm_2 = pdCommit::<EdwardsProjective, Window>::commit(&c.params, &[2], &r).unwrap();
+        let cm_3 = pdCommit::<EdwardsProjective, Window>::commit(&c.params, &[3], &r).unwrap();
+        assert_ne!(cm_2, cm_3);
+
+        let r_3 = Randomness::<EdwardsProjective>(Fr::from(3));
+        let cm_2_r_3 = pdCommit::<EdwardsProjective, Window>::commit(&c.params, &[2], &r_3).unwrap();
+        assert_ne!(cm_2, cm_2_r_3);
+    }
     
+    #[test]
+    pub fn test_sapling_note_commitment_vector() {
+        // First vector of zcash-test-vectors' sapling_note_encryption.py.
+        let d: [u8; 11] = [0xf1, 0x9d, 0x9b, 0x79, 0x7e, 0x39, 0xf3, 0x37, 0x44, 0x58, 0x39];
+        let pk_d: [u8; 32] = [
+            0xdb, 0x4c, 0xd2, 0xb0, 0xaa, 0xc4, 0xf7, 0xeb, 0x8c, 0xa1, 0x31, 0xf1, 0x65, 0x67,
+            0xc4, 0x45, 0xa9, 0x55, 0x51, 0x26, 0xd3, 0xc2, 0x9f, 0x14, 0xe3, 0xd7, 0x76, 0xe8,
+            0x41, 0xae, 0x74, 0x15,
+        ];
+        let ivk: [u8; 32] = [
+            0xb7, 0x0b, 0x7c, 0xd0, 0xed, 0x03, 0xcb, 0xdf, 0xd7, 0xad, 0xa9, 0x50, 0x2e, 0xe2,
+            0x45, 0xb1, 0x3e, 0x56, 0x9d, 0x54, 0xa5, 0x71, 0x9d, 0x2d, 0xaa, 0x0f, 0x5f, 0x14,
+            0x51, 0x47, 0x92, 0x04,
+        ];
+        let rcm: [u8; 32] = [
+            0x39, 0x17, 0x6d, 0xac, 0x39, 0xac, 0xe4, 0x98, 0x0e, 0xcc, 0x8d, 0x77, 0x8e, 0x89,
+            0x86, 0x02, 0x55, 0xec, 0x36, 0x15, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let cmu: [u8; 32] = [
+            0x63, 0x55, 0x72, 0xf5, 0x72, 0xa8, 0xa1, 0xa0, 0xb7, 0xac, 0xbc, 0x0a, 0xfc, 0x6d,
+            0x66, 0xf1, 0x4a, 0x02, 0xef, 0xac, 0xde, 0x7b, 0xdf, 0x03, 0x44, 0x3e, 0xd4, 0xc3,
+            0xe5, 0x51, 0xd4, 0x70,
+        ];
+
+        let g_d = group_hash::diversify_hash(&d).unwrap();
+        let pk_d = group_hash::abst_j(&pk_d).unwrap();
+        assert_eq!(g_d.mul_bigint(Fr::from_le_bytes_mod_order(&ivk).into_bigint()), pk_d);
+
+        let cm = note_commitment(
+            NoteCommitmentMode::Sapling,
+            &g_d,
+            &pk_d,
+            &NoteValue(100000000),
+            &Randomness(Fr::from_le_bytes_mod_order(&rcm)),
+        );
+        assert_eq!(cm.x, Fq::from_le_bytes_mod_order(&cmu));
+    }
+
     #[test]
     pub fn test_homo() {
         let rcm = ValueCommitTrapdoor::random();
@@ -0,0 +1,104 @@
+//! Values shared by the tests of several modules.
+
+use ark_crypto_primitives::commitment::pedersen::Randomness;
+use ark_ec::{AffineRepr, CurveGroup};
+use ark_ed_on_bls12_381::{EdwardsAffine, Fr};
+use ark_ff::{PrimeField, UniformRand};
+use rand::thread_rng;
+
+use crate::circuit::{ConstraintF, OutputPublicInputs, SpendPublicInputs};
+use crate::commitment::{
+    homomorphic_pedersen_commitment, note_commitment, NoteCommitmentMode, ValueCommitTrapdoor,
+};
+use crate::keygen::KeyChain;
+use crate::merkle::{compute_root, MerkleHash};
+use crate::note::NoteValue;
+use crate::prf::prf_expand::IvkMode;
+use crate::signing_key::SigningKey;
+use crate::spend_description::{Nullifier, NullifierMode};
+use crate::witness::{OutputWitness, SpendWitness};
+
+/// The spending key most tests derive their keys and addresses from.
+pub const SK: SigningKey = &[
+    24, 226, 141, 234, 92, 17, 129, 122, 238, 178, 26, 25, 152, 29, 40, 54, 142, 196, 56, 175,
+    194, 90, 141, 185, 78, 190, 8, 215, 160, 40, 142, 9,
+];
+
+/// The position in the tree of the note `spend_witness` spends.
+pub const SPEND_POSITION: u64 = 1000;
+
+/// The default diversifier of `kc`, with its `g_d` and `pk_d`.
+pub fn default_address(kc: &KeyChain) -> ([u8; 11], EdwardsAffine, EdwardsAffine) {
+    let d = kc.default_diversifier().unwrap();
+    let (g_d, pk_d) = kc.get_diversified_transmission_address_from_diversifier(&d);
+    (d, g_d, pk_d)
+}
+
+/// A witness spending a 13-zatoshi note to the default address of `SK`, at
+/// `SPEND_POSITION` of a tree with random siblings.
+pub fn spend_witness<H: MerkleHash>(
+    note_commitment_mode: NoteCommitmentMode,
+    nullifier_mode: NullifierMode,
+    ivk_mode: IvkMode,
+) -> SpendWitness {
+    let mut rng = thread_rng();
+    let kc = KeyChain::new(SK, ivk_mode);
+    let (_, g_d, pk_d) = default_address(&kc);
+    let (alpha, rk) = kc.get_randomized_ak();
+
+    let note_value = NoteValue(13);
+    let rcv = ValueCommitTrapdoor::random();
+    let rcm = Fr::rand(&mut rng);
+    let cm = note_commitment(note_commitment_mode, &g_d, &pk_d, &note_value, &Randomness(rcm));
+
+    let auth_path: Vec<(ConstraintF, bool)> = (0..H::DEPTH)
+        .map(|i| (ConstraintF::rand(&mut rng), (SPEND_POSITION >> i) & 1 == 1))
+        .collect();
+
+    SpendWitness {
+        public_inputs: SpendPublicInputs {
+            rk: rk.0,
+            cv: homomorphic_pedersen_commitment(note_value.clone(), &rcv),
+            anchor: compute_root::<H>(H::leaf(&cm), &auth_path),
+            nf: Nullifier::new(cm, SPEND_POSITION, kc.nk.0, nullifier_mode),
+        },
+        ak: kc.ak.0,
+        alpha,
+        nsk: kc.nsk.0,
+        note_value,
+        rcv,
+        rcm,
+        g_d,
+        auth_path,
+        note_commitment_mode,
+        nullifier_mode,
+        ivk_mode,
+    }
+}
+
+/// A witness creating a 10-zatoshi note to the default address of `SK`.
+pub fn output_witness(note_commitment_mode: NoteCommitmentMode) -> OutputWitness {
+    let mut rng = thread_rng();
+    let kc = KeyChain::from(SK);
+    let (_, g_d, pk_d) = default_address(&kc);
+
+    let note_value = NoteValue(10);
+    let rcv = ValueCommitTrapdoor::random();
+    let rcm = Fr::rand(&mut rng);
+    let esk = Fr::rand(&mut rng);
+
+    OutputWitness {
+        public_inputs: OutputPublicInputs {
+            cv: homomorphic_pedersen_commitment(note_value.clone(), &rcv),
+            cm: note_commitment(note_commitment_mode, &g_d, &pk_d, &note_value, &Randomness(rcm)),
+            epk: g_d.mul_bigint(esk.into_bigint()).into_affine(),
+        },
+        g_d,
+        pk_d,
+        note_value,
+        rcv,
+        rcm,
+        esk,
+        note_commitment_mode,
+    }
+}
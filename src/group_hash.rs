@@ -1,5 +1,7 @@
 use ark_ec::AffineRepr;
-use ark_ed_on_bls12_381::EdwardsAffine;
+use ark_ed_on_bls12_381::{EdwardsAffine, Fq};
+use ark_ff::{BigInteger, PrimeField, Zero};
+use ark_serialize::CanonicalDeserialize;
 use blake2s_simd::Params;
 
 pub const GH_FIRST_BLOCK: &[u8; 64] =
@@ -11,6 +13,40 @@ pub const ZCASH_PEDERSEN_GENERATORS: &[u8] = b"Zcash_PH";
 pub const ZCASH_CV: &[u8] = b"Zcash_cv";
 pub const ZCASH_J: &[u8] = b"Zcash_J_";
 
+/// Decodes a Jubjub point from its 32-byte `repr_J` encoding (§5.4.9.3 of
+/// the Zcash protocol specification): the little-endian `v` coordinate with
+/// the parity of `u` stored in the most significant bit.
+pub fn abst_j(repr: &[u8; 32]) -> Option<EdwardsAffine> {
+    let mut y_repr = *repr;
+    let sign = (y_repr[31] >> 7) == 1;
+    y_repr[31] &= 0b0111_1111;
+
+    let y = Fq::deserialize_compressed(&y_repr[..]).ok()?;
+    let (x, neg_x) = EdwardsAffine::get_xs_from_y_unchecked(y)?;
+    let x = if x.into_bigint().is_odd() == sign { x } else { neg_x };
+
+    if x.is_zero() && sign {
+        return None;
+    }
+
+    Some(EdwardsAffine::new_unchecked(x, y))
+}
+
+/// Encodes a Jubjub point as `repr_J`, the inverse of [`abst_j`].
+pub fn repr_j(p: &EdwardsAffine) -> [u8; 32] {
+    let mut repr = [0u8; 32];
+    repr.copy_from_slice(&p.y.into_bigint().to_bytes_le());
+    if p.x.into_bigint().is_odd() {
+        repr[31] |= 0b1000_0000;
+    }
+    repr
+}
+
+/// Whether `p` is of small order, i.e. `[8] p` is the identity.
+pub fn is_small_order(p: &EdwardsAffine) -> bool {
+    p.mul_by_cofactor().is_zero()
+}
+
 pub fn group_hash(tag: &[u8], personal: &[u8]) -> Option<EdwardsAffine> {
     let h = Params::new()
         .hash_length(32)
@@ -20,15 +56,12 @@ pub fn group_hash(tag: &[u8], personal: &[u8]) -> Option<EdwardsAffine> {
         .update(tag)
         .finalize();
 
-    let p: Option<EdwardsAffine> = EdwardsAffine::from_random_bytes(h.as_array());
-    p.map( |p| {
-        if !p.is_zero() {
-            let p = p.clear_cofactor();
-            Some(p)
-        } else {
-            None
-        }
-    })?
+    let p = abst_j(h.as_array())?.clear_cofactor();
+    if p.is_zero() {
+        None
+    } else {
+        Some(p)
+    }
 }
 
 pub fn calc_group_hash(tag: &[u8], personal: &[u8]) -> EdwardsAffine {
@@ -37,9 +70,9 @@ pub fn calc_group_hash(tag: &[u8], personal: &[u8]) -> EdwardsAffine {
     tag.push(0);
 
     loop {
-        let gh = group_hash(&tag, &personal);
+        let gh = group_hash(&tag, personal);
+        assert_ne!(tag[i], u8::MAX);
         tag[i] += 1;
-        assert_ne!(tag[0], u8::max_value());
 
         if let Some(gh) = gh {
             return gh;
@@ -63,6 +96,10 @@ pub fn pedersen_generator(i: &[u8]) -> EdwardsAffine {
     calc_group_hash(i, ZCASH_PEDERSEN_GENERATORS)
 }
 
+pub fn note_commitment_randomness_generator() -> EdwardsAffine {
+    calc_group_hash(b"r", ZCASH_PEDERSEN_GENERATORS)
+}
+
 pub fn calc_v_sapling() -> EdwardsAffine {
     calc_group_hash(b"v", ZCASH_CV)
 }
@@ -78,9 +115,37 @@ pub fn calc_pedersen_hash() -> EdwardsAffine {
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use ark_ed_on_bls12_381::Fq;
+    use ark_ec::CurveGroup;
+    use ark_ed_on_bls12_381::{Fq, Fr};
     use ark_ff::Field;
 
+    /// All eight points of the small-order subgroup of Jubjub.
+    pub fn small_order_points() -> Vec<EdwardsAffine> {
+        let torsion = (2u64..)
+            .filter_map(|y| EdwardsAffine::get_point_from_y_unchecked(Fq::from(y), false))
+            .map(|p| p.mul_bigint(Fr::MODULUS).into_affine())
+            .find(|t| !t.mul_bigint([4u64]).is_zero())
+            .unwrap();
+
+        (0..8u64)
+            .map(|k| torsion.mul_bigint([k]).into_affine())
+            .collect()
+    }
+
+    #[test]
+    pub fn test_is_small_order() {
+        let points = small_order_points();
+        for (i, p) in points.iter().enumerate() {
+            assert!(p.is_on_curve());
+            assert!(is_small_order(p));
+            assert!(points[i + 1..].iter().all(|q| q != p));
+        }
+
+        let g = group_hash_spend_auth();
+        assert!(!is_small_order(&g));
+        assert!(!is_small_order(&(g + points[3]).into_affine()));
+    }
+
     #[test]
     pub fn test_simple() {
         let fq1: [u64; 4] = [
@@ -115,11 +180,10 @@ pub mod test {
             ]
             .concat()),
         );
-        let mut proof_generation_key_generator: EdwardsAffine = EdwardsAffine::new(
+        let spend_auth_generator: EdwardsAffine = EdwardsAffine::new(
             Fq::from_random_bytes(&fq1n).unwrap(),
             Fq::from_random_bytes(&fq2n).unwrap(),
         );
-        proof_generation_key_generator.x = -proof_generation_key_generator.x;
-        assert_eq!(group_hash_spend_auth(), proof_generation_key_generator)
+        assert_eq!(group_hash_spend_auth(), spend_auth_generator)
     }
 }
\ No newline at end of file
@@ -1,17 +1,18 @@
 use ark_crypto_primitives::signature::schnorr::{self};
 use ark_ec::AffineRepr;
 use ark_ed_on_bls12_381::{EdwardsAffine, EdwardsProjective, Fr};
-use ark_serialize::CanonicalSerialize;
 use blake2::Blake2b512;
 use ark_ff::PrimeField;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use rand::{distributions::Standard, rngs::StdRng, thread_rng, Rng, SeedableRng};
 use ark_crypto_primitives::signature::SignatureScheme;
 use std::ops::Mul;
+use std::sync::OnceLock;
 use std::ops::Add;
 
 use crate::{
     group_hash::{self, group_hash_h_sapling, group_hash_spend_auth}, 
-    prf::prf_expand::{Crh, PRFExpand}, 
+    prf::prf_expand::{Crh, IvkMode, PRFExpand}, 
     signing_key::SigningKey
 };
 
@@ -20,7 +21,7 @@ pub type Signature = schnorr::Signature<EdwardsProjective>;
 pub type OutgoingViewKey = [u8; 32];
 pub type Params = schnorr::Parameters<EdwardsProjective, Blake2b512>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PublicKey(pub schnorr::PublicKey<EdwardsProjective>);
 
 pub struct KeyChain<'a> {
@@ -31,53 +32,118 @@ pub struct KeyChain<'a> {
     pub ivk: SecretKey,
     pub nk: PublicKey,
     pub params: Params,
-    pub ak: PublicKey
+    pub ak: PublicKey,
+    pub ivk_mode: IvkMode,
+}
+
+/// `ivk`, which finds and decrypts the notes sent to any address of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IncomingViewingKey(pub Fr);
+
+impl From<&KeyChain<'_>> for IncomingViewingKey {
+    fn from(kc: &KeyChain<'_>) -> Self {
+        IncomingViewingKey(kc.ivk.0)
+    }
 }
 
 impl PublicKey {
     pub fn to_repr_j(&self) -> [u8; 32] {
-        let mut ser: [u8; 32] = [0; 32];
-        self.0.serialize_compressed(&mut ser[..]).unwrap();
-        ser
+        group_hash::repr_j(&self.0)
     } 
 }
 
 impl<'a> From<SigningKey<'a>> for KeyChain<'a> {
     fn from(signing_key: SigningKey<'a>) -> Self {
+        Self::new(signing_key, IvkMode::default())
+    }
+}
+
+/// The 64-byte encoding of a signature: the challenge, then the response,
+/// both little-endian.
+pub fn signature_to_bytes(sig: &Signature) -> [u8; 64] {
+    let mut bytes = [0u8; 64];
+    sig.verifier_challenge
+        .serialize_compressed(&mut bytes[..32])
+        .unwrap();
+    sig.prover_response
+        .serialize_compressed(&mut bytes[32..])
+        .unwrap();
+    bytes
+}
+
+/// Decodes `signature_to_bytes`, rejecting scalars that are not reduced.
+pub fn signature_from_bytes(bytes: &[u8; 64]) -> Option<Signature> {
+    Some(Signature {
+        verifier_challenge: Fr::deserialize_compressed(&bytes[..32]).ok()?,
+        prover_response: Fr::deserialize_compressed(&bytes[32..]).ok()?,
+    })
+}
+
+/// Hashed into every challenge. It must be fixed for signatures to verify
+/// anywhere but where they were made.
+const SIGNATURE_SALT: [u8; 32] = *b"Zcash_RedJubjub_SaplingSignature";
+
+static SPEND_AUTH_PARAMS: OnceLock<Params> = OnceLock::new();
+static BINDING_SIG_PARAMS: OnceLock<Params> = OnceLock::new();
+
+fn signature_params(generator: EdwardsAffine) -> Params {
+    // `setup` is the only way to build `Parameters`, whose hash marker is
+    // private; both fields it draws are replaced, so the seed is irrelevant.
+    let mut parameters: Params =
+        schnorr::Schnorr::<EdwardsProjective, Blake2b512>::setup(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+    parameters.generator = generator;
+    parameters.salt = SIGNATURE_SALT;
+    parameters
+}
+
+/// Schnorr parameters over the spend authorization generator.
+pub fn spend_auth_params() -> &'static Params {
+    SPEND_AUTH_PARAMS.get_or_init(|| signature_params(group_hash::group_hash_spend_auth()))
+}
+
+/// Schnorr parameters over the value commitment randomness base, for binding
+/// signatures.
+pub fn binding_sig_params() -> &'static Params {
+    BINDING_SIG_PARAMS.get_or_init(|| signature_params(group_hash::calc_r_sapling()))
+}
+
+impl<'a> KeyChain<'a> {
+    pub fn new(signing_key: SigningKey<'a>, ivk_mode: IvkMode) -> Self {
         let ask: SecretKey = 
             schnorr::SecretKey(Fr::from_le_bytes_mod_order(&PRFExpand::calc_ask(signing_key)));
         let nsk: SecretKey =
             schnorr::SecretKey(Fr::from_le_bytes_mod_order(&PRFExpand::calc_nsk(signing_key)));
 
-        let mut rng = thread_rng();
-        let mut parameters: Params = 
-            schnorr::Schnorr::<EdwardsProjective, Blake2b512>::setup(&mut rng).unwrap();
-        parameters.generator = group_hash::group_hash_spend_auth();
+        let parameters = spend_auth_params();
 
-        let ak = PublicKey(parameters.generator.mul_bigint(ask.0.0).into());
+        let ak = PublicKey(parameters.generator.mul_bigint(ask.0.into_bigint()).into());
         let nsk_fr: Fr = Fr::from_le_bytes_mod_order(&PRFExpand::calc_nsk(signing_key));
-        let nk: EdwardsAffine = group_hash_h_sapling().mul_bigint(nsk_fr.0).into();
+        let nk: EdwardsAffine = group_hash_h_sapling().mul_bigint(nsk_fr.into_bigint()).into();
         let nk: PublicKey = PublicKey(nk);
 
         let mut ovk: OutgoingViewKey = [0; 32];
         ovk.copy_from_slice(&PRFExpand::calc_ovk(signing_key)[..32]);
 
-        let ivk = schnorr::SecretKey(Crh::calc(&ak.to_repr_j(), &nk.to_repr_j()));
+        let ivk = match ivk_mode {
+            IvkMode::Blake2s => Crh::calc(&ak.to_repr_j(), &nk.to_repr_j()),
+            IvkMode::Poseidon => Crh::calc_poseidon(&ak.0.y, &nk.0.y),
+        };
+        let ivk = schnorr::SecretKey(ivk);
 
         KeyChain {
             sk: signing_key,
-            ask: ask,
-            nsk: nsk,
-            ovk: ovk,
-            ivk: ivk,
-            nk: nk,
-            params: parameters,
-            ak: ak
+            ask,
+            nsk,
+            ovk,
+            ivk,
+            nk,
+            params: parameters.clone(),
+            ak,
+            ivk_mode,
         }
     }
-}
 
-impl<'a> KeyChain<'a> {
     pub fn get_diversified_transmission_address(&self) -> ([u8; 11], EdwardsAffine, PublicKey) {
         let mut d: [u8; 11]  = [0; 11];
         let mut gd: Option<EdwardsAffine>;
@@ -120,10 +186,20 @@ impl<'a> KeyChain<'a> {
         None
     }
 
+    pub fn dk(&self) -> [u8; 32] {
+        PRFExpand::calc_dk(self.sk)
+    }
+
+    /// `rsk = ask + alpha`, the secret key of the `rk` that
+    /// `get_randomized_ak` returned with `alpha`.
+    pub fn randomized_secret_key(&self, alpha: &Fr) -> SecretKey {
+        schnorr::SecretKey(self.ask.0 + alpha)
+    }
+
     pub fn get_randomized_ak(&self) -> (Fr, PublicKey) {
         let rng = thread_rng();
         let alpha: Fr = StdRng::from_rng(rng).expect("Failed").sample(Standard);
-        let ar = group_hash_spend_auth() .mul_bigint(alpha.0);
+        let ar = group_hash_spend_auth().mul_bigint(alpha.into_bigint());
         let rk = self.ak.0.add(ar);
 
         (alpha, PublicKey(rk.into()))
@@ -136,32 +212,29 @@ pub struct ProofGenerationKey {
     pub nsk: SecretKey,
 }
 
-impl<'a> Into<ProofGenerationKey> for KeyChain<'a> {
-    fn into(self) -> ProofGenerationKey {
+impl<'a> From<KeyChain<'a>> for ProofGenerationKey {
+    fn from(kc: KeyChain<'a>) -> Self {
         ProofGenerationKey {
-            ak: self.ak,
-            nsk: self.nsk,
+            ak: kc.ak,
+            nsk: kc.nsk,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::fixtures::SK;
     use crate::signing_key::SigningKey;
     use ark_ff::{BigInteger, PrimeField};
 
     use super::KeyChain;
-    const SK: SigningKey = &[
-        24, 226, 141, 234, 92, 17, 129, 122, 238, 178, 26, 25, 152, 29, 40, 54, 142, 196, 56, 175,
-        194, 90, 141, 185, 78, 190, 8, 215, 160, 40, 142, 9,
-    ];
     const EASK: [u8; 32] = [
         14_u8, 205, 90, 238, 23, 159, 250, 205, 212, 1, 166, 13, 83, 234, 140, 55, 61, 74, 210, 17,
         50, 131, 194, 125, 63, 194, 155, 101, 185, 184, 27, 4,
     ];
     const EIVK: [u8; 32] = [
-        122, 20, 109, 17, 53, 213, 214, 84, 218, 96, 59, 215, 92, 162, 179, 42, 226, 31, 203, 175, 
-        125, 117, 53, 164, 86, 89, 60, 103, 25, 74, 95, 6
+        125, 62, 60, 84, 194, 3, 86, 124, 86, 196, 243, 207, 210, 54, 123, 31, 111, 132, 26, 119,
+        71, 246, 42, 234, 69, 132, 255, 70, 129, 61, 80, 3,
     ];
     #[test]
     pub fn test_kc_from_sk() {
@@ -0,0 +1,29 @@
+pub mod prf;
+pub mod signing_key;
+pub mod group_hash;
+pub mod pedersen_crh;
+pub mod pedersen_hash;
+pub mod merkle;
+pub mod note;
+pub mod commitment;
+pub mod address;
+pub mod unified;
+pub mod payment_request;
+pub mod keygen;
+pub mod circuit;
+pub mod spend_description;
+pub mod output_description;
+pub mod memo;
+pub mod note_encryption;
+pub mod prover;
+pub mod prover_server;
+pub mod witness;
+pub mod thread_pool;
+pub mod transaction;
+pub mod sighash;
+pub mod scan;
+pub mod batch_decryption;
+#[cfg(feature = "sqlite")]
+pub mod wallet_db;
+#[cfg(test)]
+pub mod fixtures;
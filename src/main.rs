@@ -1,15 +0,0 @@
-pub mod prf;
-pub mod signing_key;
-pub mod group_hash;
-pub mod pedersen_crh;
-pub mod note;
-pub mod commitment;
-pub mod address;
-pub mod keygen;
-pub mod circuit;
-pub mod spend_description;
-pub mod output_description;
-
-fn main() {
-    println!("Hello, world!");
-}
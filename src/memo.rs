@@ -0,0 +1,126 @@
+use std::fmt;
+
+pub const MEMO_SIZE: usize = 512;
+
+/// A ZIP-302 memo, as decoded from the 512 bytes every output carries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Memo {
+    /// `0xF6` followed by zeros.
+    #[default]
+    Empty,
+    /// A lead byte of at most `0xF4`: UTF-8, right-padded with zeros.
+    Text(TextMemo),
+    /// `0xFF` followed by any 511 bytes.
+    Arbitrary(Box<[u8; MEMO_SIZE - 1]>),
+    /// Any other lead byte, reserved for formats defined later. It is kept
+    /// verbatim so that it can be passed on.
+    Future(Box<[u8; MEMO_SIZE]>),
+}
+
+/// The text of a memo, which fits in `MEMO_SIZE` bytes and has no trailing
+/// NULs. It is only built by `Memo::text` and `Memo::from_bytes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextMemo(String);
+
+impl TextMemo {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoError {
+    TooLong,
+    InvalidUtf8,
+}
+
+impl fmt::Display for MemoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoError::TooLong => write!(f, "memo text is longer than {} bytes", MEMO_SIZE),
+            MemoError::InvalidUtf8 => write!(f, "memo text is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for MemoError {}
+
+impl Memo {
+    /// A text memo. ZIP-302 has no room for trailing NULs, which are dropped.
+    pub fn text(text: &str) -> Result<Self, MemoError> {
+        if text.len() > MEMO_SIZE {
+            return Err(MemoError::TooLong);
+        }
+        Ok(Memo::Text(TextMemo(text.trim_end_matches('\0').to_owned())))
+    }
+
+    pub fn from_bytes(bytes: &[u8; MEMO_SIZE]) -> Result<Self, MemoError> {
+        match bytes[0] {
+            0xf6 if bytes[1..].iter().all(|&b| b == 0) => Ok(Memo::Empty),
+            0xff => Ok(Memo::Arbitrary(Box::new(bytes[1..].try_into().unwrap()))),
+            lead if lead <= 0xf4 => {
+                let len = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+                let text = std::str::from_utf8(&bytes[..len]).map_err(|_| MemoError::InvalidUtf8)?;
+                Ok(Memo::Text(TextMemo(text.to_owned())))
+            }
+            _ => Ok(Memo::Future(Box::new(*bytes))),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; MEMO_SIZE] {
+        let mut bytes = [0u8; MEMO_SIZE];
+        match self {
+            Memo::Empty => bytes[0] = 0xf6,
+            Memo::Text(TextMemo(text)) => bytes[..text.len()].copy_from_slice(text.as_bytes()),
+            Memo::Arbitrary(data) => {
+                bytes[0] = 0xff;
+                bytes[1..].copy_from_slice(&data[..]);
+            }
+            Memo::Future(data) => bytes = **data,
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_memo_round_trip() {
+        let memos = [
+            Memo::Empty,
+            Memo::text("").unwrap(),
+            Memo::text("invoice #42 ✓").unwrap(),
+            Memo::text(&"x".repeat(MEMO_SIZE)).unwrap(),
+            Memo::Arbitrary(Box::new([0xab; MEMO_SIZE - 1])),
+            Memo::Future(Box::new([0xf5; MEMO_SIZE])),
+        ];
+        for memo in memos {
+            assert_eq!(Memo::from_bytes(&memo.to_bytes()).unwrap(), memo);
+        }
+
+        assert_eq!(Memo::default().to_bytes()[..2], [0xf6, 0]);
+        match Memo::text("abc\0\0").unwrap() {
+            Memo::Text(text) => assert_eq!(text.as_str(), "abc"),
+            memo => panic!("{:?}", memo),
+        }
+    }
+
+    #[test]
+    pub fn test_memo_lead_bytes() {
+        let mut bytes = [0u8; MEMO_SIZE];
+        bytes[0] = 0xf6;
+        bytes[1] = 1;
+        assert!(matches!(Memo::from_bytes(&bytes), Ok(Memo::Future(_))));
+
+        bytes[0] = 0xf5;
+        assert!(matches!(Memo::from_bytes(&bytes), Ok(Memo::Future(_))));
+
+        bytes[0] = 0xc3;
+        bytes[1] = 0x28;
+        assert_eq!(Memo::from_bytes(&bytes), Err(MemoError::InvalidUtf8));
+
+        assert_eq!(Memo::text(&"x".repeat(MEMO_SIZE + 1)), Err(MemoError::TooLong));
+    }
+}
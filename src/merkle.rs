@@ -0,0 +1,600 @@
+use ark_crypto_primitives::crh::poseidon::constraints::{CRHParametersVar, TwoToOneCRHGadget};
+use ark_crypto_primitives::crh::poseidon::TwoToOneCRH;
+use ark_crypto_primitives::crh::{TwoToOneCRHScheme, TwoToOneCRHSchemeGadget};
+use ark_ed_on_bls12_381::{constraints::EdwardsVar, EdwardsAffine};
+use ark_ff::{BigInteger, One, PrimeField};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_r1cs_std::{fields::fp::FpVar, ToBitsGadget};
+use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError};
+use std::marker::PhantomData;
+
+use crate::circuit::ConstraintF;
+use crate::pedersen_hash::{self, Personalization};
+use crate::prf::poseidon_config::poseidon_parameters;
+
+/// Depth of the Sapling note commitment tree.
+pub const SAPLING_DEPTH: usize = 32;
+
+/// The two-to-one hash used to build the note commitment tree. `layer` is the
+/// height of the children being hashed, counting from 0 at the leaves.
+pub trait MerkleHash {
+    const DEPTH: usize;
+
+    /// The leaf committed to the tree for a note commitment.
+    fn leaf(cm: &EdwardsAffine) -> ConstraintF;
+
+    fn hash(layer: usize, left: &ConstraintF, right: &ConstraintF) -> ConstraintF;
+
+    fn leaf_gadget(cm: &EdwardsVar) -> FpVar<ConstraintF>;
+
+    fn hash_gadget(
+        cs: ConstraintSystemRef<ConstraintF>,
+        layer: usize,
+        left: &FpVar<ConstraintF>,
+        right: &FpVar<ConstraintF>,
+    ) -> Result<FpVar<ConstraintF>, SynthesisError>;
+}
+
+/// Poseidon `TwoToOneCRH` over the `v` coordinate of the note commitment.
+pub struct PoseidonMerkleHash;
+
+impl MerkleHash for PoseidonMerkleHash {
+    const DEPTH: usize = 32;
+
+    fn leaf(cm: &EdwardsAffine) -> ConstraintF {
+        cm.y
+    }
+
+    fn hash(_layer: usize, left: &ConstraintF, right: &ConstraintF) -> ConstraintF {
+        <TwoToOneCRH<_> as TwoToOneCRHScheme>::evaluate(&poseidon_parameters(), left, right)
+            .expect("hash failed")
+    }
+
+    fn leaf_gadget(cm: &EdwardsVar) -> FpVar<ConstraintF> {
+        cm.y.clone()
+    }
+
+    fn hash_gadget(
+        _cs: ConstraintSystemRef<ConstraintF>,
+        _layer: usize,
+        left: &FpVar<ConstraintF>,
+        right: &FpVar<ConstraintF>,
+    ) -> Result<FpVar<ConstraintF>, SynthesisError> {
+        let params = CRHParametersVar {
+            parameters: poseidon_parameters(),
+        };
+        <TwoToOneCRHGadget<_> as TwoToOneCRHSchemeGadget<_, _>>::evaluate(&params, left, right)
+    }
+}
+
+/// `MerkleCRH^Sapling`: the Pedersen hash of `left || right` as 255-bit strings,
+/// personalized by layer, over `cm_u`.
+pub struct PedersenMerkleHash;
+
+impl MerkleHash for PedersenMerkleHash {
+    const DEPTH: usize = SAPLING_DEPTH;
+
+    fn leaf(cm: &EdwardsAffine) -> ConstraintF {
+        cm.x
+    }
+
+    fn hash(layer: usize, left: &ConstraintF, right: &ConstraintF) -> ConstraintF {
+        let bits = |f: &ConstraintF| {
+            f.into_bigint()
+                .to_bits_le()
+                .into_iter()
+                .take(ConstraintF::MODULUS_BIT_SIZE as usize)
+        };
+
+        pedersen_hash::pedersen_hash(
+            Personalization::MerkleTree(layer),
+            bits(left).chain(bits(right)),
+        )
+    }
+
+    fn leaf_gadget(cm: &EdwardsVar) -> FpVar<ConstraintF> {
+        cm.x.clone()
+    }
+
+    fn hash_gadget(
+        _cs: ConstraintSystemRef<ConstraintF>,
+        layer: usize,
+        left: &FpVar<ConstraintF>,
+        right: &FpVar<ConstraintF>,
+    ) -> Result<FpVar<ConstraintF>, SynthesisError> {
+        let mut bits = left.to_bits_le()?;
+        bits.extend(right.to_bits_le()?);
+
+        let hash = pedersen_hash::constraints::pedersen_hash_to_point(
+            Personalization::MerkleTree(layer),
+            &bits,
+        )?;
+
+        Ok(hash.x)
+    }
+}
+
+/// `H` over a tree of depth `DEPTH` instead of `H::DEPTH`, e.g. to compare the
+/// cost of shallower trees.
+pub struct WithDepth<H, const DEPTH: usize>(PhantomData<H>);
+
+impl<H: MerkleHash, const DEPTH: usize> MerkleHash for WithDepth<H, DEPTH> {
+    const DEPTH: usize = DEPTH;
+
+    fn leaf(cm: &EdwardsAffine) -> ConstraintF {
+        H::leaf(cm)
+    }
+
+    fn hash(layer: usize, left: &ConstraintF, right: &ConstraintF) -> ConstraintF {
+        H::hash(layer, left, right)
+    }
+
+    fn leaf_gadget(cm: &EdwardsVar) -> FpVar<ConstraintF> {
+        H::leaf_gadget(cm)
+    }
+
+    fn hash_gadget(
+        cs: ConstraintSystemRef<ConstraintF>,
+        layer: usize,
+        left: &FpVar<ConstraintF>,
+        right: &FpVar<ConstraintF>,
+    ) -> Result<FpVar<ConstraintF>, SynthesisError> {
+        H::hash_gadget(cs, layer, left, right)
+    }
+}
+
+/// Folds `auth_path` up from `leaf`. Each entry is the sibling and whether the
+/// current node is the right child.
+pub fn compute_root<H: MerkleHash>(
+    leaf: ConstraintF,
+    auth_path: &[(ConstraintF, bool)],
+) -> ConstraintF {
+    auth_path
+        .iter()
+        .enumerate()
+        .fold(leaf, |node, (layer, (sibling, is_right))| {
+            if *is_right {
+                H::hash(layer, sibling, &node)
+            } else {
+                H::hash(layer, &node, sibling)
+            }
+        })
+}
+
+pub fn verify_path<H: MerkleHash>(
+    cm: &EdwardsAffine,
+    auth_path: &[(ConstraintF, bool)],
+    root: &ConstraintF,
+) -> bool {
+    auth_path.len() == H::DEPTH && compute_root::<H>(H::leaf(cm), auth_path) == *root
+}
+
+/// `Uncommitted^Sapling`: the leaf at positions that hold no note yet.
+pub fn uncommitted() -> ConstraintF {
+    ConstraintF::one()
+}
+
+/// The roots of empty subtrees, from a single leaf up to the whole tree.
+pub fn empty_roots<H: MerkleHash>() -> Vec<ConstraintF> {
+    let mut roots = vec![uncommitted()];
+    for layer in 0..H::DEPTH {
+        roots.push(H::hash(layer, &roots[layer], &roots[layer]));
+    }
+    roots
+}
+
+/// A note commitment tree filled from the left, of which only the frontier
+/// is kept: at each layer, the root of the complete subtree left of the next
+/// leaf, if there is one.
+pub struct CommitmentTree<H> {
+    size: u64,
+    ommers: Vec<Option<ConstraintF>>,
+    merkle_hash: PhantomData<H>,
+}
+
+impl<H: MerkleHash> Default for CommitmentTree<H> {
+    fn default() -> Self {
+        CommitmentTree {
+            size: 0,
+            ommers: vec![],
+            merkle_hash: PhantomData,
+        }
+    }
+}
+
+impl<H: MerkleHash> Clone for CommitmentTree<H> {
+    fn clone(&self) -> Self {
+        CommitmentTree {
+            size: self.size,
+            ommers: self.ommers.clone(),
+            merkle_hash: PhantomData,
+        }
+    }
+}
+
+impl<H: MerkleHash> CommitmentTree<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of leaves, which is also the position of the next one.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns false, leaving the tree as it is, if it is full.
+    pub fn append(&mut self, leaf: ConstraintF) -> bool {
+        if self.size >> H::DEPTH != 0 {
+            return false;
+        }
+
+        let mut node = leaf;
+        let mut layer = 0;
+        while (self.size >> layer) & 1 == 1 {
+            let left = self.ommers[layer].take().expect("a complete subtree on the left");
+            node = H::hash(layer, &left, &node);
+            layer += 1;
+        }
+        if self.ommers.len() <= layer {
+            self.ommers.resize(layer + 1, None);
+        }
+        self.ommers[layer] = Some(node);
+        self.size += 1;
+        true
+    }
+
+    pub fn root(&self) -> ConstraintF {
+        self.root_at(H::DEPTH, &empty_roots::<H>())
+    }
+
+    /// The root of the subtree of height `depth` holding the leaves, which
+    /// must all fit in it.
+    fn root_at(&self, depth: usize, empty: &[ConstraintF]) -> ConstraintF {
+        if let Some(Some(root)) = self.ommers.get(depth) {
+            return *root;
+        }
+
+        let mut node = None;
+        for (layer, empty) in empty.iter().enumerate().take(depth) {
+            node = match (self.ommers.get(layer).copied().flatten(), node) {
+                (Some(left), right) => Some(H::hash(layer, &left, &right.unwrap_or(*empty))),
+                (None, Some(left)) => Some(H::hash(layer, &left, empty)),
+                (None, None) => None,
+            };
+        }
+        node.unwrap_or(empty[depth])
+    }
+
+    /// The size, then for each layer a byte that is 1 if it has a subtree
+    /// followed by its root.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.size.to_le_bytes().to_vec();
+        for ommer in &self.ommers {
+            match ommer {
+                Some(node) => {
+                    bytes.push(1);
+                    node.serialize_compressed(&mut bytes).unwrap();
+                }
+                None => bytes.push(0),
+            }
+        }
+        bytes
+    }
+
+    /// Decodes `to_bytes`, checking that the subtrees match the size.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let size = u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap());
+        let mut rest = &bytes[8..];
+        let mut ommers = vec![];
+        while let Some((&tag, tail)) = rest.split_first() {
+            rest = tail;
+            ommers.push(match tag {
+                0 => None,
+                1 => {
+                    let node = ConstraintF::deserialize_compressed(rest.get(..32)?).ok()?;
+                    rest = &rest[32..];
+                    Some(node)
+                }
+                _ => return None,
+            });
+        }
+
+        let tree = CommitmentTree { size, ommers, merkle_hash: PhantomData };
+        let consistent = size >> H::DEPTH <= 1
+            && (0..=H::DEPTH).all(|layer| {
+                let ommer = tree.ommers.get(layer).copied().flatten();
+                ommer.is_some() == ((size >> layer) & 1 == 1)
+            })
+            && tree.ommers.len() <= H::DEPTH + 1;
+        consistent.then_some(tree)
+    }
+}
+
+/// The authentication path of one leaf, kept up to date as leaves are
+/// appended to the tree after it.
+pub struct IncrementalWitness<H> {
+    position: u64,
+    /// The left siblings, i.e. the frontier of the tree before the leaf.
+    left: Vec<Option<ConstraintF>>,
+    /// The right siblings completed since, from the lowest layer up.
+    filled: Vec<ConstraintF>,
+    /// The subtree growing into the next right sibling, and its height.
+    cursor: Option<(usize, CommitmentTree<H>)>,
+}
+
+impl<H: MerkleHash> IncrementalWitness<H> {
+    /// A witness for the leaf `tree` is about to append.
+    pub fn from_tree(tree: &CommitmentTree<H>) -> Self {
+        IncrementalWitness {
+            position: tree.size,
+            left: tree.ommers.clone(),
+            filled: vec![],
+            cursor: None,
+        }
+    }
+
+    /// Resumes a witness from the `path` it had when the tree was `tree`. The
+    /// subtree still growing on the right of the path is the lower part of
+    /// the frontier of `tree`. Fails if the path is not `H::DEPTH` long, its
+    /// directions are not the bits of `position`, or the leaf is not in `tree`.
+    pub fn from_path(
+        position: u64,
+        path: &[(ConstraintF, bool)],
+        tree: &CommitmentTree<H>,
+    ) -> Option<Self> {
+        if path.len() != H::DEPTH || position >= tree.size {
+            return None;
+        }
+
+        let mut witness = IncrementalWitness {
+            position,
+            left: vec![None; H::DEPTH],
+            filled: vec![],
+            cursor: None,
+        };
+
+        for (layer, (sibling, is_right)) in path.iter().enumerate() {
+            if *is_right != ((position >> layer) & 1 == 1) {
+                return None;
+            }
+            if *is_right {
+                witness.left[layer] = Some(*sibling);
+            }
+        }
+        for layer in witness.right_layers().collect::<Vec<_>>() {
+            let start = ((position >> layer) | 1) << layer;
+            if tree.size >= start + (1 << layer) {
+                witness.filled.push(path[layer].0);
+            } else {
+                if tree.size > start {
+                    let cursor = CommitmentTree {
+                        size: tree.size - start,
+                        ommers: tree.ommers.iter().take(layer).copied().collect(),
+                        merkle_hash: PhantomData,
+                    };
+                    witness.cursor = Some((layer, cursor));
+                }
+                break;
+            }
+        }
+        Some(witness)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The layers at which the path has a right sibling, from the bottom.
+    fn right_layers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..H::DEPTH).filter(move |layer| (self.position >> layer) & 1 == 0)
+    }
+
+    /// Follows the tree appending `leaf`.
+    pub fn append(&mut self, leaf: ConstraintF) {
+        if let Some((depth, cursor)) = &mut self.cursor {
+            cursor.append(leaf);
+            if cursor.size == 1 << *depth {
+                // Complete, so no empty subtree is needed.
+                let root = cursor.root_at(*depth, &[]);
+                self.filled.push(root);
+                self.cursor = None;
+            }
+            return;
+        }
+
+        let next = self.right_layers().nth(self.filled.len());
+        match next {
+            Some(0) => self.filled.push(leaf),
+            Some(depth) => {
+                let mut cursor = CommitmentTree::new();
+                cursor.append(leaf);
+                self.cursor = Some((depth, cursor));
+            }
+            None => {}
+        }
+    }
+
+    /// The path in the form `compute_root` takes.
+    pub fn path(&self) -> Vec<(ConstraintF, bool)> {
+        let empty = empty_roots::<H>();
+        let mut filled = self.filled.iter();
+        let mut cursor = self.cursor.as_ref();
+        (0..H::DEPTH)
+            .map(|layer| {
+                if (self.position >> layer) & 1 == 1 {
+                    let left = self.left.get(layer).copied().flatten();
+                    return (left.expect("a complete subtree on the left"), true);
+                }
+                let right = match filled.next() {
+                    Some(node) => *node,
+                    None => match cursor.take() {
+                        Some((depth, tree)) => tree.root_at(*depth, &empty),
+                        None => empty[layer],
+                    },
+                };
+                (right, false)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use ark_ed_on_bls12_381::EdwardsProjective;
+    use ark_ec::{CurveGroup, Group};
+    use ark_ff::UniformRand;
+    use ark_r1cs_std::{alloc::AllocVar, R1CSVar};
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::One;
+    use rand::thread_rng;
+
+    fn fq_from_le_hex(hex: &str) -> ConstraintF {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        ConstraintF::from_le_bytes_mod_order(&bytes)
+    }
+
+    #[test]
+    pub fn test_sapling_empty_roots() {
+        // Uncommitted^Sapling is 1; roots of empty subtrees as serialized by zcashd.
+        let mut node = ConstraintF::one();
+        for layer in 0..SAPLING_DEPTH {
+            node = PedersenMerkleHash::hash(layer, &node, &node);
+            if layer == 0 {
+                assert_eq!(
+                    node,
+                    fq_from_le_hex(
+                        "817de36ab2d57feb077634bca77819c8e0bd298c04f6fed0e6a83cc1356ca155"
+                    )
+                );
+            }
+        }
+
+        assert_eq!(
+            node,
+            fq_from_le_hex("fbc2f4300c01f0b7820d00e3347c8da4ee614674376cbc45359daa54f9b5493e")
+        );
+    }
+
+    fn check_path<H: MerkleHash>() {
+        let mut rng = thread_rng();
+        let cm = EdwardsProjective::generator()
+            .mul_bigint(ark_ed_on_bls12_381::Fr::rand(&mut rng).into_bigint())
+            .into_affine();
+
+        let auth_path: Vec<(ConstraintF, bool)> = (0..H::DEPTH)
+            .map(|i| (ConstraintF::rand(&mut rng), (1000u64 >> i) & 1 == 1))
+            .collect();
+        let root = compute_root::<H>(H::leaf(&cm), &auth_path);
+
+        assert!(verify_path::<H>(&cm, &auth_path, &root));
+        assert!(!verify_path::<H>(&cm, &auth_path[1..], &root));
+
+        let mut flipped = auth_path.clone();
+        flipped[3].1 = !flipped[3].1;
+        assert!(!verify_path::<H>(&cm, &flipped, &root));
+
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
+        let cm_var = EdwardsVar::new_witness(cs.clone(), || Ok(cm)).unwrap();
+        let left = FpVar::new_witness(cs.clone(), || Ok(auth_path[0].0)).unwrap();
+        let node = H::hash_gadget(cs.clone(), 5, &left, &H::leaf_gadget(&cm_var)).unwrap();
+
+        assert_eq!(node.value().unwrap(), H::hash(5, &auth_path[0].0, &H::leaf(&cm)));
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    pub fn test_poseidon_path() {
+        check_path::<PoseidonMerkleHash>();
+    }
+
+    #[test]
+    pub fn test_pedersen_path() {
+        check_path::<PedersenMerkleHash>();
+    }
+
+    #[test]
+    pub fn test_with_depth() {
+        check_path::<WithDepth<PoseidonMerkleHash, 8>>();
+    }
+
+    /// The root over `leaves`, padded with `uncommitted()`, hashing whole
+    /// layers.
+    fn naive_root<H: MerkleHash>(leaves: &[ConstraintF]) -> ConstraintF {
+        let mut layer_nodes = leaves.to_vec();
+        layer_nodes.resize(1 << H::DEPTH, uncommitted());
+        for layer in 0..H::DEPTH {
+            layer_nodes = layer_nodes
+                .chunks(2)
+                .map(|pair| H::hash(layer, &pair[0], &pair[1]))
+                .collect();
+        }
+        layer_nodes[0]
+    }
+
+    #[test]
+    pub fn test_incremental_witnesses() {
+        type H = WithDepth<PoseidonMerkleHash, 4>;
+        let mut tree = CommitmentTree::<H>::new();
+        assert_eq!(tree.root(), naive_root::<H>(&[]));
+
+        let mut leaves = vec![];
+        let mut witnesses: Vec<IncrementalWitness<H>> = vec![];
+        for i in 0..16u64 {
+            let leaf = ConstraintF::from(i + 10);
+            for witness in witnesses.iter_mut() {
+                witness.append(leaf);
+            }
+            if [0, 3, 5, 8, 15].contains(&i) {
+                witnesses.push(IncrementalWitness::from_tree(&tree));
+            }
+            assert!(tree.append(leaf));
+            leaves.push(leaf);
+
+            let root = naive_root::<H>(&leaves);
+            assert_eq!(tree.root(), root);
+            for witness in &witnesses {
+                let path = witness.path();
+                assert_eq!(compute_root::<H>(leaves[witness.position() as usize], &path), root);
+
+                let resumed = IncrementalWitness::from_path(witness.position(), &path, &tree).unwrap();
+                assert_eq!(resumed.path(), path);
+                assert!(IncrementalWitness::from_path(witness.position(), &path[1..], &tree).is_none());
+            }
+
+            let decoded = CommitmentTree::<H>::from_bytes(&tree.to_bytes()).unwrap();
+            assert_eq!(decoded.root(), root);
+        }
+        assert!(!tree.append(ConstraintF::one()));
+
+        // A witness resumed mid-way keeps following the tree.
+        let mut tree = CommitmentTree::<H>::new();
+        for i in 0..3u64 {
+            tree.append(ConstraintF::from(i));
+        }
+        let mut witness = IncrementalWitness::from_tree(&tree);
+        tree.append(ConstraintF::from(3u64));
+        for i in 4..6u64 {
+            witness.append(ConstraintF::from(i));
+            tree.append(ConstraintF::from(i));
+        }
+        let mut resumed = IncrementalWitness::from_path(3, &witness.path(), &tree).unwrap();
+        assert!(IncrementalWitness::from_path(tree.size(), &witness.path(), &tree).is_none());
+        // A direction that disagrees with the position, e.g. from a corrupt
+        // stored path, is refused rather than left to panic in `path`.
+        let mut flipped = witness.path();
+        flipped[1].1 = !flipped[1].1;
+        assert!(IncrementalWitness::from_path(3, &flipped, &tree).is_none());
+        for i in 6..11u64 {
+            witness.append(ConstraintF::from(i));
+            resumed.append(ConstraintF::from(i));
+            tree.append(ConstraintF::from(i));
+            assert_eq!(resumed.path(), witness.path());
+        }
+        assert!(CommitmentTree::<H>::from_bytes(&[0; 7]).is_none());
+        assert!(CommitmentTree::<H>::from_bytes(&1u64.to_le_bytes()).is_none());
+    }
+}
@@ -1,3 +1,335 @@
-#[derive(Clone, Debug)]
+use std::collections::{BTreeMap, HashMap};
+
+use ark_ed_on_bls12_381::EdwardsAffine;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+
+use crate::circuit::ConstraintF;
+use crate::keygen::PublicKey;
+use crate::merkle::{compute_root, MerkleHash};
+use crate::note_encryption::NotePlaintext;
+use crate::spend_description::{Nullifier, NullifierMode};
+use crate::transaction::{TxId, MAX_MONEY};
+
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
 pub struct NoteValue(pub u64);
-pub struct SpendingNotes;
\ No newline at end of file
+
+pub type BlockHeight = u32;
+
+/// The authentication path of a note commitment, from the leaf up: each
+/// sibling and whether the note's side is the right child.
+pub type MerklePath = Vec<(ConstraintF, bool)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteStatus {
+    Unspent,
+    /// Spent by a transaction that has not been mined yet.
+    PendingSpend(TxId),
+    /// Spent by a transaction mined at this height.
+    Spent(BlockHeight),
+}
+
+/// A note received by the wallet, with what it takes to spend it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedNote {
+    pub note: NotePlaintext,
+    pub cm: EdwardsAffine,
+    pub position: u64,
+    pub witness: MerklePath,
+    pub nullifier: Nullifier,
+    pub height: BlockHeight,
+    pub status: NoteStatus,
+}
+
+impl ReceivedNote {
+    /// The root the witness leads to, which a spend of the note uses as its
+    /// anchor.
+    pub fn anchor<H: MerkleHash>(&self) -> ConstraintF {
+        compute_root::<H>(H::leaf(&self.cm), &self.witness)
+    }
+
+    /// The number of blocks up to `tip` that include the note, counting the
+    /// one it arrived in.
+    pub fn confirmations(&self, tip: BlockHeight) -> u32 {
+        match tip.checked_sub(self.height) {
+            Some(depth) => depth.saturating_add(1),
+            None => 0,
+        }
+    }
+
+    /// Whether the note can go into a spend at `tip`: unspent, with a
+    /// witness and at least `min_confirmations`.
+    fn is_spendable(&self, tip: BlockHeight, min_confirmations: u32) -> bool {
+        self.status == NoteStatus::Unspent
+            && !self.witness.is_empty()
+            && self.confirmations(tip) >= min_confirmations
+    }
+}
+
+/// Amounts in zatoshis at some chain tip. `total` is everything not known to
+/// be spent; it also counts notes locked by pending spends, which are in
+/// neither of the other two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub total: u64,
+    /// Unspent notes with a witness and enough confirmations.
+    pub spendable: u64,
+    /// Unspent notes still waiting for confirmations or a witness.
+    pub pending: u64,
+}
+
+/// The notes a wallet has received, ordered by position in the note
+/// commitment tree.
+pub struct SpendingNotes {
+    nk: EdwardsAffine,
+    nullifier_mode: NullifierMode,
+    notes: BTreeMap<u64, ReceivedNote>,
+    /// The position of each note, by nullifier.
+    positions: HashMap<Nullifier, u64>,
+}
+
+impl SpendingNotes {
+    /// A store for the notes of the key with nullifier deriving key `nk`.
+    pub fn new(nk: &PublicKey, nullifier_mode: NullifierMode) -> Self {
+        SpendingNotes {
+            nk: nk.0,
+            nullifier_mode,
+            notes: BTreeMap::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    /// Records a note received at `height`, and returns its nullifier. A note
+    /// already recorded at `position` is left as it is. A note worth more
+    /// than `MAX_MONEY`, which no transaction can spend, is not recorded.
+    pub fn receive(
+        &mut self,
+        note: NotePlaintext,
+        cm: EdwardsAffine,
+        position: u64,
+        witness: MerklePath,
+        height: BlockHeight,
+    ) -> Option<Nullifier> {
+        if let Some(received) = self.notes.get(&position) {
+            return Some(received.nullifier.clone());
+        }
+        let nullifier = Nullifier::new(cm, position, self.nk, self.nullifier_mode);
+        self.insert(ReceivedNote {
+            nullifier: nullifier.clone(),
+            note,
+            cm,
+            position,
+            witness,
+            height,
+            status: NoteStatus::Unspent,
+        })
+        .then_some(nullifier)
+    }
+
+    /// Restores a note recorded earlier, e.g. from a database. It replaces any
+    /// note at the same position. Returns false, recording nothing, if the
+    /// note is worth more than `MAX_MONEY`.
+    pub fn insert(&mut self, note: ReceivedNote) -> bool {
+        if note.note.value.0 > MAX_MONEY {
+            return false;
+        }
+        if let Some(old) = self.notes.get(&note.position) {
+            self.positions.remove(&old.nullifier);
+        }
+        self.positions.insert(note.nullifier.clone(), note.position);
+        self.notes.insert(note.position, note);
+        true
+    }
+
+    pub fn notes(&self) -> impl Iterator<Item = &ReceivedNote> {
+        self.notes.values()
+    }
+
+    pub fn get(&self, nullifier: &Nullifier) -> Option<&ReceivedNote> {
+        self.notes.get(self.positions.get(nullifier)?)
+    }
+
+    fn get_mut(&mut self, nullifier: &Nullifier) -> Option<&mut ReceivedNote> {
+        self.notes.get_mut(self.positions.get(nullifier)?)
+    }
+
+    /// Replaces the witness of the note at `position`, e.g. once the tree
+    /// has grown. Returns whether there is such a note.
+    pub fn update_witness(&mut self, position: u64, witness: MerklePath) -> bool {
+        self.notes
+            .get_mut(&position)
+            .map(|note| note.witness = witness)
+            .is_some()
+    }
+
+    /// Marks the note as spent by `txid`, which is not mined yet. Returns
+    /// false if the wallet has no such unspent note.
+    pub fn mark_pending(&mut self, nullifier: &Nullifier, txid: TxId) -> bool {
+        match self.get_mut(nullifier) {
+            Some(note) if note.status == NoteStatus::Unspent => {
+                note.status = NoteStatus::PendingSpend(txid);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks the note as spent at `height`, whatever its status was. Returns
+    /// false if the nullifier is not the wallet's.
+    pub fn mark_spent(&mut self, nullifier: &Nullifier, height: BlockHeight) -> bool {
+        self.get_mut(nullifier)
+            .map(|note| note.status = NoteStatus::Spent(height))
+            .is_some()
+    }
+
+    /// Releases the notes of a pending transaction that will not be mined.
+    pub fn cancel_pending(&mut self, txid: &TxId) {
+        for note in self.notes.values_mut() {
+            if note.status == NoteStatus::PendingSpend(*txid) {
+                note.status = NoteStatus::Unspent;
+            }
+        }
+    }
+
+    /// Unspent notes with a witness and at least `min_confirmations` at
+    /// `tip`, oldest first.
+    pub fn spendable_notes(
+        &self,
+        tip: BlockHeight,
+        min_confirmations: u32,
+    ) -> impl Iterator<Item = &ReceivedNote> {
+        self.notes
+            .values()
+            .filter(move |note| note.is_spendable(tip, min_confirmations))
+    }
+
+    pub fn balance(&self, tip: BlockHeight, min_confirmations: u32) -> Balance {
+        let mut balance = Balance::default();
+        for note in self.notes.values() {
+            let value = note.note.value.0;
+            match note.status {
+                NoteStatus::Spent(_) => continue,
+                NoteStatus::PendingSpend(_) => {}
+                // Each note is at most `MAX_MONEY`, but nothing bounds how
+                // many a wallet receives.
+                NoteStatus::Unspent if note.is_spendable(tip, min_confirmations) => {
+                    balance.spendable = balance.spendable.saturating_add(value)
+                }
+                NoteStatus::Unspent => balance.pending = balance.pending.saturating_add(value),
+            }
+            balance.total = balance.total.saturating_add(value);
+        }
+        balance
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::fixtures::SK;
+    use crate::keygen::KeyChain;
+    use crate::memo::Memo;
+    use crate::merkle::PoseidonMerkleHash;
+    use ark_ec::{AffineRepr, CurveGroup};
+    use ark_ed_on_bls12_381::Fr;
+
+    pub fn received(value: u64, seed: u64) -> (NotePlaintext, EdwardsAffine) {
+        let note = NotePlaintext {
+            diversifier: [0; 11],
+            value: NoteValue(value),
+            rcm: Fr::from(seed),
+            memo: Memo::Empty.to_bytes(),
+        };
+        let cm = (EdwardsAffine::generator() * Fr::from(seed)).into_affine();
+        (note, cm)
+    }
+
+    #[test]
+    pub fn test_balance() {
+        let kc = KeyChain::from(SK);
+        let mut notes = SpendingNotes::new(&kc.nk, NullifierMode::Poseidon);
+
+        let witness: MerklePath = vec![(ConstraintF::from(5u64), false); PoseidonMerkleHash::DEPTH];
+        let mut nullifiers = vec![];
+        for (i, (value, height)) in [(10, 100), (20, 105), (40, 110)].into_iter().enumerate() {
+            let (note, cm) = received(value, i as u64 + 1);
+            nullifiers.push(notes.receive(note, cm, i as u64, witness.clone(), height).unwrap());
+        }
+        let (note, cm) = received(1000, 9);
+        assert_eq!(notes.receive(note, cm, 0, vec![], 120), Some(nullifiers[0].clone()));
+
+        let first = notes.get(&nullifiers[0]).unwrap();
+        assert_eq!(first.nullifier, Nullifier::new(first.cm, 0, kc.nk.0, NullifierMode::Poseidon));
+        assert_eq!(
+            first.anchor::<PoseidonMerkleHash>(),
+            compute_root::<PoseidonMerkleHash>(first.cm.y, &witness)
+        );
+
+        assert_eq!(notes.balance(110, 1), Balance { total: 70, spendable: 70, pending: 0 });
+        assert_eq!(notes.balance(110, 6), Balance { total: 70, spendable: 30, pending: 40 });
+        assert_eq!(notes.balance(110, 12), Balance { total: 70, spendable: 0, pending: 70 });
+
+        let txid = TxId([7; 32]);
+        assert!(notes.mark_pending(&nullifiers[1], txid));
+        assert!(!notes.mark_pending(&nullifiers[1], TxId([8; 32])));
+        assert_eq!(notes.balance(110, 6), Balance { total: 70, spendable: 10, pending: 40 });
+        let spendable: Vec<_> = notes.spendable_notes(110, 1).map(|n| n.position).collect();
+        assert_eq!(spendable, vec![0, 2]);
+
+        notes.cancel_pending(&txid);
+        assert_eq!(notes.balance(110, 6).spendable, 30);
+
+        assert!(notes.mark_spent(&nullifiers[0], 111));
+        assert!(!notes.mark_spent(&Nullifier([0; 32]), 111));
+        assert_eq!(notes.balance(111, 1), Balance { total: 60, spendable: 60, pending: 0 });
+
+        // A note waits for its witness before it can be spent.
+        let (note, cm) = received(5, 4);
+        let nf = notes.receive(note, cm, 3, vec![], 111).unwrap();
+        assert_eq!(notes.balance(111, 1), Balance { total: 65, spendable: 60, pending: 5 });
+        assert_eq!(notes.spendable_notes(111, 1).count(), 2);
+        assert!(notes.update_witness(3, witness.clone()));
+        assert_eq!(notes.spendable_notes(111, 1).last().unwrap().nullifier, nf);
+
+        // Replacing a note drops its old nullifier.
+        let mut replaced = notes.get(&nf).unwrap().clone();
+        replaced.nullifier = Nullifier([3; 32]);
+        assert!(notes.insert(replaced));
+        assert!(notes.get(&nf).is_none());
+        assert_eq!(notes.get(&Nullifier([3; 32])).unwrap().position, 3);
+
+        assert_eq!(notes.get(&nullifiers[2]).unwrap().confirmations(BlockHeight::MAX), BlockHeight::MAX - 109);
+    }
+
+    #[test]
+    pub fn test_note_values_are_bounded() {
+        let kc = KeyChain::from(SK);
+        let mut notes = SpendingNotes::new(&kc.nk, NullifierMode::Poseidon);
+
+        let (note, cm) = received(MAX_MONEY + 1, 1);
+        assert_eq!(notes.receive(note, cm, 0, vec![], 100), None);
+        assert_eq!(notes.notes().count(), 0);
+
+        // Enough notes of `MAX_MONEY` still overflow a u64.
+        let (note, cm) = received(MAX_MONEY, 2);
+        let count = u64::MAX / MAX_MONEY + 1;
+        for position in 0..count {
+            let mut nullifier = [0; 32];
+            nullifier[..8].copy_from_slice(&position.to_le_bytes());
+            assert!(notes.insert(ReceivedNote {
+                note: note.clone(),
+                cm,
+                position,
+                witness: vec![],
+                nullifier: Nullifier(nullifier),
+                height: 100,
+                status: NoteStatus::Unspent,
+            }));
+        }
+        assert_eq!(notes.balance(100, 1), Balance { total: u64::MAX, spendable: 0, pending: u64::MAX });
+
+        let mut too_much = notes.notes().next().unwrap().clone();
+        too_much.note.value = NoteValue(MAX_MONEY + 1);
+        assert!(!notes.insert(too_much));
+        assert_eq!(notes.notes().next().unwrap().note.value, NoteValue(MAX_MONEY));
+    }
+}
@@ -0,0 +1,290 @@
+use ark_ec::{AffineRepr, CurveGroup};
+use ark_ed_on_bls12_381::{EdwardsAffine, Fr};
+use ark_ff::PrimeField;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use blake2b_simd::Params;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+
+use crate::commitment::{note_commitment, NoteCommitmentMode};
+use crate::group_hash::{abst_j, diversify_hash, repr_j};
+use crate::keygen::OutgoingViewKey;
+use crate::memo::{Memo, MemoError, MEMO_SIZE};
+use crate::note::NoteValue;
+use crate::output_description::{ENC_CIPHERTEXT_SIZE, OUT_CIPHERTEXT_SIZE};
+use ark_crypto_primitives::commitment::pedersen::Randomness;
+
+pub const NOTE_PLAINTEXT_SIZE: usize = 1 + 11 + 8 + 32 + MEMO_SIZE;
+/// The start of the plaintext up to the memo, which compact blocks carry.
+pub const COMPACT_NOTE_SIZE: usize = NOTE_PLAINTEXT_SIZE - MEMO_SIZE;
+/// `pk_d || esk`, which the sender encrypts to itself in `outCiphertext`.
+pub const OUT_PLAINTEXT_SIZE: usize = 32 + 32;
+
+const KDF_SAPLING_PERSONALIZATION: &[u8; 16] = b"Zcash_SaplingKDF";
+const PRF_OCK_PERSONALIZATION: &[u8; 16] = b"Zcash_Derive_ock";
+/// Notes whose plaintext carries `rcm` itself, as before ZIP-212.
+const NOTE_PLAINTEXT_LEAD_BYTE: u8 = 0x01;
+
+/// What the recipient of an output learns from its `encCiphertext`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotePlaintext {
+    pub diversifier: [u8; 11],
+    pub value: NoteValue,
+    pub rcm: Fr,
+    pub memo: [u8; MEMO_SIZE],
+}
+
+impl NotePlaintext {
+    pub fn memo(&self) -> Result<Memo, MemoError> {
+        Memo::from_bytes(&self.memo)
+    }
+
+    fn to_bytes(&self) -> [u8; NOTE_PLAINTEXT_SIZE] {
+        let mut bytes = [0u8; NOTE_PLAINTEXT_SIZE];
+        bytes[0] = NOTE_PLAINTEXT_LEAD_BYTE;
+        bytes[1..12].copy_from_slice(&self.diversifier);
+        bytes[12..20].copy_from_slice(&self.value.0.to_le_bytes());
+        self.rcm.serialize_compressed(&mut bytes[20..52]).unwrap();
+        bytes[52..].copy_from_slice(&self.memo);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; NOTE_PLAINTEXT_SIZE]) -> Option<Self> {
+        if bytes[0] != NOTE_PLAINTEXT_LEAD_BYTE {
+            return None;
+        }
+
+        Some(NotePlaintext {
+            diversifier: bytes[1..12].try_into().unwrap(),
+            value: NoteValue(u64::from_le_bytes(bytes[12..20].try_into().unwrap())),
+            rcm: Fr::deserialize_compressed(&bytes[20..52]).ok()?,
+            memo: bytes[52..].try_into().unwrap(),
+        })
+    }
+}
+
+/// `KDF^Sapling`: BLAKE2b-256 of `repr_J(sharedSecret) || repr_J(epk)`.
+pub(crate) fn kdf(shared_secret: &EdwardsAffine, epk: &EdwardsAffine) -> Key {
+    let mut h = Params::new()
+        .hash_length(32)
+        .personal(KDF_SAPLING_PERSONALIZATION)
+        .to_state();
+    h.update(&repr_j(shared_secret));
+    h.update(&repr_j(epk));
+    *Key::from_slice(h.finalize().as_bytes())
+}
+
+/// `PRF^ock`: BLAKE2b-256 of `ovk || cv || cmu || epk`, the key the sender
+/// encrypts `outCiphertext` under.
+fn prf_ock(
+    ovk: &OutgoingViewKey,
+    cv: &EdwardsAffine,
+    cmu: &EdwardsAffine,
+    epk: &EdwardsAffine,
+) -> Key {
+    let mut h = Params::new()
+        .hash_length(32)
+        .personal(PRF_OCK_PERSONALIZATION)
+        .to_state();
+    h.update(ovk);
+    h.update(&repr_j(cv));
+    h.update(&repr_j(cmu));
+    h.update(&repr_j(epk));
+    *Key::from_slice(h.finalize().as_bytes())
+}
+
+/// Encrypts `note` to `pk_d` under the key agreed with `epk = [esk] g_d`.
+pub fn encrypt_note(
+    pk_d: &EdwardsAffine,
+    esk: &Fr,
+    epk: &EdwardsAffine,
+    note: &NotePlaintext,
+) -> [u8; ENC_CIPHERTEXT_SIZE] {
+    let shared_secret = pk_d.mul_bigint(esk.into_bigint()).into_affine().mul_by_cofactor();
+    let cipher = ChaCha20Poly1305::new(&kdf(&shared_secret, epk));
+
+    let mut ciphertext = [0u8; ENC_CIPHERTEXT_SIZE];
+    ciphertext[..NOTE_PLAINTEXT_SIZE].copy_from_slice(&note.to_bytes());
+    let tag = cipher
+        .encrypt_in_place_detached(
+            &Nonce::default(),
+            &[],
+            &mut ciphertext[..NOTE_PLAINTEXT_SIZE],
+        )
+        .expect("plaintext fits");
+    ciphertext[NOTE_PLAINTEXT_SIZE..].copy_from_slice(&tag);
+    ciphertext
+}
+
+/// Encrypts `pk_d || esk` to the sender's `ovk`, which lets it recover the
+/// note it sent with `try_output_recovery`.
+pub fn encrypt_outgoing(
+    ovk: &OutgoingViewKey,
+    cv: &EdwardsAffine,
+    cmu: &EdwardsAffine,
+    epk: &EdwardsAffine,
+    pk_d: &EdwardsAffine,
+    esk: &Fr,
+) -> [u8; OUT_CIPHERTEXT_SIZE] {
+    let cipher = ChaCha20Poly1305::new(&prf_ock(ovk, cv, cmu, epk));
+
+    let mut ciphertext = [0u8; OUT_CIPHERTEXT_SIZE];
+    ciphertext[..32].copy_from_slice(&repr_j(pk_d));
+    esk.serialize_compressed(&mut ciphertext[32..OUT_PLAINTEXT_SIZE]).unwrap();
+    let tag = cipher
+        .encrypt_in_place_detached(
+            &Nonce::default(),
+            &[],
+            &mut ciphertext[..OUT_PLAINTEXT_SIZE],
+        )
+        .expect("plaintext fits");
+    ciphertext[OUT_PLAINTEXT_SIZE..].copy_from_slice(&tag);
+    ciphertext
+}
+
+/// Recovers, with the `ovk` it was sent with, the note of an output. Like
+/// `try_decrypt_note`, the plaintext must open `cmu`, and `esk` must also
+/// give `epk`.
+#[allow(clippy::too_many_arguments)]
+pub fn try_output_recovery(
+    ovk: &OutgoingViewKey,
+    cv: &EdwardsAffine,
+    cmu: &EdwardsAffine,
+    epk: &EdwardsAffine,
+    enc_ciphertext: &[u8; ENC_CIPHERTEXT_SIZE],
+    out_ciphertext: &[u8; OUT_CIPHERTEXT_SIZE],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    let cipher = ChaCha20Poly1305::new(&prf_ock(ovk, cv, cmu, epk));
+
+    let mut plaintext: [u8; OUT_PLAINTEXT_SIZE] =
+        out_ciphertext[..OUT_PLAINTEXT_SIZE].try_into().unwrap();
+    cipher
+        .decrypt_in_place_detached(
+            &Nonce::default(),
+            &[],
+            &mut plaintext,
+            Tag::from_slice(&out_ciphertext[OUT_PLAINTEXT_SIZE..]),
+        )
+        .ok()?;
+    let pk_d = abst_j(plaintext[..32].try_into().unwrap())?;
+    let esk = Fr::deserialize_compressed(&plaintext[32..]).ok()?;
+
+    let shared_secret = pk_d.mul_bigint(esk.into_bigint()).into_affine().mul_by_cofactor();
+    let note = decrypt_plaintext(&kdf(&shared_secret, epk), enc_ciphertext)?;
+    let g_d = diversify_hash(&note.diversifier)?;
+    if g_d.mul_bigint(esk.into_bigint()).into_affine() != *epk {
+        return None;
+    }
+
+    opens_commitment(note, &g_d, &pk_d, cmu, note_commitment_mode)
+}
+
+/// The key `ivk` agrees on with the sender of an output with key `epk`.
+fn agree(ivk: &Fr, epk: &EdwardsAffine) -> Key {
+    let shared_secret = (*epk * ivk).into_affine().mul_by_cofactor();
+    kdf(&shared_secret, epk)
+}
+
+/// Decrypts an output with the incoming viewing key. Succeeds only if the
+/// output is addressed to `ivk` and the plaintext opens `cmu`.
+pub fn try_decrypt_note(
+    ivk: &Fr,
+    epk: &EdwardsAffine,
+    cmu: &EdwardsAffine,
+    enc_ciphertext: &[u8; ENC_CIPHERTEXT_SIZE],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    try_decrypt_note_with_key(&agree(ivk, epk), ivk, cmu, enc_ciphertext, note_commitment_mode)
+}
+
+/// `try_decrypt_note` with the key agreement already done.
+pub(crate) fn try_decrypt_note_with_key(
+    key: &Key,
+    ivk: &Fr,
+    cmu: &EdwardsAffine,
+    enc_ciphertext: &[u8; ENC_CIPHERTEXT_SIZE],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    let note = decrypt_plaintext(key, enc_ciphertext)?;
+    check_note_commitment(note, ivk, cmu, note_commitment_mode)
+}
+
+/// Authenticates and decrypts an `encCiphertext` under `key`.
+fn decrypt_plaintext(key: &Key, enc_ciphertext: &[u8; ENC_CIPHERTEXT_SIZE]) -> Option<NotePlaintext> {
+    let cipher = ChaCha20Poly1305::new(key);
+
+    let mut plaintext: [u8; NOTE_PLAINTEXT_SIZE] =
+        enc_ciphertext[..NOTE_PLAINTEXT_SIZE].try_into().unwrap();
+    cipher
+        .decrypt_in_place_detached(
+            &Nonce::default(),
+            &[],
+            &mut plaintext,
+            Tag::from_slice(&enc_ciphertext[NOTE_PLAINTEXT_SIZE..]),
+        )
+        .ok()?;
+    NotePlaintext::from_bytes(&plaintext)
+}
+
+/// Decrypts the first `COMPACT_NOTE_SIZE` bytes of an `encCiphertext`, as
+/// compact blocks carry them. Without the tag they are not authenticated,
+/// but the note must still open `cmu`. The memo is left empty.
+pub fn try_compact_note_decryption(
+    ivk: &Fr,
+    epk: &EdwardsAffine,
+    cmu: &EdwardsAffine,
+    ciphertext: &[u8; COMPACT_NOTE_SIZE],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    try_compact_note_decryption_with_key(&agree(ivk, epk), ivk, cmu, ciphertext, note_commitment_mode)
+}
+
+/// `try_compact_note_decryption` with the key agreement already done.
+pub(crate) fn try_compact_note_decryption_with_key(
+    key: &Key,
+    ivk: &Fr,
+    cmu: &EdwardsAffine,
+    ciphertext: &[u8; COMPACT_NOTE_SIZE],
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    let mut plaintext = [0u8; NOTE_PLAINTEXT_SIZE];
+    plaintext[..COMPACT_NOTE_SIZE].copy_from_slice(ciphertext);
+
+    // ChaCha20Poly1305 encrypts from the second block of the key stream; the
+    // first one is the Poly1305 key.
+    let mut cipher = ChaCha20::new(key, &Nonce::default());
+    cipher.seek(64);
+    cipher.apply_keystream(&mut plaintext[..COMPACT_NOTE_SIZE]);
+    plaintext[COMPACT_NOTE_SIZE..].copy_from_slice(&Memo::Empty.to_bytes());
+
+    let note = NotePlaintext::from_bytes(&plaintext)?;
+    check_note_commitment(note, ivk, cmu, note_commitment_mode)
+}
+
+fn check_note_commitment(
+    note: NotePlaintext,
+    ivk: &Fr,
+    cmu: &EdwardsAffine,
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    let g_d = diversify_hash(&note.diversifier)?;
+    let pk_d = (g_d * ivk).into_affine();
+    opens_commitment(note, &g_d, &pk_d, cmu, note_commitment_mode)
+}
+
+fn opens_commitment(
+    note: NotePlaintext,
+    g_d: &EdwardsAffine,
+    pk_d: &EdwardsAffine,
+    cmu: &EdwardsAffine,
+    note_commitment_mode: NoteCommitmentMode,
+) -> Option<NotePlaintext> {
+    let cm = note_commitment(note_commitment_mode, g_d, pk_d, &note.value, &Randomness(note.rcm));
+    if cm != *cmu {
+        return None;
+    }
+
+    Some(note)
+}
@@ -1,151 +1,431 @@
 use ark_crypto_primitives::commitment::pedersen::Randomness;
-use ark_crypto_primitives::snark::SNARK;
 use ark_ed_on_bls12_381::{EdwardsAffine, EdwardsProjective};
-use ark_groth16::{prepare_verifying_key, Groth16, PreparedVerifyingKey, Proof};
+use ark_relations::r1cs::SynthesisError;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+
+use ark_ec::{AffineRepr, CurveGroup};
+use ark_ed_on_bls12_381::Fr;
+use ark_ff::{PrimeField, UniformRand};
 use rand::thread_rng;
 
-use crate::{keygen::PublicKey, note::NoteValue};
-use crate::commitment::{Commitment, ValueCommitTrapdoor};
-use crate::circuit::Output;
+use crate::keygen::{OutgoingViewKey, PublicKey};
+use crate::note::NoteValue;
+use crate::address::PaymentAddress;
+use crate::commitment::{
+    homomorphic_pedersen_commitment, note_commitment, NoteCommitmentMode, ValueCommitTrapdoor,
+};
+use crate::circuit::{Output, OutputPublicInputs};
+use crate::group_hash::{abst_j, is_small_order, repr_j};
+use crate::memo::Memo;
+use crate::note_encryption::{
+    encrypt_note, encrypt_outgoing, try_decrypt_note, try_output_recovery, NotePlaintext,
+};
+use crate::prover::{OutputProver, Proof, SaplingVerifier};
+use crate::witness::OutputWitness;
+
 
+pub const ENC_CIPHERTEXT_SIZE: usize = 580;
+pub const OUT_CIPHERTEXT_SIZE: usize = 80;
+/// The length of an `OutputDescription` on the wire.
+pub const OUTPUT_DESCRIPTION_SIZE: usize = 96 + ENC_CIPHERTEXT_SIZE + OUT_CIPHERTEXT_SIZE + 192;
 
+#[derive(Debug)]
 pub struct OutputDescription {
-    _cv: EdwardsAffine,
-    _cmu: EdwardsAffine,
-    _epk: EdwardsAffine,
-    _output_proof: Proof<ark_bls12_381::Bls12_381>,
-    _verifying_key: PreparedVerifyingKey<ark_bls12_381::Bls12_381>,
+    cv: EdwardsAffine,
+    cmu: EdwardsAffine,
+    epk: EdwardsAffine,
+    enc_ciphertext: [u8; ENC_CIPHERTEXT_SIZE],
+    out_ciphertext: [u8; OUT_CIPHERTEXT_SIZE],
+    output_proof: Proof,
 }
 
-impl OutputDescription {
+/// An output with everything but its proof. It owns its witness, so the
+/// spends and outputs of a transaction can be proved together, see
+/// `SaplingBundle::prove`.
+#[derive(Clone, Debug)]
+pub struct UnprovenOutput {
+    witness: OutputWitness,
+    enc_ciphertext: [u8; ENC_CIPHERTEXT_SIZE],
+    out_ciphertext: [u8; OUT_CIPHERTEXT_SIZE],
+}
+
+impl UnprovenOutput {
+    /// Pays `value` to `recipient` with fresh randomness, recoverable with the
+    /// sender's `ovk`. Returns the output with its `rcv`, which goes into the
+    /// transaction's binding signing key.
+    pub fn new(
+        ovk: &OutgoingViewKey,
+        recipient: &PaymentAddress,
+        value: NoteValue,
+        memo: &Memo,
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> (Self, ValueCommitTrapdoor) {
+        let mut rng = thread_rng();
+        let g_d = recipient.g_d();
+        let rcv = ValueCommitTrapdoor::random();
+        let rcm = Randomness(Fr::rand(&mut rng));
+        let esk = Fr::rand(&mut rng);
+
+        let output = Self::from_values(
+            ovk,
+            homomorphic_pedersen_commitment(value.clone(), &rcv),
+            note_commitment(note_commitment_mode, &g_d, recipient.pk_d(), &value, &rcm),
+            PublicKey(g_d.mul_bigint(esk.into_bigint()).into_affine()),
+            *recipient.diversifier(),
+            g_d,
+            *recipient.pk_d(),
+            value,
+            rcv.clone(),
+            rcm,
+            esk,
+            note_commitment_mode,
+            memo,
+        );
+        (output, rcv)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn from_values(
+        ovk: &OutgoingViewKey,
         cv_new: EdwardsAffine,
         note_com: EdwardsAffine,
         epk: PublicKey,
+        diversifier: [u8; 11],
         g_d: EdwardsAffine,
         pk_d: EdwardsAffine,
         note_value: NoteValue,
         rcv: ValueCommitTrapdoor,
         rm_new: Randomness<EdwardsProjective>,
         esk: ark_ed_on_bls12_381::Fr,
+        note_commitment_mode: NoteCommitmentMode,
+        memo: &Memo,
     ) -> Self {
-        let output = Output {
-            cv_new: Some(cv_new),
-            note_com_new: Some(note_com),
-            epk: Some(epk.0.into()),
-            gd: Some(g_d),
-            pk_d: Some(pk_d),
-            v_new: Some(note_value.clone()),
-            rcv_new: Some(rcv.clone()),
-            rcm_new: Some(rm_new.clone()),
-            esk: Some(esk),
-            note_com_params: Commitment::setup(),
-        };
-        let output2 = Output {
-            cv_new: Some(cv_new),
-            note_com_new: Some(note_com),
-            epk: Some(epk.0.into()),
-            gd: Some(g_d),
-            pk_d: Some(pk_d),
-            v_new: Some(note_value),
-            rcv_new: Some(rcv),
-            rcm_new: Some(rm_new),
-            esk: Some(esk),
-            note_com_params: Commitment::setup(),
+        let enc_ciphertext = encrypt_note(
+            &pk_d,
+            &esk,
+            &epk.0,
+            &NotePlaintext {
+                diversifier,
+                value: note_value.clone(),
+                rcm: rm_new.0,
+                memo: memo.to_bytes(),
+            },
+        );
+
+        let out_ciphertext = encrypt_outgoing(ovk, &cv_new, &note_com, &epk.0, &pk_d, &esk);
+
+        let witness = OutputWitness {
+            public_inputs: OutputPublicInputs {
+                cv: cv_new,
+                cm: note_com,
+                epk: epk.0,
+            },
+            g_d,
+            pk_d,
+            note_value,
+            rcv,
+            rcm: rm_new.0,
+            esk,
+            note_commitment_mode,
         };
+        UnprovenOutput { witness, enc_ciphertext, out_ciphertext }
+    }
 
-        let mut rng = thread_rng();
-        let (pk, vk) =
-            Groth16::<ark_bls12_381::Bls12_381>::circuit_specific_setup(
-                output, 
-                &mut rng
-            ).unwrap();
-        
-        let proof =
-            Groth16::<ark_bls12_381::Bls12_381>::prove(
-                &pk, 
-                output2, 
-                &mut rng
-            ).expect("failed");
-
-        let pvk = prepare_verifying_key(&vk);
-
-        OutputDescription {
-            _cv: cv_new,
-            _cmu: note_com,
-            _epk: epk.0,
-            _output_proof: proof,
-            _verifying_key: pvk,
+    pub fn witness(&self) -> &OutputWitness {
+        &self.witness
+    }
+
+    pub fn prove(&self, prover: &impl OutputProver) -> Result<OutputDescription, SynthesisError> {
+        let proof = prover.prove_output(Output::from(&self.witness))?;
+        let public_inputs = &self.witness.public_inputs;
+
+        Ok(OutputDescription {
+            cv: public_inputs.cv,
+            cmu: public_inputs.cm,
+            epk: public_inputs.epk,
+            enc_ciphertext: self.enc_ciphertext,
+            out_ciphertext: self.out_ciphertext,
+            output_proof: proof,
+        })
+    }
+}
+
+impl OutputDescription {
+    /// Pays `value` to `recipient` with fresh randomness, recoverable with the
+    /// sender's `ovk`. Returns the output with its `rcv`, which goes into the
+    /// transaction's binding signing key.
+    pub fn new(
+        prover: &impl OutputProver,
+        ovk: &OutgoingViewKey,
+        recipient: &PaymentAddress,
+        value: NoteValue,
+        memo: &Memo,
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> Result<(Self, ValueCommitTrapdoor), SynthesisError> {
+        let (output, rcv) = UnprovenOutput::new(ovk, recipient, value, memo, note_commitment_mode);
+        Ok((output.prove(prover)?, rcv))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_values(
+        prover: &impl OutputProver,
+        ovk: &OutgoingViewKey,
+        cv_new: EdwardsAffine,
+        note_com: EdwardsAffine,
+        epk: PublicKey,
+        diversifier: [u8; 11],
+        g_d: EdwardsAffine,
+        pk_d: EdwardsAffine,
+        note_value: NoteValue,
+        rcv: ValueCommitTrapdoor,
+        rm_new: Randomness<EdwardsProjective>,
+        esk: ark_ed_on_bls12_381::Fr,
+        note_commitment_mode: NoteCommitmentMode,
+        memo: &Memo,
+    ) -> Result<Self, SynthesisError> {
+        UnprovenOutput::from_values(
+            ovk,
+            cv_new,
+            note_com,
+            epk,
+            diversifier,
+            g_d,
+            pk_d,
+            note_value,
+            rcv,
+            rm_new,
+            esk,
+            note_commitment_mode,
+            memo,
+        )
+        .prove(prover)
+    }
+
+    pub fn cmu(&self) -> &EdwardsAffine {
+        &self.cmu
+    }
+
+    pub fn epk(&self) -> &EdwardsAffine {
+        &self.epk
+    }
+
+    pub fn enc_ciphertext(&self) -> &[u8; ENC_CIPHERTEXT_SIZE] {
+        &self.enc_ciphertext
+    }
+
+    /// Recovers the note, including its memo, if the output is addressed to
+    /// `ivk`.
+    pub fn try_decrypt(
+        &self,
+        ivk: &ark_ed_on_bls12_381::Fr,
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> Option<NotePlaintext> {
+        try_decrypt_note(ivk, &self.epk, &self.cmu, &self.enc_ciphertext, note_commitment_mode)
+    }
+
+    /// Recovers the note, including its memo, if the output was sent with
+    /// `ovk`.
+    pub fn try_recover(
+        &self,
+        ovk: &OutgoingViewKey,
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> Option<NotePlaintext> {
+        try_output_recovery(
+            ovk,
+            &self.cv,
+            &self.cmu,
+            &self.epk,
+            &self.enc_ciphertext,
+            &self.out_ciphertext,
+            note_commitment_mode,
+        )
+    }
+
+    /// The Sapling v4 encoding: `cv`, `cmu`, `ephemeralKey`, `encCiphertext`,
+    /// `outCiphertext` and `zkproof`. Unlike Sapling, `cmu` is the `repr_J` of
+    /// the whole note commitment, since the circuit exposes both coordinates.
+    pub fn to_bytes(&self) -> [u8; OUTPUT_DESCRIPTION_SIZE] {
+        let mut bytes = [0u8; OUTPUT_DESCRIPTION_SIZE];
+        bytes[..32].copy_from_slice(&repr_j(&self.cv));
+        bytes[32..64].copy_from_slice(&repr_j(&self.cmu));
+        bytes[64..96].copy_from_slice(&repr_j(&self.epk));
+        bytes[96..676].copy_from_slice(&self.enc_ciphertext);
+        bytes[676..756].copy_from_slice(&self.out_ciphertext);
+        self.output_proof
+            .serialize_compressed(&mut bytes[756..])
+            .unwrap();
+        bytes
+    }
+
+    /// Decodes `to_bytes`, rejecting non-canonical points and points that are
+    /// not on the curve.
+    pub fn from_bytes(bytes: &[u8; OUTPUT_DESCRIPTION_SIZE]) -> Option<Self> {
+        Some(OutputDescription {
+            cv: abst_j(bytes[..32].try_into().unwrap())?,
+            cmu: abst_j(bytes[32..64].try_into().unwrap())?,
+            epk: abst_j(bytes[64..96].try_into().unwrap())?,
+            enc_ciphertext: bytes[96..676].try_into().unwrap(),
+            out_ciphertext: bytes[676..756].try_into().unwrap(),
+            output_proof: Proof::deserialize_compressed(&bytes[756..]).ok()?,
+        })
+    }
+
+    pub fn public_inputs(&self) -> OutputPublicInputs {
+        OutputPublicInputs {
+            cv: self.cv,
+            cm: self.cmu,
+            epk: self.epk,
         }
     }
+
+    pub fn verify_proof(&self, verifier: &impl SaplingVerifier) -> bool {
+        if is_small_order(&self.cv) || is_small_order(&self.epk) {
+            return false;
+        }
+
+        verifier.verify_output(&self.public_inputs(), &self.output_proof)
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use crate::commitment::homomorphic_pedersen_commitment;
+    use crate::fixtures::{default_address, output_witness, SK};
+    use crate::group_hash::test::small_order_points;
     use crate::keygen::KeyChain;
-    use crate::pedersen_crh::Window;
+    use crate::merkle::PoseidonMerkleHash;
+    use crate::note_encryption::{try_compact_note_decryption, COMPACT_NOTE_SIZE};
+    use crate::prf::prf_expand::IvkMode;
+    use crate::prover::{LocalProver, MockProver, MockVerifier};
     use crate::signing_key::SigningKey;
-    use ark_crypto_primitives::commitment::pedersen::{
-        Commitment as pdCommit, Randomness as pdRand,
-    };
-    use ark_crypto_primitives::commitment::CommitmentScheme;
-    use ark_ec::AffineRepr;
-    use ark_ed_on_bls12_381::Fr;
-    use ark_groth16::Groth16;
-    use ark_serialize::CanonicalSerialize;
-    const SK: SigningKey = &[
-        24, 226, 141, 234, 92, 17, 129, 122, 238, 178, 26, 25, 152, 29, 40, 54, 142, 196, 56, 175,
-        194, 90, 141, 185, 78, 190, 8, 215, 160, 40, 142, 9,
-    ];
-    #[test]
-    pub fn test_output_description() {
+    use crate::spend_description::NullifierMode;
+    use crate::witness::OutputWitness;
+    use ark_crypto_primitives::commitment::pedersen::Randomness as pdRand;
+    use ark_ec::{AffineRepr, CurveGroup, Group};
+    use rand::thread_rng;
+
+    /// An output of a 10-zatoshi note, with its `rcv`.
+    pub fn output_description(prover: &impl OutputProver) -> (OutputDescription, ValueCommitTrapdoor) {
+        let (output, rcv) = unproven_output();
+        (output.prove(prover).unwrap(), rcv)
+    }
+
+    /// The output of `output_description`, before it is proved.
+    pub fn unproven_output() -> (UnprovenOutput, ValueCommitTrapdoor) {
         let kc = KeyChain::from(SK);
-        let value = NoteValue(10);
-        let rcv = ValueCommitTrapdoor::random();
-        let cv_new = homomorphic_pedersen_commitment(value.clone(), &rcv);
-        let (_, g_d, pk_d) = kc.get_diversified_transmission_address();
-
-        let cm_params = Commitment::setup();
-        let rcm = pdRand::<EdwardsProjective>(Fr::from(46));
-        let mut note_com_inp = vec![];
-        let mut g_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&g_d, &mut g_d_repr[..])
-            .expect("failed");
-
-        let mut pk_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&pk_d.0, &mut pk_d_repr[..])
-            .expect("failed");
-        let v_repr = value.0.to_le_bytes();
-        note_com_inp.extend(g_d_repr);
-        note_com_inp.extend(pk_d_repr);
-        note_com_inp.extend(v_repr);
-        let note_comm =
-            pdCommit::<EdwardsProjective, Window>::commit(&cm_params.params, &note_com_inp, &rcm)
-                .expect("failed");
-        let esk = Fr::from(5345345);
-        let epk = g_d.mul_bigint(esk.0);
-        let od = OutputDescription::from_values(
-            cv_new,
-            note_comm,
-            PublicKey(epk.into()),
+        let (d, _, _) = default_address(&kc);
+        let OutputWitness { public_inputs, g_d, pk_d, note_value, rcv, rcm, esk, .. } =
+            output_witness(NoteCommitmentMode::Arkworks);
+        let output = UnprovenOutput::from_values(
+            &kc.ovk,
+            public_inputs.cv,
+            public_inputs.cm,
+            PublicKey(public_inputs.epk),
+            d,
             g_d,
-            pk_d.0,
-            value.clone(),
+            pk_d,
+            note_value,
             rcv.clone(),
-            rcm.clone(),
-            esk.clone(),
+            pdRand(rcm),
+            esk,
+            NoteCommitmentMode::Arkworks,
+            &Memo::text("invoice 42").unwrap(),
+        );
+        (output, rcv)
+    }
+
+    #[test]
+    pub fn test_output_description() {
+        let prover = LocalProver::<PoseidonMerkleHash>::setup(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+            &mut thread_rng(),
         );
-        
-        let public_inputs = [od._cmu.x, od._cmu.y, od._cv.x, od._cv.y, od._epk.x, od._epk.y];
-        let res = Groth16::<ark_bls12_381::Bls12_381>::verify_with_processed_vk(
-            &od._verifying_key,
-            &public_inputs,
-            &od._output_proof,
-        ).unwrap();
-        
-        println!("proof: {:?}", od._output_proof);
-        println!("res {:?}", res);
-    }
-}
\ No newline at end of file
+        let verifier = prover.verifier();
+        let (od, _) = output_description(&prover);
+
+        println!("proof: {:?}", od.output_proof);
+        assert!(od.verify_proof(&verifier));
+        assert!(!od.verify_proof(&MockVerifier));
+
+        let mut tampered = od.public_inputs();
+        tampered.cv = od.cv.into_group().double().into_affine();
+        assert!(!verifier.verify_output(&tampered, &od.output_proof));
+
+        let mut od = od;
+        od.epk = small_order_points()[2];
+        assert!(!od.verify_proof(&verifier));
+    }
+
+    #[test]
+    pub fn test_output_description_encoding() {
+        let (od, _) = output_description(&MockProver);
+        let bytes = od.to_bytes();
+        let decoded = OutputDescription::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.public_inputs(), od.public_inputs());
+        assert!(decoded.verify_proof(&MockVerifier));
+
+        // cmu with v >= q.
+        let mut non_canonical = bytes;
+        non_canonical[32..64].copy_from_slice(&[0xff; 32]);
+        non_canonical[63] = 0x7f;
+        assert!(OutputDescription::from_bytes(&non_canonical).is_none());
+    }
+
+    #[test]
+    pub fn test_memo_is_recovered_by_the_recipient() {
+        let (od, _) = output_description(&MockProver);
+        let kc = KeyChain::from(SK);
+        let note = od.try_decrypt(&kc.ivk.0, NoteCommitmentMode::Arkworks).unwrap();
+        assert_eq!(note.value, NoteValue(10));
+        assert_eq!(note.memo().unwrap(), Memo::text("invoice 42").unwrap());
+
+        let other: SigningKey = &[3; 32];
+        assert!(od.try_decrypt(&KeyChain::from(other).ivk.0, NoteCommitmentMode::Arkworks).is_none());
+        assert!(od.try_decrypt(&kc.ivk.0, NoteCommitmentMode::Sapling).is_none());
+
+        let mut tampered = od.to_bytes();
+        tampered[200] ^= 1;
+        let tampered = OutputDescription::from_bytes(&tampered).unwrap();
+        assert!(tampered.try_decrypt(&kc.ivk.0, NoteCommitmentMode::Arkworks).is_none());
+
+        let compact = try_compact_note_decryption(
+            &kc.ivk.0,
+            od.epk(),
+            od.cmu(),
+            od.enc_ciphertext()[..COMPACT_NOTE_SIZE].try_into().unwrap(),
+            NoteCommitmentMode::Arkworks,
+        )
+        .unwrap();
+        assert_eq!(compact.memo().unwrap(), Memo::Empty);
+        assert_eq!((compact.diversifier, compact.value, compact.rcm), (note.diversifier, note.value, note.rcm));
+    }
+
+    #[test]
+    pub fn test_note_is_recovered_by_the_sender() {
+        let (od, _) = output_description(&MockProver);
+        let kc = KeyChain::from(SK);
+        let note = od.try_recover(&kc.ovk, NoteCommitmentMode::Arkworks).unwrap();
+        assert_eq!(note, od.try_decrypt(&kc.ivk.0, NoteCommitmentMode::Arkworks).unwrap());
+
+        let other: SigningKey = &[3; 32];
+        assert!(od.try_recover(&KeyChain::from(other).ovk, NoteCommitmentMode::Arkworks).is_none());
+
+        let mut tampered = od.to_bytes();
+        tampered[700] ^= 1;
+        let tampered = OutputDescription::from_bytes(&tampered).unwrap();
+        assert!(tampered.try_recover(&kc.ovk, NoteCommitmentMode::Arkworks).is_none());
+    }
+
+    #[test]
+    pub fn test_mock_output_description() {
+        let (od, _) = output_description(&MockProver);
+        assert!(od.verify_proof(&MockVerifier));
+
+        let mut od = od;
+        od.epk = small_order_points()[2];
+        assert!(!od.verify_proof(&MockVerifier));
+    }
+}
@@ -0,0 +1,492 @@
+//! ZIP-321 payment requests: `zcash:` URIs naming one or more Sapling
+//! recipients, e.g.
+//!
+//! ```text
+//! zcash:zs1...?amount=1.5&memo=SW52b2ljZSA0Mg&message=Thanks
+//! zcash:?address=zs1...&amount=1&address.1=zs1...&amount.1=0.25
+//! ```
+//!
+//! An address may also be a unified address with a Sapling receiver, which
+//! is what is paid.
+//!
+//! Parameters of the `n`-th payment carry a `.n` suffix, except for the first
+//! payment, which has none.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::address::{Network, PaymentAddress};
+use crate::commitment::{NoteCommitmentMode, ValueCommitTrapdoor};
+use crate::keygen::OutgoingViewKey;
+use crate::memo::{Memo, MEMO_SIZE};
+use crate::note::NoteValue;
+use crate::output_description::UnprovenOutput;
+use crate::transaction::MAX_MONEY;
+use crate::unified::UnifiedAddress;
+
+const SCHEME: &str = "zcash:";
+const COIN: u64 = 100_000_000;
+const MAX_PAYMENTS: usize = 10_000;
+
+/// Everything but ZIP-321's `qchar`s, i.e. the unreserved characters, the
+/// allowed delimiters, `:` and `@`.
+const QCHAR_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b'+')
+    .remove(b',')
+    .remove(b';')
+    .remove(b':')
+    .remove(b'@');
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payment {
+    pub recipient: PaymentAddress,
+    /// Left to the payer when absent.
+    pub amount: Option<NoteValue>,
+    pub memo: Option<Memo>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// Parameters this crate does not interpret, percent-decoded, so that a
+    /// request can be passed on unchanged.
+    pub other_params: Vec<(String, String)>,
+}
+
+impl Payment {
+    pub fn new(recipient: PaymentAddress, amount: NoteValue) -> Self {
+        Payment {
+            recipient,
+            amount: Some(amount),
+            memo: None,
+            label: None,
+            message: None,
+            other_params: vec![],
+        }
+    }
+
+    /// The output paying this request, to be proved with the rest of the
+    /// transaction by `SaplingBundle::prove`. Fails with `MissingAmount` if
+    /// the request leaves the amount to the payer.
+    pub fn to_output(
+        &self,
+        ovk: &OutgoingViewKey,
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> Result<(UnprovenOutput, ValueCommitTrapdoor), Zip321Error> {
+        Ok(UnprovenOutput::new(
+            ovk,
+            &self.recipient,
+            self.amount.clone().ok_or(Zip321Error::MissingAmount)?,
+            self.memo.as_ref().unwrap_or(&Memo::Empty),
+            note_commitment_mode,
+        ))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Zip321Error {
+    InvalidScheme,
+    /// A parameter that is malformed, or whose index is not `1..=9999`
+    /// without leading zeros.
+    InvalidParam(String),
+    DuplicateParam(String),
+    /// A `req-` parameter, which a payer must not ignore.
+    UnsupportedRequiredParam(String),
+    InvalidAddress(usize),
+    InvalidAmount(usize),
+    InvalidMemo(usize),
+    MissingAddress(usize),
+    /// A payment that leaves its amount to the payer was to be paid.
+    MissingAmount,
+    NoPayments,
+}
+
+impl fmt::Display for Zip321Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zip321Error::InvalidScheme => write!(f, "not a zcash: URI"),
+            Zip321Error::InvalidParam(p) => write!(f, "invalid parameter {}", p),
+            Zip321Error::DuplicateParam(p) => write!(f, "duplicate parameter {}", p),
+            Zip321Error::UnsupportedRequiredParam(p) => {
+                write!(f, "unsupported required parameter {}", p)
+            }
+            Zip321Error::InvalidAddress(i) => write!(f, "invalid address in payment {}", i),
+            Zip321Error::InvalidAmount(i) => write!(f, "invalid amount in payment {}", i),
+            Zip321Error::InvalidMemo(i) => write!(f, "invalid memo in payment {}", i),
+            Zip321Error::MissingAddress(i) => write!(f, "payment {} has no address", i),
+            Zip321Error::MissingAmount => write!(f, "payment has no amount"),
+            Zip321Error::NoPayments => write!(f, "no payments"),
+        }
+    }
+}
+
+impl std::error::Error for Zip321Error {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransactionRequest {
+    pub payments: Vec<Payment>,
+}
+
+impl TransactionRequest {
+    /// Fails with `InvalidAmount(i)` if the amounts of `payments[..=i]` add
+    /// up to more than `MAX_MONEY`, which one transaction cannot pay.
+    pub fn new(payments: Vec<Payment>) -> Result<Self, Zip321Error> {
+        if payments.is_empty() {
+            return Err(Zip321Error::NoPayments);
+        }
+        if payments.len() > MAX_PAYMENTS {
+            return Err(Zip321Error::InvalidParam(format!("address.{}", MAX_PAYMENTS)));
+        }
+
+        let mut total = 0u64;
+        for (i, payment) in payments.iter().enumerate() {
+            let amount = payment.amount.as_ref().map_or(0, |amount| amount.0);
+            total = total
+                .checked_add(amount)
+                .filter(|&total| total <= MAX_MONEY)
+                .ok_or(Zip321Error::InvalidAmount(i))?;
+        }
+        Ok(TransactionRequest { payments })
+    }
+
+    /// The sum of the requested amounts, or `None` if it overflows.
+    pub fn total(&self) -> Option<u64> {
+        self.payments
+            .iter()
+            .filter_map(|payment| payment.amount.as_ref())
+            .try_fold(0u64, |total, amount| total.checked_add(amount.0))
+    }
+
+    /// Puts a single payment's address in the path and otherwise numbers the
+    /// payments from 0.
+    pub fn to_uri(&self, network: Network) -> String {
+        let mut params = vec![];
+        let mut path = String::new();
+        for (i, payment) in self.payments.iter().enumerate() {
+            let suffix = if i == 0 { String::new() } else { format!(".{}", i) };
+            let address = payment.recipient.encode(network);
+            if self.payments.len() == 1 {
+                path = address;
+            } else {
+                params.push(format!("address{}={}", suffix, address));
+            }
+
+            if let Some(amount) = &payment.amount {
+                params.push(format!("amount{}={}", suffix, format_amount(amount.0)));
+            }
+            if let Some(memo) = &payment.memo {
+                // The padding is implied, and restored by `parse_memo`.
+                let bytes = memo.to_bytes();
+                let len = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+                params.push(format!("memo{}={}", suffix, URL_SAFE_NO_PAD.encode(&bytes[..len])));
+            }
+            if let Some(label) = &payment.label {
+                params.push(format!("label{}={}", suffix, utf8_percent_encode(label, QCHAR_ENCODE)));
+            }
+            if let Some(message) = &payment.message {
+                params.push(format!("message{}={}", suffix, utf8_percent_encode(message, QCHAR_ENCODE)));
+            }
+            for (name, value) in &payment.other_params {
+                params.push(format!("{}{}={}", name, suffix, utf8_percent_encode(value, QCHAR_ENCODE)));
+            }
+        }
+
+        let mut uri = format!("{}{}", SCHEME, path);
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+
+    pub fn from_uri(uri: &str, network: Network) -> Result<Self, Zip321Error> {
+        if !uri.get(..SCHEME.len()).is_some_and(|scheme| scheme.eq_ignore_ascii_case(SCHEME)) {
+            return Err(Zip321Error::InvalidScheme);
+        }
+        let (path, query) = match uri[SCHEME.len()..].split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (&uri[SCHEME.len()..], None),
+        };
+
+        // Every parameter, by payment index and then by name.
+        let mut payments: BTreeMap<usize, BTreeMap<String, String>> = BTreeMap::new();
+        if !path.is_empty() {
+            payments.entry(0).or_default().insert("address".into(), path.into());
+        }
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            let (key, value) = param
+                .split_once('=')
+                .filter(|(key, _)| !key.is_empty())
+                .ok_or_else(|| Zip321Error::InvalidParam(param.into()))?;
+            let (name, index) = parse_key(key)?;
+            if payments.entry(index).or_default().insert(name.into(), value.into()).is_some() {
+                return Err(Zip321Error::DuplicateParam(key.into()));
+            }
+        }
+
+        let indices: Vec<usize> = payments.keys().copied().collect();
+        let payments = payments
+            .into_iter()
+            .map(|(index, params)| parse_payment(index, params, network))
+            .collect::<Result<Vec<_>, _>>()?;
+        // `new` numbers the payments by position, the URI by parameter index.
+        Self::new(payments).map_err(|error| match error {
+            Zip321Error::InvalidAmount(i) => Zip321Error::InvalidAmount(indices[i]),
+            error => error,
+        })
+    }
+}
+
+/// Splits `name.index` into its parts; a key without an index is payment 0.
+/// Names are a letter followed by letters, digits, `+` and `-`.
+fn parse_key(key: &str) -> Result<(&str, usize), Zip321Error> {
+    let invalid = || Zip321Error::InvalidParam(key.into());
+    let (name, index) = match key.split_once('.') {
+        Some((name, index)) => (name, Some(index)),
+        None => (key, None),
+    };
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'-');
+    if !valid_name {
+        return Err(invalid());
+    }
+
+    match index {
+        None => Ok((name, 0)),
+        Some(index) => {
+            let valid = (1..=4).contains(&index.len())
+                && index.bytes().all(|b| b.is_ascii_digit())
+                && !index.starts_with('0');
+            if !valid {
+                return Err(invalid());
+            }
+            Ok((name, index.parse().map_err(|_| invalid())?))
+        }
+    }
+}
+
+fn parse_payment(
+    index: usize,
+    mut params: BTreeMap<String, String>,
+    network: Network,
+) -> Result<Payment, Zip321Error> {
+    let decode = |value: String| {
+        percent_decode_str(&value)
+            .decode_utf8()
+            .map(|text| text.into_owned())
+            .map_err(|_| Zip321Error::InvalidParam(value.clone()))
+    };
+
+    let address = params.remove("address").ok_or(Zip321Error::MissingAddress(index))?;
+    let recipient = PaymentAddress::decode(&address, network)
+        .or_else(|| UnifiedAddress::decode(&address, network).ok()?.sapling())
+        .ok_or(Zip321Error::InvalidAddress(index))?;
+    let amount = params
+        .remove("amount")
+        .map(|amount| parse_amount(&amount).ok_or(Zip321Error::InvalidAmount(index)))
+        .transpose()?;
+    let memo = params
+        .remove("memo")
+        .map(|memo| parse_memo(&memo).ok_or(Zip321Error::InvalidMemo(index)))
+        .transpose()?;
+    let label = params.remove("label").map(decode).transpose()?;
+    let message = params.remove("message").map(decode).transpose()?;
+    // What is left is unknown to this crate, which may only skip it if it is
+    // not required.
+    let other_params = params
+        .into_iter()
+        .map(|(name, value)| {
+            if name.starts_with("req-") {
+                let key = if index == 0 { name } else { format!("{}.{}", name, index) };
+                return Err(Zip321Error::UnsupportedRequiredParam(key));
+            }
+            Ok((name, decode(value)?))
+        })
+        .collect::<Result<_, Zip321Error>>()?;
+
+    Ok(Payment {
+        recipient,
+        amount: amount.map(NoteValue),
+        memo,
+        label,
+        message,
+        other_params,
+    })
+}
+
+/// ZEC with at most 8 decimals, as zatoshis.
+fn parse_amount(amount: &str) -> Option<u64> {
+    let (coins, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if coins.is_empty() || !digits(coins) || !digits(fraction) || fraction.len() > 8 {
+        return None;
+    }
+    if amount.ends_with('.') {
+        return None;
+    }
+
+    let coins: u64 = coins.parse().ok()?;
+    let fraction: u64 = format!("{:0<8}", fraction).parse().ok()?;
+    let zatoshis = coins.checked_mul(COIN)?.checked_add(fraction)?;
+    (zatoshis <= MAX_MONEY).then_some(zatoshis)
+}
+
+fn format_amount(zatoshis: u64) -> String {
+    let (coins, fraction) = (zatoshis / COIN, zatoshis % COIN);
+    if fraction == 0 {
+        return coins.to_string();
+    }
+    format!("{}.{}", coins, format!("{:08}", fraction).trim_end_matches('0'))
+}
+
+fn parse_memo(memo: &str) -> Option<Memo> {
+    let bytes = URL_SAFE_NO_PAD.decode(memo).ok()?;
+    if bytes.len() > MEMO_SIZE {
+        return None;
+    }
+
+    let mut padded = [0u8; MEMO_SIZE];
+    padded[..bytes.len()].copy_from_slice(&bytes);
+    Memo::from_bytes(&padded).ok()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::keygen::KeyChain;
+    use crate::prover::MockProver;
+
+    /// An address of the key `[seed; 32]`, with a random diversifier.
+    fn address(seed: u8) -> PaymentAddress {
+        let sk: &[u8] = &[seed; 32];
+        PaymentAddress::from(KeyChain::from(sk))
+    }
+
+    #[test]
+    pub fn test_single_payment_uri() {
+        let recipient = address(1);
+        let mut payment = Payment::new(recipient.clone(), NoteValue(150_000_000));
+        payment.memo = Some(Memo::text("Invoice 42").unwrap());
+        payment.message = Some("Thanks & see you".into());
+        let request = TransactionRequest::new(vec![payment]).unwrap();
+
+        let uri = request.to_uri(Network::Main);
+        assert!(uri.starts_with(&format!("zcash:{}?amount=1.5&memo=", recipient.encode(Network::Main))));
+        assert!(uri.ends_with("&message=Thanks%20%26%20see%20you"));
+        let memo = URL_SAFE_NO_PAD.encode("Invoice 42");
+        assert!(uri.contains(&format!("&memo={}&", memo)));
+        assert_eq!(TransactionRequest::from_uri(&uri, Network::Main).unwrap(), request);
+        assert_eq!(
+            TransactionRequest::from_uri(&uri, Network::Test),
+            Err(Zip321Error::InvalidAddress(0))
+        );
+
+        let payer = KeyChain::from(&[2u8; 32][..]);
+        let (output, _) = request.payments[0].to_output(&payer.ovk, NoteCommitmentMode::Arkworks).unwrap();
+        let output = output.prove(&MockProver).unwrap();
+        let note = output.try_decrypt(&KeyChain::from(&[1u8; 32][..]).ivk.0, NoteCommitmentMode::Arkworks).unwrap();
+        assert_eq!(note.value, NoteValue(150_000_000));
+        assert_eq!(note.memo().unwrap(), Memo::text("Invoice 42").unwrap());
+        assert_eq!(output.try_recover(&payer.ovk, NoteCommitmentMode::Arkworks), Some(note));
+    }
+
+    #[test]
+    pub fn test_multiple_payments_uri() {
+        let recipient = address(2);
+        let uri = format!(
+            "zcash:?address={}&amount=0.0001&label.1=Caf%C3%A9&address.1={}&other=x&amount.1=20999999.9999",
+            address(1).encode(Network::Test),
+            recipient.encode(Network::Test),
+        );
+        let request = TransactionRequest::from_uri(&uri, Network::Test).unwrap();
+        assert_eq!(request.payments.len(), 2);
+        assert_eq!(request.payments[0].amount, Some(NoteValue(10_000)));
+        assert_eq!(request.payments[0].other_params, vec![("other".into(), "x".into())]);
+        assert_eq!(request.payments[1].recipient, recipient);
+        assert_eq!(request.payments[1].label.as_deref(), Some("Café"));
+        assert_eq!(request.total(), Some(MAX_MONEY));
+
+        let round_trip = TransactionRequest::from_uri(&request.to_uri(Network::Test), Network::Test);
+        assert_eq!(round_trip.unwrap(), request);
+
+        // A unified address pays its Sapling receiver.
+        let ua = UnifiedAddress::from(&recipient).encode(Network::Test);
+        let request = TransactionRequest::from_uri(&format!("zcash:{}?amount=1", ua), Network::Test).unwrap();
+        assert_eq!(request.payments[0].recipient, recipient);
+    }
+
+    #[test]
+    pub fn test_total_is_bounded() {
+        let payment = |zatoshis| Payment::new(address(1), NoteValue(zatoshis));
+        assert!(TransactionRequest::new(vec![payment(MAX_MONEY - 1), payment(1)]).is_ok());
+        assert_eq!(
+            TransactionRequest::new(vec![payment(1), payment(MAX_MONEY - 1), payment(1)]),
+            Err(Zip321Error::InvalidAmount(2))
+        );
+        assert_eq!(
+            TransactionRequest::new(vec![payment(u64::MAX), payment(u64::MAX)]),
+            Err(Zip321Error::InvalidAmount(0))
+        );
+
+        let mut payment = payment(1);
+        payment.amount = None;
+        let ovk = KeyChain::from(&[2u8; 32][..]).ovk;
+        assert_eq!(
+            payment.to_output(&ovk, NoteCommitmentMode::Arkworks).err(),
+            Some(Zip321Error::MissingAmount)
+        );
+    }
+
+    #[test]
+    pub fn test_invalid_uris() {
+        let a = address(1).encode(Network::Main);
+        let cases = [
+            (format!("bitcoin:{}", a), Zip321Error::InvalidScheme),
+            (format!("zcash:{}?amount=1.000000001", a), Zip321Error::InvalidAmount(0)),
+            (format!("zcash:{}?amount=21000000.00000001", a), Zip321Error::InvalidAmount(0)),
+            (format!("zcash:{}?amount=.5", a), Zip321Error::InvalidAmount(0)),
+            (format!("zcash:{}?amount=1&amount=2", a), Zip321Error::DuplicateParam("amount".into())),
+            (format!("zcash:{}?address={}", a, a), Zip321Error::DuplicateParam("address".into())),
+            (format!("zcash:{}?amount.01=1", a), Zip321Error::InvalidParam("amount.01".into())),
+            (format!("zcash:{}?req-fee=1", a), Zip321Error::UnsupportedRequiredParam("req-fee".into())),
+            (
+                format!("zcash:{}?address.1={}&req-fee.1=1", a, a),
+                Zip321Error::UnsupportedRequiredParam("req-fee.1".into()),
+            ),
+            (format!("zcash:{}?1abc=1", a), Zip321Error::InvalidParam("1abc".into())),
+            (format!("zcash:{}?a%62c=1", a), Zip321Error::InvalidParam("a%62c".into())),
+            (format!("zcash:{}?amount.1=1", a), Zip321Error::MissingAddress(1)),
+            (format!("zcash:{}?memo={}", a, "A".repeat(700)), Zip321Error::InvalidMemo(0)),
+            (
+                format!("zcash:?address={}&amount=0.0001&address.2={}&amount.2=21000000", a, a),
+                Zip321Error::InvalidAmount(2),
+            ),
+            ("zcash:".to_string(), Zip321Error::NoPayments),
+            ("zcašh".to_string(), Zip321Error::InvalidScheme),
+        ];
+        for (uri, error) in cases {
+            assert_eq!(TransactionRequest::from_uri(&uri, Network::Main), Err(error), "{}", uri);
+        }
+    }
+
+    #[test]
+    pub fn test_amounts() {
+        for (zatoshis, text) in [(0, "0"), (1, "0.00000001"), (123_450_000, "1.2345"), (MAX_MONEY, "21000000")] {
+            assert_eq!(format_amount(zatoshis), text);
+            assert_eq!(parse_amount(text), Some(zatoshis));
+        }
+        assert_eq!(parse_amount("1e3"), None);
+        assert_eq!(parse_amount("1."), None);
+    }
+}
@@ -31,7 +31,8 @@ pub mod test {
     use ark_ff::PrimeField;
     use ark_serialize::CanonicalSerialize;
     
-    const GENERATORS_X: [[u64; 4]; 6] = [
+    /// The x coordinates of Sapling's `PEDERSEN_HASH_GENERATORS`.
+    pub const GENERATORS_X: [[u64; 4]; 6] = [
         [
             0x194e_4292_6f66_1b51_u64,
             0x2f0c_718f_6f0f_badd_u64,
@@ -70,7 +71,8 @@ pub mod test {
         ],
     ];
     
-    const GENERATORS_Y: [[u64; 4]; 6] = [
+    /// The y coordinates of Sapling's `PEDERSEN_HASH_GENERATORS`.
+    pub const GENERATORS_Y: [[u64; 4]; 6] = [
         [
             0x77bf_abd4_3224_3cca,
             0xf947_2e8b_c04e_4632,
@@ -146,4 +148,18 @@ pub mod test {
         
         assert_eq!(ev, v[0]);
     }
+
+    #[test]
+    pub fn test_generators_match_sapling() {
+        let v = get_pedersen_generators();
+
+        for (i, (x, y)) in GENERATORS_X.iter().zip(GENERATORS_Y.iter()).enumerate() {
+            let expected = EdwardsAffine::new(
+                Fq::from_bigint(ark_ff::BigInt(*x)).unwrap(),
+                Fq::from_bigint(ark_ff::BigInt(*y)).unwrap(),
+            );
+
+            assert_eq!(EdwardsProjective::from(expected), v[i], "generator {}", i);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+use ark_ed_on_bls12_381::constraints::EdwardsVar;
+use ark_r1cs_std::groups::CurveVar;
+use ark_r1cs_std::prelude::Boolean;
+use ark_relations::r1cs::SynthesisError;
+
+use super::{generator_table, Personalization, CHUNKS_PER_GENERATOR, CHUNK_SIZE, NUM_GENERATORS};
+use crate::circuit::ConstraintF;
+use crate::commitment::NoteCommitmentParams;
+
+/// In-circuit `PedersenHashToPoint`. Each 3-bit chunk costs a single lookup
+/// in Montgomery form, as in the Sapling circuit.
+#[tracing::instrument(target = "r1cs", skip(bits))]
+pub fn pedersen_hash_to_point(
+    personalization: Personalization,
+    bits: &[Boolean<ConstraintF>],
+) -> Result<EdwardsVar, SynthesisError> {
+    let mut input: Vec<Boolean<ConstraintF>> = personalization
+        .get_bits()
+        .into_iter()
+        .map(Boolean::constant)
+        .collect();
+    input.extend_from_slice(bits);
+
+    assert!(input.len() <= CHUNK_SIZE * CHUNKS_PER_GENERATOR * NUM_GENERATORS);
+
+    while !input.len().is_multiple_of(CHUNK_SIZE) {
+        input.push(Boolean::constant(false));
+    }
+
+    let segments = input
+        .chunks(CHUNK_SIZE * CHUNKS_PER_GENERATOR)
+        .map(|segment| segment.chunks(CHUNK_SIZE).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    EdwardsVar::precomputed_base_3_bit_signed_digit_scalar_mul(generator_table(), &segments)
+}
+
+/// In-circuit `WindowedPedersenCommit_rcm(s)`; `rcm` is given as little-endian bits.
+#[tracing::instrument(target = "r1cs", skip(bits, rcm))]
+pub fn windowed_pedersen_commit(
+    personalization: Personalization,
+    bits: &[Boolean<ConstraintF>],
+    rcm: &[Boolean<ConstraintF>],
+) -> Result<EdwardsVar, SynthesisError> {
+    let hash = pedersen_hash_to_point(personalization, bits)?;
+
+    let mut blinding = EdwardsVar::zero();
+    blinding.precomputed_base_scalar_mul_le(
+        rcm.iter()
+            .zip(&NoteCommitmentParams::get().params.randomness_generator),
+    )?;
+
+    Ok(hash + blinding)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::pedersen_hash::{self, bytes_to_bits_le};
+    use ark_ec::CurveGroup;
+    use ark_ed_on_bls12_381::Fr;
+    use ark_ff::{BigInteger, PrimeField};
+    use ark_r1cs_std::{alloc::AllocVar, R1CSVar};
+    use ark_relations::r1cs::ConstraintSystem;
+
+    #[test]
+    pub fn test_windowed_pedersen_commit_gadget() {
+        let cs = ConstraintSystem::<ConstraintF>::new_ref();
+        let input: Vec<bool> = bytes_to_bits_le(b"sapling note commitment input");
+        let rcm = Fr::from(0x1234_5678_9abc_u64);
+
+        let input_var = Vec::<Boolean<ConstraintF>>::new_witness(cs.clone(), || Ok(input.clone()))
+            .unwrap();
+        let rcm_var = Vec::<Boolean<ConstraintF>>::new_witness(cs.clone(), || {
+            Ok(rcm.into_bigint().to_bits_le())
+        })
+        .unwrap();
+
+        let hash =
+            pedersen_hash_to_point(Personalization::MerkleTree(3), &input_var).unwrap();
+        let comm = windowed_pedersen_commit(Personalization::NoteCommitment, &input_var, &rcm_var)
+            .unwrap();
+
+        assert_eq!(
+            hash.value().unwrap().into_affine(),
+            pedersen_hash::pedersen_hash_to_point(Personalization::MerkleTree(3), input.clone())
+                .into_affine()
+        );
+        assert_eq!(
+            comm.value().unwrap().into_affine(),
+            pedersen_hash::windowed_pedersen_commit(Personalization::NoteCommitment, input, &rcm)
+        );
+        assert!(cs.is_satisfied().unwrap());
+    }
+}
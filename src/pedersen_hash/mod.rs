@@ -0,0 +1,170 @@
+use std::sync::OnceLock;
+
+use ark_ec::{CurveGroup, Group};
+use ark_ed_on_bls12_381::{EdwardsAffine, EdwardsProjective, Fq, Fr};
+use ark_ff::{Field, PrimeField};
+use ark_std::{One, Zero};
+
+use crate::commitment::NoteCommitmentParams;
+use crate::group_hash;
+
+pub mod constraints;
+#[cfg(test)]
+mod test_vectors;
+
+pub const CHUNK_SIZE: usize = 3;
+pub const CHUNKS_PER_GENERATOR: usize = 63;
+pub const NUM_GENERATORS: usize = 6;
+
+static GENERATOR_TABLE: OnceLock<Vec<Vec<EdwardsProjective>>> = OnceLock::new();
+
+/// The 6-bit domain separator prepended to every Sapling Pedersen hash input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Personalization {
+    NoteCommitment,
+    MerkleTree(usize),
+}
+
+impl Personalization {
+    pub fn get_bits(&self) -> Vec<bool> {
+        match *self {
+            Personalization::NoteCommitment => vec![true; 6],
+            Personalization::MerkleTree(layer) => {
+                assert!(layer < 63);
+                (0..6).map(|i| (layer >> i) & 1 == 1).collect()
+            }
+        }
+    }
+}
+
+/// `[2^(4i)] I_j` for every chunk `i` of every segment `j`, where `I_j` is the
+/// `j`-th `Zcash_PH` generator.
+pub fn generator_table() -> &'static [Vec<EdwardsProjective>] {
+    GENERATOR_TABLE.get_or_init(|| {
+        (0..NUM_GENERATORS)
+            .map(|j| {
+                let mut base: EdwardsProjective =
+                    group_hash::pedersen_generator(&(j as u32).to_le_bytes()).into();
+                let mut powers = Vec::with_capacity(CHUNKS_PER_GENERATOR);
+
+                for _ in 0..CHUNKS_PER_GENERATOR {
+                    powers.push(base);
+                    for _ in 0..4 {
+                        base.double_in_place();
+                    }
+                }
+
+                powers
+            })
+            .collect()
+    })
+}
+
+/// `PedersenHashToPoint` from §5.4.1.7 of the protocol specification: the input
+/// is split into 3-bit signed digits, 63 digits per generator.
+pub fn pedersen_hash_to_point<I>(personalization: Personalization, bits: I) -> EdwardsProjective
+where
+    I: IntoIterator<Item = bool>,
+{
+    let bits: Vec<bool> = personalization.get_bits().into_iter().chain(bits).collect();
+    assert!(
+        bits.len() <= CHUNK_SIZE * CHUNKS_PER_GENERATOR * NUM_GENERATORS,
+        "pedersen hash input of {} bits is too long",
+        bits.len()
+    );
+
+    let sixteen = Fr::from(16u8);
+
+    bits.chunks(CHUNK_SIZE * CHUNKS_PER_GENERATOR)
+        .zip(generator_table())
+        .map(|(segment, powers)| {
+            let mut acc = Fr::zero();
+            let mut cur = Fr::one();
+
+            for chunk in segment.chunks(CHUNK_SIZE) {
+                let bit = |i: usize| chunk.get(i).copied().unwrap_or(false);
+
+                let mut enc = cur;
+                if bit(0) {
+                    enc += cur;
+                }
+                if bit(1) {
+                    enc += cur.double();
+                }
+                if bit(2) {
+                    enc = -enc;
+                }
+
+                acc += enc;
+                cur *= sixteen;
+            }
+
+            powers[0].mul_bigint(acc.into_bigint())
+        })
+        .sum()
+}
+
+pub fn pedersen_hash<I>(personalization: Personalization, bits: I) -> Fq
+where
+    I: IntoIterator<Item = bool>,
+{
+    pedersen_hash_to_point(personalization, bits).into_affine().x
+}
+
+/// `WindowedPedersenCommit_rcm(s)`: the Pedersen hash of `s` blinded by
+/// `[rcm]` of the `Zcash_PH` randomness generator.
+pub fn windowed_pedersen_commit<I>(personalization: Personalization, bits: I, rcm: &Fr) -> EdwardsAffine
+where
+    I: IntoIterator<Item = bool>,
+{
+    let r = NoteCommitmentParams::get().params.randomness_generator[0];
+    (pedersen_hash_to_point(personalization, bits) + r.mul_bigint(rcm.into_bigint())).into_affine()
+}
+
+/// Little-endian bits of each byte, i.e. `LEOS2BSP`.
+pub fn bytes_to_bits_le(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|b| (0..8).map(move |i| (b >> i) & 1 == 1))
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use super::test_vectors::get_vectors;
+
+    pub fn fq_from_hex(hex: &str) -> Fq {
+        let hex = hex.trim_start_matches("0x");
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        Fq::from_be_bytes_mod_order(&bytes)
+    }
+
+    #[test]
+    pub fn test_pedersen_hash_vectors() {
+        for v in get_vectors() {
+            let bits: Vec<bool> = v.input_bits.chars().map(|c| c == '1').collect();
+            assert_eq!(v.personalization.get_bits(), &bits[..6]);
+
+            let p = pedersen_hash_to_point(v.personalization, bits.into_iter().skip(6))
+                .into_affine();
+
+            assert_eq!(p.x, fq_from_hex(v.hash_u));
+            assert_eq!(p.y, fq_from_hex(v.hash_v));
+        }
+    }
+
+    #[test]
+    pub fn test_generator_table() {
+        let table = generator_table();
+        assert_eq!(table.len(), NUM_GENERATORS);
+
+        for segment in table {
+            assert_eq!(segment.len(), CHUNKS_PER_GENERATOR);
+            assert_eq!(segment[1], segment[0].mul_bigint([16u64]));
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Test vectors from https://github.com/zcash-hackworks/zcash-test-vectors/blob/master/sapling_pedersen.py
+
+use super::Personalization;
+
+pub struct TestVector<'a> {
+    pub personalization: Personalization,
+    pub input_bits: &'a str,
+    pub hash_u: &'a str,
+    pub hash_v: &'a str,
+}
+
+pub fn get_vectors<'a>() -> Vec<TestVector<'a>> {
+    vec![
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: "111111",
+            hash_u: "0x06b1187c11ca4fb4383b2e0d0dbbde3ad3617338b5029187ec65a5eaed5e4d0b",
+            hash_v: "0x3ce70f536652f0dea496393a1e55c4e08b9d55508e16d11e5db40d4810cbc982",
+        },
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: "1111110",
+            hash_u: "0x2fc3bc454c337f71d4f04f86304262fcbfc9ecd808716b92fc42cbe6827f7f1a",
+            hash_v: "0x46d0d25bf1a654eedc6a9b1e5af398925113959feac31b7a2c036ff9b9ec0638",
+        },
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: "1111111",
+            hash_u: "0x4f8ce0e0a9e674b3ab9606a7d7aefba386e81583d81918127814cde41d209d97",
+            hash_v: "0x312b5ab93b14c9b9af334fe1fe3c50fffb53fbd074fa40ca600febde7c97e346",
+        },
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: "111111100",
+            hash_u: "0x4f8ce0e0a9e674b3ab9606a7d7aefba386e81583d81918127814cde41d209d97",
+            hash_v: "0x312b5ab93b14c9b9af334fe1fe3c50fffb53fbd074fa40ca600febde7c97e346",
+        },
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: concat!(
+                "11111110100011010001000100000011000001111100001110001110110011111001010100001011",
+                "01100111110100010101000011000000001110010110000110010010010000001011011111011110",
+                "110001100111100001011111000011",
+            ),
+            hash_u: "0x601247c7e640992d193dfb51df6ed93446687a7f2bcd0e4a598e6feb1ef20c40",
+            hash_v: "0x371931733b73e7b95c2cad55a6cebd15c83619f697c64283e54e5ef61442a743",
+        },
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: concat!(
+                "11111111001101011101011100100111010000000101010110000110111100111000011011001010",
+                "11011001001000100011101011001011000011110100101001010000100101100011110011100111",
+                "01011010011011000110011001011101011001111000010110110100100110101011101110101100",
+                "01010111010111110111110001101100100000101000100001000100011011101100101111101100",
+                "01111111100010101100100011100110100011010101110110010101100011111010101011110101",
+                "01111100001011011001100001100000011001001000110011001100010011010010010100010101",
+                "00011110101010001011100011111010101001010101011100100011111010101010000101101110",
+                "01011110110110001000000100010011011110100011001011011001011100010101101111100000",
+                "01010101101100010011111101010011010000110111010101000001010011001100010000111010",
+                "1100001111010111110010100001010101110",
+            ),
+            hash_u: "0x0666c2bce7f362a2b807d212e9a577f116891a932affd7addec39fbf372c494e",
+            hash_v: "0x6758bccfaf2e47c07756b96edea23aa8d10c33b38220bd1c411af612eeec18ab",
+        },
+        TestVector {
+            personalization: Personalization::NoteCommitment,
+            input_bits: concat!(
+                "11111101100110100110110011101101101000110011110110100101011100001010111001100000",
+                "01001010101100010001001101000110010101111110010111101100010011110010101000100000",
+                "00011101001001111101010111101100100100001110110101110000110010101001100010101010",
+                "00010110010001000100111001010000101011111011001100010000000010101100111110000000",
+                "11011000010000111000100100000110101000001100011010100110001010100010011111010110",
+                "10001001011110110101101100011101110000000110100010111101111001101101010111110010",
+                "10010101100100101000001101000010101100010101001001011011001110001011010101011000",
+                "10001100000000111000110010111110010000101001011011100110100011000101110110111000",
+                "00001111100000000010111000001110111011110000011101011001010000101111100110100001",
+                "11001110110100001011110001010110100101110011110001101111110000110100010010111000",
+                "00010110010011111000101110011011101011111100110110110111110000111000100111010001",
+                "001010001001111001000110111100001001111110111110000111011001110001",
+            ),
+            hash_u: "0x67914ebd539961b70f468fa23d4cb42133693a8ac57cd35a1e6369fe34fbedf7",
+            hash_v: "0x44770870c0f0cfe59a10df95d6c21e6f1514a2f464b66377599438c126052d9f",
+        },
+        TestVector {
+            personalization: Personalization::MerkleTree(0),
+            input_bits: "000000100",
+            hash_u: "0x1f1086b287636a20063c9614db2de66bb7d49242e88060956a5e5845057f6f5d",
+            hash_v: "0x6b1b395421dde74d53341caa9e01f39d7a3138efb9b57fc0381f98f4868df622",
+        },
+        TestVector {
+            personalization: Personalization::MerkleTree(34),
+            input_bits: "010001",
+            hash_u: "0x61f8e2cb8e945631677b450d5e5669bc6b5f2ec69b321ac550dbe74525d7ac9a",
+            hash_v: "0x4e11951ab9c9400ee38a18bd98cdb9453f1f67141ee9d9bf0c1c157d4fb34f9a",
+        },
+        TestVector {
+            personalization: Personalization::MerkleTree(27),
+            input_bits: concat!(
+                "11011011011011011011011011011011011011011011011011011011011011011011011011011011",
+                "01101101101101101101101101101101101101101101101101101101101101101101101101101101",
+                "10110110110110110110110110110",
+            ),
+            hash_u: "0x414f6ba05f6b92da1f9051950769e1083d05615def32b016ae424309828a11f4",
+            hash_v: "0x471d2109656afcb96d0609b371b132b97efcf72c6051064dd19fdc004799bfa9",
+        },
+    ]
+}
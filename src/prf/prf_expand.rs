@@ -1,15 +1,28 @@
+use crate::prf::poseidon_config::poseidon_parameters;
 use crate::signing_key::SigningKey;
 use blake2b_simd::Params;
-use ark_ed_on_bls12_381::Fr;
+use ark_crypto_primitives::crh::{poseidon::CRH, CRHScheme};
+use ark_ed_on_bls12_381::{Fq, Fr};
+use ark_ff::BigInteger;
 use blake2::digest::Digest;
 use blake2::Blake2s256;
 use ark_ff::PrimeField;
-use ark_crypto_primitives::prf::{
-    Blake2s, PRF
-};
 
 const EXPAND_SEED: &[u8] = b"Zcash_ExpandSeed";
 pub const IVK: &[u8] = b"Zcashivk";
+pub const NF: &[u8] = b"Zcash_nf";
+/// `ivk` is truncated to 251 bits so that it is always below the Jubjub scalar modulus.
+pub const IVK_BITS: usize = 251;
+
+/// How `CRH^ivk` is computed from `ak` and `nk`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IvkMode {
+    /// BLAKE2s-256 over `repr(ak) || repr(nk)`.
+    #[default]
+    Blake2s,
+    /// Poseidon over `ak.v` and `nk.v`, domain separated by `Zcashivk`.
+    Poseidon,
+}
 
 pub struct PRFExpand {}
 pub struct Crh {}
@@ -38,9 +51,14 @@ impl PRFExpand {
         Self::calc(signing_key, &[2u8])
     }
 
+    /// The ZIP-32 diversifier key of a master key.
+    pub fn calc_dk(signing_key: SigningKey) -> [u8; 32] {
+        Self::calc(signing_key, &[0x10])[..32].try_into().unwrap()
+    }
+
     pub fn calc_default_diversified(signing_key: SigningKey, i: u8) -> [u8; 11] {
         let mut t = [0u8; 11];
-        t.copy_from_slice(&Self::calc(signing_key, &[3, i]));
+        t.copy_from_slice(&Self::calc(signing_key, &[3, i])[..11]);
         t
     }
 }
@@ -50,7 +68,6 @@ impl Crh {
         let mut inp: Vec<u8> = vec![];
         inp.extend(ak);
         inp.extend(nk);
-        println!("hash input: {:?}", inp);
 
         let mut b2s = Blake2s256::new();
         b2s.update(&inp);
@@ -61,16 +78,26 @@ impl Crh {
         Fr::from_le_bytes_mod_order(&h)
     } 
 
-    pub fn find_nullifier(nk: &[u8], rho: &[u8]) -> [u8; 32] {
-        let mut inp = [0; 32];
-        let mut seed = [0; 32];
+    pub fn calc_poseidon(ak: &Fq, nk: &Fq) -> Fr {
+        let h = CRH::<Fq>::evaluate(
+            &poseidon_parameters(),
+            [Fq::from_le_bytes_mod_order(IVK), *ak, *nk],
+        )
+        .expect("hash failed");
 
-        inp.copy_from_slice(nk);
-        seed.copy_from_slice(rho);
+        let bits = h.into_bigint().to_bits_le();
+        Fr::from_bigint(BigInteger::from_bits_le(&bits[..IVK_BITS])).unwrap()
+    }
 
-        let h = Blake2s::evaluate(&inp, &seed).expect("failed");
-        println!("nullifier: {:?}", h);
+    /// `PRF^nf_nk(rho) = BLAKE2s-256("Zcash_nf", repr(nk) || repr(rho))`.
+    pub fn find_nullifier(nk: &[u8], rho: &[u8]) -> [u8; 32] {
+        let mut h = blake2s_simd::Params::new()
+            .hash_length(32)
+            .personal(NF)
+            .to_state();
 
-        h
+        h.update(nk);
+        h.update(rho);
+        *h.finalize().as_array()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,372 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use ark_bls12_381::Bls12_381;
+use ark_crypto_primitives::snark::SNARK;
+use ark_groth16::{prepare_verifying_key, Groth16, PreparedVerifyingKey};
+use ark_relations::r1cs::SynthesisError;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, SerializationError};
+use rand::{CryptoRng, RngCore};
+
+use crate::circuit::{Output, OutputPublicInputs, Spend, SpendPublicInputs};
+use crate::commitment::NoteCommitmentMode;
+use crate::merkle::{MerkleHash, PoseidonMerkleHash};
+use crate::prf::prf_expand::IvkMode;
+use crate::spend_description::NullifierMode;
+
+pub type Proof = ark_groth16::Proof<Bls12_381>;
+pub type ProvingKey = ark_groth16::ProvingKey<Bls12_381>;
+pub type VerifyingKey = ark_groth16::VerifyingKey<Bls12_381>;
+
+/// Proves `Spend` statements over a note commitment tree hashed with `H`.
+pub trait SpendProver<H: MerkleHash = PoseidonMerkleHash> {
+    fn prove_spend(&self, spend: Spend<'_, H>) -> Result<Proof, SynthesisError>;
+}
+
+pub trait OutputProver {
+    fn prove_output(&self, output: Output) -> Result<Proof, SynthesisError>;
+}
+
+/// Checks Spend and Output proofs against their public inputs. Callers are
+/// responsible for the consensus checks on the inputs themselves, such as
+/// rejecting small-order points.
+pub trait SaplingVerifier {
+    fn verify_spend(&self, public_inputs: &SpendPublicInputs, proof: &Proof) -> bool;
+
+    fn verify_output(&self, public_inputs: &OutputPublicInputs, proof: &Proof) -> bool;
+}
+
+const SPEND_PARAMS_MAGIC: [u8; 4] = *b"ZSPS";
+const OUTPUT_PARAMS_MAGIC: [u8; 4] = *b"ZSPO";
+const PARAMS_VERSION: u8 = 1;
+
+/// The circuit a parameter file was generated for, written before its key so
+/// that keys for other modes or another tree are refused rather than used to
+/// produce proofs that never verify.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParamsHeader {
+    pub note_commitment_mode: NoteCommitmentMode,
+    pub nullifier_mode: NullifierMode,
+    pub ivk_mode: IvkMode,
+    /// `H::DEPTH` of the note commitment tree.
+    pub depth: u32,
+}
+
+impl ParamsHeader {
+    fn write<W: Write>(&self, magic: [u8; 4], mut writer: W) -> Result<(), SerializationError> {
+        writer.write_all(&magic)?;
+        writer.write_all(&[PARAMS_VERSION])?;
+        self.note_commitment_mode.serialize_uncompressed(&mut writer)?;
+        self.nullifier_mode.serialize_uncompressed(&mut writer)?;
+        self.ivk_mode.serialize_uncompressed(&mut writer)?;
+        Ok(writer.write_all(&self.depth.to_le_bytes())?)
+    }
+
+    fn read<R: Read>(magic: [u8; 4], mut reader: R) -> Result<Self, ParamsError> {
+        let mut prefix = [0; 5];
+        reader.read_exact(&mut prefix)?;
+        if prefix[..4] != magic {
+            return Err(ParamsError::NotParams);
+        }
+        if prefix[4] != PARAMS_VERSION {
+            return Err(ParamsError::UnsupportedVersion(prefix[4]));
+        }
+
+        let note_commitment_mode = NoteCommitmentMode::deserialize_uncompressed(&mut reader)?;
+        let nullifier_mode = NullifierMode::deserialize_uncompressed(&mut reader)?;
+        let ivk_mode = IvkMode::deserialize_uncompressed(&mut reader)?;
+        let mut depth = [0; 4];
+        reader.read_exact(&mut depth)?;
+        Ok(ParamsHeader {
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode,
+            depth: u32::from_le_bytes(depth),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamsError {
+    Serialization(SerializationError),
+    /// The file does not start with the magic of the expected circuit.
+    NotParams,
+    UnsupportedVersion(u8),
+    /// The parameters were generated for other modes or another tree depth.
+    Mismatch { expected: ParamsHeader, found: ParamsHeader },
+}
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamsError::Serialization(e) => write!(f, "invalid parameters: {}", e),
+            ParamsError::NotParams => write!(f, "not a parameter file of the expected circuit"),
+            ParamsError::UnsupportedVersion(v) => write!(f, "unsupported parameter file version {}", v),
+            ParamsError::Mismatch { expected, found } => {
+                write!(f, "parameters generated for {:?}, expected {:?}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+impl From<SerializationError> for ParamsError {
+    fn from(e: SerializationError) -> Self {
+        ParamsError::Serialization(e)
+    }
+}
+
+impl From<std::io::Error> for ParamsError {
+    fn from(e: std::io::Error) -> Self {
+        ParamsError::Serialization(e.into())
+    }
+}
+
+/// Groth16 proving on this machine.
+pub struct LocalProver<H: MerkleHash = PoseidonMerkleHash> {
+    pub spend_params: ProvingKey,
+    pub output_params: ProvingKey,
+    /// The modes the parameters were generated for.
+    pub note_commitment_mode: NoteCommitmentMode,
+    pub nullifier_mode: NullifierMode,
+    pub ivk_mode: IvkMode,
+    merkle_hash: PhantomData<H>,
+}
+
+impl<H: MerkleHash> LocalProver<H> {
+    pub fn new(
+        spend_params: ProvingKey,
+        output_params: ProvingKey,
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+    ) -> Self {
+        LocalProver {
+            spend_params,
+            output_params,
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode,
+            merkle_hash: PhantomData,
+        }
+    }
+
+    /// Generates fresh, circuit-specific parameters for the given modes.
+    pub fn setup<R: RngCore + CryptoRng>(
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+        rng: &mut R,
+    ) -> Self {
+        let (spend_params, _) = Groth16::<Bls12_381>::circuit_specific_setup(
+            Spend::<H>::blank(note_commitment_mode, nullifier_mode, ivk_mode),
+            rng,
+        )
+        .expect("spend setup failed");
+        let (output_params, _) = Groth16::<Bls12_381>::circuit_specific_setup(
+            Output::blank(note_commitment_mode),
+            rng,
+        )
+        .expect("output setup failed");
+
+        Self::new(spend_params, output_params, note_commitment_mode, nullifier_mode, ivk_mode)
+    }
+
+    fn header(&self) -> ParamsHeader {
+        ParamsHeader {
+            note_commitment_mode: self.note_commitment_mode,
+            nullifier_mode: self.nullifier_mode,
+            ivk_mode: self.ivk_mode,
+            depth: H::DEPTH as u32,
+        }
+    }
+
+    /// Reads parameters written by `write_params`, refusing them unless they
+    /// were generated for the given modes and `H::DEPTH`. They are trusted
+    /// local files, so the points are not validated again.
+    pub fn read_params<R: Read>(
+        mut spend: R,
+        mut output: R,
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        ivk_mode: IvkMode,
+    ) -> Result<Self, ParamsError> {
+        let expected = ParamsHeader {
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode,
+            depth: H::DEPTH as u32,
+        };
+        for (magic, reader) in [(SPEND_PARAMS_MAGIC, &mut spend), (OUTPUT_PARAMS_MAGIC, &mut output)] {
+            let found = ParamsHeader::read(magic, reader)?;
+            if found != expected {
+                return Err(ParamsError::Mismatch { expected, found });
+            }
+        }
+
+        Ok(Self::new(
+            ProvingKey::deserialize_uncompressed_unchecked(spend)?,
+            ProvingKey::deserialize_uncompressed_unchecked(output)?,
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode,
+        ))
+    }
+
+    pub fn write_params<W: Write>(&self, mut spend: W, mut output: W) -> Result<(), SerializationError> {
+        self.header().write(SPEND_PARAMS_MAGIC, &mut spend)?;
+        self.spend_params.serialize_uncompressed(spend)?;
+        self.header().write(OUTPUT_PARAMS_MAGIC, &mut output)?;
+        self.output_params.serialize_uncompressed(output)
+    }
+
+    pub fn verifier(&self) -> LocalVerifier {
+        LocalVerifier::new(&self.spend_params.vk, &self.output_params.vk)
+    }
+}
+
+impl<H: MerkleHash> SpendProver<H> for LocalProver<H> {
+    fn prove_spend(&self, spend: Spend<'_, H>) -> Result<Proof, SynthesisError> {
+        Groth16::<Bls12_381>::prove(&self.spend_params, spend, &mut rand::thread_rng())
+    }
+}
+
+impl<H: MerkleHash> OutputProver for LocalProver<H> {
+    fn prove_output(&self, output: Output) -> Result<Proof, SynthesisError> {
+        Groth16::<Bls12_381>::prove(&self.output_params, output, &mut rand::thread_rng())
+    }
+}
+
+/// Groth16 verification with prepared verifying keys.
+pub struct LocalVerifier {
+    spend_vk: PreparedVerifyingKey<Bls12_381>,
+    output_vk: PreparedVerifyingKey<Bls12_381>,
+}
+
+impl LocalVerifier {
+    pub fn new(spend_vk: &VerifyingKey, output_vk: &VerifyingKey) -> Self {
+        LocalVerifier {
+            spend_vk: prepare_verifying_key(spend_vk),
+            output_vk: prepare_verifying_key(output_vk),
+        }
+    }
+}
+
+impl SaplingVerifier for LocalVerifier {
+    fn verify_spend(&self, public_inputs: &SpendPublicInputs, proof: &Proof) -> bool {
+        Groth16::<Bls12_381>::verify_with_processed_vk(
+            &self.spend_vk,
+            &public_inputs.to_field_elements(),
+            proof,
+        )
+        .unwrap_or(false)
+    }
+
+    fn verify_output(&self, public_inputs: &OutputPublicInputs, proof: &Proof) -> bool {
+        Groth16::<Bls12_381>::verify_with_processed_vk(
+            &self.output_vk,
+            &public_inputs.to_field_elements(),
+            proof,
+        )
+        .unwrap_or(false)
+    }
+}
+
+/// Returns the same dummy proof for every statement without synthesizing the
+/// circuit. Only `MockVerifier` accepts it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MockProver;
+
+impl<H: MerkleHash> SpendProver<H> for MockProver {
+    fn prove_spend(&self, _spend: Spend<'_, H>) -> Result<Proof, SynthesisError> {
+        Ok(Proof::default())
+    }
+}
+
+impl OutputProver for MockProver {
+    fn prove_output(&self, _output: Output) -> Result<Proof, SynthesisError> {
+        Ok(Proof::default())
+    }
+}
+
+/// Accepts exactly the proofs `MockProver` produces.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MockVerifier;
+
+impl SaplingVerifier for MockVerifier {
+    fn verify_spend(&self, _public_inputs: &SpendPublicInputs, proof: &Proof) -> bool {
+        *proof == Proof::default()
+    }
+
+    fn verify_output(&self, _public_inputs: &OutputPublicInputs, proof: &Proof) -> bool {
+        *proof == Proof::default()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::merkle::PedersenMerkleHash;
+
+    fn header_bytes(magic: [u8; 4], header: &ParamsHeader) -> Vec<u8> {
+        let mut bytes = vec![];
+        header.write(magic, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    pub fn test_params_header() {
+        let header = ParamsHeader {
+            note_commitment_mode: NoteCommitmentMode::Sapling,
+            nullifier_mode: NullifierMode::Sapling,
+            ivk_mode: IvkMode::Poseidon,
+            depth: 32,
+        };
+        let bytes = header_bytes(SPEND_PARAMS_MAGIC, &header);
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(ParamsHeader::read(SPEND_PARAMS_MAGIC, &bytes[..]).unwrap(), header);
+        assert!(matches!(
+            ParamsHeader::read(OUTPUT_PARAMS_MAGIC, &bytes[..]),
+            Err(ParamsError::NotParams)
+        ));
+
+        let mut future = bytes.clone();
+        future[4] = PARAMS_VERSION + 1;
+        assert!(matches!(
+            ParamsHeader::read(SPEND_PARAMS_MAGIC, &future[..]),
+            Err(ParamsError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_params_for_other_modes_are_refused() {
+        let header = ParamsHeader {
+            note_commitment_mode: NoteCommitmentMode::Arkworks,
+            nullifier_mode: NullifierMode::Poseidon,
+            ivk_mode: IvkMode::Blake2s,
+            depth: PedersenMerkleHash::DEPTH as u32,
+        };
+        let spend = header_bytes(SPEND_PARAMS_MAGIC, &header);
+        let output = header_bytes(OUTPUT_PARAMS_MAGIC, &header);
+
+        let result = LocalProver::<PedersenMerkleHash>::read_params(
+            &spend[..],
+            &output[..],
+            NoteCommitmentMode::Sapling,
+            NullifierMode::Sapling,
+            IvkMode::Blake2s,
+        );
+        assert!(matches!(result, Err(ParamsError::Mismatch { found, .. }) if found == header));
+
+        let result = LocalProver::<PoseidonMerkleHash>::read_params(
+            &spend[..],
+            &output[..],
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Poseidon,
+        );
+        assert!(matches!(result, Err(ParamsError::Mismatch { .. })));
+    }
+}
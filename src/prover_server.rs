@@ -0,0 +1,793 @@
+//! A proving worker for clients too weak to prove locally. Requests and
+//! responses are frames of a little-endian `u32` length followed by the body:
+//!
+//! - request: `id: u64`, then `0` and a `SpendWitness`, `1` and an
+//!   `OutputWitness`, or `2` and the `u64` id of a request to cancel;
+//! - response: `id: u64`, then `0` and a `Proof`, `1` and a UTF-8 error
+//!   message, or `2` once the request was cancelled.
+//!
+//! Integers are little-endian and witnesses and proofs are compressed
+//! `CanonicalSerialize` encodings. Every proving request gets exactly one
+//! response, in whatever order the proofs complete. A witness that does not
+//! satisfy its circuit is answered with an error rather than a proof.
+//!
+//! A connection queues at most `MAX_QUEUED_REQUESTS` requests that are not
+//! proving yet; past that, requests are answered with an error right away.
+//! Cancelling a queued request means it is never proved. Cancelling one that
+//! is already proving only drops its response: the proof runs to completion
+//! and keeps its slot until then.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem, SynthesisError};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+
+use crate::circuit::{ConstraintF, Output};
+use crate::commitment::NoteCommitmentMode;
+use crate::merkle::{MerkleHash, PoseidonMerkleHash};
+use crate::prf::prf_expand::IvkMode;
+use crate::prover::{OutputProver, Proof, SpendProver};
+use crate::spend_description::NullifierMode;
+use crate::thread_pool::ProvingPool;
+use crate::witness::{OutputWitness, SpendWitness};
+
+/// Largest frame accepted, comfortably above a depth-32 `SpendWitness`.
+pub const MAX_FRAME_LEN: usize = 1 << 16;
+
+/// Most requests a connection can have waiting for a worker.
+pub const MAX_QUEUED_REQUESTS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestBody {
+    Spend(SpendWitness),
+    Output(OutputWitness),
+    Cancel(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub id: u64,
+    pub body: RequestBody,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseBody {
+    Proof(Proof),
+    Error(String),
+    Cancelled,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub id: u64,
+    pub body: ResponseBody,
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn write_frame<W: Write>(mut writer: W, body: &[u8]) -> io::Result<()> {
+    if body.len() > MAX_FRAME_LEN {
+        return Err(invalid_data("frame too long"));
+    }
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend((body.len() as u32).to_le_bytes());
+    frame.extend(body);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads one frame, or `None` if the stream ends before it starts.
+fn read_frame<R: Read>(mut reader: R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data("frame too long"));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Splits the `u64` id and the tag off the front of a frame.
+fn split_header(body: &[u8]) -> io::Result<(u64, u8, &[u8])> {
+    if body.len() < 9 {
+        return Err(invalid_data("frame too short"));
+    }
+
+    let id = u64::from_le_bytes(body[..8].try_into().unwrap());
+    Ok((id, body[8], &body[9..]))
+}
+
+fn deserialize_all<T: CanonicalDeserialize>(mut bytes: &[u8]) -> io::Result<T> {
+    let t = T::deserialize_compressed(&mut bytes).map_err(invalid_data)?;
+    if !bytes.is_empty() {
+        return Err(invalid_data("trailing bytes"));
+    }
+    Ok(t)
+}
+
+impl Request {
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut body = self.id.to_le_bytes().to_vec();
+        match &self.body {
+            RequestBody::Spend(witness) => {
+                body.push(0);
+                witness.serialize_compressed(&mut body).map_err(invalid_data)?;
+            }
+            RequestBody::Output(witness) => {
+                body.push(1);
+                witness.serialize_compressed(&mut body).map_err(invalid_data)?;
+            }
+            RequestBody::Cancel(id) => {
+                body.push(2);
+                body.extend(id.to_le_bytes());
+            }
+        }
+        write_frame(writer, &body)
+    }
+
+    /// Reads the next request, or `None` at the end of the stream.
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Option<Self>> {
+        let Some(frame) = read_frame(reader)? else {
+            return Ok(None);
+        };
+        let (id, tag, payload) = split_header(&frame)?;
+
+        let body = match tag {
+            0 => RequestBody::Spend(deserialize_all(payload)?),
+            1 => RequestBody::Output(deserialize_all(payload)?),
+            2 => RequestBody::Cancel(deserialize_all(payload)?),
+            _ => return Err(invalid_data("unknown request")),
+        };
+        Ok(Some(Request { id, body }))
+    }
+}
+
+impl Response {
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut body = self.id.to_le_bytes().to_vec();
+        match &self.body {
+            ResponseBody::Proof(proof) => {
+                body.push(0);
+                proof.serialize_compressed(&mut body).map_err(invalid_data)?;
+            }
+            ResponseBody::Error(message) => {
+                body.push(1);
+                body.extend(message.as_bytes());
+            }
+            ResponseBody::Cancelled => body.push(2),
+        }
+        write_frame(writer, &body)
+    }
+
+    /// Reads the next response, or `None` at the end of the stream.
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Option<Self>> {
+        let Some(frame) = read_frame(reader)? else {
+            return Ok(None);
+        };
+        let (id, tag, payload) = split_header(&frame)?;
+
+        let body = match tag {
+            0 => ResponseBody::Proof(deserialize_all(payload)?),
+            1 => ResponseBody::Error(String::from_utf8(payload.to_vec()).map_err(invalid_data)?),
+            2 if payload.is_empty() => ResponseBody::Cancelled,
+            _ => return Err(invalid_data("unknown response")),
+        };
+        Ok(Some(Response { id, body }))
+    }
+}
+
+/// The circuit the server's parameters were generated for, and how many
+/// proofs it computes at once across all connections.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerConfig {
+    pub note_commitment_mode: NoteCommitmentMode,
+    pub nullifier_mode: NullifierMode,
+    pub ivk_mode: IvkMode,
+    pub max_jobs: usize,
+}
+
+/// Counts the proofs in progress.
+struct Slots {
+    in_use: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A slot taken in `Slots`, given back when dropped.
+struct Slot<'a>(&'a Slots);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        // Not `unwrap`: this may run while a panic unwinds.
+        let mut in_use = self.0.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        *in_use -= 1;
+        self.0.freed.notify_all();
+    }
+}
+
+/// Whether `circuit` is satisfied by its witness. Groth16 gives no error for
+/// a witness that is not: it proves a statement that does not verify, or
+/// panics in debug builds.
+fn is_satisfied(circuit: impl ConstraintSynthesizer<ConstraintF>) -> Result<bool, SynthesisError> {
+    let cs = ConstraintSystem::new_ref();
+    circuit.generate_constraints(cs.clone())?;
+    cs.is_satisfied()
+}
+
+pub struct ProverServer<P, H: MerkleHash = PoseidonMerkleHash> {
+    prover: P,
+    config: ServerConfig,
+    pool: ProvingPool,
+    slots: Slots,
+    merkle_hash: PhantomData<H>,
+}
+
+impl<P, H> ProverServer<P, H>
+where
+    P: SpendProver<H> + OutputProver + Sync,
+    H: MerkleHash + Sync,
+{
+    pub fn new(prover: P, config: ServerConfig, pool: ProvingPool) -> Self {
+        assert!(config.max_jobs > 0);
+
+        ProverServer {
+            prover,
+            config,
+            pool,
+            slots: Slots {
+                in_use: Mutex::new(0),
+                freed: Condvar::new(),
+            },
+            merkle_hash: PhantomData,
+        }
+    }
+
+    /// Answers the requests read from `reader` on `writer` until the reader is
+    /// exhausted, then waits for the proofs still in progress. The requests
+    /// are proved by `max_jobs` workers fed from a queue of
+    /// `MAX_QUEUED_REQUESTS`.
+    pub fn serve<R: Read, W: Write + Send>(&self, mut reader: R, writer: W) -> io::Result<()> {
+        let writer = Mutex::new(writer);
+        let jobs: Mutex<HashMap<u64, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+        let respond = |response: Response| {
+            // The client may already be gone; there is nobody to tell.
+            let _ = response.write_to(&mut *writer.lock().unwrap());
+        };
+        let (queue, queued) = sync_channel::<(Request, Arc<AtomicBool>)>(MAX_QUEUED_REQUESTS);
+        let queued = Mutex::new(queued);
+
+        thread::scope(|s| {
+            for _ in 0..self.config.max_jobs {
+                let (jobs, respond, queued) = (&jobs, &respond, &queued);
+                s.spawn(move || loop {
+                    // The queue closes once the reader is exhausted.
+                    let Ok((request, cancelled)) = queued.lock().unwrap().recv() else {
+                        return;
+                    };
+                    let Some(body) = self.run(request.body, &cancelled) else {
+                        continue;
+                    };
+
+                    // Responding under the lock orders this against a cancel.
+                    let mut in_flight = jobs.lock().unwrap();
+                    if !cancelled.load(Ordering::SeqCst) {
+                        in_flight.remove(&request.id);
+                        respond(Response {
+                            id: request.id,
+                            body,
+                        });
+                    }
+                });
+            }
+
+            self.read_requests(&mut reader, queue, &jobs, &respond)
+        })
+    }
+
+    /// Queues the requests read from `reader` and handles cancellations.
+    /// Dropping `queue` on return lets the workers finish.
+    fn read_requests<R: Read>(
+        &self,
+        mut reader: R,
+        queue: SyncSender<(Request, Arc<AtomicBool>)>,
+        jobs: &Mutex<HashMap<u64, Arc<AtomicBool>>>,
+        respond: &impl Fn(Response),
+    ) -> io::Result<()> {
+        while let Some(request) = Request::read_from(&mut reader)? {
+            let mut in_flight = jobs.lock().unwrap();
+
+            if let RequestBody::Cancel(target) = request.body {
+                if let Some(cancelled) = in_flight.remove(&target) {
+                    cancelled.store(true, Ordering::SeqCst);
+                    // Wakes the job if it is waiting for a slot.
+                    let _slots = self.slots.in_use.lock().unwrap();
+                    self.slots.freed.notify_all();
+                    respond(Response {
+                        id: target,
+                        body: ResponseBody::Cancelled,
+                    });
+                }
+                continue;
+            }
+
+            if in_flight.contains_key(&request.id) {
+                respond(Response {
+                    id: request.id,
+                    body: ResponseBody::Error("duplicate request id".to_string()),
+                });
+                continue;
+            }
+
+            let (id, cancelled) = (request.id, Arc::new(AtomicBool::new(false)));
+            match queue.try_send((request, cancelled.clone())) {
+                Ok(()) => {
+                    in_flight.insert(id, cancelled);
+                }
+                Err(TrySendError::Full(_)) => respond(Response {
+                    id,
+                    body: ResponseBody::Error("too many queued requests".to_string()),
+                }),
+                Err(TrySendError::Disconnected(_)) => unreachable!("the workers outlive the queue"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits for a free slot and proves `body`, unless it is cancelled first.
+    fn run(&self, body: RequestBody, cancelled: &AtomicBool) -> Option<ResponseBody> {
+        let _slot = {
+            let mut in_use = self.slots.in_use.lock().unwrap();
+            while *in_use >= self.config.max_jobs && !cancelled.load(Ordering::SeqCst) {
+                in_use = self.slots.freed.wait(in_use).unwrap();
+            }
+            if cancelled.load(Ordering::SeqCst) {
+                return None;
+            }
+            *in_use += 1;
+            Slot(&self.slots)
+        };
+
+        // A prover that panics fails this request only; the slot is freed as
+        // the panic unwinds.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.pool.install(|| self.prove(&body))))
+            .unwrap_or_else(|_| Err("the prover panicked".to_string()));
+
+        Some(match result {
+            Ok(proof) => ResponseBody::Proof(proof),
+            Err(message) => ResponseBody::Error(message),
+        })
+    }
+
+    fn prove(&self, body: &RequestBody) -> Result<Proof, String> {
+        let config = &self.config;
+
+        match body {
+            RequestBody::Spend(witness) => {
+                if witness.note_commitment_mode != config.note_commitment_mode
+                    || witness.nullifier_mode != config.nullifier_mode
+                    || witness.ivk_mode != config.ivk_mode
+                {
+                    return Err("witness modes do not match the parameters".to_string());
+                }
+                if witness.auth_path.len() != H::DEPTH {
+                    return Err(format!("expected a Merkle path of depth {}", H::DEPTH));
+                }
+
+                if !witness.with_circuit::<H, _>(|spend| is_satisfied(spend)).map_err(|e| e.to_string())? {
+                    return Err("the witness does not satisfy the Spend circuit".to_string());
+                }
+                witness
+                    .with_circuit::<H, _>(|spend| self.prover.prove_spend(spend))
+                    .map_err(|e| e.to_string())
+            }
+            RequestBody::Output(witness) => {
+                if witness.note_commitment_mode != config.note_commitment_mode {
+                    return Err("witness modes do not match the parameters".to_string());
+                }
+
+                if !is_satisfied(Output::from(witness)).map_err(|e| e.to_string())? {
+                    return Err("the witness does not satisfy the Output circuit".to_string());
+                }
+                self.prover
+                    .prove_output(Output::from(witness))
+                    .map_err(|e| e.to_string())
+            }
+            RequestBody::Cancel(_) => unreachable!("cancellations are not proved"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::prover::MockProver;
+    use crate::fixtures::{output_witness, spend_witness};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    fn config(max_jobs: usize) -> ServerConfig {
+        ServerConfig {
+            note_commitment_mode: NoteCommitmentMode::Arkworks,
+            nullifier_mode: NullifierMode::Poseidon,
+            ivk_mode: IvkMode::Blake2s,
+            max_jobs,
+        }
+    }
+
+    fn encode(requests: &[Request]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for request in requests {
+            request.write_to(&mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Vec<Response> {
+        let mut responses = vec![];
+        while let Some(response) = Response::read_from(&mut bytes).unwrap() {
+            responses.push(response);
+        }
+        responses
+    }
+
+    #[test]
+    pub fn test_protocol_round_trip() {
+        let requests = vec![
+            Request {
+                id: 1,
+                body: RequestBody::Spend(spend_witness::<PoseidonMerkleHash>(
+                    NoteCommitmentMode::Sapling,
+                    NullifierMode::Sapling,
+                    IvkMode::Blake2s,
+                )),
+            },
+            Request {
+                id: 2,
+                body: RequestBody::Output(output_witness(NoteCommitmentMode::Arkworks)),
+            },
+            Request {
+                id: 3,
+                body: RequestBody::Cancel(1),
+            },
+        ];
+        let bytes = encode(&requests);
+
+        let mut reader = &bytes[..];
+        for request in &requests {
+            assert_eq!(Request::read_from(&mut reader).unwrap().as_ref(), Some(request));
+        }
+        assert!(Request::read_from(&mut reader).unwrap().is_none());
+        assert!(Request::read_from(&bytes[..bytes.len() - 1]).is_ok());
+        assert!(Request::read_from(&bytes[..10]).is_err());
+
+        let mut unknown = vec![];
+        write_frame(&mut unknown, &[7, 0, 0, 0, 0, 0, 0, 0, 3]).unwrap();
+        assert!(Request::read_from(&unknown[..]).is_err());
+
+        let responses = vec![
+            Response {
+                id: 1,
+                body: ResponseBody::Proof(Proof::default()),
+            },
+            Response {
+                id: 2,
+                body: ResponseBody::Error("no".to_string()),
+            },
+            Response {
+                id: 3,
+                body: ResponseBody::Cancelled,
+            },
+        ];
+        let mut bytes = vec![];
+        for response in &responses {
+            response.write_to(&mut bytes).unwrap();
+        }
+        assert_eq!(decode(&bytes), responses);
+    }
+
+    #[test]
+    pub fn test_serve() {
+        let server = ProverServer::<_, PoseidonMerkleHash>::new(
+            MockProver,
+            config(2),
+            ProvingPool::new(1),
+        );
+        let spend = spend_witness::<PoseidonMerkleHash>(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+        );
+        let mut wrong_modes = spend.clone();
+        wrong_modes.nullifier_mode = NullifierMode::Sapling;
+
+        let input = encode(&[
+            Request {
+                id: 1,
+                body: RequestBody::Spend(spend),
+            },
+            Request {
+                id: 2,
+                body: RequestBody::Output(output_witness(NoteCommitmentMode::Arkworks)),
+            },
+            Request {
+                id: 3,
+                body: RequestBody::Spend(wrong_modes),
+            },
+        ]);
+        let mut output = vec![];
+        server.serve(&input[..], &mut output).unwrap();
+
+        let mut responses = decode(&output);
+        responses.sort_by_key(|r| r.id);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].body, ResponseBody::Proof(Proof::default()));
+        assert_eq!(responses[1].body, ResponseBody::Proof(Proof::default()));
+        assert!(matches!(responses[2].body, ResponseBody::Error(_)));
+    }
+
+    #[test]
+    pub fn test_unsatisfied_witness_is_refused() {
+        let server = ProverServer::<_, PoseidonMerkleHash>::new(MockProver, config(1), ProvingPool::new(1));
+        let mut spend = spend_witness::<PoseidonMerkleHash>(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+        );
+        spend.public_inputs.anchor += ConstraintF::from(1u64);
+        let mut output = output_witness(NoteCommitmentMode::Arkworks);
+        output.public_inputs.cm = output.public_inputs.epk;
+
+        let input = encode(&[
+            Request {
+                id: 1,
+                body: RequestBody::Spend(spend),
+            },
+            Request {
+                id: 2,
+                body: RequestBody::Output(output),
+            },
+        ]);
+        let mut responses = vec![];
+        server.serve(&input[..], &mut responses).unwrap();
+
+        let responses = decode(&responses);
+        assert_eq!(responses.len(), 2);
+        assert!(responses.iter().all(|r| matches!(&r.body, ResponseBody::Error(e) if e.contains("satisfy"))));
+    }
+
+    /// Panics on every proof.
+    struct PanickingProver;
+
+    impl SpendProver<PoseidonMerkleHash> for PanickingProver {
+        fn prove_spend(
+            &self,
+            _spend: crate::circuit::Spend<'_, PoseidonMerkleHash>,
+        ) -> Result<Proof, SynthesisError> {
+            panic!("prover failure")
+        }
+    }
+
+    impl OutputProver for PanickingProver {
+        fn prove_output(&self, _output: Output) -> Result<Proof, SynthesisError> {
+            panic!("prover failure")
+        }
+    }
+
+    #[test]
+    pub fn test_prover_panic_frees_its_slot() {
+        let server = ProverServer::<_, PoseidonMerkleHash>::new(PanickingProver, config(1), ProvingPool::new(1));
+        let output = output_witness(NoteCommitmentMode::Arkworks);
+        let input = encode(&[
+            Request {
+                id: 1,
+                body: RequestBody::Output(output.clone()),
+            },
+            Request {
+                id: 2,
+                body: RequestBody::Output(output),
+            },
+        ]);
+        let mut responses = vec![];
+        server.serve(&input[..], &mut responses).unwrap();
+
+        let responses = decode(&responses);
+        assert_eq!(responses.len(), 2);
+        assert!(responses.iter().all(|r| matches!(r.body, ResponseBody::Error(_))));
+    }
+
+    /// Proves only once the test lets it, and says when it starts.
+    struct GatedProver {
+        gate: Mutex<Receiver<()>>,
+        started: Mutex<Sender<()>>,
+    }
+
+    impl GatedProver {
+        /// The prover, the sender that lets a proof complete and the receiver
+        /// told of every proof started.
+        fn new() -> (Self, Sender<()>, Receiver<()>) {
+            let (release, gate) = channel();
+            let (started, starts) = channel();
+            let prover = GatedProver {
+                gate: Mutex::new(gate),
+                started: Mutex::new(started),
+            };
+            (prover, release, starts)
+        }
+
+        fn prove(&self) -> Result<Proof, SynthesisError> {
+            // The test may not be listening.
+            let _ = self.started.lock().unwrap().send(());
+            self.gate.lock().unwrap().recv().unwrap();
+            Ok(Proof::default())
+        }
+    }
+
+    impl SpendProver<PoseidonMerkleHash> for GatedProver {
+        fn prove_spend(
+            &self,
+            _spend: crate::circuit::Spend<'_, PoseidonMerkleHash>,
+        ) -> Result<Proof, SynthesisError> {
+            self.prove()
+        }
+    }
+
+    impl OutputProver for GatedProver {
+        fn prove_output(&self, _output: Output) -> Result<Proof, SynthesisError> {
+            self.prove()
+        }
+    }
+
+    /// One end of an in-memory pipe.
+    struct Pipe(Receiver<Vec<u8>>, Vec<u8>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.1.is_empty() {
+                match self.0.recv() {
+                    Ok(bytes) => self.1 = bytes,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.1.len());
+            buf[..n].copy_from_slice(&self.1[..n]);
+            self.1.drain(..n);
+            Ok(n)
+        }
+    }
+
+    struct PipeWriter(Sender<Vec<u8>>);
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf.to_vec()).map_err(|_| io::ErrorKind::BrokenPipe)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_cancel_queued_request() {
+        let (prover, release, _) = GatedProver::new();
+        let server = ProverServer::<_, PoseidonMerkleHash>::new(prover, config(1), ProvingPool::new(1));
+
+        let (to_server, server_in) = channel();
+        let (server_out, from_server) = channel();
+        let mut responses = Pipe(from_server, vec![]);
+        let output = output_witness(NoteCommitmentMode::Arkworks);
+
+        thread::scope(|s| {
+            let serving = s.spawn(|| server.serve(Pipe(server_in, vec![]), PipeWriter(server_out)));
+
+            // Whichever request gets the slot first blocks the other, which is
+            // cancelled whether it is still waiting or already proving.
+            to_server
+                .send(encode(&[
+                    Request {
+                        id: 1,
+                        body: RequestBody::Output(output.clone()),
+                    },
+                    Request {
+                        id: 2,
+                        body: RequestBody::Output(output),
+                    },
+                    Request {
+                        id: 7,
+                        body: RequestBody::Cancel(2),
+                    },
+                    Request {
+                        id: 8,
+                        body: RequestBody::Cancel(99),
+                    },
+                ]))
+                .unwrap();
+
+            assert_eq!(
+                Response::read_from(&mut responses).unwrap(),
+                Some(Response {
+                    id: 2,
+                    body: ResponseBody::Cancelled
+                })
+            );
+
+            release.send(()).unwrap();
+            release.send(()).unwrap();
+            assert_eq!(
+                Response::read_from(&mut responses).unwrap(),
+                Some(Response {
+                    id: 1,
+                    body: ResponseBody::Proof(Proof::default())
+                })
+            );
+
+            drop(to_server);
+            serving.join().unwrap().unwrap();
+        });
+
+        assert!(Response::read_from(&mut responses).unwrap().is_none());
+    }
+
+    #[test]
+    pub fn test_queue_is_bounded() {
+        let (prover, release, starts) = GatedProver::new();
+        let server = ProverServer::<_, PoseidonMerkleHash>::new(prover, config(1), ProvingPool::new(1));
+
+        let (to_server, server_in) = channel();
+        let (server_out, from_server) = channel();
+        let mut responses = Pipe(from_server, vec![]);
+        let request = |id| Request {
+            id,
+            body: RequestBody::Output(output_witness(NoteCommitmentMode::Arkworks)),
+        };
+
+        thread::scope(|s| {
+            let serving = s.spawn(|| server.serve(Pipe(server_in, vec![]), PipeWriter(server_out)));
+
+            // The only worker is busy with the first request, so the next
+            // `MAX_QUEUED_REQUESTS` wait and the one after is refused.
+            to_server.send(encode(&[request(0)])).unwrap();
+            starts.recv().unwrap();
+            let queued: Vec<_> = (1..=MAX_QUEUED_REQUESTS as u64 + 1).map(request).collect();
+            to_server.send(encode(&queued)).unwrap();
+
+            let refused = Response::read_from(&mut responses).unwrap().unwrap();
+            assert_eq!(refused.id, MAX_QUEUED_REQUESTS as u64 + 1);
+            assert!(matches!(refused.body, ResponseBody::Error(_)));
+
+            for _ in 0..=MAX_QUEUED_REQUESTS {
+                release.send(()).unwrap();
+            }
+            drop(to_server);
+            serving.join().unwrap().unwrap();
+        });
+
+        let mut ids: Vec<_> = std::iter::from_fn(|| Response::read_from(&mut responses).unwrap())
+            .map(|response| {
+                assert_eq!(response.body, ResponseBody::Proof(Proof::default()));
+                response.id
+            })
+            .collect();
+        ids.sort();
+        assert_eq!(ids, (0..=MAX_QUEUED_REQUESTS as u64).collect::<Vec<_>>());
+    }
+}
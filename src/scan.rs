@@ -0,0 +1,527 @@
+//! Compact blocks and the scanner that finds a wallet's notes in them.
+//!
+//! A compact block keeps, of each transaction, only what a wallet needs to
+//! detect its payments and spends: the nullifiers and, of each output,
+//! `cmu`, `epk` and the first `COMPACT_NOTE_SIZE` bytes of the ciphertext.
+//! Memos are not included; fetch the full transaction for those.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use ark_ed_on_bls12_381::EdwardsAffine;
+
+use crate::commitment::NoteCommitmentMode;
+use crate::group_hash::{abst_j, repr_j};
+use crate::keygen::IncomingViewingKey;
+use crate::merkle::{CommitmentTree, IncrementalWitness, MerkleHash};
+use crate::note::{BlockHeight, MerklePath, NoteStatus, SpendingNotes};
+use crate::batch_decryption::batch_try_compact_note_decryption;
+use crate::note_encryption::{NotePlaintext, COMPACT_NOTE_SIZE};
+use crate::output_description::OutputDescription;
+use crate::spend_description::Nullifier;
+use crate::transaction::{
+    invalid_data, read_compact_size, read_u32, write_compact_size, Transaction, TxId,
+};
+
+pub type AccountId = u32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactOutput {
+    pub cmu: EdwardsAffine,
+    pub epk: EdwardsAffine,
+    pub ciphertext: [u8; COMPACT_NOTE_SIZE],
+}
+
+impl From<&OutputDescription> for CompactOutput {
+    fn from(output: &OutputDescription) -> Self {
+        CompactOutput {
+            cmu: *output.cmu(),
+            epk: *output.epk(),
+            ciphertext: output.enc_ciphertext()[..COMPACT_NOTE_SIZE].try_into().unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactTx {
+    pub txid: TxId,
+    pub nullifiers: Vec<Nullifier>,
+    pub outputs: Vec<CompactOutput>,
+}
+
+impl From<&Transaction> for CompactTx {
+    fn from(tx: &Transaction) -> Self {
+        let bundle = &tx.sapling_bundle;
+        CompactTx {
+            txid: tx.txid(),
+            nullifiers: bundle.spends.iter().map(|spend| spend.nullifier().clone()).collect(),
+            outputs: bundle.outputs.iter().map(CompactOutput::from).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactBlock {
+    pub height: BlockHeight,
+    pub hash: [u8; 32],
+    pub prev_hash: [u8; 32],
+    pub vtx: Vec<CompactTx>,
+}
+
+impl CompactBlock {
+    /// `height`, `hash` and `prev_hash`, then each transaction: its txid, its
+    /// nullifiers and its outputs, each list prefixed by a `CompactSize`.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.height.to_le_bytes())?;
+        writer.write_all(&self.hash)?;
+        writer.write_all(&self.prev_hash)?;
+        write_compact_size(&mut writer, self.vtx.len())?;
+        for tx in &self.vtx {
+            writer.write_all(&tx.txid.0)?;
+            write_compact_size(&mut writer, tx.nullifiers.len())?;
+            for nf in &tx.nullifiers {
+                writer.write_all(&nf.0)?;
+            }
+            write_compact_size(&mut writer, tx.outputs.len())?;
+            for output in &tx.outputs {
+                writer.write_all(&repr_j(&output.cmu))?;
+                writer.write_all(&repr_j(&output.epk))?;
+                writer.write_all(&output.ciphertext)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let height = read_u32(&mut reader)?;
+        let hash = read_array(&mut reader)?;
+        let prev_hash = read_array(&mut reader)?;
+        let vtx = (0..read_compact_size(&mut reader)?)
+            .map(|_| {
+                let txid = TxId(read_array(&mut reader)?);
+                let nullifiers = (0..read_compact_size(&mut reader)?)
+                    .map(|_| Ok(Nullifier(read_array(&mut reader)?)))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let outputs = (0..read_compact_size(&mut reader)?)
+                    .map(|_| {
+                        let point = |bytes: [u8; 32]| {
+                            abst_j(&bytes).ok_or_else(|| invalid_data("invalid point"))
+                        };
+                        Ok(CompactOutput {
+                            cmu: point(read_array(&mut reader)?)?,
+                            epk: point(read_array(&mut reader)?)?,
+                            ciphertext: read_array(&mut reader)?,
+                        })
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                Ok(CompactTx { txid, nullifiers, outputs })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(CompactBlock { height, hash, prev_hash, vtx })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Reads a block that takes up all of `bytes`.
+    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Self> {
+        let block = Self::read(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(invalid_data("trailing bytes after the block"));
+        }
+        Ok(block)
+    }
+}
+
+fn read_array<R: Read, const N: usize>(mut reader: R) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A note found for `account` in output `output_index` of `txid`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScannedNote {
+    pub account: AccountId,
+    pub txid: TxId,
+    pub output_index: u32,
+    pub note: NotePlaintext,
+    pub cm: EdwardsAffine,
+    pub position: u64,
+    pub nullifier: Nullifier,
+}
+
+/// Everything a block changed for the scanned accounts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScannedBlock {
+    pub height: BlockHeight,
+    pub hash: [u8; 32],
+    pub received: Vec<ScannedNote>,
+    /// The accounts' notes spent in the block, by the transaction spending
+    /// them.
+    pub spent: Vec<(TxId, Nullifier)>,
+    /// The witnesses of the accounts' unspent notes as of this block, by
+    /// position.
+    pub witnesses: Vec<(u64, MerklePath)>,
+    /// `CommitmentTree::to_bytes` of the tree after the block.
+    pub tree: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanError {
+    /// A block that is not at the height following the last one scanned.
+    NonContiguousBlock { expected: BlockHeight, height: BlockHeight },
+    /// A block whose parent is not the last one scanned, e.g. after a reorg.
+    PrevHashMismatch(BlockHeight),
+    /// More outputs than the note commitment tree has room for.
+    TreeFull,
+    /// A note, by position, whose witness does not fit the tree resumed from.
+    InvalidWitness(u64),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::NonContiguousBlock { expected, height } => {
+                write!(f, "expected block {}, got {}", expected, height)
+            }
+            ScanError::PrevHashMismatch(height) => {
+                write!(f, "block {} does not follow the last block scanned", height)
+            }
+            ScanError::TreeFull => write!(f, "the note commitment tree is full"),
+            ScanError::InvalidWitness(position) => {
+                write!(f, "the witness of the note at {} does not fit the tree", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+/// An account the scanner looks for notes of.
+pub struct ScanningAccount {
+    pub id: AccountId,
+    pub ivk: IncomingViewingKey,
+    pub notes: SpendingNotes,
+}
+
+/// Follows the chain block by block, keeping the note commitment tree, the
+/// accounts' notes and their witnesses up to date.
+pub struct Scanner<H: MerkleHash> {
+    accounts: Vec<ScanningAccount>,
+    tree: CommitmentTree<H>,
+    witnesses: BTreeMap<u64, IncrementalWitness<H>>,
+    tip: (BlockHeight, [u8; 32]),
+    note_commitment_mode: NoteCommitmentMode,
+}
+
+impl<H: MerkleHash> Scanner<H> {
+    /// Resumes scanning after `tip`, at which the tree was `tree` and the
+    /// witnesses of the accounts' notes were those recorded. A new wallet
+    /// starts from the block before the accounts' birthday and the tree after
+    /// it, as served by a lightwalletd `GetTreeState`.
+    pub fn new(
+        accounts: Vec<ScanningAccount>,
+        tree: CommitmentTree<H>,
+        tip: (BlockHeight, [u8; 32]),
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> Result<Self, ScanError> {
+        let witnesses = accounts
+            .iter()
+            .flat_map(|account| account.notes.notes())
+            .filter(|note| !matches!(note.status, NoteStatus::Spent(_)) && !note.witness.is_empty())
+            .map(|note| {
+                let witness = IncrementalWitness::from_path(note.position, &note.witness, &tree)
+                    .ok_or(ScanError::InvalidWitness(note.position))?;
+                Ok((note.position, witness))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Scanner {
+            accounts,
+            tree,
+            witnesses,
+            tip,
+            note_commitment_mode,
+        })
+    }
+
+    pub fn accounts(&self) -> &[ScanningAccount] {
+        &self.accounts
+    }
+
+    pub fn tree(&self) -> &CommitmentTree<H> {
+        &self.tree
+    }
+
+    pub fn tip(&self) -> (BlockHeight, [u8; 32]) {
+        self.tip
+    }
+
+    /// Scans the block following the tip. On error nothing is changed.
+    pub fn scan_block(&mut self, block: &CompactBlock) -> Result<ScannedBlock, ScanError> {
+        let (height, hash) = self.tip;
+        if block.height != height + 1 {
+            return Err(ScanError::NonContiguousBlock { expected: height + 1, height: block.height });
+        }
+        if block.prev_hash != hash {
+            return Err(ScanError::PrevHashMismatch(block.height));
+        }
+        let outputs: u64 = block.vtx.iter().map(|tx| tx.outputs.len() as u64).sum();
+        if self.tree.size() + outputs > 1 << H::DEPTH {
+            return Err(ScanError::TreeFull);
+        }
+
+        // Trial-decrypt every output of the block with every account's key in
+        // one batch, then walk the block in order to update the tree.
+        let ivks: Vec<_> = self.accounts.iter().map(|account| account.ivk).collect();
+        let all_outputs: Vec<_> = block.vtx.iter().flat_map(|tx| tx.outputs.iter().cloned()).collect();
+        let mut decrypted =
+            batch_try_compact_note_decryption(&ivks, &all_outputs, self.note_commitment_mode).into_iter().peekable();
+
+        let mut received = vec![];
+        let mut spent = vec![];
+        let mut block_index = 0;
+        for tx in &block.vtx {
+            for nf in &tx.nullifiers {
+                for account in &mut self.accounts {
+                    if let Some(position) = account.notes.get(nf).map(|note| note.position) {
+                        account.notes.mark_spent(nf, block.height);
+                        self.witnesses.remove(&position);
+                        spent.push((tx.txid, nf.clone()));
+                    }
+                }
+            }
+
+            for (output_index, output) in tx.outputs.iter().enumerate() {
+                let leaf = H::leaf(&output.cmu);
+                for witness in self.witnesses.values_mut() {
+                    witness.append(leaf);
+                }
+
+                let position = self.tree.size();
+                let mut found = None;
+                while let Some(d) = decrypted.next_if(|d| d.output_index == block_index) {
+                    // Only the first key that decrypts an output gets it.
+                    if found.is_none() {
+                        found = Some((d.ivk_index, d.note));
+                    }
+                }
+                block_index += 1;
+                let account = found.and_then(|(account, note)| {
+                    let account = &mut self.accounts[account];
+                    let nullifier =
+                        account.notes.receive(note.clone(), output.cmu, position, vec![], block.height)?;
+                    Some((account.id, note, nullifier))
+                });
+                // A note worth more than `MAX_MONEY` is dropped like one for
+                // another wallet.
+                if let Some((account, note, nullifier)) = account {
+                    self.witnesses.insert(position, IncrementalWitness::from_tree(&self.tree));
+                    received.push(ScannedNote {
+                        account,
+                        txid: tx.txid,
+                        output_index: output_index as u32,
+                        note,
+                        cm: output.cmu,
+                        position,
+                        nullifier,
+                    });
+                }
+                self.tree.append(leaf);
+            }
+        }
+
+        let mut witnesses = vec![];
+        for (position, witness) in &self.witnesses {
+            let path = witness.path();
+            for account in &mut self.accounts {
+                account.notes.update_witness(*position, path.clone());
+            }
+            witnesses.push((*position, path));
+        }
+
+        self.tip = (block.height, block.hash);
+        Ok(ScannedBlock {
+            height: block.height,
+            hash: block.hash,
+            received,
+            spent,
+            witnesses,
+            tree: self.tree.to_bytes(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::fixtures::SK;
+    use crate::address::PaymentAddress;
+    use crate::keygen::KeyChain;
+    use crate::memo::Memo;
+    use crate::merkle::{compute_root, PoseidonMerkleHash};
+    use crate::note::{NoteValue, ReceivedNote};
+    use crate::prover::MockProver;
+    use crate::spend_description::NullifierMode;
+    use crate::transaction::test::sample_transaction;
+
+    /// A transaction with one output paying `value` to `recipient`, and
+    /// revealing `nullifiers`.
+    pub fn compact_tx(recipient: &PaymentAddress, value: u64, nullifiers: Vec<Nullifier>) -> CompactTx {
+        let (output, _) = OutputDescription::new(
+            &MockProver,
+            &[0; 32],
+            recipient,
+            NoteValue(value),
+            &Memo::Empty,
+            NoteCommitmentMode::Arkworks,
+        )
+        .unwrap();
+        CompactTx {
+            txid: TxId([value as u8; 32]),
+            nullifiers,
+            outputs: vec![CompactOutput::from(&output)],
+        }
+    }
+
+    pub fn compact_block(height: BlockHeight, vtx: Vec<CompactTx>) -> CompactBlock {
+        CompactBlock {
+            height,
+            hash: [height as u8; 32],
+            prev_hash: [height as u8 - 1; 32],
+            vtx,
+        }
+    }
+
+    fn account(id: AccountId, kc: &KeyChain) -> ScanningAccount {
+        ScanningAccount {
+            id,
+            ivk: IncomingViewingKey::from(kc),
+            notes: SpendingNotes::new(&kc.nk, NullifierMode::Poseidon),
+        }
+    }
+
+    #[test]
+    pub fn test_compact_block_encoding() {
+        let tx = sample_transaction();
+        let block = compact_block(7, vec![CompactTx::from(&tx)]);
+        assert_eq!(block.vtx[0].txid, tx.txid());
+        assert_eq!(block.vtx[0].outputs.len(), 2);
+        assert_eq!(CompactBlock::from_bytes(&block.to_bytes()).unwrap(), block);
+
+        let mut trailing = block.to_bytes();
+        trailing.push(0);
+        assert!(CompactBlock::from_bytes(&trailing).is_err());
+        let bytes = block.to_bytes();
+        assert!(CompactBlock::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    pub fn test_scanner() {
+        let (sk_2, sk_3): (&[u8], &[u8]) = (&[2; 32], &[3; 32]);
+        let (kc_1, kc_2) = (KeyChain::from(SK), KeyChain::from(sk_2));
+        let (pa_1, pa_2) = (PaymentAddress::from(KeyChain::from(SK)), PaymentAddress::from(KeyChain::from(sk_2)));
+        let stranger = PaymentAddress::from(KeyChain::from(sk_3));
+
+        let start = |tree| {
+            Scanner::<PoseidonMerkleHash>::new(
+                vec![account(1, &kc_1), account(2, &kc_2)],
+                tree,
+                (9, [9; 32]),
+                NoteCommitmentMode::Arkworks,
+            )
+            .unwrap()
+        };
+        let block = compact_block(10, vec![compact_tx(&stranger, 1, vec![]), compact_tx(&pa_1, 20, vec![])]);
+
+        // Positions follow on from the tree the scan starts from.
+        let mut tree = CommitmentTree::new();
+        for i in 0..3u64 {
+            tree.append(crate::circuit::ConstraintF::from(i));
+        }
+        assert_eq!(start(tree).scan_block(&block).unwrap().received[0].position, 4);
+
+        let mut scanner = start(CommitmentTree::new());
+        assert_eq!(scanner.scan_block(&compact_block(11, vec![])).err(), Some(ScanError::NonContiguousBlock {
+            expected: 10,
+            height: 11
+        }));
+        let scanned = scanner.scan_block(&block).unwrap();
+        assert_eq!(scanned.received.len(), 1);
+        let note = &scanned.received[0];
+        assert_eq!((note.account, note.position, note.note.value.0), (1, 1, 20));
+        assert_eq!(scanned.witnesses, vec![(1, scanner.accounts()[0].notes.notes().next().unwrap().witness.clone())]);
+
+        let block = compact_block(11, vec![
+            compact_tx(&pa_2, 5, vec![]),
+            compact_tx(&stranger, 2, vec![Nullifier([9; 32])]),
+        ]);
+        let scanned = scanner.scan_block(&block).unwrap();
+        assert_eq!(scanned.received[0].account, 2);
+        assert!(scanned.spent.is_empty());
+        assert_eq!(scanned.witnesses.len(), 2);
+
+        let root = scanner.tree().root();
+        for account in scanner.accounts() {
+            let note: &ReceivedNote = account.notes.notes().next().unwrap();
+            assert_eq!(compute_root::<PoseidonMerkleHash>(PoseidonMerkleHash::leaf(&note.cm), &note.witness), root);
+            assert_eq!(note.anchor::<PoseidonMerkleHash>(), root);
+        }
+        assert_eq!(scanner.accounts()[0].notes.balance(11, 2).spendable, 20);
+        assert_eq!(scanner.accounts()[1].notes.balance(11, 2).pending, 5);
+
+        assert_eq!(
+            scanner.scan_block(&compact_block(13, vec![])),
+            Err(ScanError::NonContiguousBlock { expected: 12, height: 13 })
+        );
+        let mut orphan = compact_block(12, vec![]);
+        orphan.prev_hash = [0; 32];
+        assert_eq!(scanner.scan_block(&orphan), Err(ScanError::PrevHashMismatch(12)));
+
+        // A scanner resumed from the state after block 11 follows the same
+        // tree and witnesses.
+        let resume = |scanner: &Scanner<PoseidonMerkleHash>| {
+            let accounts = scanner
+                .accounts()
+                .iter()
+                .zip([&kc_1, &kc_2])
+                .map(|(scanned, kc)| {
+                    let mut account = account(scanned.id, kc);
+                    for note in scanned.notes.notes() {
+                        account.notes.insert(note.clone());
+                    }
+                    account
+                })
+                .collect();
+            Scanner::new(accounts, scanner.tree().clone(), scanner.tip(), NoteCommitmentMode::Arkworks)
+        };
+        let mut resumed = resume(&scanner).unwrap();
+
+        // A witness cut short, e.g. read from a damaged wallet, is refused.
+        let mut damaged = scanner.accounts()[0].notes.notes().next().unwrap().clone();
+        damaged.witness.pop();
+        let mut account = account(1, &kc_1);
+        account.notes.insert(damaged.clone());
+        assert_eq!(
+            Scanner::new(vec![account], scanner.tree().clone(), scanner.tip(), NoteCommitmentMode::Arkworks).err(),
+            Some(ScanError::InvalidWitness(damaged.position))
+        );
+
+        let spent_nf = scanner.accounts()[0].notes.notes().next().unwrap().nullifier.clone();
+        let block = compact_block(12, vec![compact_tx(&stranger, 3, vec![spent_nf.clone()])]);
+        let scanned = scanner.scan_block(&block).unwrap();
+        assert_eq!(scanned.spent, vec![(TxId([3; 32]), spent_nf)]);
+        assert_eq!(scanned.witnesses.len(), 1);
+        assert_eq!(scanner.accounts()[0].notes.balance(12, 1).total, 0);
+        assert_eq!(resumed.scan_block(&block).unwrap(), scanned);
+
+        let block = compact_block(13, vec![compact_tx(&stranger, 4, vec![]), compact_tx(&pa_1, 6, vec![])]);
+        assert_eq!(resumed.scan_block(&block).unwrap(), scanner.scan_block(&block).unwrap());
+    }
+}
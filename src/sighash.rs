@@ -0,0 +1,239 @@
+//! ZIP-244 transaction digests. Every field of the transaction except the
+//! proofs and signatures is committed to by a tree of BLAKE2b-256 hashes, each
+//! personalized by the part of the transaction it covers.
+//!
+//! Fields are hashed in this crate's encodings, so `cmu` is the `repr_J` of
+//! the whole note commitment rather than its `u`-coordinate.
+//!
+//! The shielded signatures sign the same tree with the transparent branch
+//! swapped for its `SIGHASH_ALL` signature digest, which also commits to the
+//! value and script of every coin the transparent inputs spend.
+
+use blake2b_simd::{Params, State};
+
+use crate::transaction::{
+    write_bytes, Transaction, TransparentBundle, TxOut, OVERWINTERED_FLAG, VERSION_GROUP_ID,
+};
+
+const TXID_PERSONALIZATION_PREFIX: &[u8; 12] = b"ZcashTxHash_";
+const HEADERS: &[u8; 16] = b"ZTxIdHeadersHash";
+const TRANSPARENT: &[u8; 16] = b"ZTxIdTranspaHash";
+const PREVOUTS: &[u8; 16] = b"ZTxIdPrevoutHash";
+const SEQUENCE: &[u8; 16] = b"ZTxIdSequencHash";
+const OUTPUTS: &[u8; 16] = b"ZTxIdOutputsHash";
+const SAPLING: &[u8; 16] = b"ZTxIdSaplingHash";
+const SAPLING_SPENDS: &[u8; 16] = b"ZTxIdSSpendsHash";
+const SAPLING_SPENDS_COMPACT: &[u8; 16] = b"ZTxIdSSpendCHash";
+const SAPLING_SPENDS_NONCOMPACT: &[u8; 16] = b"ZTxIdSSpendNHash";
+const SAPLING_OUTPUTS: &[u8; 16] = b"ZTxIdSOutputHash";
+const SAPLING_OUTPUTS_COMPACT: &[u8; 16] = b"ZTxIdSOutC__Hash";
+const SAPLING_OUTPUTS_MEMOS: &[u8; 16] = b"ZTxIdSOutM__Hash";
+const SAPLING_OUTPUTS_NONCOMPACT: &[u8; 16] = b"ZTxIdSOutN__Hash";
+const ORCHARD: &[u8; 16] = b"ZTxIdOrchardHash";
+const AMOUNTS: &[u8; 16] = b"ZTxTrAmountsHash";
+const SCRIPTS: &[u8; 16] = b"ZTxTrScriptsHash";
+const TXIN: &[u8; 16] = b"Zcash___TxInHash";
+
+const SIGHASH_ALL: u8 = 0x01;
+
+fn hasher(personal: &[u8]) -> State {
+    Params::new().hash_length(32).personal(personal).to_state()
+}
+
+fn finalize(state: State) -> [u8; 32] {
+    state.finalize().as_bytes().try_into().unwrap()
+}
+
+fn hash(personal: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut h = hasher(personal);
+    h.update(data);
+    finalize(h)
+}
+
+/// T.1
+fn header_digest(tx: &Transaction) -> [u8; 32] {
+    let header = &tx.header;
+    let mut h = hasher(HEADERS);
+    h.update(&(header.version | OVERWINTERED_FLAG).to_le_bytes());
+    h.update(&VERSION_GROUP_ID.to_le_bytes());
+    h.update(&header.consensus_branch_id.to_le_bytes());
+    h.update(&header.lock_time.to_le_bytes());
+    h.update(&header.expiry_height.to_le_bytes());
+    finalize(h)
+}
+
+/// T.2a, T.2b and T.2c: the prevouts, sequence and outputs digests.
+fn transparent_parts(bundle: &TransparentBundle) -> [[u8; 32]; 3] {
+    let mut prevouts = hasher(PREVOUTS);
+    let mut sequence = hasher(SEQUENCE);
+    for input in &bundle.vin {
+        prevouts.update(&input.prevout.txid.0);
+        prevouts.update(&input.prevout.n.to_le_bytes());
+        sequence.update(&input.sequence.to_le_bytes());
+    }
+    let mut outputs = hasher(OUTPUTS);
+    for output in &bundle.vout {
+        let mut bytes = vec![];
+        output.write(&mut bytes).unwrap();
+        outputs.update(&bytes);
+    }
+    [finalize(prevouts), finalize(sequence), finalize(outputs)]
+}
+
+/// T.2
+fn transparent_digest(bundle: Option<&TransparentBundle>) -> [u8; 32] {
+    let bundle = match bundle {
+        Some(bundle) if !bundle.vin.is_empty() || !bundle.vout.is_empty() => bundle,
+        _ => return hash(TRANSPARENT, &[]),
+    };
+
+    let [prevouts, sequence, outputs] = transparent_parts(bundle);
+    let mut h = hasher(TRANSPARENT);
+    h.update(&prevouts);
+    h.update(&sequence);
+    h.update(&outputs);
+    finalize(h)
+}
+
+/// S.2 for `SIGHASH_ALL` with no transparent input being signed, or `None`
+/// if `spent_outputs` does not hold one coin per input.
+fn transparent_sig_digest(
+    bundle: Option<&TransparentBundle>,
+    spent_outputs: &[TxOut],
+) -> Option<[u8; 32]> {
+    let bundle = match bundle {
+        Some(bundle) if !bundle.vin.is_empty() && !bundle.is_coinbase() => bundle,
+        _ => return Some(transparent_digest(bundle)),
+    };
+    if spent_outputs.len() != bundle.vin.len() {
+        return None;
+    }
+
+    let mut amounts = hasher(AMOUNTS);
+    let mut scripts = hasher(SCRIPTS);
+    for coin in spent_outputs {
+        amounts.update(&coin.value.to_le_bytes());
+        let mut bytes = vec![];
+        write_bytes(&mut bytes, &coin.script_pubkey).unwrap();
+        scripts.update(&bytes);
+    }
+
+    let [prevouts, sequence, outputs] = transparent_parts(bundle);
+    let mut h = hasher(TRANSPARENT);
+    h.update(&[SIGHASH_ALL]);
+    h.update(&prevouts);
+    h.update(&finalize(amounts));
+    h.update(&finalize(scripts));
+    h.update(&sequence);
+    h.update(&outputs);
+    h.update(&hash(TXIN, &[]));
+    Some(finalize(h))
+}
+
+/// T.3
+fn sapling_digest(tx: &Transaction) -> [u8; 32] {
+    let bundle = &tx.sapling_bundle;
+    if bundle.spends.is_empty() && bundle.outputs.is_empty() {
+        return hash(SAPLING, &[]);
+    }
+
+    let mut spends = hasher(SAPLING_SPENDS);
+    if !bundle.spends.is_empty() {
+        let mut compact = hasher(SAPLING_SPENDS_COMPACT);
+        let mut noncompact = hasher(SAPLING_SPENDS_NONCOMPACT);
+        for spend in &bundle.spends {
+            // cv, anchor, nf, rk
+            let bytes = spend.to_bytes();
+            compact.update(&bytes[64..96]);
+            noncompact.update(&bytes[..64]);
+            noncompact.update(&bytes[96..128]);
+        }
+        spends.update(&finalize(compact));
+        spends.update(&finalize(noncompact));
+    }
+
+    let mut outputs = hasher(SAPLING_OUTPUTS);
+    if !bundle.outputs.is_empty() {
+        let mut compact = hasher(SAPLING_OUTPUTS_COMPACT);
+        let mut memos = hasher(SAPLING_OUTPUTS_MEMOS);
+        let mut noncompact = hasher(SAPLING_OUTPUTS_NONCOMPACT);
+        for output in &bundle.outputs {
+            // cv, cmu, epk, encCiphertext, outCiphertext
+            let bytes = output.to_bytes();
+            compact.update(&bytes[32..96]);
+            compact.update(&bytes[96..148]);
+            memos.update(&bytes[148..660]);
+            noncompact.update(&bytes[..32]);
+            noncompact.update(&bytes[660..756]);
+        }
+        outputs.update(&finalize(compact));
+        outputs.update(&finalize(memos));
+        outputs.update(&finalize(noncompact));
+    }
+
+    let mut h = hasher(SAPLING);
+    h.update(&finalize(spends));
+    h.update(&finalize(outputs));
+    h.update(&bundle.value_balance.to_le_bytes());
+    finalize(h)
+}
+
+fn root_digest(tx: &Transaction, transparent: [u8; 32]) -> [u8; 32] {
+    let mut personal = [0u8; 16];
+    personal[..12].copy_from_slice(TXID_PERSONALIZATION_PREFIX);
+    personal[12..].copy_from_slice(&tx.header.consensus_branch_id.to_le_bytes());
+
+    let mut h = hasher(&personal);
+    h.update(&header_digest(tx));
+    h.update(&transparent);
+    h.update(&sapling_digest(tx));
+    h.update(&hash(ORCHARD, &[]));
+    finalize(h)
+}
+
+/// The root of the tree, which is the transaction ID.
+pub fn txid_digest(tx: &Transaction) -> [u8; 32] {
+    root_digest(tx, transparent_digest(tx.transparent_bundle.as_ref()))
+}
+
+/// The digest the spend authorization and binding signatures sign.
+/// `spent_outputs` are the coins spent by the transparent inputs, in order;
+/// without transparent inputs it is empty and the digest equals the txid
+/// digest. Returns `None` if there is not one coin per input.
+pub fn shielded_sighash(tx: &Transaction, spent_outputs: &[TxOut]) -> Option<[u8; 32]> {
+    let transparent = transparent_sig_digest(tx.transparent_bundle.as_ref(), spent_outputs)?;
+    Some(root_digest(tx, transparent))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::transaction::test::sample_transaction;
+
+    #[test]
+    pub fn test_txid_covers_the_effecting_data() {
+        let mut tx = sample_transaction();
+        let txid = txid_digest(&tx);
+        assert_eq!(shielded_sighash(&tx, &[]), Some(txid));
+
+        tx.sapling_bundle.value_balance += 1;
+        assert_ne!(txid_digest(&tx), txid);
+        tx.sapling_bundle.value_balance -= 1;
+
+        tx.sapling_bundle.outputs.swap(0, 1);
+        assert_ne!(txid_digest(&tx), txid);
+        tx.sapling_bundle.outputs.swap(0, 1);
+
+        tx.header.consensus_branch_id += 1;
+        assert_ne!(txid_digest(&tx), txid);
+    }
+
+    #[test]
+    pub fn test_empty_bundles() {
+        let mut tx = sample_transaction();
+        tx.sapling_bundle.spends.clear();
+        tx.sapling_bundle.outputs.clear();
+        assert_eq!(sapling_digest(&tx), hash(SAPLING, &[]));
+        assert_eq!(transparent_digest(None), hash(TRANSPARENT, &[]));
+    }
+}
@@ -1,57 +1,104 @@
 use ark_crypto_primitives::commitment::pedersen::Randomness;
 use ark_crypto_primitives::signature::{schnorr, SignatureScheme};
-use ark_crypto_primitives::snark::SNARK;
 use ark_ed_on_bls12_381::{EdwardsAffine, EdwardsProjective};
 use ark_crypto_primitives::crh::poseidon::TwoToOneCRH;
 use ark_crypto_primitives::crh::TwoToOneCRHScheme;
 use ark_ff::PrimeField;
-use ark_ed_on_bls12_381::{Fq, Fr};
-use ark_groth16::{prepare_verifying_key, Groth16, PreparedVerifyingKey, Proof};
+use ark_ed_on_bls12_381::Fr;
+use ark_relations::r1cs::SynthesisError;
 use ark_ff::BigInteger;
-use ark_serialize::CanonicalSerialize;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use blake2::Blake2b512;
 use rand::thread_rng;
 
-use crate::circuit::Spend;
-use crate::commitment::{mixing_pedersen_hash, Commitment, ValueCommitTrapdoor};
-use crate::keygen::{KeyChain, PublicKey, Signature};
+use crate::circuit::SpendPublicInputs;
+use crate::commitment::{mixing_pedersen_hash, NoteCommitmentMode, ValueCommitTrapdoor};
+use crate::keygen::{
+    signature_from_bytes, signature_to_bytes, spend_auth_params, KeyChain, PublicKey, SecretKey,
+    Signature,
+};
 use crate::note::NoteValue;
+use crate::group_hash::{abst_j, is_small_order, repr_j};
+use crate::merkle::MerkleHash;
 use crate::prf::poseidon_config::poseidon_parameters;
+use crate::prf::prf_expand::Crh;
+use crate::prover::{Proof, SaplingVerifier, SpendProver};
+use crate::transaction::SignError;
+use crate::witness::SpendWitness;
 
 
-#[derive(Debug, Clone)]
-pub struct Nullifier(pub Fq);
+/// How a nullifier is derived from `nk` and `rho`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NullifierMode {
+    /// Poseidon `TwoToOneCRH` over `nk.v` and `rho.v`.
+    #[default]
+    Poseidon,
+    /// Sapling `PRF^nf`: `BLAKE2s-256("Zcash_nf", repr(nk) || repr(rho))`.
+    Sapling,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Nullifier(pub [u8; 32]);
 
 impl Nullifier {
-    pub fn new(note_commitment: EdwardsAffine, pos: u64, nk: EdwardsAffine) -> Self {
+    pub fn new(
+        note_commitment: EdwardsAffine,
+        pos: u64,
+        nk: EdwardsAffine,
+        mode: NullifierMode,
+    ) -> Self {
         let rho = mixing_pedersen_hash(
             note_commitment,
             Fr::from_le_bytes_mod_order(&pos.to_le_bytes()),
         );
-        
-        let val =
-            <TwoToOneCRH<_> as TwoToOneCRHScheme>::evaluate(
-                &poseidon_parameters(), 
-                &nk.y, 
-                &rho.y
-            ).unwrap();
-        
-        Self(val)
+
+        match mode {
+            NullifierMode::Poseidon => {
+                let val = <TwoToOneCRH<_> as TwoToOneCRHScheme>::evaluate(
+                    &poseidon_parameters(),
+                    &nk.y,
+                    &rho.y,
+                )
+                .unwrap();
+
+                let mut nf = [0u8; 32];
+                nf.copy_from_slice(&val.into_bigint().to_bytes_le());
+                Self(nf)
+            }
+            NullifierMode::Sapling => {
+                Self(Crh::find_nullifier(&repr_j(&nk), &repr_j(&rho)))
+            }
+        }
     }
 }
 
+/// The length of a `SpendDescription` on the wire.
+pub const SPEND_DESCRIPTION_SIZE: usize = 384;
+
 #[derive(Debug)]
 pub struct SpendDescription {
-    _cv: EdwardsAffine,
-    _anchor: ark_bls12_381::Fr,
-    _nf: Nullifier,
-    _rk: PublicKey,
-    _spend_proof: Proof<ark_bls12_381::Bls12_381>,
-    _sig: Signature,
-    _vk: PreparedVerifyingKey<ark_bls12_381::Bls12_381>,
+    cv: EdwardsAffine,
+    anchor: ark_bls12_381::Fr,
+    nf: Nullifier,
+    rk: PublicKey,
+    spend_proof: Proof,
+    spend_auth_sig: Signature,
 }
 
-impl SpendDescription {
+type SpendAuthSig = schnorr::Schnorr<EdwardsProjective, Blake2b512>;
+
+/// A spend with everything but its proof. It owns its witness, so the spends
+/// and outputs of a transaction can be proved together, see
+/// `SaplingBundle::prove`.
+#[derive(Clone, Debug)]
+pub struct UnprovenSpend {
+    witness: SpendWitness,
+}
+
+impl UnprovenSpend {
+    /// Returns the spend with `rsk`, the key to sign the transaction's
+    /// sighash with once the transaction is complete.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         kc: KeyChain,
         merkle_path: Vec<Option<(ark_bls12_381::Fr, bool)>>,
@@ -60,188 +107,264 @@ impl SpendDescription {
         nf: Nullifier,
         note_val: NoteValue,
         rcv: ValueCommitTrapdoor,
-        note_com: EdwardsAffine,
         note_com_randomness: Randomness<EdwardsProjective>,
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
         diversifier: [u8; 11],
-    ) -> Self {
+    ) -> Result<(Self, SecretKey), SynthesisError> {
         let (randomizer, randomized_ak) = kc.get_randomized_ak();
-        let (gd, pk_d) = kc.get_diversified_transmission_address_from_diversifier(&diversifier);
-        let note_comm_params = Commitment::setup();
-        let mut pos = 0_u64;
-        let mut coeff = 1;
-        for &x in merkle_path.iter() {
-            if let Some((_, bit)) = x {
-                pos += (bit as u64) * coeff;
-            }
-            coeff <<= 1;
-        }
-        let mut oa = vec![];
-        for i in randomizer.0.to_bytes_le() {
-            oa.push(Some(i))
-        }
-        let mut nsk = vec![];
-        for i in kc.nsk.0 .0.to_bytes_le() {
-            nsk.push(Some(i));
-        }
-        let spend_circuit = Spend {
-            auth_path: merkle_path.clone(),
-            root: Some(anchor),
-            ak: Some(kc.ak.clone().0),
-            randomized_ak: Some(randomized_ak.0.clone()),
-            randomness: &oa,
-            sig_params: kc.params.clone(),
-            nsk: &nsk,
-            nk: Some(kc.nk.0.clone()),
-            note_val: Some(note_val.clone()),
-            rcv_old: Some(rcv.clone()),
-            val_cm_old: Some(cv.into()),
-            cm_params: Some(note_comm_params.clone()),
-            crh_rand: Some(note_com_randomness.clone()),
-            note_com: note_com.clone(),
-            ivk: Some(kc.ivk.0.clone()),
-            gd: Some(gd),
-            pk_d: Some(pk_d),
-            nf_old: Some(nf.clone()),
-            pos: Some(pos),
-        };
-        let spend_circuit2 = Spend {
-            auth_path: merkle_path,
-            root: Some(anchor),
-            ak: Some(kc.ak.clone().0),
-            randomized_ak: Some(randomized_ak.0.clone()),
-            randomness: &oa,
-            sig_params: kc.params.clone(),
-            nsk: &nsk,
-            nk: Some(kc.nk.0.clone()),
-            note_val: Some(note_val),
-            rcv_old: Some(rcv),
-            val_cm_old: Some(cv.into()),
-            cm_params: Some(note_comm_params),
-            crh_rand: Some(note_com_randomness),
-            note_com: note_com,
-            ivk: Some(kc.ivk.0.clone()),
-            gd: Some(gd),
-            pk_d: Some(pk_d),
-            nf_old: Some(nf.clone()),
-            pos: Some(pos),
+        let (gd, _) = kc.get_diversified_transmission_address_from_diversifier(&diversifier);
+        let auth_path = merkle_path
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or(SynthesisError::AssignmentMissing)?;
+
+        let witness = SpendWitness {
+            public_inputs: SpendPublicInputs {
+                rk: randomized_ak.0,
+                cv,
+                anchor,
+                nf,
+            },
+            ak: kc.ak.0,
+            alpha: randomizer,
+            nsk: kc.nsk.0,
+            note_value: note_val,
+            rcv,
+            rcm: note_com_randomness.0,
+            g_d: gd,
+            auth_path,
+            note_commitment_mode,
+            nullifier_mode,
+            ivk_mode: kc.ivk_mode,
         };
-        let mut rng = thread_rng();
-        
-        let (pk, vk) =
-            Groth16::<ark_bls12_381::Bls12_381>::circuit_specific_setup(spend_circuit2, &mut rng)
-                .unwrap();
-        
-        let proof = Groth16::<ark_bls12_381::Bls12_381>::prove(&pk, spend_circuit, &mut rng)
-            .expect("proof failed");
-        let mut spend_statement = vec![];
-        let mut cv_to_bytes = [0_u8; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&cv, &mut cv_to_bytes[..])
+        let rsk = kc.randomized_secret_key(&randomizer);
+        Ok((UnprovenSpend { witness }, rsk))
+    }
+
+    pub fn witness(&self) -> &SpendWitness {
+        &self.witness
+    }
+
+    pub fn prove<H: MerkleHash>(
+        &self,
+        prover: &impl SpendProver<H>,
+    ) -> Result<SpendDescription, SynthesisError> {
+        let proof = self.witness.with_circuit::<H, _>(|spend| prover.prove_spend(spend))?;
+        let public_inputs = &self.witness.public_inputs;
+
+        Ok(SpendDescription {
+            cv: public_inputs.cv,
+            anchor: public_inputs.anchor,
+            nf: public_inputs.nf.clone(),
+            rk: PublicKey(public_inputs.rk),
+            spend_proof: proof,
+            spend_auth_sig: Signature::default(),
+        })
+    }
+}
+
+impl SpendDescription {
+    /// Proves the spend and returns it with `rsk`, the key to sign the
+    /// transaction's sighash with once the transaction is complete.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<H: MerkleHash>(
+        prover: &impl SpendProver<H>,
+        kc: KeyChain,
+        merkle_path: Vec<Option<(ark_bls12_381::Fr, bool)>>,
+        cv: EdwardsAffine,
+        anchor: ark_bls12_381::Fr,
+        nf: Nullifier,
+        note_val: NoteValue,
+        rcv: ValueCommitTrapdoor,
+        note_com_randomness: Randomness<EdwardsProjective>,
+        note_commitment_mode: NoteCommitmentMode,
+        nullifier_mode: NullifierMode,
+        diversifier: [u8; 11],
+    ) -> Result<(Self, SecretKey), SynthesisError> {
+        let (spend, rsk) = UnprovenSpend::new(
+            kc,
+            merkle_path,
+            cv,
+            anchor,
+            nf,
+            note_val,
+            rcv,
+            note_com_randomness,
+            note_commitment_mode,
+            nullifier_mode,
+            diversifier,
+        )?;
+        Ok((spend.prove(prover)?, rsk))
+    }
+
+    /// Signs the transaction's sighash with `rsk`. Until then the description
+    /// carries an invalid signature.
+    pub fn sign(&mut self, rsk: &SecretKey, sighash: &[u8; 32]) -> Result<(), SignError> {
+        self.spend_auth_sig = SpendAuthSig::sign(spend_auth_params(), rsk, sighash, &mut thread_rng())
+            .map_err(|e| SignError::Signing(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn verify_signature(&self, sighash: &[u8; 32]) -> bool {
+        SpendAuthSig::verify(spend_auth_params(), &self.rk.0, sighash, &self.spend_auth_sig)
+            .unwrap_or(false)
+    }
+
+    /// The Sapling v4 encoding: `cv`, `anchor`, `nf`, `rk`, `zkproof` and
+    /// `spendAuthSig`. Points are `repr_J`, field elements little-endian, the
+    /// proof is the compressed Groth16 proof and the signature is encoded by
+    /// `signature_to_bytes`.
+    pub fn to_bytes(&self) -> [u8; SPEND_DESCRIPTION_SIZE] {
+        let mut bytes = [0u8; SPEND_DESCRIPTION_SIZE];
+        bytes[..32].copy_from_slice(&repr_j(&self.cv));
+        self.anchor.serialize_compressed(&mut bytes[32..64]).unwrap();
+        bytes[64..96].copy_from_slice(&self.nf.0);
+        bytes[96..128].copy_from_slice(&repr_j(&self.rk.0));
+        self.spend_proof
+            .serialize_compressed(&mut bytes[128..320])
             .unwrap();
-        spend_statement.extend(cv_to_bytes);
-        let sk = schnorr::SecretKey(kc.ask.0 * randomizer);
-        let sig = <schnorr::Schnorr<ark_ed_on_bls12_381::EdwardsProjective,Blake2b512> as SignatureScheme>::sign(&kc.params.clone(), &sk, &spend_statement, &mut rng).expect("signature succeeded");
-        let pvk = prepare_verifying_key(&vk);
-        Self {
-            _cv: cv,
-            _anchor: anchor,
-            _nf: nf,
-            _rk: randomized_ak,
-            _spend_proof: proof,
-            _sig: sig,
-            _vk: pvk,
+        bytes[320..].copy_from_slice(&signature_to_bytes(&self.spend_auth_sig));
+        bytes
+    }
+
+    /// Decodes `to_bytes`, rejecting non-canonical points and scalars and
+    /// points that are not on the curve.
+    pub fn from_bytes(bytes: &[u8; SPEND_DESCRIPTION_SIZE]) -> Option<Self> {
+        Some(SpendDescription {
+            cv: abst_j(bytes[..32].try_into().unwrap())?,
+            anchor: ark_bls12_381::Fr::deserialize_compressed(&bytes[32..64]).ok()?,
+            nf: Nullifier(bytes[64..96].try_into().unwrap()),
+            rk: PublicKey(abst_j(bytes[96..128].try_into().unwrap())?),
+            spend_proof: Proof::deserialize_compressed(&bytes[128..320]).ok()?,
+            spend_auth_sig: signature_from_bytes(bytes[320..].try_into().unwrap())?,
+        })
+    }
+
+    pub fn nullifier(&self) -> &Nullifier {
+        &self.nf
+    }
+
+    pub fn public_inputs(&self) -> SpendPublicInputs {
+        SpendPublicInputs {
+            rk: self.rk.0,
+            cv: self.cv,
+            anchor: self.anchor,
+            nf: self.nf.clone(),
         }
     }
+
+    pub fn verify_proof(&self, verifier: &impl SaplingVerifier) -> bool {
+        if is_small_order(&self.cv) || is_small_order(&self.rk.0) {
+            return false;
+        }
+
+        verifier.verify_spend(&self.public_inputs(), &self.spend_proof)
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use crate::commitment::homomorphic_pedersen_commitment;
+    use crate::fixtures::{default_address, spend_witness, SK};
     use crate::keygen::KeyChain;
-    use crate::pedersen_crh::Window;
-    use crate::signing_key::SigningKey;
-    use crate::prf::poseidon_config;
-    use ark_crypto_primitives::commitment::pedersen::{
-        Commitment as pdCommit, Randomness as pdRand,
-    };
-    use ark_crypto_primitives::commitment::CommitmentScheme;
-    use ark_crypto_primitives::crh::{poseidon::TwoToOneCRH, TwoToOneCRHScheme};
-    use ark_ff::UniformRand;
-    use ark_std::One;
-    const SK: SigningKey = &[
-        24, 226, 141, 234, 92, 17, 129, 122, 238, 178, 26, 25, 152, 29, 40, 54, 142, 196, 56, 175,
-        194, 90, 141, 185, 78, 190, 8, 215, 160, 40, 142, 9,
-    ];
-    #[test]
-    pub fn test_proof_generation() {
+    use crate::merkle::PoseidonMerkleHash;
+    use crate::prf::prf_expand::IvkMode;
+    use crate::prover::{LocalProver, MockProver, MockVerifier};
+    use crate::witness::SpendWitness;
+    use ark_crypto_primitives::commitment::pedersen::Randomness as pdRand;
+
+    /// A spend of a 13-zatoshi note, with its `rsk` and `rcv`.
+    pub fn spend_description(
+        prover: &impl SpendProver<PoseidonMerkleHash>,
+    ) -> (SpendDescription, SecretKey, ValueCommitTrapdoor) {
+        let (spend, rsk, rcv) = unproven_spend();
+        (spend.prove(prover).unwrap(), rsk, rcv)
+    }
+
+    /// The spend of `spend_description`, before it is proved.
+    pub fn unproven_spend() -> (UnprovenSpend, SecretKey, ValueCommitTrapdoor) {
         let kc = KeyChain::from(SK);
-        let note_val = NoteValue(13);
-        let (d, g_d, pk_d) = kc.get_diversified_transmission_address();
-        let rcv = ValueCommitTrapdoor::random();
-        let val_commitment = homomorphic_pedersen_commitment(note_val.clone(), &rcv);
-        let comm = Commitment::setup();
-        let crh_rand = pdRand::<EdwardsProjective>(Fr::one());
-        let mut kc_key = vec![];
-        kc_key.extend(kc.ak.to_repr_j());
-        kc_key.extend(kc.nk.to_repr_j());
-        let mut note_com_inp = vec![];
-        let mut g_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&g_d, &mut g_d_repr[..])
-            .expect("failed");
-
-        let mut pk_d_repr: [u8; 32] = [0; 32];
-        <EdwardsAffine as CanonicalSerialize>::serialize_compressed(&pk_d.0, &mut pk_d_repr[..])
-            .expect("failed");
-        let v_repr = note_val.0.to_le_bytes();
-        note_com_inp.extend(g_d_repr);
-        note_com_inp.extend(pk_d_repr);
-        note_com_inp.extend(v_repr);
-        let note_com = pdCommit::<EdwardsProjective, Window>::commit(
-            &comm.params.clone(),
-            &note_com_inp,
-            &crh_rand,
-        )
-        .expect("asdf");
-        let mut ivk: [u8; 32] = [0; 32];
-        ivk.copy_from_slice(&kc.ivk.0 .0.to_bytes_le());
-        let mut pos: u64 = 1000;
-        let mut merkle_path: Vec<Option<(ark_bls12_381::Fr, bool)>> = vec![];
-        let mut root_till_now: ark_bls12_381::Fr = note_com.y;
-        let mut rng = thread_rng();
-        let p = pos.clone();
-        for _ in 0..64 {
-            let (lef, rig);
-            if pos % 2 == 1 {
-                lef = ark_bls12_381::Fr::rand(&mut rng);
-
-                rig = root_till_now;
-                merkle_path.push(Some((lef, true)));
-            } else {
-                rig = ark_bls12_381::Fr::rand(&mut rng);
-                lef = root_till_now;
-                merkle_path.push(Some((rig, false)));
-            }
-            root_till_now = <TwoToOneCRH<_> as TwoToOneCRHScheme>::evaluate(
-                &poseidon_config::poseidon_parameters(),
-                lef,
-                rig,
-            )
-            .expect("hash failed");
-            pos = pos / 2;
-        }
-        let nf = Nullifier::new(note_com, p, kc.nk.0);
-        let spend_des = SpendDescription::new(
+        let (d, _, _) = default_address(&kc);
+        let SpendWitness { public_inputs, note_value, rcv, rcm, auth_path, .. } =
+            spend_witness::<PoseidonMerkleHash>(
+                NoteCommitmentMode::Arkworks,
+                NullifierMode::Poseidon,
+                kc.ivk_mode,
+            );
+        let (spend, rsk) = UnprovenSpend::new(
             kc,
-            merkle_path,
-            val_commitment,
-            root_till_now,
-            nf,
-            note_val,
-            rcv,
-            note_com,
-            crh_rand,
+            auth_path.into_iter().map(Some).collect(),
+            public_inputs.cv,
+            public_inputs.anchor,
+            public_inputs.nf,
+            note_value,
+            rcv.clone(),
+            pdRand(rcm),
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
             d,
+        )
+        .unwrap();
+        (spend, rsk, rcv)
+    }
+
+    #[test]
+    pub fn test_proof_generation() {
+        let prover = LocalProver::<PoseidonMerkleHash>::setup(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+            &mut thread_rng(),
         );
+        let (spend_des, _, _) = spend_description(&prover);
         println!("generated_spend_desc : {:?}", spend_des);
+        assert!(spend_des.verify_proof(&prover.verifier()));
+        assert!(!spend_des.verify_proof(&MockVerifier));
+
+        let decoded = SpendDescription::from_bytes(&spend_des.to_bytes()).unwrap();
+        assert!(decoded.verify_proof(&prover.verifier()));
+    }
+
+    #[test]
+    pub fn test_spend_auth_signature() {
+        let (mut spend_des, rsk, _) = spend_description(&MockProver);
+        assert!(!spend_des.verify_signature(&[1; 32]));
+
+        spend_des.sign(&rsk, &[1; 32]).unwrap();
+        assert!(spend_des.verify_signature(&[1; 32]));
+        assert!(!spend_des.verify_signature(&[2; 32]));
+
+        let decoded = SpendDescription::from_bytes(&spend_des.to_bytes()).unwrap();
+        assert!(decoded.verify_signature(&[1; 32]));
+    }
+
+    #[test]
+    pub fn test_spend_description_encoding() {
+        let (spend_des, _, _) = spend_description(&MockProver);
+        let bytes = spend_des.to_bytes();
+        let decoded = SpendDescription::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.public_inputs(), spend_des.public_inputs());
+
+        // cv with v >= q.
+        let mut non_canonical = bytes;
+        non_canonical[..32].copy_from_slice(&[0xff; 32]);
+        non_canonical[31] = 0x7f;
+        assert!(SpendDescription::from_bytes(&non_canonical).is_none());
+
+        // The identity with the sign bit of u set.
+        let mut non_canonical = bytes;
+        non_canonical[96..128].copy_from_slice(&[0; 32]);
+        non_canonical[96] = 1;
+        non_canonical[127] = 0x80;
+        assert!(SpendDescription::from_bytes(&non_canonical).is_none());
+
+        // An anchor and a signature response that are not reduced.
+        for range in [32..64, 352..384] {
+            let mut non_canonical = bytes;
+            non_canonical[range].copy_from_slice(&[0xff; 32]);
+            assert!(SpendDescription::from_bytes(&non_canonical).is_none());
+        }
     }
 }
@@ -0,0 +1,159 @@
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// A bounded pool of threads to prove on. With the `parallel` feature the
+/// arkworks MSMs and FFTs of a proof run on this pool, and `map` proves
+/// independent descriptions concurrently; without it everything runs on the
+/// calling thread.
+pub struct ProvingPool {
+    #[cfg(feature = "parallel")]
+    pool: rayon::ThreadPool,
+}
+
+impl ProvingPool {
+    /// A pool of `num_threads` threads, or one per CPU if `num_threads` is 0.
+    pub fn new(num_threads: usize) -> Self {
+        #[cfg(feature = "parallel")]
+        {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .thread_name(|i| format!("prover-{}", i))
+                .build()
+                .expect("failed to build the proving thread pool");
+
+            ProvingPool { pool }
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            let _ = num_threads;
+            ProvingPool {}
+        }
+    }
+
+    pub fn num_threads(&self) -> usize {
+        #[cfg(feature = "parallel")]
+        {
+            self.pool.current_num_threads()
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            1
+        }
+    }
+
+    /// Runs `f` on the pool, so that any parallelism inside it is bounded by
+    /// the pool's threads.
+    pub fn install<R, F>(&self, f: F) -> R
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        #[cfg(feature = "parallel")]
+        {
+            self.pool.install(f)
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            f()
+        }
+    }
+
+    /// Applies `f` to every item on the pool, e.g. to prove all the
+    /// descriptions of a transaction. The output keeps the order of `items`.
+    pub fn map<T, R, F>(&self, items: Vec<T>, f: F) -> Vec<R>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(T) -> R + Send + Sync,
+    {
+        #[cfg(feature = "parallel")]
+        {
+            self.install(|| items.into_par_iter().map(f).collect())
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            items.into_iter().map(f).collect()
+        }
+    }
+}
+
+impl Default for ProvingPool {
+    fn default() -> Self {
+        ProvingPool::new(0)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::commitment::{
+        homomorphic_pedersen_commitment, note_commitment, NoteCommitmentMode, ValueCommitTrapdoor,
+    };
+    use crate::keygen::{KeyChain, PublicKey};
+    use crate::merkle::PoseidonMerkleHash;
+    use crate::prf::prf_expand::IvkMode;
+    use crate::prover::LocalProver;
+    use crate::spend_description::NullifierMode;
+    use crate::memo::Memo;
+    use crate::note::NoteValue;
+    use crate::output_description::OutputDescription;
+    use ark_crypto_primitives::commitment::pedersen::Randomness;
+    use ark_ec::{AffineRepr, CurveGroup};
+    use ark_ff::PrimeField;
+    use ark_ed_on_bls12_381::Fr;
+    use rand::thread_rng;
+
+    #[test]
+    pub fn test_pool_size() {
+        let pool = ProvingPool::new(2);
+        if cfg!(feature = "parallel") {
+            assert_eq!(pool.num_threads(), 2);
+        } else {
+            assert_eq!(pool.num_threads(), 1);
+        }
+        assert_eq!(pool.map(vec![1, 2, 3], |i| i * 2), vec![2, 4, 6]);
+    }
+
+    #[test]
+    pub fn test_prove_outputs_on_pool() {
+        let sk: &[u8] = &[7; 32];
+        let kc = KeyChain::from(sk);
+        let (d, g_d, pk_d) = kc.get_diversified_transmission_address();
+
+        let prover = LocalProver::<PoseidonMerkleHash>::setup(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+            &mut thread_rng(),
+        );
+
+        let outputs = ProvingPool::new(2).map((1..=2u64).collect(), |v| {
+            let value = NoteValue(v);
+            let rcv = ValueCommitTrapdoor::random();
+            let rcm = Randomness(Fr::from(v));
+            let esk = Fr::from(v + 100);
+
+            OutputDescription::from_values(
+                &prover,
+                &kc.ovk,
+                homomorphic_pedersen_commitment(value.clone(), &rcv),
+                note_commitment(NoteCommitmentMode::Arkworks, &g_d, &pk_d.0, &value, &rcm),
+                PublicKey(g_d.mul_bigint(esk.into_bigint()).into_affine()),
+                d,
+                g_d,
+                pk_d.0,
+                value,
+                rcv,
+                rcm,
+                esk,
+                NoteCommitmentMode::Arkworks,
+                &Memo::Empty,
+            )
+            .unwrap()
+        });
+
+        assert_eq!(outputs.len(), 2);
+        let verifier = prover.verifier();
+        assert!(outputs.iter().all(|output| output.verify_proof(&verifier)));
+    }
+}
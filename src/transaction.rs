@@ -0,0 +1,709 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use ark_crypto_primitives::signature::{schnorr, SignatureScheme};
+use ark_ec::CurveGroup;
+use ark_ed_on_bls12_381::{EdwardsProjective, Fr};
+use ark_relations::r1cs::SynthesisError;
+use blake2::Blake2b512;
+use rand::thread_rng;
+
+use crate::group_hash;
+use crate::keygen::{binding_sig_params, signature_from_bytes, signature_to_bytes, SecretKey, Signature};
+use crate::merkle::MerkleHash;
+use crate::output_description::{OutputDescription, UnprovenOutput, OUTPUT_DESCRIPTION_SIZE};
+use crate::prover::{OutputProver, SaplingVerifier, SpendProver};
+use crate::sighash;
+use crate::spend_description::{SpendDescription, UnprovenSpend, SPEND_DESCRIPTION_SIZE};
+use crate::thread_pool::ProvingPool;
+
+/// The only transaction version this crate reads and writes.
+pub const TX_VERSION: u32 = 5;
+/// Set in the encoded version of every transaction since Overwinter.
+pub(crate) const OVERWINTERED_FLAG: u32 = 1 << 31;
+/// The version group ID of v5 transactions, encoded after the version.
+pub(crate) const VERSION_GROUP_ID: u32 = 0x26a7_270a;
+
+/// The consensus branch ID of NU5.
+pub const NU5_BRANCH_ID: u32 = 0xc2d6_d0b4;
+
+/// 21 million ZEC in zatoshis. No amount, and no `valueBalance` either way,
+/// may exceed it.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+pub(crate) fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TxId(pub [u8; 32]);
+
+/// Formats as the reversed hex used by block explorers and RPCs.
+impl fmt::Display for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().rev().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignError {
+    /// There must be one spend authorization key per spend.
+    WrongKeyCount { spends: usize, keys: usize },
+    /// There must be one spent coin per transparent input.
+    MissingSpentOutputs,
+    /// The signature scheme failed, with its message.
+    Signing(String),
+}
+
+impl fmt::Display for SignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignError::WrongKeyCount { spends, keys } => {
+                write!(f, "{} spend authorization keys for {} spends", keys, spends)
+            }
+            SignError::MissingSpentOutputs => {
+                write!(f, "the coins spent by the transparent inputs are missing")
+            }
+            SignError::Signing(e) => write!(f, "signing failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SignError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxHeader {
+    pub version: u32,
+    pub consensus_branch_id: u32,
+    pub lock_time: u32,
+    pub expiry_height: u32,
+}
+
+impl TxHeader {
+    pub fn new(consensus_branch_id: u32, lock_time: u32, expiry_height: u32) -> Self {
+        TxHeader {
+            version: TX_VERSION,
+            consensus_branch_id,
+            lock_time,
+            expiry_height,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutPoint {
+    pub txid: TxId,
+    pub n: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxIn {
+    pub prevout: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxOut {
+    /// In zatoshis.
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+impl TxOut {
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.value.to_le_bytes())?;
+        write_bytes(&mut writer, &self.script_pubkey)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransparentBundle {
+    pub vin: Vec<TxIn>,
+    pub vout: Vec<TxOut>,
+}
+
+impl TransparentBundle {
+    /// Whether the only input is a coinbase, which spends no coin.
+    pub fn is_coinbase(&self) -> bool {
+        match &self.vin[..] {
+            [input] => input.prevout.txid == TxId([0; 32]) && input.prevout.n == u32::MAX,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SaplingBundle {
+    pub spends: Vec<SpendDescription>,
+    pub outputs: Vec<OutputDescription>,
+    /// The net value leaving the shielded pool, in zatoshis.
+    pub value_balance: i64,
+    pub binding_sig: Signature,
+}
+
+/// A shielded transaction. It is encoded as
+///
+/// - the header: the version with the overwintered flag, the version group
+///   ID, the consensus branch ID, the lock time and the expiry height, each a
+///   little-endian `u32`;
+/// - the transparent inputs and outputs, each list prefixed by its
+///   `CompactSize` length; an absent section is two empty lists;
+/// - the Sapling spends and outputs, again prefixed by their lengths, in the
+///   encodings of `SpendDescription::to_bytes` and
+///   `OutputDescription::to_bytes`, followed by `valueBalance` as a
+///   little-endian `i64` and the 64-byte `bindingSig`.
+#[derive(Debug)]
+pub struct Transaction {
+    pub header: TxHeader,
+    pub transparent_bundle: Option<TransparentBundle>,
+    pub sapling_bundle: SaplingBundle,
+}
+
+impl Transaction {
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let header = &self.header;
+        writer.write_all(&(header.version | OVERWINTERED_FLAG).to_le_bytes())?;
+        writer.write_all(&VERSION_GROUP_ID.to_le_bytes())?;
+        writer.write_all(&header.consensus_branch_id.to_le_bytes())?;
+        writer.write_all(&header.lock_time.to_le_bytes())?;
+        writer.write_all(&header.expiry_height.to_le_bytes())?;
+
+        let empty = TransparentBundle::default();
+        let transparent = self.transparent_bundle.as_ref().unwrap_or(&empty);
+        write_compact_size(&mut writer, transparent.vin.len())?;
+        for input in &transparent.vin {
+            writer.write_all(&input.prevout.txid.0)?;
+            writer.write_all(&input.prevout.n.to_le_bytes())?;
+            write_bytes(&mut writer, &input.script_sig)?;
+            writer.write_all(&input.sequence.to_le_bytes())?;
+        }
+        write_compact_size(&mut writer, transparent.vout.len())?;
+        for output in &transparent.vout {
+            output.write(&mut writer)?;
+        }
+
+        let sapling = &self.sapling_bundle;
+        write_compact_size(&mut writer, sapling.spends.len())?;
+        for spend in &sapling.spends {
+            writer.write_all(&spend.to_bytes())?;
+        }
+        write_compact_size(&mut writer, sapling.outputs.len())?;
+        for output in &sapling.outputs {
+            writer.write_all(&output.to_bytes())?;
+        }
+        writer.write_all(&sapling.value_balance.to_le_bytes())?;
+        writer.write_all(&signature_to_bytes(&sapling.binding_sig))
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = read_u32(&mut reader)?;
+        if version != TX_VERSION | OVERWINTERED_FLAG {
+            return Err(invalid_data(format!(
+                "unsupported transaction version {:#x}",
+                version
+            )));
+        }
+        let version_group_id = read_u32(&mut reader)?;
+        if version_group_id != VERSION_GROUP_ID {
+            return Err(invalid_data(format!(
+                "unsupported version group ID {:#x}",
+                version_group_id
+            )));
+        }
+        let header = TxHeader {
+            version: TX_VERSION,
+            consensus_branch_id: read_u32(&mut reader)?,
+            lock_time: read_u32(&mut reader)?,
+            expiry_height: read_u32(&mut reader)?,
+        };
+
+        let vin = (0..read_compact_size(&mut reader)?)
+            .map(|_| {
+                let mut txid = [0u8; 32];
+                reader.read_exact(&mut txid)?;
+                Ok(TxIn {
+                    prevout: OutPoint {
+                        txid: TxId(txid),
+                        n: read_u32(&mut reader)?,
+                    },
+                    script_sig: read_bytes(&mut reader)?,
+                    sequence: read_u32(&mut reader)?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let vout = (0..read_compact_size(&mut reader)?)
+            .map(|_| {
+                let mut value = [0u8; 8];
+                reader.read_exact(&mut value)?;
+                Ok(TxOut {
+                    value: u64::from_le_bytes(value),
+                    script_pubkey: read_bytes(&mut reader)?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let transparent_bundle = if vin.is_empty() && vout.is_empty() {
+            None
+        } else {
+            Some(TransparentBundle { vin, vout })
+        };
+
+        let spends = (0..read_compact_size(&mut reader)?)
+            .map(|_| {
+                let mut bytes = [0u8; SPEND_DESCRIPTION_SIZE];
+                reader.read_exact(&mut bytes)?;
+                SpendDescription::from_bytes(&bytes).ok_or_else(|| invalid_data("invalid spend"))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let outputs = (0..read_compact_size(&mut reader)?)
+            .map(|_| {
+                let mut bytes = [0u8; OUTPUT_DESCRIPTION_SIZE];
+                reader.read_exact(&mut bytes)?;
+                OutputDescription::from_bytes(&bytes).ok_or_else(|| invalid_data("invalid output"))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut value_balance = [0u8; 8];
+        reader.read_exact(&mut value_balance)?;
+        let value_balance = i64::from_le_bytes(value_balance);
+        if value_balance.unsigned_abs() > MAX_MONEY {
+            return Err(invalid_data("value balance out of range"));
+        }
+        let mut binding_sig = [0u8; 64];
+        reader.read_exact(&mut binding_sig)?;
+
+        Ok(Transaction {
+            header,
+            transparent_bundle,
+            sapling_bundle: SaplingBundle {
+                spends,
+                outputs,
+                value_balance,
+                binding_sig: signature_from_bytes(&binding_sig)
+                    .ok_or_else(|| invalid_data("invalid binding signature"))?,
+            },
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Reads a transaction that takes up all of `bytes`.
+    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Self> {
+        let tx = Self::read(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(invalid_data("trailing bytes after the transaction"));
+        }
+        Ok(tx)
+    }
+
+    /// The ZIP-244 transaction ID. It does not commit to proofs or
+    /// signatures, so it is fixed before the transaction is signed.
+    pub fn txid(&self) -> TxId {
+        TxId(sighash::txid_digest(self))
+    }
+
+    /// The digest the shielded signatures sign, given the coins the
+    /// transparent inputs spend; `None` unless there is one per input.
+    pub fn sighash(&self, spent_outputs: &[TxOut]) -> Option<[u8; 32]> {
+        sighash::shielded_sighash(self, spent_outputs)
+    }
+
+    /// Signs the spends with their `rsk`s, in order, and the bundle with
+    /// `bsk`. `spent_outputs` are the coins the transparent inputs spend. The
+    /// transaction must not change afterwards.
+    pub fn sign(
+        &mut self,
+        spent_outputs: &[TxOut],
+        spend_auth_keys: &[SecretKey],
+        bsk: &SecretKey,
+    ) -> Result<(), SignError> {
+        let spends = self.sapling_bundle.spends.len();
+        if spend_auth_keys.len() != spends {
+            return Err(SignError::WrongKeyCount { spends, keys: spend_auth_keys.len() });
+        }
+
+        let sighash = self.sighash(spent_outputs).ok_or(SignError::MissingSpentOutputs)?;
+        for (spend, rsk) in self.sapling_bundle.spends.iter_mut().zip(spend_auth_keys) {
+            spend.sign(rsk, &sighash)?;
+        }
+        self.sapling_bundle.binding_sig =
+            BindingSig::sign(binding_sig_params(), bsk, &sighash, &mut thread_rng())
+                .map_err(|e| SignError::Signing(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Checks every signature against a freshly computed sighash.
+    pub fn verify_signatures(&self, spent_outputs: &[TxOut]) -> bool {
+        let sighash = match self.sighash(spent_outputs) {
+            Some(sighash) => sighash,
+            None => return false,
+        };
+        let bundle = &self.sapling_bundle;
+
+        bundle.spends.iter().all(|spend| spend.verify_signature(&sighash))
+            && BindingSig::verify(
+                binding_sig_params(),
+                &bundle.binding_verification_key(),
+                &sighash,
+                &bundle.binding_sig,
+            )
+            .unwrap_or(false)
+    }
+
+    /// Checks the value balance, proofs and signatures.
+    pub fn verify(&self, verifier: &impl SaplingVerifier, spent_outputs: &[TxOut]) -> bool {
+        let bundle = &self.sapling_bundle;
+
+        bundle.value_balance.unsigned_abs() <= MAX_MONEY
+            && bundle.spends.iter().all(|spend| spend.verify_proof(verifier))
+            && bundle.outputs.iter().all(|output| output.verify_proof(verifier))
+            && self.verify_signatures(spent_outputs)
+    }
+}
+
+type BindingSig = schnorr::Schnorr<EdwardsProjective, Blake2b512>;
+
+// Both only live for the duration of `SaplingBundle::prove`.
+#[allow(clippy::large_enum_variant)]
+enum Unproven {
+    Spend(UnprovenSpend),
+    Output(UnprovenOutput),
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Proven {
+    Spend(SpendDescription),
+    Output(OutputDescription),
+}
+
+impl SaplingBundle {
+    /// Proves all the spends and outputs of a transaction concurrently on
+    /// `pool`. The descriptions keep their order; `binding_sig` is left for
+    /// `Transaction::sign`.
+    pub fn prove<P, H>(
+        pool: &ProvingPool,
+        prover: &P,
+        spends: Vec<UnprovenSpend>,
+        outputs: Vec<UnprovenOutput>,
+        value_balance: i64,
+    ) -> Result<Self, SynthesisError>
+    where
+        P: SpendProver<H> + OutputProver + Sync,
+        H: MerkleHash,
+    {
+        let descriptions = spends
+            .into_iter()
+            .map(Unproven::Spend)
+            .chain(outputs.into_iter().map(Unproven::Output))
+            .collect();
+        let proven = pool.map(descriptions, |description| match description {
+            Unproven::Spend(spend) => spend.prove(prover).map(Proven::Spend),
+            Unproven::Output(output) => output.prove(prover).map(Proven::Output),
+        });
+
+        let mut bundle = SaplingBundle {
+            spends: vec![],
+            outputs: vec![],
+            value_balance,
+            binding_sig: Signature::default(),
+        };
+        for description in proven {
+            match description? {
+                Proven::Spend(spend) => bundle.spends.push(spend),
+                Proven::Output(output) => bundle.outputs.push(output),
+            }
+        }
+        Ok(bundle)
+    }
+
+    /// `bvk`: the spends' value commitments minus the outputs' and minus a
+    /// commitment to `value_balance` without randomness. It is `[bsk] R`
+    /// exactly when the values balance.
+    fn binding_verification_key(&self) -> schnorr::PublicKey<EdwardsProjective> {
+        let spends: EdwardsProjective = self.spends.iter().map(|spend| spend.public_inputs().cv).sum();
+        let outputs: EdwardsProjective = self.outputs.iter().map(|output| output.public_inputs().cv).sum();
+        let balance = group_hash::calc_v_sapling() * Fr::from(self.value_balance);
+
+        (spends - outputs - balance).into_affine()
+    }
+}
+
+pub(crate) fn read_u32<R: Read>(mut reader: R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Bitcoin's variable-length integer, limited to `u32` lengths.
+pub(crate) fn write_compact_size<W: Write>(mut writer: W, n: usize) -> io::Result<()> {
+    match n {
+        0..=0xfc => writer.write_all(&[n as u8]),
+        0xfd..=0xffff => {
+            writer.write_all(&[0xfd])?;
+            writer.write_all(&(n as u16).to_le_bytes())
+        }
+        _ => {
+            let n = u32::try_from(n).map_err(|_| invalid_data("length does not fit in a u32"))?;
+            writer.write_all(&[0xfe])?;
+            writer.write_all(&n.to_le_bytes())
+        }
+    }
+}
+
+/// Reads a `CompactSize`, rejecting encodings that are not the shortest.
+pub(crate) fn read_compact_size<R: Read>(mut reader: R) -> io::Result<usize> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    let (n, min) = match tag[0] {
+        0xfd => {
+            let mut bytes = [0u8; 2];
+            reader.read_exact(&mut bytes)?;
+            (u16::from_le_bytes(bytes) as usize, 0xfd)
+        }
+        0xfe => (read_u32(&mut reader)? as usize, 0x1_0000),
+        0xff => return Err(invalid_data("CompactSize too large")),
+        n => (n as usize, 0),
+    };
+    if n < min {
+        return Err(invalid_data("non-canonical CompactSize"));
+    }
+    Ok(n)
+}
+
+pub(crate) fn write_bytes<W: Write>(mut writer: W, bytes: &[u8]) -> io::Result<()> {
+    write_compact_size(&mut writer, bytes.len())?;
+    writer.write_all(bytes)
+}
+
+pub(crate) fn read_bytes<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut reader = reader;
+    let len = read_compact_size(&mut reader)?;
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::output_description::test::unproven_output;
+    use crate::commitment::binding_signing_key;
+    use crate::commitment::NoteCommitmentMode;
+    use crate::merkle::PoseidonMerkleHash;
+    use crate::prf::prf_expand::IvkMode;
+    use crate::prover::{LocalProver, MockProver, MockVerifier};
+    use crate::spend_description::test::unproven_spend;
+    use crate::spend_description::NullifierMode;
+    use rand::thread_rng;
+
+    /// Spends 13 zatoshis into two outputs of 10, with `value_balance`
+    /// making up the difference when it is -7.
+    pub fn unsigned_transaction(value_balance: i64) -> (Transaction, SecretKey, SecretKey) {
+        let (spend, rsk, spend_rcv) = unproven_spend();
+        let (output_1, output_rcv_1) = unproven_output();
+        let (output_2, output_rcv_2) = unproven_output();
+        let bsk = binding_signing_key(&[spend_rcv], &[output_rcv_1, output_rcv_2]);
+
+        let tx = Transaction {
+            header: TxHeader::new(NU5_BRANCH_ID, 0, 1_000_000),
+            transparent_bundle: None,
+            sapling_bundle: SaplingBundle::prove::<_, PoseidonMerkleHash>(
+                &ProvingPool::new(2),
+                &MockProver,
+                vec![spend],
+                vec![output_1, output_2],
+                value_balance,
+            )
+            .unwrap(),
+        };
+        (tx, rsk, bsk)
+    }
+
+    pub fn sample_transaction() -> Transaction {
+        let (mut tx, rsk, bsk) = unsigned_transaction(-7);
+        tx.sign(&[], &[rsk], &bsk).unwrap();
+        tx
+    }
+
+    #[test]
+    pub fn test_signed_transaction() {
+        let (mut tx, rsk, bsk) = unsigned_transaction(-7);
+        let txid = tx.txid();
+        assert!(!tx.verify_signatures(&[]));
+        assert_eq!(tx.sign(&[], &[], &bsk), Err(SignError::WrongKeyCount { spends: 1, keys: 0 }));
+
+        tx.sign(&[], &[rsk], &bsk).unwrap();
+        assert_eq!(tx.txid(), txid);
+        assert!(tx.verify(&MockVerifier, &[]));
+        assert!(Transaction::from_bytes(&tx.to_bytes()).unwrap().verify(&MockVerifier, &[]));
+
+        tx.header.lock_time = 1;
+        assert!(!tx.verify_signatures(&[]));
+    }
+
+    #[test]
+    pub fn test_prove_bundle_on_pool() {
+        let prover = LocalProver::<PoseidonMerkleHash>::setup(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+            &mut thread_rng(),
+        );
+        let (spend, rsk, spend_rcv) = unproven_spend();
+        let (output, output_rcv) = unproven_output();
+        let bsk = binding_signing_key(&[spend_rcv], &[output_rcv]);
+
+        let mut tx = Transaction {
+            header: TxHeader::new(NU5_BRANCH_ID, 0, 1_000_000),
+            transparent_bundle: None,
+            sapling_bundle: SaplingBundle::prove(&ProvingPool::new(2), &prover, vec![spend], vec![output], 3)
+                .unwrap(),
+        };
+        tx.sign(&[], &[rsk], &bsk).unwrap();
+        assert!(tx.verify(&prover.verifier(), &[]));
+    }
+
+    #[test]
+    pub fn test_unbalanced_transaction() {
+        let (mut tx, rsk, bsk) = unsigned_transaction(-6);
+        tx.sign(&[], &[rsk], &bsk).unwrap();
+        assert!(tx.sapling_bundle.spends[0].verify_signature(&tx.sighash(&[]).unwrap()));
+        assert!(!tx.verify_signatures(&[]));
+    }
+
+    #[test]
+    pub fn test_spend_cannot_be_moved() {
+        let tx = sample_transaction();
+        let mut other = sample_transaction();
+        other.sapling_bundle.spends = tx.sapling_bundle.spends;
+        assert!(!other.verify_signatures(&[]));
+    }
+
+    #[test]
+    pub fn test_sign_transparent_inputs() {
+        let (mut tx, rsk, bsk) = unsigned_transaction(-7);
+        tx.transparent_bundle = Some(TransparentBundle {
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: TxId([7; 32]),
+                    n: 1,
+                },
+                script_sig: vec![],
+                sequence: u32::MAX,
+            }],
+            vout: vec![],
+        });
+        let coins = vec![TxOut {
+            value: 5,
+            script_pubkey: vec![0x76, 0xa9],
+        }];
+        assert_eq!(tx.sighash(&[]), None);
+        let keys = [rsk];
+        assert_eq!(tx.sign(&[], &keys, &bsk), Err(SignError::MissingSpentOutputs));
+        let sighash = tx.sighash(&coins).unwrap();
+        assert_ne!(sighash, tx.txid().0);
+
+        tx.sign(&coins, &keys, &bsk).unwrap();
+        assert!(tx.verify_signatures(&coins));
+        assert!(!tx.verify_signatures(&[]));
+
+        // The signatures commit to the value and script of the spent coin.
+        let other = TxOut { value: 6, ..coins[0].clone() };
+        assert!(!tx.verify_signatures(&[other]));
+        let other = TxOut { script_pubkey: vec![0x76], ..coins[0].clone() };
+        assert!(!tx.verify_signatures(&[other]));
+    }
+
+    #[test]
+    pub fn test_value_balance_range() {
+        let mut tx = sample_transaction();
+        for value_balance in [-(MAX_MONEY as i64) - 1, MAX_MONEY as i64 + 1, i64::MIN] {
+            tx.sapling_bundle.value_balance = value_balance;
+            assert!(!tx.verify(&MockVerifier, &[]));
+            assert!(Transaction::from_bytes(&tx.to_bytes()).is_err());
+        }
+        tx.sapling_bundle.value_balance = -(MAX_MONEY as i64);
+        assert!(Transaction::from_bytes(&tx.to_bytes()).is_ok());
+    }
+
+    #[test]
+    pub fn test_transaction_round_trip() {
+        let mut tx = sample_transaction();
+        let bytes = tx.to_bytes();
+        assert_eq!(
+            bytes.len(),
+            20 + 2 + 1 + SPEND_DESCRIPTION_SIZE + 1 + 2 * OUTPUT_DESCRIPTION_SIZE + 8 + 64
+        );
+        let decoded = Transaction::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.txid(), tx.txid());
+        assert!(decoded.transparent_bundle.is_none());
+
+        tx.transparent_bundle = Some(TransparentBundle {
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: TxId([7; 32]),
+                    n: 1,
+                },
+                script_sig: vec![0xab; 300],
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value: 5,
+                script_pubkey: vec![0x76, 0xa9],
+            }],
+        });
+        let decoded = Transaction::from_bytes(&tx.to_bytes()).unwrap();
+        assert_eq!(decoded.transparent_bundle, tx.transparent_bundle);
+        assert_eq!(decoded.txid(), tx.txid());
+    }
+
+    #[test]
+    pub fn test_txid_commits_to_the_header() {
+        let mut tx = sample_transaction();
+        let txid = tx.txid();
+        tx.header.lock_time = 1;
+        assert_ne!(tx.txid(), txid);
+        assert_eq!(format!("{}", TxId([1; 32])), "01".repeat(32));
+    }
+
+    #[test]
+    pub fn test_malformed_transactions_are_rejected() {
+        let bytes = sample_transaction().to_bytes();
+
+        let mut unsupported = bytes.clone();
+        unsupported[0] = 4;
+        assert!(Transaction::from_bytes(&unsupported).is_err());
+
+        let mut wrong_group = bytes.clone();
+        wrong_group[4] ^= 1;
+        assert!(Transaction::from_bytes(&wrong_group).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Transaction::from_bytes(&trailing).is_err());
+
+        assert!(Transaction::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // One spend, as a three-byte CompactSize.
+        let mut non_canonical = bytes[..22].to_vec();
+        non_canonical.extend([0xfd, 1, 0]);
+        non_canonical.extend(&bytes[23..]);
+        assert!(Transaction::from_bytes(&non_canonical).is_err());
+    }
+
+    #[test]
+    pub fn test_compact_size() {
+        for n in [0, 0xfc, 0xfd, 0xffff, 0x1_0000] {
+            let mut bytes = vec![];
+            write_compact_size(&mut bytes, n).unwrap();
+            assert_eq!(read_compact_size(&bytes[..]).unwrap(), n);
+        }
+    }
+}
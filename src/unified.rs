@@ -0,0 +1,551 @@
+//! ZIP-316 unified addresses and viewing keys. A unified encoding bundles
+//! one item per receiver or key type, each tagged with a typecode, then
+//! jumbles the bundle with F4Jumble and encodes it with Bech32m:
+//!
+//! ```text
+//! u1...           mainnet address     utest1...       testnet address
+//! uview1...       full viewing key    uviewtest1...
+//! uivk1...        incoming viewing    uivktest1...
+//! ```
+//!
+//! Only Sapling items are interpreted. Items of other types are checked for
+//! their length if the typecode is known, and kept verbatim either way.
+
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use ark_ed_on_bls12_381::Fr;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use bech32::{FromBase32, ToBase32, Variant};
+
+use crate::address::{Network, PaymentAddress};
+use crate::group_hash::{abst_j, is_small_order};
+use crate::keygen::{IncomingViewingKey, KeyChain, OutgoingViewKey, PublicKey};
+use crate::prf::prf_expand::{Crh, IvkMode};
+use crate::transaction::{read_bytes, read_compact_size, write_compact_size};
+
+pub const P2PKH: u32 = 0x00;
+pub const P2SH: u32 = 0x01;
+pub const SAPLING: u32 = 0x02;
+pub const ORCHARD: u32 = 0x03;
+
+/// The HRP, zero-padded to this length, ends the jumbled bytes.
+const PADDING_SIZE: usize = 16;
+
+pub const SAPLING_FVK_SIZE: usize = 128;
+pub const SAPLING_IVK_SIZE: usize = 64;
+
+/// One receiver or key of a unified encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    pub typecode: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnifiedError {
+    /// Not Bech32m, or the jumbled bytes or their padding are malformed.
+    InvalidEncoding,
+    /// The HRP is not the one of the expected kind and network.
+    WrongHrp(String),
+    /// Items that are not in increasing order of typecode, which also
+    /// rules out duplicates.
+    ItemsOutOfOrder,
+    /// A typecode that is not allowed, or whose data is invalid.
+    InvalidItem(u32),
+    /// No item other than transparent ones.
+    NoShieldedItem,
+    /// Both a P2PKH and a P2SH receiver.
+    BothTransparentReceivers,
+}
+
+impl fmt::Display for UnifiedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnifiedError::InvalidEncoding => write!(f, "invalid unified encoding"),
+            UnifiedError::WrongHrp(hrp) => write!(f, "unexpected human-readable part {}", hrp),
+            UnifiedError::ItemsOutOfOrder => write!(f, "items are not ordered by typecode"),
+            UnifiedError::InvalidItem(typecode) => write!(f, "invalid item of type {:#x}", typecode),
+            UnifiedError::NoShieldedItem => write!(f, "no shielded item"),
+            UnifiedError::BothTransparentReceivers => {
+                write!(f, "both a P2PKH and a P2SH receiver")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UnifiedError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Address,
+    FullViewingKey,
+    IncomingViewingKey,
+}
+
+impl Kind {
+    fn hrp(self, network: Network) -> &'static str {
+        match (self, network) {
+            (Kind::Address, Network::Main) => "u",
+            (Kind::Address, Network::Test) => "utest",
+            (Kind::FullViewingKey, Network::Main) => "uview",
+            (Kind::FullViewingKey, Network::Test) => "uviewtest",
+            (Kind::IncomingViewingKey, Network::Main) => "uivk",
+            (Kind::IncomingViewingKey, Network::Test) => "uivktest",
+        }
+    }
+
+    /// The size of items of a known typecode, or `None` if the typecode is
+    /// not allowed for this kind. Unknown typecodes may have any size.
+    fn item_size(self, typecode: u32) -> Option<Option<usize>> {
+        let size = match (self, typecode) {
+            (Kind::Address, P2PKH | P2SH) => 20,
+            (Kind::Address, SAPLING | ORCHARD) => 43,
+            (Kind::FullViewingKey | Kind::IncomingViewingKey, P2SH) => return None,
+            (Kind::FullViewingKey | Kind::IncomingViewingKey, P2PKH) => 65,
+            (Kind::FullViewingKey, SAPLING) => SAPLING_FVK_SIZE,
+            (Kind::FullViewingKey, ORCHARD) => 96,
+            (Kind::IncomingViewingKey, SAPLING) => SAPLING_IVK_SIZE,
+            (Kind::IncomingViewingKey, ORCHARD) => 64,
+            _ => return Some(None),
+        };
+        Some(Some(size))
+    }
+
+    /// Sorts `items` and checks the rules common to all kinds. Sapling items
+    /// are checked by `validate_sapling`.
+    fn validate(
+        self,
+        mut items: Vec<Item>,
+        validate_sapling: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<Item>, UnifiedError> {
+        items.sort_by_key(|item| item.typecode);
+        if items.windows(2).any(|w| w[0].typecode == w[1].typecode) {
+            return Err(UnifiedError::ItemsOutOfOrder);
+        }
+
+        for item in &items {
+            let valid = match self.item_size(item.typecode) {
+                None => false,
+                Some(Some(size)) if item.data.len() != size => false,
+                _ => item.typecode != SAPLING || validate_sapling(&item.data),
+            };
+            if !valid {
+                return Err(UnifiedError::InvalidItem(item.typecode));
+            }
+        }
+
+        let has = |typecode| items.iter().any(|item| item.typecode == typecode);
+        if has(P2PKH) && has(P2SH) {
+            return Err(UnifiedError::BothTransparentReceivers);
+        }
+        if items.iter().all(|item| item.typecode == P2PKH || item.typecode == P2SH) {
+            return Err(UnifiedError::NoShieldedItem);
+        }
+        Ok(items)
+    }
+
+    fn padding(self, network: Network) -> [u8; PADDING_SIZE] {
+        let hrp = self.hrp(network).as_bytes();
+        let mut padding = [0u8; PADDING_SIZE];
+        padding[..hrp.len()].copy_from_slice(hrp);
+        padding
+    }
+
+    fn encode(self, items: &[Item], network: Network) -> String {
+        let mut bytes = vec![];
+        for item in items {
+            write_compact_size(&mut bytes, item.typecode as usize).unwrap();
+            write_compact_size(&mut bytes, item.data.len()).unwrap();
+            bytes.extend_from_slice(&item.data);
+        }
+        bytes.extend_from_slice(&self.padding(network));
+
+        let jumbled = f4jumble::f4jumble(&bytes).expect("items fit in an F4Jumble message");
+        bech32::encode(self.hrp(network), jumbled.to_base32(), Variant::Bech32m)
+            .expect("valid human-readable part")
+    }
+
+    /// Decodes the items of `encoded`, in the order they were encoded.
+    fn decode(self, encoded: &str, network: Network) -> Result<Vec<Item>, UnifiedError> {
+        let (hrp, data, variant) =
+            bech32::decode(encoded).map_err(|_| UnifiedError::InvalidEncoding)?;
+        if variant != Variant::Bech32m {
+            return Err(UnifiedError::InvalidEncoding);
+        }
+        if hrp != self.hrp(network) {
+            return Err(UnifiedError::WrongHrp(hrp));
+        }
+
+        let jumbled = Vec::<u8>::from_base32(&data).map_err(|_| UnifiedError::InvalidEncoding)?;
+        let bytes = f4jumble::f4jumble_inv(&jumbled).map_err(|_| UnifiedError::InvalidEncoding)?;
+        let (bytes, padding) = bytes.split_at(bytes.len() - PADDING_SIZE);
+        if padding != self.padding(network) {
+            return Err(UnifiedError::InvalidEncoding);
+        }
+
+        let mut reader = Cursor::new(bytes);
+        let mut items: Vec<Item> = vec![];
+        while (reader.position() as usize) < bytes.len() {
+            let item = read_item(&mut reader).map_err(|_| UnifiedError::InvalidEncoding)?;
+            if items.last().is_some_and(|last| last.typecode >= item.typecode) {
+                return Err(UnifiedError::ItemsOutOfOrder);
+            }
+            items.push(item);
+        }
+        Ok(items)
+    }
+}
+
+fn read_item<R: Read>(mut reader: R) -> std::io::Result<Item> {
+    let typecode = read_compact_size(&mut reader)? as u32;
+    let data = read_bytes(&mut reader)?;
+    Ok(Item { typecode, data })
+}
+
+fn sapling_item(items: &[Item]) -> Option<&[u8]> {
+    items
+        .iter()
+        .find(|item| item.typecode == SAPLING)
+        .map(|item| &item.data[..])
+}
+
+/// A unified address. Its Sapling receiver, if any, is a valid
+/// `PaymentAddress`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnifiedAddress {
+    items: Vec<Item>,
+}
+
+impl From<&PaymentAddress> for UnifiedAddress {
+    fn from(address: &PaymentAddress) -> Self {
+        UnifiedAddress {
+            items: vec![Item { typecode: SAPLING, data: address.to_bytes().to_vec() }],
+        }
+    }
+}
+
+impl UnifiedAddress {
+    pub fn new(items: Vec<Item>) -> Result<Self, UnifiedError> {
+        let items = Kind::Address.validate(items, |data| {
+            PaymentAddress::from_bytes(data.try_into().unwrap()).is_some()
+        })?;
+        Ok(UnifiedAddress { items })
+    }
+
+    /// The receivers, ordered by typecode.
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn sapling(&self) -> Option<PaymentAddress> {
+        sapling_item(&self.items)
+            .map(|data| PaymentAddress::from_bytes(data.try_into().unwrap()).expect("checked in new"))
+    }
+
+    pub fn encode(&self, network: Network) -> String {
+        Kind::Address.encode(&self.items, network)
+    }
+
+    pub fn decode(encoded: &str, network: Network) -> Result<Self, UnifiedError> {
+        Self::new(Kind::Address.decode(encoded, network)?)
+    }
+}
+
+/// The Sapling item of a unified full viewing key: `ak`, `nk`, `ovk` and the
+/// ZIP-32 diversifier key `dk`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaplingFullViewingKey {
+    pub ak: PublicKey,
+    pub nk: PublicKey,
+    pub ovk: OutgoingViewKey,
+    pub dk: [u8; 32],
+}
+
+impl From<&KeyChain<'_>> for SaplingFullViewingKey {
+    fn from(kc: &KeyChain<'_>) -> Self {
+        SaplingFullViewingKey {
+            ak: kc.ak.clone(),
+            nk: kc.nk.clone(),
+            ovk: kc.ovk,
+            dk: kc.dk(),
+        }
+    }
+}
+
+impl SaplingFullViewingKey {
+    /// Decodes `to_bytes`, checking that `ak` and `nk` are canonical and that
+    /// `ak` is not of small order.
+    pub fn from_bytes(bytes: &[u8; SAPLING_FVK_SIZE]) -> Option<Self> {
+        let ak = abst_j(bytes[..32].try_into().unwrap())?;
+        if is_small_order(&ak) {
+            return None;
+        }
+        let nk = abst_j(bytes[32..64].try_into().unwrap())?;
+
+        Some(SaplingFullViewingKey {
+            ak: PublicKey(ak),
+            nk: PublicKey(nk),
+            ovk: bytes[64..96].try_into().unwrap(),
+            dk: bytes[96..].try_into().unwrap(),
+        })
+    }
+
+    /// `CRH^ivk` of `ak` and `nk`, as `KeyChain` computes it.
+    pub fn ivk(&self, ivk_mode: IvkMode) -> IncomingViewingKey {
+        IncomingViewingKey(match ivk_mode {
+            IvkMode::Blake2s => Crh::calc(&self.ak.to_repr_j(), &self.nk.to_repr_j()),
+            IvkMode::Poseidon => Crh::calc_poseidon(&self.ak.0.y, &self.nk.0.y),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; SAPLING_FVK_SIZE] {
+        let mut bytes = [0u8; SAPLING_FVK_SIZE];
+        bytes[..32].copy_from_slice(&self.ak.to_repr_j());
+        bytes[32..64].copy_from_slice(&self.nk.to_repr_j());
+        bytes[64..96].copy_from_slice(&self.ovk);
+        bytes[96..].copy_from_slice(&self.dk);
+        bytes
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnifiedFullViewingKey {
+    items: Vec<Item>,
+}
+
+impl From<&SaplingFullViewingKey> for UnifiedFullViewingKey {
+    fn from(fvk: &SaplingFullViewingKey) -> Self {
+        UnifiedFullViewingKey {
+            items: vec![Item { typecode: SAPLING, data: fvk.to_bytes().to_vec() }],
+        }
+    }
+}
+
+impl UnifiedFullViewingKey {
+    pub fn new(items: Vec<Item>) -> Result<Self, UnifiedError> {
+        let items = Kind::FullViewingKey.validate(items, |data| {
+            SaplingFullViewingKey::from_bytes(data.try_into().unwrap()).is_some()
+        })?;
+        Ok(UnifiedFullViewingKey { items })
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn sapling(&self) -> Option<SaplingFullViewingKey> {
+        sapling_item(&self.items).map(|data| {
+            SaplingFullViewingKey::from_bytes(data.try_into().unwrap()).expect("checked in new")
+        })
+    }
+
+    pub fn encode(&self, network: Network) -> String {
+        Kind::FullViewingKey.encode(&self.items, network)
+    }
+
+    pub fn decode(encoded: &str, network: Network) -> Result<Self, UnifiedError> {
+        Self::new(Kind::FullViewingKey.decode(encoded, network)?)
+    }
+}
+
+/// The Sapling item of a unified incoming viewing key: `dk`, then `ivk`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaplingIncomingViewingKey {
+    pub dk: [u8; 32],
+    pub ivk: Fr,
+}
+
+impl From<&KeyChain<'_>> for SaplingIncomingViewingKey {
+    fn from(kc: &KeyChain<'_>) -> Self {
+        SaplingIncomingViewingKey { dk: kc.dk(), ivk: kc.ivk.0 }
+    }
+}
+
+impl From<&SaplingIncomingViewingKey> for IncomingViewingKey {
+    fn from(key: &SaplingIncomingViewingKey) -> Self {
+        IncomingViewingKey(key.ivk)
+    }
+}
+
+impl SaplingIncomingViewingKey {
+    /// Decodes `to_bytes`, checking that `ivk` is a canonical scalar.
+    pub fn from_bytes(bytes: &[u8; SAPLING_IVK_SIZE]) -> Option<Self> {
+        Some(SaplingIncomingViewingKey {
+            dk: bytes[..32].try_into().unwrap(),
+            ivk: Fr::deserialize_compressed(&bytes[32..]).ok()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; SAPLING_IVK_SIZE] {
+        let mut bytes = [0u8; SAPLING_IVK_SIZE];
+        bytes[..32].copy_from_slice(&self.dk);
+        self.ivk.serialize_compressed(&mut bytes[32..]).unwrap();
+        bytes
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnifiedIncomingViewingKey {
+    items: Vec<Item>,
+}
+
+impl From<&SaplingIncomingViewingKey> for UnifiedIncomingViewingKey {
+    fn from(ivk: &SaplingIncomingViewingKey) -> Self {
+        UnifiedIncomingViewingKey {
+            items: vec![Item { typecode: SAPLING, data: ivk.to_bytes().to_vec() }],
+        }
+    }
+}
+
+impl UnifiedIncomingViewingKey {
+    pub fn new(items: Vec<Item>) -> Result<Self, UnifiedError> {
+        let items = Kind::IncomingViewingKey.validate(items, |data| {
+            SaplingIncomingViewingKey::from_bytes(data.try_into().unwrap()).is_some()
+        })?;
+        Ok(UnifiedIncomingViewingKey { items })
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn sapling(&self) -> Option<SaplingIncomingViewingKey> {
+        sapling_item(&self.items).map(|data| {
+            SaplingIncomingViewingKey::from_bytes(data.try_into().unwrap()).expect("checked in new")
+        })
+    }
+
+    pub fn encode(&self, network: Network) -> String {
+        Kind::IncomingViewingKey.encode(&self.items, network)
+    }
+
+    pub fn decode(encoded: &str, network: Network) -> Result<Self, UnifiedError> {
+        Self::new(Kind::IncomingViewingKey.decode(encoded, network)?)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::fixtures::SK;
+
+    #[test]
+    pub fn test_unified_address() {
+        let pa = PaymentAddress::from(KeyChain::from(SK));
+        let unknown = Item { typecode: 0x42, data: vec![7; 5] };
+        let transparent = Item { typecode: P2PKH, data: vec![1; 20] };
+        let ua = UnifiedAddress::new(vec![
+            unknown.clone(),
+            Item { typecode: SAPLING, data: pa.to_bytes().to_vec() },
+            transparent.clone(),
+        ])
+        .unwrap();
+        assert_eq!(ua.items()[0], transparent);
+        assert_eq!(ua.items()[2], unknown);
+
+        let encoded = ua.encode(Network::Main);
+        assert!(encoded.starts_with("u1"));
+        let decoded = UnifiedAddress::decode(&encoded, Network::Main).unwrap();
+        assert_eq!(decoded, ua);
+        assert_eq!(decoded.sapling(), Some(pa.clone()));
+        assert_eq!(
+            UnifiedAddress::decode(&encoded, Network::Test),
+            Err(UnifiedError::WrongHrp("u".into()))
+        );
+
+        let test = UnifiedAddress::from(&pa).encode(Network::Test);
+        assert!(test.starts_with("utest1"));
+        assert_eq!(UnifiedAddress::decode(&test, Network::Test).unwrap().sapling(), Some(pa.clone()));
+
+        let mut corrupted = encoded.into_bytes();
+        corrupted[10] = if corrupted[10] == b'q' { b'p' } else { b'q' };
+        assert_eq!(
+            UnifiedAddress::decode(std::str::from_utf8(&corrupted).unwrap(), Network::Main),
+            Err(UnifiedError::InvalidEncoding)
+        );
+    }
+
+    #[test]
+    pub fn test_unified_address_rules() {
+        let pa = PaymentAddress::from(KeyChain::from(SK));
+        let sapling = Item { typecode: SAPLING, data: pa.to_bytes().to_vec() };
+
+        let mut small_order = pa.to_bytes();
+        small_order[11..].copy_from_slice(&[0; 32]);
+        small_order[11] = 1;
+        let invalid = Item { typecode: SAPLING, data: small_order.to_vec() };
+        assert_eq!(UnifiedAddress::new(vec![invalid]), Err(UnifiedError::InvalidItem(SAPLING)));
+
+        let short = Item { typecode: ORCHARD, data: vec![0; 42] };
+        assert_eq!(UnifiedAddress::new(vec![short]), Err(UnifiedError::InvalidItem(ORCHARD)));
+        assert_eq!(
+            UnifiedAddress::new(vec![sapling.clone(), sapling.clone()]),
+            Err(UnifiedError::ItemsOutOfOrder)
+        );
+        assert_eq!(
+            UnifiedAddress::new(vec![Item { typecode: P2SH, data: vec![0; 20] }]),
+            Err(UnifiedError::NoShieldedItem)
+        );
+        assert_eq!(
+            UnifiedAddress::new(vec![
+                sapling.clone(),
+                Item { typecode: P2PKH, data: vec![0; 20] },
+                Item { typecode: P2SH, data: vec![0; 20] },
+            ]),
+            Err(UnifiedError::BothTransparentReceivers)
+        );
+
+        // Items must be encoded in order of typecode.
+        let mut bytes = vec![];
+        for item in [&sapling, &Item { typecode: P2PKH, data: vec![0; 20] }] {
+            write_compact_size(&mut bytes, item.typecode as usize).unwrap();
+            write_compact_size(&mut bytes, item.data.len()).unwrap();
+            bytes.extend_from_slice(&item.data);
+        }
+        bytes.extend_from_slice(&Kind::Address.padding(Network::Main));
+        let jumbled = f4jumble::f4jumble(&bytes).unwrap();
+        let encoded = bech32::encode("u", jumbled.to_base32(), Variant::Bech32m).unwrap();
+        assert_eq!(
+            UnifiedAddress::decode(&encoded, Network::Main),
+            Err(UnifiedError::ItemsOutOfOrder)
+        );
+    }
+
+    #[test]
+    pub fn test_item_length_is_bounded_by_the_input() {
+        // A 4 GiB item with a single byte of data.
+        let bytes = [SAPLING as u8, 0xfe, 0xff, 0xff, 0xff, 0xff, 0];
+        assert!(read_item(&bytes[..]).is_err());
+
+        let bytes = [SAPLING as u8, 1, 7];
+        assert_eq!(read_item(&bytes[..]).unwrap(), Item { typecode: SAPLING, data: vec![7] });
+    }
+
+    #[test]
+    pub fn test_unified_viewing_keys() {
+        let kc = KeyChain::from(SK);
+
+        let fvk = SaplingFullViewingKey::from(&kc);
+        let ufvk = UnifiedFullViewingKey::from(&fvk);
+        let encoded = ufvk.encode(Network::Main);
+        assert!(encoded.starts_with("uview1"));
+        let decoded = UnifiedFullViewingKey::decode(&encoded, Network::Main).unwrap();
+        assert_eq!(decoded.sapling().unwrap().ivk(kc.ivk_mode), IncomingViewingKey::from(&kc));
+        assert_eq!(decoded.sapling(), Some(fvk));
+        assert!(UnifiedAddress::decode(&encoded, Network::Main).is_err());
+
+        let ivk = SaplingIncomingViewingKey::from(&kc);
+        let uivk = UnifiedIncomingViewingKey::from(&ivk);
+        let encoded = uivk.encode(Network::Test);
+        assert!(encoded.starts_with("uivktest1"));
+        let decoded = UnifiedIncomingViewingKey::decode(&encoded, Network::Test).unwrap();
+        assert_eq!(decoded.sapling(), Some(ivk));
+
+        let p2sh = Item { typecode: P2SH, data: vec![0; 65] };
+        assert_eq!(
+            UnifiedIncomingViewingKey::new(vec![p2sh]),
+            Err(UnifiedError::InvalidItem(P2SH))
+        );
+    }
+}
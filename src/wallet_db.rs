@@ -0,0 +1,930 @@
+//! A wallet database in an SQLite file, behind the `sqlite` feature.
+//!
+//! Accounts are stored as unified full viewing keys, so the file never holds
+//! spending keys. Each scanned block is applied in a single SQLite
+//! transaction together with the record of its height, so a crash leaves the
+//! wallet at the end of the last complete block, from which scanning resumes.
+
+use std::fmt;
+use std::path::Path;
+
+use ark_ed_on_bls12_381::Fr;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::address::{Network, PaymentAddress};
+use crate::circuit::ConstraintF;
+use crate::commitment::NoteCommitmentMode;
+use crate::group_hash::{abst_j, repr_j};
+use crate::memo::{Memo, MEMO_SIZE};
+use crate::note::{
+    Balance, BlockHeight, MerklePath, NoteStatus, NoteValue, ReceivedNote, SpendingNotes,
+};
+use crate::merkle::{CommitmentTree, MerkleHash};
+use crate::note_encryption::NotePlaintext;
+use crate::prf::prf_expand::IvkMode;
+use crate::scan::{AccountId, ScannedBlock, ScanningAccount, Scanner};
+use crate::spend_description::{Nullifier, NullifierMode};
+use crate::transaction::{Transaction, TxId};
+use crate::unified::{SaplingFullViewingKey, UnifiedFullViewingKey};
+
+/// Witnesses older than this many blocks are dropped, except for the latest
+/// of each note. `truncate_to_height` can rewind this far without losing the
+/// witnesses of notes that were received before.
+pub const WITNESS_RETENTION: BlockHeight = 100;
+
+/// Applied in order; `PRAGMA user_version` is the number already applied.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE settings (
+        network TEXT NOT NULL
+    );
+    CREATE TABLE accounts (
+        id INTEGER PRIMARY KEY,
+        ufvk TEXT NOT NULL UNIQUE,
+        birthday_height INTEGER NOT NULL
+    );
+    CREATE TABLE addresses (
+        account_id INTEGER NOT NULL REFERENCES accounts(id),
+        diversifier BLOB NOT NULL,
+        address TEXT NOT NULL,
+        PRIMARY KEY (account_id, diversifier)
+    );
+    CREATE TABLE blocks (
+        height INTEGER PRIMARY KEY,
+        hash BLOB NOT NULL
+    );
+    CREATE TABLE transactions (
+        id INTEGER PRIMARY KEY,
+        txid BLOB NOT NULL UNIQUE,
+        raw BLOB,
+        mined_height INTEGER
+    );
+    CREATE TABLE received_notes (
+        id INTEGER PRIMARY KEY,
+        account_id INTEGER NOT NULL REFERENCES accounts(id),
+        tx_id INTEGER NOT NULL REFERENCES transactions(id),
+        output_index INTEGER NOT NULL,
+        diversifier BLOB NOT NULL,
+        value INTEGER NOT NULL,
+        rcm BLOB NOT NULL,
+        memo BLOB NOT NULL,
+        cm BLOB NOT NULL,
+        position INTEGER NOT NULL UNIQUE,
+        nullifier BLOB NOT NULL UNIQUE,
+        height INTEGER NOT NULL,
+        spent_in INTEGER REFERENCES transactions(id),
+        UNIQUE (tx_id, output_index)
+    );
+    CREATE TABLE witnesses (
+        note_id INTEGER NOT NULL REFERENCES received_notes(id) ON DELETE CASCADE,
+        height INTEGER NOT NULL,
+        witness BLOB NOT NULL,
+        PRIMARY KEY (note_id, height)
+    );
+    CREATE TABLE sent_outputs (
+        tx_id INTEGER NOT NULL REFERENCES transactions(id),
+        output_index INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        value INTEGER NOT NULL,
+        memo BLOB NOT NULL,
+        PRIMARY KEY (tx_id, output_index)
+    );
+"#, r#"
+    ALTER TABLE blocks ADD COLUMN sapling_tree BLOB;
+    -- The tree after the blocks already scanned cannot be recovered, so
+    -- they are scanned again from a new `init_chain_state`. Sent
+    -- transactions are kept.
+    DELETE FROM witnesses;
+    DELETE FROM received_notes;
+    DELETE FROM blocks;
+    UPDATE transactions SET mined_height = NULL;
+"#];
+
+#[derive(Debug)]
+pub enum WalletDbError {
+    Sqlite(rusqlite::Error),
+    /// The file was created for the other network.
+    WrongNetwork,
+    /// The file was written by a newer version with more migrations.
+    UnsupportedSchema(u32),
+    /// A stored value that does not decode.
+    Corrupt(&'static str),
+    UnknownAccount(AccountId),
+    /// A block that does not follow the last one applied.
+    NonContiguousBlock { expected: BlockHeight, height: BlockHeight },
+    /// Scanning needs a starting block, see `WalletDb::init_chain_state`.
+    NoChainState,
+    /// A starting block given after blocks were applied, or not below every
+    /// account's birthday.
+    InvalidChainState,
+    /// A rewind below `min_height`, past the witnesses kept.
+    RewindTooDeep { height: BlockHeight, min_height: BlockHeight },
+}
+
+impl fmt::Display for WalletDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletDbError::Sqlite(e) => write!(f, "sqlite: {}", e),
+            WalletDbError::WrongNetwork => write!(f, "the wallet is for another network"),
+            WalletDbError::UnsupportedSchema(v) => write!(f, "unsupported schema version {}", v),
+            WalletDbError::Corrupt(what) => write!(f, "corrupt {} in the wallet", what),
+            WalletDbError::UnknownAccount(id) => write!(f, "unknown account {}", id),
+            WalletDbError::NonContiguousBlock { expected, height } => {
+                write!(f, "expected block {}, got {}", expected, height)
+            }
+            WalletDbError::NoChainState => write!(f, "the wallet has no starting block to scan from"),
+            WalletDbError::InvalidChainState => write!(f, "invalid starting block"),
+            WalletDbError::RewindTooDeep { height, min_height } => {
+                write!(f, "cannot rewind to {}, below {}", height, min_height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WalletDbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WalletDbError::Sqlite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for WalletDbError {
+    fn from(e: rusqlite::Error) -> Self {
+        WalletDbError::Sqlite(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, WalletDbError>;
+
+/// An output of a transaction the wallet sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentOutput {
+    pub output_index: u32,
+    pub recipient: PaymentAddress,
+    pub value: NoteValue,
+    pub memo: Memo,
+}
+
+pub struct WalletDb {
+    conn: Connection,
+    network: Network,
+}
+
+impl WalletDb {
+    /// Opens the wallet at `path`, creating it or bringing its schema up to
+    /// date if needed.
+    pub fn open<P: AsRef<Path>>(path: P, network: Network) -> Result<Self> {
+        Self::init(Connection::open(path)?, network)
+    }
+
+    pub fn open_in_memory(network: Network) -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, network)
+    }
+
+    fn init(mut conn: Connection, network: Network) -> Result<Self> {
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA synchronous = FULL;")?;
+        migrate(&mut conn)?;
+
+        let stored: Option<String> = conn
+            .query_row("SELECT network FROM settings", [], |row| row.get(0))
+            .optional()?;
+        match stored {
+            None => {
+                conn.execute("INSERT INTO settings (network) VALUES (?1)", [network_name(network)])?;
+            }
+            Some(name) if name == network_name(network) => {}
+            Some(_) => return Err(WalletDbError::WrongNetwork),
+        }
+
+        Ok(WalletDb { conn, network })
+    }
+
+    /// Adds an account that will see the notes of `ufvk` from the block at
+    /// `birthday` on.
+    pub fn add_account(
+        &mut self,
+        ufvk: &UnifiedFullViewingKey,
+        birthday: BlockHeight,
+    ) -> Result<AccountId> {
+        self.conn.execute(
+            "INSERT INTO accounts (ufvk, birthday_height) VALUES (?1, ?2)",
+            params![ufvk.encode(self.network), birthday],
+        )?;
+        Ok(self.conn.last_insert_rowid() as AccountId)
+    }
+
+    /// The earliest birthday of the accounts: a new wallet scans from there,
+    /// see `init_chain_state`.
+    pub fn birthday(&self) -> Result<Option<BlockHeight>> {
+        Ok(self.conn.query_row("SELECT MIN(birthday_height) FROM accounts", [], |row| row.get(0))?)
+    }
+
+    /// Records the block scanning starts after, with the note commitment
+    /// tree after it, e.g. from a lightwalletd `GetTreeState`. It must be
+    /// below every account's birthday, and is only accepted before any block
+    /// is applied.
+    pub fn init_chain_state<H: MerkleHash>(
+        &mut self,
+        height: BlockHeight,
+        hash: [u8; 32],
+        tree: &CommitmentTree<H>,
+    ) -> Result<()> {
+        if self.chain_tip()?.is_some() || self.birthday()?.is_some_and(|birthday| height >= birthday) {
+            return Err(WalletDbError::InvalidChainState);
+        }
+        self.conn.execute(
+            "INSERT INTO blocks (height, hash, sapling_tree) VALUES (?1, ?2, ?3)",
+            params![height, &hash[..], tree.to_bytes()],
+        )?;
+        Ok(())
+    }
+
+    pub fn accounts(&self) -> Result<Vec<(AccountId, UnifiedFullViewingKey)>> {
+        let mut stmt = self.conn.prepare("SELECT id, ufvk FROM accounts ORDER BY id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        rows.map(|row| {
+            let (id, ufvk) = row?;
+            Ok((id, self.decode_ufvk(&ufvk)?))
+        })
+        .collect()
+    }
+
+    fn decode_ufvk(&self, ufvk: &str) -> Result<UnifiedFullViewingKey> {
+        UnifiedFullViewingKey::decode(ufvk, self.network)
+            .map_err(|_| WalletDbError::Corrupt("viewing key"))
+    }
+
+    fn sapling_fvk(&self, account: AccountId) -> Result<SaplingFullViewingKey> {
+        let ufvk: String = self
+            .conn
+            .query_row("SELECT ufvk FROM accounts WHERE id = ?1", [account], |row| row.get(0))
+            .optional()?
+            .ok_or(WalletDbError::UnknownAccount(account))?;
+        self.decode_ufvk(&ufvk)?
+            .sapling()
+            .ok_or(WalletDbError::Corrupt("viewing key"))
+    }
+
+    pub fn add_address(&mut self, account: AccountId, address: &PaymentAddress) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO addresses (account_id, diversifier, address) VALUES (?1, ?2, ?3)",
+            params![account, &address.diversifier()[..], address.encode(self.network)],
+        )?;
+        Ok(())
+    }
+
+    pub fn addresses(&self, account: AccountId) -> Result<Vec<PaymentAddress>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT address FROM addresses WHERE account_id = ?1 ORDER BY diversifier")?;
+        let rows = stmt.query_map([account], |row| row.get::<_, String>(0))?;
+        rows.map(|address| {
+            PaymentAddress::decode(&address?, self.network).ok_or(WalletDbError::Corrupt("address"))
+        })
+        .collect()
+    }
+
+    /// The height of the last block applied.
+    pub fn chain_tip(&self) -> Result<Option<BlockHeight>> {
+        Ok(self.conn.query_row("SELECT MAX(height) FROM blocks", [], |row| row.get(0))?)
+    }
+
+    pub fn block_hash(&self, height: BlockHeight) -> Result<Option<[u8; 32]>> {
+        let hash: Option<Vec<u8>> = self
+            .conn
+            .query_row("SELECT hash FROM blocks WHERE height = ?1", [height], |row| row.get(0))
+            .optional()?;
+        hash.map(|hash| array(hash, "block hash")).transpose()
+    }
+
+    /// The note commitment tree after the block at `height`.
+    pub fn commitment_tree<H: MerkleHash>(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Option<CommitmentTree<H>>> {
+        let tree: Option<Option<Vec<u8>>> = self
+            .conn
+            .query_row("SELECT sapling_tree FROM blocks WHERE height = ?1", [height], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        tree.map(|tree| {
+            tree.as_deref()
+                .and_then(CommitmentTree::from_bytes)
+                .ok_or(WalletDbError::Corrupt("note commitment tree"))
+        })
+        .transpose()
+    }
+
+    /// A scanner for every account, resuming after the last block applied or
+    /// the starting block of `init_chain_state`.
+    pub fn scanner<H: MerkleHash>(
+        &self,
+        ivk_mode: IvkMode,
+        nullifier_mode: NullifierMode,
+        note_commitment_mode: NoteCommitmentMode,
+    ) -> Result<Scanner<H>> {
+        let accounts = self
+            .accounts()?
+            .into_iter()
+            .map(|(id, ufvk)| {
+                let fvk = ufvk.sapling().ok_or(WalletDbError::Corrupt("viewing key"))?;
+                Ok(ScanningAccount {
+                    id,
+                    ivk: fvk.ivk(ivk_mode),
+                    notes: self.spending_notes(id, nullifier_mode)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let height = self.chain_tip()?.ok_or(WalletDbError::NoChainState)?;
+        let tree = self.commitment_tree(height)?.expect("the tip is a stored block");
+        let hash = self.block_hash(height)?.expect("the tip is a stored block");
+        Scanner::new(accounts, tree, (height, hash), note_commitment_mode)
+            .map_err(|_| WalletDbError::Corrupt("witness"))
+    }
+
+    /// Applies a scanned block atomically. It must follow the last block
+    /// applied, if any.
+    pub fn apply_block(&mut self, block: &ScannedBlock) -> Result<()> {
+        if let Some(tip) = self.chain_tip()? {
+            if block.height != tip + 1 {
+                return Err(WalletDbError::NonContiguousBlock { expected: tip + 1, height: block.height });
+            }
+        }
+
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "INSERT INTO blocks (height, hash, sapling_tree) VALUES (?1, ?2, ?3)",
+            params![block.height, &block.hash[..], block.tree],
+        )?;
+
+        for new in &block.received {
+            let tx_id = put_transaction(&tx, &new.txid, None, Some(block.height))?;
+            let mut rcm = vec![];
+            new.note.rcm.serialize_compressed(&mut rcm).unwrap();
+            tx.execute(
+                "INSERT INTO received_notes (account_id, tx_id, output_index, diversifier, value,
+                    rcm, memo, cm, position, nullifier, height)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    new.account,
+                    tx_id,
+                    new.output_index,
+                    &new.note.diversifier[..],
+                    new.note.value.0 as i64,
+                    rcm,
+                    &new.note.memo[..],
+                    &repr_j(&new.cm)[..],
+                    new.position as i64,
+                    &new.nullifier.0[..],
+                    block.height,
+                ],
+            )?;
+        }
+
+        for (txid, nullifier) in &block.spent {
+            let ours: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM received_notes WHERE nullifier = ?1)",
+                [&nullifier.0[..]],
+                |row| row.get(0),
+            )?;
+            if ours {
+                let tx_id = put_transaction(&tx, txid, None, Some(block.height))?;
+                tx.execute(
+                    "UPDATE received_notes SET spent_in = ?1 WHERE nullifier = ?2",
+                    params![tx_id, &nullifier.0[..]],
+                )?;
+            }
+        }
+
+        for (position, witness) in &block.witnesses {
+            tx.execute(
+                "INSERT OR REPLACE INTO witnesses (note_id, height, witness)
+                 SELECT id, ?2, ?3 FROM received_notes WHERE position = ?1",
+                params![*position as i64, block.height, witness_to_bytes(witness)],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM witnesses WHERE height < ?1 AND height < (
+                SELECT MAX(height) FROM witnesses AS latest WHERE latest.note_id = witnesses.note_id
+             )",
+            [block.height.saturating_sub(WITNESS_RETENTION)],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Forgets the blocks above `height`, e.g. after a reorg. Transactions
+    /// mined in them become unmined, so notes they spent are pending spends.
+    /// Fails below `WITNESS_RETENTION` blocks under the tip, where the notes
+    /// would be left without witnesses.
+    pub fn truncate_to_height(&mut self, height: BlockHeight) -> Result<()> {
+        if let Some(tip) = self.chain_tip()? {
+            let min_height = tip.saturating_sub(WITNESS_RETENTION);
+            if height < min_height {
+                return Err(WalletDbError::RewindTooDeep { height, min_height });
+            }
+        }
+
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute("DELETE FROM blocks WHERE height > ?1", [height])?;
+        tx.execute("DELETE FROM witnesses WHERE height > ?1", [height])?;
+        tx.execute("DELETE FROM received_notes WHERE height > ?1", [height])?;
+        tx.execute("UPDATE transactions SET mined_height = NULL WHERE mined_height > ?1", [height])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records a transaction the wallet created, and marks the notes it
+    /// spends as pending spends until it is mined.
+    pub fn store_sent_transaction(
+        &mut self,
+        transaction: &Transaction,
+        outputs: &[SentOutput],
+    ) -> Result<TxId> {
+        let txid = transaction.txid();
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let tx_id = put_transaction(&tx, &txid, Some(&transaction.to_bytes()), None)?;
+
+        for spend in &transaction.sapling_bundle.spends {
+            tx.execute(
+                "UPDATE received_notes SET spent_in = ?1 WHERE nullifier = ?2",
+                params![tx_id, &spend.nullifier().0[..]],
+            )?;
+        }
+        for output in outputs {
+            tx.execute(
+                "INSERT OR REPLACE INTO sent_outputs (tx_id, output_index, address, value, memo)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    tx_id,
+                    output.output_index,
+                    output.recipient.encode(self.network),
+                    output.value.0 as i64,
+                    &output.memo.to_bytes()[..],
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(txid)
+    }
+
+    /// A transaction the wallet sent, if it has it.
+    pub fn transaction(&self, txid: &TxId) -> Result<Option<Transaction>> {
+        let raw: Option<Option<Vec<u8>>> = self
+            .conn
+            .query_row("SELECT raw FROM transactions WHERE txid = ?1", [&txid.0[..]], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        raw.flatten()
+            .map(|raw| Transaction::from_bytes(&raw).map_err(|_| WalletDbError::Corrupt("transaction")))
+            .transpose()
+    }
+
+    pub fn sent_outputs(&self, txid: &TxId) -> Result<Vec<SentOutput>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT output_index, address, value, memo FROM sent_outputs
+             JOIN transactions ON transactions.id = sent_outputs.tx_id
+             WHERE txid = ?1 ORDER BY output_index",
+        )?;
+        let rows = stmt.query_map([&txid.0[..]], |row| {
+            Ok((row.get(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?, row.get::<_, Vec<u8>>(3)?))
+        })?;
+        rows.map(|row| {
+            let (output_index, address, value, memo) = row?;
+            Ok(SentOutput {
+                output_index,
+                recipient: PaymentAddress::decode(&address, self.network)
+                    .ok_or(WalletDbError::Corrupt("address"))?,
+                value: NoteValue(value as u64),
+                memo: Memo::from_bytes(&array(memo, "memo")?)
+                    .map_err(|_| WalletDbError::Corrupt("memo"))?,
+            })
+        })
+        .collect()
+    }
+
+    /// The notes of `account`, each with its latest witness.
+    pub fn spending_notes(
+        &self,
+        account: AccountId,
+        nullifier_mode: NullifierMode,
+    ) -> Result<SpendingNotes> {
+        let fvk = self.sapling_fvk(account)?;
+        let mut notes = SpendingNotes::new(&fvk.nk, nullifier_mode);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT n.diversifier, n.value, n.rcm, n.memo, n.cm, n.position, n.nullifier, n.height,
+                s.txid, s.mined_height,
+                (SELECT witness FROM witnesses WHERE note_id = n.id ORDER BY height DESC LIMIT 1)
+             FROM received_notes AS n LEFT JOIN transactions AS s ON s.id = n.spent_in
+             WHERE n.account_id = ?1",
+        )?;
+        let rows = stmt.query_map([account], |row| {
+            Ok(StoredNote {
+                diversifier: row.get(0)?,
+                value: row.get(1)?,
+                rcm: row.get(2)?,
+                memo: row.get(3)?,
+                cm: row.get(4)?,
+                position: row.get(5)?,
+                nullifier: row.get(6)?,
+                height: row.get(7)?,
+                spent_in: row.get(8)?,
+                spent_height: row.get(9)?,
+                witness: row.get(10)?,
+            })
+        })?;
+        for row in rows {
+            if !notes.insert(row?.decode()?) {
+                return Err(WalletDbError::Corrupt("note value"));
+            }
+        }
+        Ok(notes)
+    }
+
+    /// The balance of `account` at the last block applied.
+    pub fn balance(&self, account: AccountId, min_confirmations: u32) -> Result<Balance> {
+        let tip = match self.chain_tip()? {
+            Some(tip) => tip,
+            None => return Ok(Balance::default()),
+        };
+        // The nullifier mode only matters for notes received later.
+        let notes = self.spending_notes(account, NullifierMode::default())?;
+        Ok(notes.balance(tip, min_confirmations))
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(WalletDbError::UnsupportedSchema(version));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn network_name(network: Network) -> &'static str {
+    match network {
+        Network::Main => "main",
+        Network::Test => "test",
+    }
+}
+
+/// Inserts `txid` if it is new, and fills in `raw` and `mined_height` where
+/// given. Returns its row id.
+fn put_transaction(
+    conn: &Connection,
+    txid: &TxId,
+    raw: Option<&[u8]>,
+    mined_height: Option<BlockHeight>,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO transactions (txid, raw, mined_height) VALUES (?1, ?2, ?3)
+         ON CONFLICT (txid) DO UPDATE SET
+            raw = COALESCE(excluded.raw, raw),
+            mined_height = COALESCE(excluded.mined_height, mined_height)",
+        params![&txid.0[..], raw, mined_height],
+    )?;
+    Ok(conn.query_row("SELECT id FROM transactions WHERE txid = ?1", [&txid.0[..]], |row| {
+        row.get(0)
+    })?)
+}
+
+fn array<const N: usize>(bytes: Vec<u8>, what: &'static str) -> Result<[u8; N]> {
+    bytes.try_into().map_err(|_| WalletDbError::Corrupt(what))
+}
+
+/// Each sibling in 32 bytes, followed by a byte that is 1 for a right child.
+fn witness_to_bytes(witness: &MerklePath) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(witness.len() * 33);
+    for (sibling, is_right) in witness {
+        sibling.serialize_compressed(&mut bytes).unwrap();
+        bytes.push(*is_right as u8);
+    }
+    bytes
+}
+
+/// Decodes `witness_to_bytes`. The depth is checked against the tree when
+/// the witness is resumed, by `Scanner::new`.
+fn witness_from_bytes(bytes: &[u8]) -> Option<MerklePath> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(33) {
+        return None;
+    }
+    bytes
+        .chunks(33)
+        .map(|chunk| {
+            let sibling = ConstraintF::deserialize_compressed(&chunk[..32]).ok()?;
+            match chunk[32] {
+                0 => Some((sibling, false)),
+                1 => Some((sibling, true)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// A row of `received_notes`, as stored.
+struct StoredNote {
+    diversifier: Vec<u8>,
+    value: i64,
+    rcm: Vec<u8>,
+    memo: Vec<u8>,
+    cm: Vec<u8>,
+    position: i64,
+    nullifier: Vec<u8>,
+    height: BlockHeight,
+    spent_in: Option<Vec<u8>>,
+    spent_height: Option<BlockHeight>,
+    witness: Option<Vec<u8>>,
+}
+
+impl StoredNote {
+    fn decode(self) -> Result<ReceivedNote> {
+        let status = match (self.spent_in, self.spent_height) {
+            (None, _) => NoteStatus::Unspent,
+            (Some(txid), None) => NoteStatus::PendingSpend(TxId(array(txid, "txid")?)),
+            (Some(_), Some(height)) => NoteStatus::Spent(height),
+        };
+        let witness = match self.witness {
+            Some(witness) => witness_from_bytes(&witness).ok_or(WalletDbError::Corrupt("witness"))?,
+            None => vec![],
+        };
+
+        Ok(ReceivedNote {
+            note: NotePlaintext {
+                diversifier: array(self.diversifier, "diversifier")?,
+                value: NoteValue(self.value as u64),
+                rcm: Fr::deserialize_compressed(&self.rcm[..])
+                    .map_err(|_| WalletDbError::Corrupt("rcm"))?,
+                memo: array::<MEMO_SIZE>(self.memo, "memo")?,
+            },
+            cm: abst_j(&array(self.cm, "note commitment")?)
+                .ok_or(WalletDbError::Corrupt("note commitment"))?,
+            position: self.position as u64,
+            witness,
+            nullifier: Nullifier(array(self.nullifier, "nullifier")?),
+            height: self.height,
+            status,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::fixtures::SK;
+    use crate::keygen::KeyChain;
+    use crate::merkle::{MerkleHash, PoseidonMerkleHash};
+    use crate::note::test::received;
+    use crate::scan::test::{compact_block, compact_tx};
+    use crate::scan::ScannedNote;
+    use crate::transaction::test::sample_transaction;
+
+    fn account(db: &mut WalletDb, kc: &KeyChain) -> AccountId {
+        let ufvk = UnifiedFullViewingKey::from(&SaplingFullViewingKey::from(kc));
+        db.add_account(&ufvk, 100).unwrap()
+    }
+
+    fn new_note(account: AccountId, value: u64, position: u64) -> ScannedNote {
+        let (note, cm) = received(value, position + 1);
+        ScannedNote {
+            account,
+            txid: TxId([position as u8; 32]),
+            output_index: 0,
+            note,
+            cm,
+            position,
+            nullifier: Nullifier([position as u8 + 100; 32]),
+        }
+    }
+
+    fn block(height: BlockHeight, received: Vec<ScannedNote>) -> ScannedBlock {
+        let witness = vec![(ConstraintF::from(height), true); PoseidonMerkleHash::DEPTH];
+        let witnesses = received.iter().map(|note| (note.position, witness.clone())).collect();
+        ScannedBlock { height, hash: [height as u8; 32], received, spent: vec![], witnesses, tree: vec![] }
+    }
+
+    #[test]
+    pub fn test_scanned_blocks() {
+        let mut db = WalletDb::open_in_memory(Network::Test).unwrap();
+        let sk_2: &[u8] = &[2; 32];
+        let id = account(&mut db, &KeyChain::from(SK));
+        let other = account(&mut db, &KeyChain::from(sk_2));
+        assert_eq!(db.accounts().unwrap().len(), 2);
+        assert_eq!(db.balance(id, 1).unwrap(), Balance::default());
+
+        db.apply_block(&block(100, vec![new_note(id, 10, 0), new_note(other, 5, 1)])).unwrap();
+        db.apply_block(&block(101, vec![new_note(id, 20, 2)])).unwrap();
+        assert!(matches!(
+            db.apply_block(&block(103, vec![])),
+            Err(WalletDbError::NonContiguousBlock { expected: 102, height: 103 })
+        ));
+        assert_eq!(db.chain_tip().unwrap(), Some(101));
+        assert_eq!(db.block_hash(100).unwrap(), Some([100; 32]));
+        assert_eq!(db.balance(id, 2).unwrap(), Balance { total: 30, spendable: 10, pending: 20 });
+        assert_eq!(db.balance(other, 1).unwrap().total, 5);
+
+        let notes = db.spending_notes(id, NullifierMode::Poseidon).unwrap();
+        let first = notes.notes().next().unwrap();
+        assert_eq!((first.note.clone(), first.cm), received(10, 1));
+        assert_eq!(first.witness.len(), PoseidonMerkleHash::DEPTH);
+        assert_eq!(first.anchor::<PoseidonMerkleHash>(), {
+            let witness = vec![(ConstraintF::from(100u64), true); PoseidonMerkleHash::DEPTH];
+            crate::merkle::compute_root::<PoseidonMerkleHash>(PoseidonMerkleHash::leaf(&first.cm), &witness)
+        });
+
+        // Spent in a later block, which is then reorged out.
+        let mut spend = block(102, vec![]);
+        spend.spent = vec![(TxId([9; 32]), first.nullifier.clone()), (TxId([8; 32]), Nullifier([0; 32]))];
+        db.apply_block(&spend).unwrap();
+        assert_eq!(db.balance(id, 1).unwrap().total, 20);
+        assert!(db.transaction(&TxId([8; 32])).unwrap().is_none());
+
+        db.truncate_to_height(101).unwrap();
+        assert_eq!(db.chain_tip().unwrap(), Some(101));
+        let notes = db.spending_notes(id, NullifierMode::Poseidon).unwrap();
+        assert_eq!(notes.notes().next().unwrap().status, NoteStatus::PendingSpend(TxId([9; 32])));
+
+        db.truncate_to_height(100).unwrap();
+        assert_eq!(db.balance(id, 1).unwrap().total, 10);
+    }
+
+    #[test]
+    pub fn test_deep_rewind_is_refused() {
+        let mut db = WalletDb::open_in_memory(Network::Test).unwrap();
+        let id = account(&mut db, &KeyChain::from(SK));
+        db.apply_block(&block(100, vec![new_note(id, 10, 0)])).unwrap();
+        for height in 101..=250 {
+            db.apply_block(&block(height, vec![])).unwrap();
+        }
+
+        assert!(matches!(
+            db.truncate_to_height(149),
+            Err(WalletDbError::RewindTooDeep { height: 149, min_height: 150 })
+        ));
+        assert_eq!(db.chain_tip().unwrap(), Some(250));
+
+        db.truncate_to_height(150).unwrap();
+        let notes = db.spending_notes(id, NullifierMode::Poseidon).unwrap();
+        assert_eq!(notes.notes().next().unwrap().witness.len(), PoseidonMerkleHash::DEPTH);
+    }
+
+    #[test]
+    pub fn test_sent_transaction() {
+        let mut db = WalletDb::open_in_memory(Network::Test).unwrap();
+        let kc = KeyChain::from(SK);
+        let id = account(&mut db, &kc);
+        let pa = PaymentAddress::from(kc);
+        db.add_address(id, &pa).unwrap();
+        db.add_address(id, &pa).unwrap();
+        assert_eq!(db.addresses(id).unwrap(), vec![pa.clone()]);
+
+        let transaction = sample_transaction();
+        let mut note = new_note(id, 13, 0);
+        note.nullifier = transaction.sapling_bundle.spends[0].nullifier().clone();
+        db.apply_block(&block(100, vec![note])).unwrap();
+        assert_eq!(db.balance(id, 1).unwrap().spendable, 13);
+
+        let sent = SentOutput {
+            output_index: 1,
+            recipient: pa,
+            value: NoteValue(10),
+            memo: Memo::text("invoice 42").unwrap(),
+        };
+        let txid = db.store_sent_transaction(&transaction, std::slice::from_ref(&sent)).unwrap();
+        assert_eq!(txid, transaction.txid());
+        assert_eq!(db.balance(id, 1).unwrap(), Balance { total: 13, spendable: 0, pending: 0 });
+        assert_eq!(db.transaction(&txid).unwrap().unwrap().txid(), txid);
+        assert_eq!(db.sent_outputs(&txid).unwrap(), vec![sent]);
+
+        let mut mined = block(101, vec![]);
+        mined.spent = vec![(txid, transaction.sapling_bundle.spends[0].nullifier().clone())];
+        db.apply_block(&mined).unwrap();
+        assert_eq!(db.balance(id, 1).unwrap(), Balance::default());
+        assert!(db.transaction(&txid).unwrap().is_some());
+    }
+
+    #[test]
+    pub fn test_reopen() {
+        let path = std::env::temp_dir().join(format!("wallet-db-test-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db = WalletDb::open(&path, Network::Main).unwrap();
+            let sk: &[u8] = &[3; 32];
+            let id = account(&mut db, &KeyChain::from(sk));
+            db.apply_block(&block(100, vec![new_note(id, 10, 0)])).unwrap();
+        }
+
+        assert!(matches!(WalletDb::open(&path, Network::Test), Err(WalletDbError::WrongNetwork)));
+        let db = WalletDb::open(&path, Network::Main).unwrap();
+        assert_eq!(db.chain_tip().unwrap(), Some(100));
+        assert_eq!(db.balance(1, 1).unwrap().total, 10);
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    pub fn test_open_v1_wallet() {
+        let path = std::env::temp_dir().join(format!("wallet-db-v1-test-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sk: &[u8] = &[3; 32];
+        let ufvk = UnifiedFullViewingKey::from(&SaplingFullViewingKey::from(&KeyChain::from(sk)));
+        {
+            // A wallet with a scanned note, from before the tree was stored.
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.pragma_update(None, "user_version", 1).unwrap();
+            conn.execute_batch(&format!(
+                "INSERT INTO settings (network) VALUES ('test');
+                 INSERT INTO accounts (id, ufvk, birthday_height) VALUES (1, '{}', 100);
+                 INSERT INTO blocks (height, hash) VALUES (100, x'00');
+                 INSERT INTO transactions (id, txid, mined_height) VALUES (1, x'01', 100);
+                 INSERT INTO received_notes (account_id, tx_id, output_index, diversifier, value, rcm,
+                    memo, cm, position, nullifier, height)
+                 VALUES (1, 1, 0, x'00', 10, x'00', x'00', x'00', 0, x'00', 100);",
+                ufvk.encode(Network::Test)
+            ))
+            .unwrap();
+        }
+
+        // The blocks are forgotten, to be scanned again with their trees.
+        let mut db = WalletDb::open(&path, Network::Test).unwrap();
+        assert_eq!(db.accounts().unwrap().len(), 1);
+        assert_eq!(db.chain_tip().unwrap(), None);
+        assert_eq!(db.balance(1, 1).unwrap(), Balance::default());
+        let scanner = |db: &WalletDb| {
+            db.scanner::<PoseidonMerkleHash>(IvkMode::Blake2s, NullifierMode::Poseidon, NoteCommitmentMode::Arkworks)
+        };
+        assert!(matches!(scanner(&db), Err(WalletDbError::NoChainState)));
+        db.init_chain_state(99, [99; 32], &CommitmentTree::<PoseidonMerkleHash>::new()).unwrap();
+        assert_eq!(scanner(&db).unwrap().tip(), (99, [99; 32]));
+        db.apply_block(&block(100, vec![new_note(1, 10, 0)])).unwrap();
+        assert_eq!(db.balance(1, 1).unwrap().total, 10);
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    pub fn test_scanner_resumes_from_the_wallet() {
+        let mut db = WalletDb::open_in_memory(Network::Test).unwrap();
+        let (sk, other): (&[u8], &[u8]) = (SK, &[2; 32]);
+        let kc = KeyChain::from(sk);
+        let id = account(&mut db, &kc);
+        let pa = PaymentAddress::from(KeyChain::from(sk));
+        let stranger = PaymentAddress::from(KeyChain::from(other));
+        let scanner = |db: &WalletDb| {
+            db.scanner::<PoseidonMerkleHash>(kc.ivk_mode, NullifierMode::Poseidon, NoteCommitmentMode::Arkworks)
+                .unwrap()
+        };
+
+        let tree = CommitmentTree::<PoseidonMerkleHash>::new();
+        assert_eq!(db.birthday().unwrap(), Some(100));
+        assert!(matches!(db.init_chain_state(100, [9; 32], &tree), Err(WalletDbError::InvalidChainState)));
+        db.init_chain_state(9, [9; 32], &tree).unwrap();
+        assert!(matches!(db.init_chain_state(8, [8; 32], &tree), Err(WalletDbError::InvalidChainState)));
+
+        let mut scanner_1 = scanner(&db);
+        assert_eq!(scanner_1.tip(), (9, [9; 32]));
+        for block in [
+            compact_block(10, vec![compact_tx(&stranger, 1, vec![]), compact_tx(&pa, 20, vec![])]),
+            compact_block(11, vec![compact_tx(&stranger, 2, vec![])]),
+        ] {
+            db.apply_block(&scanner_1.scan_block(&block).unwrap()).unwrap();
+        }
+
+        let mut scanner_2 = scanner(&db);
+        assert_eq!(scanner_2.tip(), scanner_1.tip());
+        assert_eq!(scanner_2.tree().root(), scanner_1.tree().root());
+
+        let block = compact_block(12, vec![compact_tx(&stranger, 3, vec![]), compact_tx(&pa, 4, vec![])]);
+        let scanned = scanner_2.scan_block(&block).unwrap();
+        assert_eq!(scanned, scanner_1.scan_block(&block).unwrap());
+        db.apply_block(&scanned).unwrap();
+
+        let notes = db.spending_notes(id, NullifierMode::Poseidon).unwrap();
+        for note in notes.notes() {
+            assert_eq!(note.anchor::<PoseidonMerkleHash>(), scanner_1.tree().root());
+        }
+        assert_eq!(db.balance(id, 2).unwrap(), Balance { total: 24, spendable: 20, pending: 4 });
+    }
+}
@@ -0,0 +1,282 @@
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use ark_crypto_primitives::commitment::pedersen::Randomness;
+use ark_ed_on_bls12_381::{EdwardsAffine, Fr};
+use ark_ff::{BigInteger, PrimeField};
+use ark_relations::r1cs::SynthesisError;
+use ark_serialize::{
+    CanonicalDeserialize, CanonicalSerialize, Compress, SerializationError, Valid, Validate,
+};
+
+use crate::circuit::{ConstraintF, Output, OutputPublicInputs, Spend, SpendPublicInputs};
+use crate::commitment::{NoteCommitmentMode, ValueCommitTrapdoor};
+use crate::keygen;
+use crate::merkle::MerkleHash;
+use crate::note::NoteValue;
+use crate::prf::prf_expand::IvkMode;
+use crate::spend_description::NullifierMode;
+
+/// Everything a Spend proof is computed from, owned so that it can be sent to
+/// another process.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct SpendWitness {
+    pub public_inputs: SpendPublicInputs,
+    pub ak: EdwardsAffine,
+    /// The spend authorization randomizer `alpha`.
+    pub alpha: Fr,
+    pub nsk: Fr,
+    pub note_value: NoteValue,
+    pub rcv: ValueCommitTrapdoor,
+    pub rcm: Fr,
+    pub g_d: EdwardsAffine,
+    /// Siblings from the leaf up, with whether the current node is the right
+    /// child. These bits are also the note's position, which the nullifier
+    /// commits to.
+    pub auth_path: Vec<(ConstraintF, bool)>,
+    pub note_commitment_mode: NoteCommitmentMode,
+    pub nullifier_mode: NullifierMode,
+    pub ivk_mode: IvkMode,
+}
+
+impl SpendWitness {
+    /// Calls `f` with the `Spend` circuit for this witness. `Spend` borrows
+    /// its scalars as bytes, so it cannot outlive this call.
+    pub fn with_circuit<H: MerkleHash, R>(&self, f: impl FnOnce(Spend<'_, H>) -> R) -> R {
+        let randomness: Vec<Option<u8>> =
+            self.alpha.into_bigint().to_bytes_le().into_iter().map(Some).collect();
+        let nsk: Vec<Option<u8>> = self.nsk.into_bigint().to_bytes_le().into_iter().map(Some).collect();
+
+        f(Spend {
+            public_inputs: Some(self.public_inputs.clone()),
+            ak: Some(self.ak),
+            sig_params: keygen::spend_auth_params().clone(),
+            randomness: &randomness,
+            nsk: &nsk,
+            note_val: Some(self.note_value.clone()),
+            rcv_old: Some(self.rcv.clone()),
+            crh_rand: Some(Randomness(self.rcm)),
+            note_commitment_mode: self.note_commitment_mode,
+            ivk_mode: self.ivk_mode,
+            gd: Some(self.g_d),
+            nullifier_mode: self.nullifier_mode,
+            auth_path: self.auth_path.iter().copied().map(Some).collect(),
+            merkle_hash: PhantomData,
+        })
+    }
+}
+
+/// Captures a filled `Spend`, e.g. to save a failing one. `sig_params` is not
+/// kept: it is the same for every spend.
+impl<H: MerkleHash> TryFrom<&Spend<'_, H>> for SpendWitness {
+    type Error = SynthesisError;
+
+    fn try_from(spend: &Spend<'_, H>) -> Result<Self, Self::Error> {
+        Ok(SpendWitness {
+            public_inputs: spend.public_inputs.clone().ok_or(SynthesisError::AssignmentMissing)?,
+            ak: spend.ak.ok_or(SynthesisError::AssignmentMissing)?,
+            alpha: scalar_from_bytes(spend.randomness)?,
+            nsk: scalar_from_bytes(spend.nsk)?,
+            note_value: spend.note_val.clone().ok_or(SynthesisError::AssignmentMissing)?,
+            rcv: spend.rcv_old.clone().ok_or(SynthesisError::AssignmentMissing)?,
+            rcm: spend.crh_rand.as_ref().ok_or(SynthesisError::AssignmentMissing)?.0,
+            g_d: spend.gd.ok_or(SynthesisError::AssignmentMissing)?,
+            auth_path: spend
+                .auth_path
+                .iter()
+                .map(|node| node.ok_or(SynthesisError::AssignmentMissing))
+                .collect::<Result<_, _>>()?,
+            note_commitment_mode: spend.note_commitment_mode,
+            nullifier_mode: spend.nullifier_mode,
+            ivk_mode: spend.ivk_mode,
+        })
+    }
+}
+
+/// Inverts the `x.into_bigint().to_bytes_le()` encoding `Spend` takes its
+/// scalars in, rejecting values that are not reduced.
+fn scalar_from_bytes(bytes: &[Option<u8>]) -> Result<Fr, SynthesisError> {
+    let bytes = bytes
+        .iter()
+        .copied()
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(SynthesisError::AssignmentMissing)?;
+
+    let x = Fr::from_le_bytes_mod_order(&bytes);
+    if x.into_bigint().to_bytes_le() != bytes {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    Ok(x)
+}
+
+/// Everything an Output proof is computed from.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct OutputWitness {
+    pub public_inputs: OutputPublicInputs,
+    pub g_d: EdwardsAffine,
+    pub pk_d: EdwardsAffine,
+    pub note_value: NoteValue,
+    pub rcv: ValueCommitTrapdoor,
+    pub rcm: Fr,
+    pub esk: Fr,
+    pub note_commitment_mode: NoteCommitmentMode,
+}
+
+impl From<&OutputWitness> for Output {
+    fn from(witness: &OutputWitness) -> Self {
+        Output {
+            public_inputs: Some(witness.public_inputs.clone()),
+            gd: Some(witness.g_d),
+            pk_d: Some(witness.pk_d),
+            v_new: Some(witness.note_value.clone()),
+            rcv_new: Some(witness.rcv.clone()),
+            rcm_new: Some(Randomness(witness.rcm)),
+            esk: Some(witness.esk),
+            note_commitment_mode: witness.note_commitment_mode,
+        }
+    }
+}
+
+impl TryFrom<&Output> for OutputWitness {
+    type Error = SynthesisError;
+
+    fn try_from(output: &Output) -> Result<Self, Self::Error> {
+        Ok(OutputWitness {
+            public_inputs: output.public_inputs.clone().ok_or(SynthesisError::AssignmentMissing)?,
+            g_d: output.gd.ok_or(SynthesisError::AssignmentMissing)?,
+            pk_d: output.pk_d.ok_or(SynthesisError::AssignmentMissing)?,
+            note_value: output.v_new.clone().ok_or(SynthesisError::AssignmentMissing)?,
+            rcv: output.rcv_new.clone().ok_or(SynthesisError::AssignmentMissing)?,
+            rcm: output.rcm_new.as_ref().ok_or(SynthesisError::AssignmentMissing)?.0,
+            esk: output.esk.ok_or(SynthesisError::AssignmentMissing)?,
+            note_commitment_mode: output.note_commitment_mode,
+        })
+    }
+}
+
+/// Serializes a fieldless enum as a one-byte tag.
+macro_rules! impl_mode_serialization {
+    ($mode:ident { $($variant:ident = $tag:literal),* $(,)? }) => {
+        impl CanonicalSerialize for $mode {
+            fn serialize_with_mode<W: Write>(
+                &self,
+                writer: W,
+                compress: Compress,
+            ) -> Result<(), SerializationError> {
+                let tag: u8 = match self {
+                    $($mode::$variant => $tag,)*
+                };
+                tag.serialize_with_mode(writer, compress)
+            }
+
+            fn serialized_size(&self, _compress: Compress) -> usize {
+                1
+            }
+        }
+
+        impl Valid for $mode {
+            fn check(&self) -> Result<(), SerializationError> {
+                Ok(())
+            }
+        }
+
+        impl CanonicalDeserialize for $mode {
+            fn deserialize_with_mode<R: Read>(
+                reader: R,
+                compress: Compress,
+                validate: Validate,
+            ) -> Result<Self, SerializationError> {
+                match u8::deserialize_with_mode(reader, compress, validate)? {
+                    $($tag => Ok($mode::$variant),)*
+                    _ => Err(SerializationError::InvalidData),
+                }
+            }
+        }
+    };
+}
+
+impl_mode_serialization!(NoteCommitmentMode { Arkworks = 0, Sapling = 1 });
+impl_mode_serialization!(NullifierMode { Poseidon = 0, Sapling = 1 });
+impl_mode_serialization!(IvkMode { Blake2s = 0, Poseidon = 1 });
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::fixtures::{output_witness, spend_witness};
+    use crate::merkle::PoseidonMerkleHash;
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem};
+
+    fn round_trip<T: CanonicalSerialize + CanonicalDeserialize + PartialEq + std::fmt::Debug>(t: &T) {
+        let mut bytes = vec![];
+        t.serialize_compressed(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), t.compressed_size());
+        assert_eq!(&T::deserialize_compressed(&bytes[..]).unwrap(), t);
+        assert!(T::deserialize_compressed(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    pub fn test_spend_witness() {
+        for (nc_mode, nf_mode) in [
+            (NoteCommitmentMode::Arkworks, NullifierMode::Poseidon),
+            (NoteCommitmentMode::Sapling, NullifierMode::Sapling),
+        ] {
+            let witness = spend_witness::<PoseidonMerkleHash>(nc_mode, nf_mode, IvkMode::Blake2s);
+            round_trip(&witness);
+
+            let cs = ConstraintSystem::new_ref();
+            witness
+                .with_circuit::<PoseidonMerkleHash, _>(|spend| {
+                    assert_eq!(SpendWitness::try_from(&spend).unwrap(), witness);
+                    spend.generate_constraints(cs.clone())
+                })
+                .unwrap();
+            assert!(cs.is_satisfied().unwrap());
+        }
+    }
+
+    #[test]
+    pub fn test_output_witness() {
+        for mode in [NoteCommitmentMode::Arkworks, NoteCommitmentMode::Sapling] {
+            let witness = output_witness(mode);
+            round_trip(&witness);
+
+            let cs = ConstraintSystem::new_ref();
+            let output = Output::from(&witness);
+            assert_eq!(OutputWitness::try_from(&output).unwrap(), witness);
+            output.generate_constraints(cs.clone()).unwrap();
+            assert!(cs.is_satisfied().unwrap());
+        }
+    }
+
+    #[test]
+    pub fn test_blank_circuits_have_no_witness() {
+        let spend = Spend::<PoseidonMerkleHash>::blank(
+            NoteCommitmentMode::Arkworks,
+            NullifierMode::Poseidon,
+            IvkMode::Blake2s,
+        );
+        assert!(SpendWitness::try_from(&spend).is_err());
+        assert!(OutputWitness::try_from(&Output::blank(NoteCommitmentMode::Arkworks)).is_err());
+    }
+
+    #[test]
+    pub fn test_unreduced_scalar_is_rejected() {
+        assert_eq!(scalar_from_bytes(&[Some(0xff); 32]), Err(SynthesisError::Unsatisfiable));
+        assert_eq!(scalar_from_bytes(&[Some(0); 31]), Err(SynthesisError::Unsatisfiable));
+        let modulus: Vec<_> = Fr::MODULUS.to_bytes_le().into_iter().map(Some).collect();
+        assert_eq!(scalar_from_bytes(&modulus), Err(SynthesisError::Unsatisfiable));
+
+        let x = Fr::from(5u64);
+        let bytes: Vec<_> = x.into_bigint().to_bytes_le().into_iter().map(Some).collect();
+        assert_eq!(scalar_from_bytes(&bytes), Ok(x));
+    }
+
+    #[test]
+    pub fn test_unknown_mode_is_rejected() {
+        assert_eq!(
+            NullifierMode::deserialize_compressed(&[1u8][..]).unwrap(),
+            NullifierMode::Sapling
+        );
+        assert!(NullifierMode::deserialize_compressed(&[2u8][..]).is_err());
+    }
+}